@@ -0,0 +1,197 @@
+//! Rate limiter throughput benchmarks.
+//!
+//! `check_scaling` runs the same number of checks split across 1 to N
+//! threads against one shared `RateLimiter`, so throughput should grow with
+//! the thread count as long as checks don't serialize on a shared lock.
+//!
+//! `multi_descriptor` measures the throughput of requests carrying several
+//! descriptors, one of them aggregated, against counters that already exist,
+//! in both the local and distributed limiters. The allocations on that path
+//! are asserted in `tests/allocations.rs`.
+
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::executor::block_on;
+
+use hivemind::grpc::proto::envoy::extensions::common::ratelimit::v3::rate_limit_descriptor::Entry;
+use hivemind::grpc::proto::envoy::extensions::common::ratelimit::v3::RateLimitDescriptor;
+use hivemind::mesh::{Cluster, ClusterConfig};
+use hivemind::ratelimit::{DistributedRateLimiter, RateLimitConfig, RateLimiter};
+
+/// Checks performed per thread in each measured iteration.
+const CHECKS_PER_THREAD: u64 = 10_000;
+
+/// Distinct API keys the checks are spread over.
+const KEYS: usize = 1024;
+
+const CONFIG: &str = r#"
+domain: bench
+descriptors:
+  - key: api_key
+    rate_limit:
+      requests_per_unit: 1000000000
+      unit: second
+"#;
+
+const MULTI_DESCRIPTOR_CONFIG: &str = r#"
+domain: bench
+descriptors:
+  - key: remote_address
+    aggregate:
+      ipv4_prefix: 24
+    rate_limit:
+      requests_per_unit: 1000000000
+      unit: second
+  - key: api_key
+    rate_limits:
+      - requests_per_unit: 1000000000
+        unit: second
+      - requests_per_unit: 1000000000
+        unit: day
+  - key: tenant
+    descriptors:
+      - key: path
+        value: "/api/*"
+        rate_limit:
+          requests_per_unit: 1000000000
+          unit: second
+  - key: user_agent
+    rate_limit:
+      requests_per_unit: 1000000000
+      unit: second
+"#;
+
+fn descriptor(key: usize) -> RateLimitDescriptor {
+    RateLimitDescriptor {
+        entries: vec![Entry {
+            key: "api_key".to_string(),
+            value: format!("key-{}", key),
+        }],
+        limit: None,
+    }
+}
+
+fn entries(pairs: &[(&str, String)]) -> RateLimitDescriptor {
+    RateLimitDescriptor {
+        entries: pairs
+            .iter()
+            .map(|(key, value)| Entry {
+                key: key.to_string(),
+                value: value.clone(),
+            })
+            .collect(),
+        limit: None,
+    }
+}
+
+/// A request with four descriptors, one of them nested and one with two limits.
+fn multi_descriptor_request(key: usize) -> Vec<RateLimitDescriptor> {
+    vec![
+        entries(&[("remote_address", format!("10.0.{}.{}", key % 256, key % 200))]),
+        entries(&[("api_key", format!("key-{}", key))]),
+        entries(&[
+            ("tenant", format!("tenant-{}", key % 16)),
+            ("path", format!("/api/items/{}", key)),
+        ]),
+        entries(&[("user_agent", "envoy/1.31".to_string())]),
+    ]
+}
+
+fn multi_descriptor(c: &mut Criterion) {
+    let config = RateLimitConfig::from_yaml(MULTI_DESCRIPTOR_CONFIG).unwrap();
+    let requests: Vec<_> = (0..KEYS).map(multi_descriptor_request).collect();
+
+    let mut group = c.benchmark_group("multi_descriptor");
+    group.throughput(Throughput::Elements(1));
+
+    let limiter = RateLimiter::with_config(config.clone());
+    for request in &requests {
+        block_on(limiter.check_rate_limits("bench", request, 1));
+    }
+    let mut i = 0;
+    group.bench_function("local", |b| {
+        b.iter(|| {
+            i = (i + 1) % KEYS;
+            block_on(limiter.check_rate_limits("bench", &requests[i], 1))
+        })
+    });
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let cluster = runtime.block_on(async {
+        let addr: std::net::SocketAddr = ([127, 0, 0, 1], 17946).into();
+        Cluster::start(ClusterConfig {
+            listen_addr: addr,
+            advertise_addr: addr,
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+    });
+    let limiter = DistributedRateLimiter::with_config(Arc::new(cluster), config);
+    for request in &requests {
+        runtime.block_on(limiter.check_rate_limits("bench", request, 1));
+    }
+    let mut i = 0;
+    group.bench_function("distributed", |b| {
+        b.iter(|| {
+            i = (i + 1) % KEYS;
+            runtime.block_on(limiter.check_rate_limits("bench", &requests[i], 1))
+        })
+    });
+
+    group.finish();
+}
+
+fn check_scaling(c: &mut Criterion) {
+    let limiter = Arc::new(RateLimiter::with_config(
+        RateLimitConfig::from_yaml(CONFIG).unwrap(),
+    ));
+    let descriptors: Arc<Vec<_>> = Arc::new((0..KEYS).map(descriptor).collect());
+
+    // Create the counters up front so the benchmark measures the check path
+    for descriptor in descriptors.iter() {
+        block_on(limiter.check_rate_limit("bench", descriptor, 1));
+    }
+
+    let max_threads = thread::available_parallelism().map_or(4, |n| n.get()).min(16);
+    let thread_counts = std::iter::successors(Some(1), |n| Some(n * 2))
+        .take_while(|&n| n <= max_threads);
+
+    let mut group = c.benchmark_group("check_scaling");
+    for threads in thread_counts {
+        group.throughput(Throughput::Elements(threads as u64 * CHECKS_PER_THREAD));
+        group.bench_with_input(BenchmarkId::from_parameter(threads), &threads, |b, &threads| {
+            b.iter_custom(|iters| {
+                let mut elapsed = Duration::ZERO;
+                for _ in 0..iters {
+                    let start = Instant::now();
+                    thread::scope(|scope| {
+                        for thread in 0..threads {
+                            let limiter = &limiter;
+                            let descriptors = &descriptors;
+                            scope.spawn(move || {
+                                for i in 0..CHECKS_PER_THREAD as usize {
+                                    let descriptor = &descriptors[(thread * 7919 + i) % KEYS];
+                                    block_on(limiter.check_rate_limit("bench", descriptor, 1));
+                                }
+                            });
+                        }
+                    });
+                    elapsed += start.elapsed();
+                }
+                elapsed
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10).measurement_time(Duration::from_secs(5));
+    targets = check_scaling, multi_descriptor
+}
+criterion_main!(benches);
@@ -17,5 +17,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             &[&proto_dir],
         )?;
 
+    // The peer service is both served and called by every node
+    tonic_build::configure()
+        .build_server(true)
+        .build_client(true)
+        .compile_protos(&[proto_dir.join("hivemind/peer/v1/peer.proto")], &[&proto_dir])?;
+
     Ok(())
 }
@@ -0,0 +1,258 @@
+//! Admin HTTP server.
+//!
+//! Serves the probes orchestrators use to manage the process:
+//!
+//! - `GET /healthz`: liveness, `200` while the process is serving
+//! - `GET /ready`: readiness, `200` once the node is ready to make rate
+//!   limit decisions and `503` until then
+//!
+//! In mesh mode, it also reports how this node's rate limit rules compare
+//! with the rest of the cluster:
+//!
+//! - `GET /config`: the configuration each live node published and, for
+//!   every domain, whether this node's rules differ from the majority (JSON)
+//! - `GET /metrics`: the same drift as Prometheus gauges
+
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::net::SocketAddr;
+
+use axum::extract::State;
+use axum::http::{header, StatusCode};
+use axum::routing::get;
+use axum::Router;
+use serde_json::json;
+use tracing::{error, info};
+
+use crate::drift::{format_hash, ConfigDrift};
+use crate::error::Result;
+use crate::readiness::Readiness;
+
+/// HTTP server for health and readiness probes.
+pub struct AdminServer {
+    /// Address to bind to
+    addr: SocketAddr,
+    /// Readiness reported by the readiness probe
+    readiness: Readiness,
+    /// Configuration drift across the cluster, in mesh mode
+    drift: Option<ConfigDrift>,
+}
+
+impl AdminServer {
+    /// Create a new admin server.
+    pub fn new(addr: SocketAddr, readiness: Readiness) -> Self {
+        Self {
+            addr,
+            readiness,
+            drift: None,
+        }
+    }
+
+    /// Report how this node's rate limit rules compare with the cluster's.
+    pub fn with_config_drift(mut self, drift: ConfigDrift) -> Self {
+        self.drift = Some(drift);
+        self
+    }
+
+    /// Build the admin routes.
+    fn router(readiness: Readiness, drift: Option<ConfigDrift>) -> Router {
+        let router = Router::new()
+            .route("/healthz", get(|| async { "ok" }))
+            .route("/ready", get(ready))
+            .with_state(readiness);
+        match drift {
+            Some(drift) => router.merge(
+                Router::new()
+                    .route("/config", get(config))
+                    .route("/metrics", get(metrics))
+                    .with_state(drift),
+            ),
+            None => router,
+        }
+    }
+
+    /// Start the admin server.
+    ///
+    /// This method will block until the server fails.
+    pub async fn serve(self) -> Result<()> {
+        self.serve_with_shutdown(std::future::pending()).await
+    }
+
+    /// Start the admin server with graceful shutdown.
+    ///
+    /// The server will shut down when the provided signal resolves.
+    pub async fn serve_with_shutdown<F>(self, signal: F) -> Result<()>
+    where
+        F: std::future::Future<Output = ()> + Send + 'static,
+    {
+        let listener = tokio::net::TcpListener::bind(self.addr).await?;
+        info!(addr = %self.addr, "Starting admin server");
+
+        axum::serve(listener, Self::router(self.readiness, self.drift))
+            .with_graceful_shutdown(signal)
+            .await
+            .inspect_err(|e| error!(error = %e, "Admin server failed"))?;
+        Ok(())
+    }
+}
+
+/// Report whether the node is ready.
+async fn ready(State(readiness): State<Readiness>) -> (StatusCode, &'static str) {
+    if readiness.is_ready() {
+        (StatusCode::OK, "ready")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "not ready")
+    }
+}
+
+/// Report the configuration of every live node and how each domain's rules compare.
+async fn config(State(drift): State<ConfigDrift>) -> ([(header::HeaderName, &'static str); 1], String) {
+    let nodes: Vec<_> = drift
+        .nodes()
+        .into_iter()
+        .map(|node| {
+            json!({
+                "node_id": node.node_id,
+                "hash": format_hash(Some(node.hash)),
+                "version": node.version,
+            })
+        })
+        .collect();
+    let domains: Vec<_> = drift
+        .domains()
+        .into_iter()
+        .map(|domain| {
+            let hashes: serde_json::Map<String, serde_json::Value> = domain
+                .nodes
+                .iter()
+                .map(|(node_id, hash)| (node_id.clone(), format_hash(*hash).into()))
+                .collect();
+            json!({
+                "domain": domain.domain,
+                "drifted": domain.drifted(),
+                "local_hash": format_hash(domain.local_hash),
+                "majority_hash": domain.has_majority.then(|| format_hash(domain.majority_hash)),
+                "nodes": hashes,
+            })
+        })
+        .collect();
+    let body = json!({ "nodes": nodes, "domains": domains });
+    ([(header::CONTENT_TYPE, "application/json")], body.to_string())
+}
+
+/// Report configuration drift as Prometheus gauges.
+async fn metrics(State(drift): State<ConfigDrift>) -> ([(header::HeaderName, &'static str); 1], String) {
+    let nodes = drift.nodes();
+    let hashes: BTreeSet<u64> = nodes.iter().map(|node| node.hash).collect();
+
+    // Writing to a String never fails
+    let mut body = String::new();
+    let _ = writeln!(body, "# HELP hivemind_config_drift Whether this node's rules for a domain differ from the cluster majority.");
+    let _ = writeln!(body, "# TYPE hivemind_config_drift gauge");
+    for domain in drift.domains() {
+        let _ = writeln!(
+            body,
+            "hivemind_config_drift{{domain=\"{}\"}} {}",
+            escape_label(&domain.domain),
+            u8::from(domain.drifted())
+        );
+    }
+    let _ = writeln!(body, "# HELP hivemind_config_nodes Live nodes that published a rate limit configuration.");
+    let _ = writeln!(body, "# TYPE hivemind_config_nodes gauge");
+    let _ = writeln!(body, "hivemind_config_nodes {}", nodes.len());
+    let _ = writeln!(body, "# HELP hivemind_config_versions Distinct rate limit configurations among live nodes.");
+    let _ = writeln!(body, "# TYPE hivemind_config_versions gauge");
+    let _ = writeln!(body, "hivemind_config_versions {}", hashes.len());
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
+
+/// Escape a Prometheus label value.
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn get_status(addr: SocketAddr, path: &str) -> String {
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response.lines().next().unwrap_or_default().to_string()
+    }
+
+    async fn get_body(addr: SocketAddr, path: &str) -> String {
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response.split_once("\r\n\r\n").map(|(_, body)| body.to_string()).unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn test_admin_probes() {
+        let readiness = Readiness::new();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = AdminServer::router(readiness.clone(), None);
+        let server = tokio::spawn(async move { axum::serve(listener, router).await });
+
+        assert_eq!(get_status(addr, "/healthz").await, "HTTP/1.1 200 OK");
+        assert_eq!(get_status(addr, "/ready").await, "HTTP/1.1 503 Service Unavailable");
+
+        readiness.mark_ready();
+        assert_eq!(get_status(addr, "/ready").await, "HTTP/1.1 200 OK");
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_admin_config_drift() {
+        use crate::mesh::{DomainDrift, NodeConfig};
+        use std::collections::BTreeMap;
+
+        let node = |node_id: &str, hash: u64| NodeConfig {
+            node_id: node_id.to_string(),
+            hash,
+            version: 1700000000,
+            domains: BTreeMap::from([("api".to_string(), hash)]),
+        };
+        let drift = ConfigDrift::new();
+        drift.update(
+            vec![node("a", 1), node("b", 2), node("c", 2)],
+            vec![DomainDrift {
+                domain: "api".to_string(),
+                local_hash: Some(1),
+                has_majority: true,
+                majority_hash: Some(2),
+                nodes: BTreeMap::from([
+                    ("a".to_string(), Some(1)),
+                    ("b".to_string(), Some(2)),
+                    ("c".to_string(), Some(2)),
+                ]),
+            }],
+        );
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = AdminServer::router(Readiness::ready(), Some(drift));
+        let server = tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let body = get_body(addr, "/config").await;
+        let report: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(report["nodes"].as_array().unwrap().len(), 3);
+        assert_eq!(report["domains"][0]["drifted"], true);
+        assert_eq!(report["domains"][0]["majority_hash"], "0000000000000002");
+
+        let body = get_body(addr, "/metrics").await;
+        assert!(body.contains("hivemind_config_drift{domain=\"api\"} 1\n"));
+        assert!(body.contains("hivemind_config_versions 2\n"));
+
+        server.abort();
+    }
+}
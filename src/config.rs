@@ -3,8 +3,11 @@
 use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
 
+use crate::ratelimit::EvaluationMode;
+use crate::readiness::NotReadyPolicy;
+
 /// Main configuration for the Hivemind service.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct HivemindConfig {
     /// Server configuration
     #[serde(default)]
@@ -19,16 +22,6 @@ pub struct HivemindConfig {
     pub mesh: MeshConfig,
 }
 
-impl Default for HivemindConfig {
-    fn default() -> Self {
-        Self {
-            server: ServerConfig::default(),
-            rate_limiting: RateLimitingConfig::default(),
-            mesh: MeshConfig::default(),
-        }
-    }
-}
-
 /// Server configuration.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServerConfig {
@@ -43,6 +36,11 @@ pub struct ServerConfig {
     /// Admin server port
     #[serde(default = "default_admin_port")]
     pub admin_port: u16,
+
+    /// Seconds to keep serving after a shutdown signal while reporting not
+    /// serving, so clients stop routing to the server before it exits
+    #[serde(default = "default_shutdown_drain")]
+    pub shutdown_drain_secs: u64,
 }
 
 impl Default for ServerConfig {
@@ -51,6 +49,7 @@ impl Default for ServerConfig {
             grpc_addr: default_grpc_addr(),
             metrics_port: default_metrics_port(),
             admin_port: default_admin_port(),
+            shutdown_drain_secs: default_shutdown_drain(),
         }
     }
 }
@@ -67,6 +66,10 @@ fn default_admin_port() -> u16 {
     8080
 }
 
+fn default_shutdown_drain() -> u64 {
+    5
+}
+
 /// Rate limiting configuration.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RateLimitingConfig {
@@ -84,6 +87,17 @@ pub struct RateLimitingConfig {
     /// Staleness threshold in milliseconds
     #[serde(default = "default_staleness_threshold")]
     pub staleness_threshold_ms: u64,
+
+    /// How the descriptors of a request are evaluated
+    #[serde(default)]
+    pub evaluation_mode: EvaluationMode,
+
+    /// Path of the file counters are snapshotted to and restored from on startup
+    pub snapshot_path: Option<String>,
+
+    /// Counter snapshot interval in seconds
+    #[serde(default = "default_snapshot_interval")]
+    pub snapshot_interval_secs: u64,
 }
 
 impl Default for RateLimitingConfig {
@@ -93,6 +107,9 @@ impl Default for RateLimitingConfig {
             config_reload_interval_secs: default_reload_interval(),
             local_cache_size: default_cache_size(),
             staleness_threshold_ms: default_staleness_threshold(),
+            evaluation_mode: EvaluationMode::default(),
+            snapshot_path: None,
+            snapshot_interval_secs: default_snapshot_interval(),
         }
     }
 }
@@ -109,6 +126,10 @@ fn default_staleness_threshold() -> u64 {
     500
 }
 
+fn default_snapshot_interval() -> u64 {
+    10
+}
+
 /// Mesh networking configuration.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MeshConfig {
@@ -123,6 +144,22 @@ pub struct MeshConfig {
     #[serde(default = "default_mesh_bind_addr")]
     pub bind_addr: SocketAddr,
 
+    /// Address (IP or hostname, optionally with a port) advertised to peers.
+    /// Detected when unset and binding to a wildcard address.
+    pub advertise_addr: Option<String>,
+
+    /// UDP address to exchange eager counter broadcasts on, for rules with
+    /// `broadcast: true`. Broadcast is disabled if unset. Broadcasts are
+    /// unauthenticated, so the address must only be reachable by peers.
+    pub broadcast_addr: Option<SocketAddr>,
+
+    /// Address to serve the peer service on, which other nodes forward hits
+    /// of strongly consistent counters to. Peers reach it at the advertised
+    /// IP. Nodes without one own no counters, and rules with `consistency:
+    /// strong` need it. The service is unauthenticated beyond checking that
+    /// callers are cluster members, so bind it to a private network only.
+    pub peer_addr: Option<SocketAddr>,
+
     /// Bootstrap peer addresses to connect to on startup.
     #[serde(default)]
     pub bootstrap_peers: Vec<String>,
@@ -150,6 +187,25 @@ pub struct MeshConfig {
     /// Time after which a suspect peer is considered failed (milliseconds).
     #[serde(default = "default_failed_timeout")]
     pub failed_timeout_ms: u64,
+
+    /// How long a joining node waits to catch up with cluster state before
+    /// reporting ready anyway (seconds).
+    #[serde(default = "default_sync_timeout")]
+    pub sync_timeout_secs: u64,
+
+    /// How rate limit requests are answered until the node is ready.
+    #[serde(default)]
+    pub not_ready_policy: NotReadyPolicy,
+
+    /// How often to compare this node's rate limit rules with the other
+    /// nodes' (seconds).
+    #[serde(default = "default_config_check_interval")]
+    pub config_check_interval_secs: u64,
+
+    /// Refuse requests with `UNAVAILABLE` for domains whose rules differ
+    /// from the cluster majority.
+    #[serde(default)]
+    pub refuse_config_drift: bool,
 }
 
 impl Default for MeshConfig {
@@ -158,6 +214,9 @@ impl Default for MeshConfig {
             enabled: default_mesh_enabled(),
             node_id: None,
             bind_addr: default_mesh_bind_addr(),
+            advertise_addr: None,
+            broadcast_addr: None,
+            peer_addr: None,
             bootstrap_peers: Vec::new(),
             gossip_interval_ms: default_gossip_interval(),
             sync_interval_ms: default_sync_interval(),
@@ -165,6 +224,10 @@ impl Default for MeshConfig {
             health_check_interval_ms: default_health_check_interval(),
             suspect_timeout_ms: default_suspect_timeout(),
             failed_timeout_ms: default_failed_timeout(),
+            sync_timeout_secs: default_sync_timeout(),
+            not_ready_policy: NotReadyPolicy::default(),
+            config_check_interval_secs: default_config_check_interval(),
+            refuse_config_drift: false,
         }
     }
 }
@@ -201,6 +264,14 @@ fn default_failed_timeout() -> u64 {
     15000
 }
 
+fn default_sync_timeout() -> u64 {
+    30
+}
+
+fn default_config_check_interval() -> u64 {
+    10
+}
+
 impl HivemindConfig {
     /// Load configuration from a file path.
     pub fn from_file(path: &str) -> crate::error::Result<Self> {
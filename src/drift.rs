@@ -0,0 +1,137 @@
+//! Rate limit configuration drift across the cluster.
+//!
+//! Nodes count against shared counters, so a node running a stale
+//! `ratelimit.yaml` enforces a different limit on the same counter than its
+//! peers. Each node publishes a hash of every domain's rules, and
+//! periodically compares its own with the hashes most live nodes have. Drift
+//! is logged when it starts and ends, reported by the admin server, and can
+//! optionally make the node refuse requests for drifted domains.
+
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+use tracing::{info, warn};
+
+use crate::mesh::{detect_drift, Cluster, DomainDrift, NodeConfig};
+
+/// Shared view of how this node's rules compare with the cluster's.
+///
+/// Clones share the same state, so the task that checks for drift and the
+/// servers that act on it each hold their own handle.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigDrift {
+    state: Arc<ArcSwap<DriftState>>,
+}
+
+/// The configurations of the live nodes, as last checked.
+#[derive(Debug, Default)]
+struct DriftState {
+    /// Configuration published by each live node
+    nodes: Vec<NodeConfig>,
+    /// Comparison of every domain's rules, sorted by domain
+    domains: Vec<DomainDrift>,
+}
+
+impl DriftState {
+    /// Check whether this node's rules for a domain differ from the cluster majority.
+    fn is_drifted(&self, domain: &str) -> bool {
+        self.domains
+            .binary_search_by(|drift| drift.domain.as_str().cmp(domain))
+            .is_ok_and(|index| self.domains[index].drifted())
+    }
+}
+
+impl ConfigDrift {
+    /// Create a drift state with no domains compared yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the configuration published by each live node, as last checked.
+    pub fn nodes(&self) -> Vec<NodeConfig> {
+        self.state.load().nodes.clone()
+    }
+
+    /// Get the latest comparison of every domain's rules, sorted by domain.
+    pub fn domains(&self) -> Vec<DomainDrift> {
+        self.state.load().domains.clone()
+    }
+
+    /// Check whether this node's rules for a domain differ from the cluster majority.
+    pub fn is_drifted(&self, domain: &str) -> bool {
+        self.state.load().is_drifted(domain)
+    }
+
+    /// Compare this node's rules with those published by the live nodes.
+    pub async fn check(&self, cluster: &Cluster) {
+        let nodes = cluster.node_configs().await;
+        let domains = detect_drift(cluster.node_id(), &nodes);
+        self.update(nodes, domains);
+    }
+
+    /// Replace the node configurations and their comparison, logging domains
+    /// that started or stopped drifting.
+    pub fn update(&self, nodes: Vec<NodeConfig>, domains: Vec<DomainDrift>) {
+        let previous = self.state.swap(Arc::new(DriftState { nodes, domains }));
+        let current = self.state.load();
+
+        for drift in &current.domains {
+            let was_drifted = previous.is_drifted(&drift.domain);
+            if drift.drifted() && !was_drifted {
+                warn!(
+                    domain = %drift.domain,
+                    local_hash = %format_hash(drift.local_hash),
+                    majority_hash = %format_hash(drift.majority_hash),
+                    nodes = drift.nodes.len(),
+                    "Rate limit configuration differs from the cluster majority"
+                );
+            } else if !drift.drifted() && was_drifted {
+                info!(domain = %drift.domain, "Rate limit configuration matches the cluster majority again");
+            }
+        }
+    }
+}
+
+/// Format a configuration hash, or `none` for a domain a node doesn't have.
+pub fn format_hash(hash: Option<u64>) -> String {
+    match hash {
+        Some(hash) => format!("{:016x}", hash),
+        None => "none".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn domain(name: &str, local_hash: Option<u64>, majority_hash: Option<u64>) -> DomainDrift {
+        DomainDrift {
+            domain: name.to_string(),
+            local_hash,
+            has_majority: true,
+            majority_hash,
+            nodes: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_config_drift() {
+        let drift = ConfigDrift::new();
+        assert!(!drift.is_drifted("api"));
+
+        drift.update(Vec::new(), vec![domain("api", Some(1), Some(2)), domain("web", Some(5), Some(5))]);
+        assert!(drift.is_drifted("api"));
+        assert!(!drift.is_drifted("web"));
+        assert!(!drift.is_drifted("unknown"));
+
+        // Clones share the state
+        let shared = drift.clone();
+        drift.update(Vec::new(), vec![domain("api", Some(2), Some(2))]);
+        assert!(!shared.is_drifted("api"));
+        assert_eq!(shared.domains().len(), 1);
+
+        assert_eq!(format_hash(Some(255)), "00000000000000ff");
+        assert_eq!(format_hash(None), "none");
+    }
+}
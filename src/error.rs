@@ -17,6 +17,10 @@ pub enum HivemindError {
     #[error("gRPC error: {0}")]
     Grpc(#[from] tonic::transport::Error),
 
+    /// Counter snapshot errors
+    #[error("Snapshot error: {0}")]
+    Snapshot(String),
+
     /// I/O errors
     #[error("I/O error: {0}")]
     Io(#[from] std::io::Error),
@@ -1,12 +1,15 @@
 //! gRPC server module for Envoy rate limit service.
 
+mod peer;
 mod server;
 mod service;
 
+pub use peer::{PeerClient, PeerServiceImpl};
 pub use server::GrpcServer;
 pub use service::RateLimitServiceImpl;
 
 // Include the generated protobuf code
+#[allow(clippy::doc_overindented_list_items)]
 pub mod proto {
     pub mod envoy {
         pub mod extensions {
@@ -26,6 +29,13 @@ pub mod proto {
             }
         }
     }
+    pub mod hivemind {
+        pub mod peer {
+            pub mod v1 {
+                tonic::include_proto!("hivemind.peer.v1");
+            }
+        }
+    }
 }
 
 // Re-export commonly used types
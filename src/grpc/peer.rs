@@ -0,0 +1,207 @@
+//! Peer service for strongly consistent counters.
+//!
+//! Mesh nodes with a peer address serve the peer service on its own listener,
+//! apart from the rate limit service, and call it on other nodes to increment
+//! the counters they own. Calls are only answered for live cluster members.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use dashmap::DashMap;
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Request, Response, Status};
+use tracing::debug;
+
+use super::proto::hivemind::peer::v1::{
+    peer_service_client::PeerServiceClient, peer_service_server::PeerService, IncrementOwnedRequest,
+    IncrementOwnedResponse, OwnedCounter, ReleaseOwnedRequest, ReleaseOwnedResponse,
+};
+use crate::mesh::{Cluster, CounterKey, OwnedIncrement};
+
+/// Implementation of the peer service, incrementing counters this node owns.
+pub struct PeerServiceImpl {
+    /// The cluster holding the owned counters
+    cluster: Arc<Cluster>,
+}
+
+impl PeerServiceImpl {
+    /// Create a new peer service for a cluster node.
+    pub fn new(cluster: Arc<Cluster>) -> Self {
+        Self { cluster }
+    }
+
+    /// Build the error for a request from a host that is not a live cluster
+    /// member, if it is one.
+    async fn non_member<T>(&self, request: &Request<T>) -> Option<Status> {
+        let is_member = match request.remote_addr() {
+            Some(remote_addr) => self.cluster.is_member_ip(remote_addr.ip()).await,
+            None => false,
+        };
+        (!is_member).then(|| {
+            debug!(remote_addr = ?request.remote_addr(), "Refusing peer request from a non-member");
+            Status::permission_denied("caller is not a cluster member")
+        })
+    }
+
+    /// Build the error for a request meant for another node, if it is one.
+    ///
+    /// The sender's view of the cluster may be stale, for example when a
+    /// restarted node reuses a previous node's address.
+    fn wrong_owner(&self, owner_node_id: &str) -> Option<Status> {
+        (owner_node_id != self.cluster.node_id()).then(|| {
+            Status::failed_precondition(format!(
+                "request for node {:?} received by node {:?}",
+                owner_node_id,
+                self.cluster.node_id()
+            ))
+        })
+    }
+}
+
+/// Parse the chitchat counter keys sent by a peer.
+fn parse_keys<'a>(keys: impl IntoIterator<Item = &'a str>) -> Option<Vec<CounterKey>> {
+    keys.into_iter().map(CounterKey::from_chitchat_key).collect()
+}
+
+#[tonic::async_trait]
+impl PeerService for PeerServiceImpl {
+    async fn increment_owned(
+        &self,
+        request: Request<IncrementOwnedRequest>,
+    ) -> Result<Response<IncrementOwnedResponse>, Status> {
+        if let Some(status) = self.non_member(&request).await {
+            return Err(status);
+        }
+        let request = request.into_inner();
+        if let Some(status) = self.wrong_owner(&request.owner_node_id) {
+            return Err(status);
+        }
+        let keys = parse_keys(request.counters.iter().map(|counter| counter.key.as_str()))
+            .ok_or_else(|| Status::invalid_argument("invalid counter key"))?;
+        let counters: Vec<OwnedIncrement> = keys
+            .into_iter()
+            .zip(&request.counters)
+            .map(|(key, counter)| OwnedIncrement {
+                key,
+                limit: counter.limit,
+                window_secs: counter.window_secs,
+            })
+            .collect();
+
+        let (committed, totals) = self
+            .cluster
+            .increment_owned(&counters, request.hits, request.atomic)
+            .await;
+        Ok(Response::new(IncrementOwnedResponse { committed, totals }))
+    }
+
+    async fn release_owned(
+        &self,
+        request: Request<ReleaseOwnedRequest>,
+    ) -> Result<Response<ReleaseOwnedResponse>, Status> {
+        if let Some(status) = self.non_member(&request).await {
+            return Err(status);
+        }
+        let request = request.into_inner();
+        if let Some(status) = self.wrong_owner(&request.owner_node_id) {
+            return Err(status);
+        }
+        let keys = parse_keys(request.keys.iter().map(String::as_str))
+            .ok_or_else(|| Status::invalid_argument("invalid counter key"))?;
+
+        self.cluster.release_owned(&keys, request.hits);
+        Ok(Response::new(ReleaseOwnedResponse {}))
+    }
+}
+
+/// Client for the peer services of other nodes.
+///
+/// Connections are opened on first use and reused for later calls to the
+/// same address.
+#[derive(Debug)]
+pub struct PeerClient {
+    /// Clients by peer service address
+    clients: DashMap<SocketAddr, PeerServiceClient<Channel>>,
+    /// Timeout for connecting to a peer and for each call
+    timeout: Duration,
+}
+
+impl PeerClient {
+    /// Create a new peer client.
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            clients: DashMap::new(),
+            timeout,
+        }
+    }
+
+    /// Get the client for a peer, connecting lazily.
+    fn client(&self, addr: SocketAddr) -> Result<PeerServiceClient<Channel>, tonic::transport::Error> {
+        if let Some(client) = self.clients.get(&addr) {
+            return Ok(client.clone());
+        }
+        let endpoint = Endpoint::from_shared(format!("http://{}", addr))?
+            .connect_timeout(self.timeout)
+            .timeout(self.timeout);
+        let client = PeerServiceClient::new(endpoint.connect_lazy());
+        self.clients.insert(addr, client.clone());
+        Ok(client)
+    }
+
+    /// Increment counters owned by the peer at `addr`.
+    ///
+    /// Returns whether the increments were committed, along with the count
+    /// of each counter.
+    pub async fn increment(
+        &self,
+        addr: SocketAddr,
+        owner_node_id: &str,
+        counters: &[OwnedIncrement],
+        hits: u64,
+        atomic: bool,
+    ) -> Result<(bool, Vec<u64>), Status> {
+        let request = IncrementOwnedRequest {
+            owner_node_id: owner_node_id.to_string(),
+            counters: counters
+                .iter()
+                .map(|counter| OwnedCounter {
+                    key: counter.key.as_chitchat_key().to_string(),
+                    limit: counter.limit,
+                    window_secs: counter.window_secs,
+                })
+                .collect(),
+            hits,
+            atomic,
+        };
+        let mut client = self.client(addr).map_err(|e| Status::internal(e.to_string()))?;
+        let response = client.increment_owned(request).await?.into_inner();
+        if response.totals.len() != counters.len() {
+            return Err(Status::internal(format!(
+                "expected {} totals from peer, got {}",
+                counters.len(),
+                response.totals.len()
+            )));
+        }
+        debug!(peer = %addr, key_count = counters.len(), "Forwarded owned counter increments");
+        Ok((response.committed, response.totals))
+    }
+
+    /// Take back hits from counters owned by the peer at `addr`.
+    pub async fn release(
+        &self,
+        addr: SocketAddr,
+        owner_node_id: &str,
+        keys: &[CounterKey],
+        hits: u64,
+    ) -> Result<(), Status> {
+        let request = ReleaseOwnedRequest {
+            owner_node_id: owner_node_id.to_string(),
+            keys: keys.iter().map(|key| key.as_chitchat_key().to_string()).collect(),
+            hits,
+        };
+        let mut client = self.client(addr).map_err(|e| Status::internal(e.to_string()))?;
+        client.release_owned(request).await?;
+        Ok(())
+    }
+}
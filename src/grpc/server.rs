@@ -2,13 +2,24 @@
 
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tonic::transport::server::{Router, TcpIncoming};
 use tonic::transport::Server;
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
 use tracing::{info, error};
 
+use super::peer::PeerServiceImpl;
 use super::proto::envoy::service::ratelimit::v3::rate_limit_service_server::RateLimitServiceServer;
+use super::proto::hivemind::peer::v1::peer_service_server::PeerServiceServer;
 use super::service::RateLimitServiceImpl;
+use crate::drift::ConfigDrift;
 use crate::error::{HivemindError, Result};
-use crate::ratelimit::{RateLimiter, RateLimiterBackend, DistributedRateLimiter};
+use crate::ratelimit::{
+    EvaluationMode, RateLimiter, RateLimiterBackend, DistributedRateLimiter, ScopedRateLimiter,
+};
+use crate::readiness::{NotReadyPolicy, Readiness, ReadinessStatus};
 
 /// gRPC server for the rate limit service.
 pub struct GrpcServer<R: RateLimiterBackend + 'static> {
@@ -16,37 +27,209 @@ pub struct GrpcServer<R: RateLimiterBackend + 'static> {
     addr: SocketAddr,
     /// The rate limiter instance
     rate_limiter: Arc<R>,
+    /// How the descriptors of a request are evaluated
+    evaluation_mode: EvaluationMode,
+    /// Whether the node is ready to make decisions
+    readiness: Readiness,
+    /// How requests are answered while the node is not ready
+    not_ready_policy: NotReadyPolicy,
+    /// How long to keep serving after the shutdown signal, while reporting not serving
+    drain_period: Duration,
+    /// Drift state of domains whose requests are refused while drifted, if refusing
+    refused_drift: Option<ConfigDrift>,
+    /// Peer service for counters this node owns, in distributed mode
+    peer_service: Option<PeerServiceServer<PeerServiceImpl>>,
+    /// Address to serve the peer service on, apart from the rate limit service
+    peer_addr: Option<SocketAddr>,
 }
 
 impl GrpcServer<RateLimiter> {
     /// Create a new gRPC server with a local rate limiter.
     pub fn new(addr: SocketAddr, rate_limiter: Arc<RateLimiter>) -> Self {
-        Self { addr, rate_limiter }
+        Self {
+            addr,
+            rate_limiter,
+            evaluation_mode: EvaluationMode::default(),
+            readiness: Readiness::ready(),
+            not_ready_policy: NotReadyPolicy::default(),
+            drain_period: Duration::ZERO,
+            refused_drift: None,
+            peer_service: None,
+            peer_addr: None,
+        }
     }
 }
 
 impl GrpcServer<DistributedRateLimiter> {
     /// Create a new gRPC server with a distributed rate limiter.
+    ///
+    /// The server can also serve the peer service, so other nodes can forward
+    /// increments of strongly consistent counters this node owns (see
+    /// [`GrpcServer::with_peer_addr`]).
     pub fn with_distributed_limiter(addr: SocketAddr, rate_limiter: Arc<DistributedRateLimiter>) -> Self {
-        Self { addr, rate_limiter }
+        let peer_service = PeerServiceServer::new(PeerServiceImpl::new(rate_limiter.cluster().clone()));
+        Self {
+            addr,
+            rate_limiter,
+            evaluation_mode: EvaluationMode::default(),
+            readiness: Readiness::ready(),
+            not_ready_policy: NotReadyPolicy::default(),
+            drain_period: Duration::ZERO,
+            refused_drift: None,
+            peer_service: Some(peer_service),
+            peer_addr: None,
+        }
+    }
+}
+
+impl GrpcServer<ScopedRateLimiter> {
+    /// Create a new gRPC server with a scoped rate limiter, counting local
+    /// limits in-process and global limits across the cluster.
+    ///
+    /// Like [`GrpcServer::with_distributed_limiter`], the server can also
+    /// serve the peer service.
+    pub fn with_scoped_limiter(addr: SocketAddr, rate_limiter: Arc<ScopedRateLimiter>) -> Self {
+        let cluster = rate_limiter.global().cluster().clone();
+        Self {
+            addr,
+            rate_limiter,
+            evaluation_mode: EvaluationMode::default(),
+            readiness: Readiness::ready(),
+            not_ready_policy: NotReadyPolicy::default(),
+            drain_period: Duration::ZERO,
+            refused_drift: None,
+            peer_service: Some(PeerServiceServer::new(PeerServiceImpl::new(cluster))),
+            peer_addr: None,
+        }
     }
 }
 
 impl<R: RateLimiterBackend + 'static> GrpcServer<R> {
+    /// Set how the descriptors of a request are evaluated.
+    pub fn with_evaluation_mode(mut self, evaluation_mode: EvaluationMode) -> Self {
+        self.evaluation_mode = evaluation_mode;
+        self
+    }
+
+    /// Set the readiness state and how requests are answered while not ready.
+    pub fn with_readiness(mut self, readiness: Readiness, policy: NotReadyPolicy) -> Self {
+        self.readiness = readiness;
+        self.not_ready_policy = policy;
+        self
+    }
+
+    /// Refuse requests with `UNAVAILABLE` for domains whose rules differ from
+    /// the cluster majority, leaving the decision to the client's failure mode.
+    pub fn with_drift_refusal(mut self, drift: ConfigDrift) -> Self {
+        self.refused_drift = Some(drift);
+        self
+    }
+
+    /// Serve the peer service on its own listener at `addr`, in distributed mode.
+    ///
+    /// The peer service is never served next to the rate limit service, so
+    /// its listener can be bound to an address only peers reach.
+    pub fn with_peer_addr(mut self, addr: SocketAddr) -> Self {
+        self.peer_addr = Some(addr);
+        self
+    }
+
+    /// Set how long to keep serving after the shutdown signal.
+    ///
+    /// The health service reports `NOT_SERVING` for this period, so clients
+    /// health checking the server stop routing to it before it exits.
+    pub fn with_drain_period(mut self, drain_period: Duration) -> Self {
+        self.drain_period = drain_period;
+        self
+    }
+
+    /// Build the server with the rate limit and health services.
+    ///
+    /// The health service reports `SERVING` only while the node is ready.
+    async fn router(self) -> Router {
+        let mut service = RateLimitServiceImpl::new(self.rate_limiter)
+            .with_evaluation_mode(self.evaluation_mode)
+            .with_readiness(self.readiness.clone(), self.not_ready_policy);
+        if let Some(drift) = self.refused_drift {
+            service = service.with_drift_refusal(drift);
+        }
+
+        let (mut reporter, health_service) = tonic_health::server::health_reporter();
+        let mut status = self.readiness.subscribe();
+        let initial = *status.borrow_and_update();
+        Self::report_health(&mut reporter, initial).await;
+        tokio::spawn(Self::watch_health(reporter, status));
+
+        Server::builder()
+            .add_service(health_service)
+            .add_service(RateLimitServiceServer::new(service))
+    }
+
+    /// Bind the peer service's listener and serve it until `stop` resolves.
+    ///
+    /// Returns `None` if the server has no peer service or no address for it.
+    fn spawn_peer_server<F>(&mut self, stop: F) -> Result<Option<tokio::task::JoinHandle<()>>>
+    where
+        F: std::future::Future<Output = ()> + Send + 'static,
+    {
+        let (Some(service), Some(addr)) = (self.peer_service.take(), self.peer_addr) else {
+            return Ok(None);
+        };
+        let incoming = TcpIncoming::new(addr, true, None)
+            .map_err(|e| HivemindError::Io(std::io::Error::other(format!("failed to bind peer service {}: {}", addr, e))))?;
+        info!(addr = %addr, "Starting gRPC server for PeerService");
+
+        Ok(Some(tokio::spawn(async move {
+            if let Err(e) = Server::builder()
+                .add_service(service)
+                .serve_with_incoming_shutdown(incoming, stop)
+                .await
+            {
+                error!(error = %e, "Peer service failed");
+            }
+        })))
+    }
+
+    /// Report readiness changes to the health service until the server is dropped.
+    async fn watch_health(mut reporter: HealthReporter, mut status: watch::Receiver<ReadinessStatus>) {
+        while status.changed().await.is_ok() {
+            let current = *status.borrow_and_update();
+            Self::report_health(&mut reporter, current).await;
+        }
+    }
+
+    /// Set the health of the server and the rate limit service.
+    async fn report_health(reporter: &mut HealthReporter, status: ReadinessStatus) {
+        let serving_status = if status == ReadinessStatus::Ready {
+            ServingStatus::Serving
+        } else {
+            ServingStatus::NotServing
+        };
+        info!(status = ?status, serving_status = ?serving_status, "Updating gRPC health status");
+
+        reporter.set_service_status("", serving_status).await;
+        reporter
+            .set_service_status(
+                <RateLimitServiceServer<RateLimitServiceImpl<R>> as tonic::server::NamedService>::NAME,
+                serving_status,
+            )
+            .await;
+    }
+
     /// Start the gRPC server.
     ///
     /// This method will block until the server is shut down.
-    pub async fn serve(self) -> Result<()> {
-        let service = RateLimitServiceImpl::new(self.rate_limiter);
-
+    pub async fn serve(mut self) -> Result<()> {
+        let addr = self.addr;
+        self.spawn_peer_server(std::future::pending())?;
         info!(
-            addr = %self.addr,
+            addr = %addr,
             "Starting gRPC server for RateLimitService"
         );
 
-        Server::builder()
-            .add_service(RateLimitServiceServer::new(service))
-            .serve(self.addr)
+        self.router()
+            .await
+            .serve(addr)
             .await
             .map_err(|e| {
                 error!(error = %e, "gRPC server failed");
@@ -56,26 +239,51 @@ impl<R: RateLimiterBackend + 'static> GrpcServer<R> {
 
     /// Start the gRPC server with graceful shutdown.
     ///
-    /// The server will shut down when the provided signal resolves.
-    pub async fn serve_with_shutdown<F>(self, signal: F) -> Result<()>
+    /// When the provided signal resolves, the node is marked as draining and
+    /// the server keeps serving for the drain period before shutting down.
+    ///
+    /// The peer service keeps serving until the rate limit service has shut
+    /// down, so peers can still forward hits while this node drains.
+    pub async fn serve_with_shutdown<F>(mut self, signal: F) -> Result<()>
     where
         F: std::future::Future<Output = ()> + Send,
     {
-        let service = RateLimitServiceImpl::new(self.rate_limiter);
-
+        let addr = self.addr;
+        let readiness = self.readiness.clone();
+        let drain_period = self.drain_period;
+        let (stop_peer, peer_stopped) = tokio::sync::oneshot::channel::<()>();
+        let peer_server = self.spawn_peer_server(async {
+            let _ = peer_stopped.await;
+        })?;
         info!(
-            addr = %self.addr,
+            addr = %addr,
             "Starting gRPC server for RateLimitService with graceful shutdown"
         );
 
-        Server::builder()
-            .add_service(RateLimitServiceServer::new(service))
-            .serve_with_shutdown(self.addr, signal)
+        let signal = async move {
+            signal.await;
+            readiness.mark_draining();
+            if !drain_period.is_zero() {
+                info!(drain_period = ?drain_period, "Draining before shutdown");
+                tokio::time::sleep(drain_period).await;
+            }
+        };
+
+        let served = self
+            .router()
+            .await
+            .serve_with_shutdown(addr, signal)
             .await
             .map_err(|e| {
                 error!(error = %e, "gRPC server failed");
                 HivemindError::Grpc(e)
-            })
+            });
+
+        drop(stop_peer);
+        if let Some(peer_server) = peer_server {
+            let _ = peer_server.await;
+        }
+        served
     }
 }
 
@@ -89,4 +297,105 @@ mod tests {
         let rate_limiter = Arc::new(RateLimiter::new());
         let _server = GrpcServer::new(addr, rate_limiter);
     }
+
+    #[tokio::test]
+    async fn test_health_follows_readiness() {
+        use tonic_health::pb::health_client::HealthClient;
+        use tonic_health::pb::HealthCheckRequest;
+
+        let addr: SocketAddr = "127.0.0.1:18961".parse().unwrap();
+        let readiness = Readiness::new();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = GrpcServer::new(addr, Arc::new(RateLimiter::new()))
+            .with_readiness(readiness.clone(), NotReadyPolicy::default())
+            .with_drain_period(Duration::from_millis(500));
+        let server = tokio::spawn(server.serve_with_shutdown(async {
+            let _ = stopped.await;
+        }));
+
+        let endpoint = tonic::transport::Endpoint::from_shared(format!("http://{}", addr)).unwrap();
+        let client = loop {
+            match endpoint.connect().await {
+                Ok(channel) => break HealthClient::new(channel),
+                Err(_) => tokio::time::sleep(Duration::from_millis(20)).await,
+            }
+        };
+        let status = |service: &str| {
+            let request = HealthCheckRequest { service: service.to_string() };
+            let mut client = client.clone();
+            async move { client.check(request).await.unwrap().into_inner().status() }
+        };
+        let service = "envoy.service.ratelimit.v3.RateLimitService";
+
+        assert_eq!(status("").await, ServingStatus::NotServing.into());
+        assert_eq!(status(service).await, ServingStatus::NotServing.into());
+
+        readiness.mark_ready();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(status("").await, ServingStatus::Serving.into());
+        assert_eq!(status(service).await, ServingStatus::Serving.into());
+
+        // The server keeps answering while draining, but reports not serving
+        stop.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(status(service).await, ServingStatus::NotServing.into());
+
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_peer_service_on_own_listener() {
+        use crate::grpc::proto::hivemind::peer::v1::peer_service_server::PeerService;
+        use crate::grpc::proto::hivemind::peer::v1::ReleaseOwnedRequest;
+        use crate::grpc::PeerClient;
+        use crate::mesh::{Cluster, ClusterConfig, CounterKey, OwnedIncrement};
+
+        let gossip_addr: SocketAddr = "127.0.0.1:18976".parse().unwrap();
+        let addr: SocketAddr = "127.0.0.1:18977".parse().unwrap();
+        let peer_addr: SocketAddr = "127.0.0.1:18978".parse().unwrap();
+        let cluster = Arc::new(
+            Cluster::start(ClusterConfig {
+                node_id: "test-node-18976".to_string(),
+                listen_addr: gossip_addr,
+                advertise_addr: gossip_addr,
+                peer_addr: Some(peer_addr),
+                ..Default::default()
+            })
+            .await
+            .unwrap(),
+        );
+        let limiter = Arc::new(DistributedRateLimiter::new(cluster.clone()));
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = GrpcServer::with_distributed_limiter(addr, limiter).with_peer_addr(peer_addr);
+        let server = tokio::spawn(server.serve_with_shutdown(async {
+            let _ = stopped.await;
+        }));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let client = PeerClient::new(Duration::from_secs(1));
+        let counter = OwnedIncrement {
+            key: CounterKey::new("test", "peer", 1000),
+            limit: 10,
+            window_secs: 60,
+        };
+        let counters = std::slice::from_ref(&counter);
+        let result = client.increment(peer_addr, "test-node-18976", counters, 1, false).await;
+        assert_eq!(result.unwrap(), (true, vec![1]));
+
+        // The rate limit listener doesn't serve the peer service
+        let status = client.increment(addr, "test-node-18976", counters, 1, false).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unimplemented);
+
+        // Callers that aren't cluster members are refused
+        let request = tonic::Request::new(ReleaseOwnedRequest {
+            owner_node_id: "test-node-18976".to_string(),
+            keys: vec![counter.key.as_chitchat_key().to_string()],
+            hits: 1,
+        });
+        let status = PeerServiceImpl::new(cluster).release_owned(request).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
 }
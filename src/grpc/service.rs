@@ -6,22 +6,75 @@ use tracing::{debug, info, instrument, warn};
 
 use super::proto::envoy::service::ratelimit::v3::{
     rate_limit_service_server::RateLimitService,
-    rate_limit_response::Code,
+    rate_limit_response::{Code, DescriptorStatus},
     RateLimitRequest, RateLimitResponse,
 };
 
-use crate::ratelimit::RateLimiterBackend;
+use crate::drift::ConfigDrift;
+use crate::ratelimit::{EvaluationMode, RateLimiterBackend};
+use crate::readiness::{NotReadyPolicy, Readiness, ReadinessStatus};
 
 /// Implementation of the Envoy RateLimitService gRPC interface.
 pub struct RateLimitServiceImpl<R: RateLimiterBackend> {
     /// The rate limiter instance
     rate_limiter: Arc<R>,
+    /// How the descriptors of a request are evaluated
+    evaluation_mode: EvaluationMode,
+    /// Whether the node is ready to make decisions
+    readiness: Readiness,
+    /// How requests are answered while the node is not ready
+    not_ready_policy: NotReadyPolicy,
+    /// Drift state of domains whose requests are refused while their rules
+    /// differ from the cluster majority, if refusing
+    refused_drift: Option<ConfigDrift>,
 }
 
 impl<R: RateLimiterBackend> RateLimitServiceImpl<R> {
     /// Create a new RateLimitServiceImpl with the given rate limiter.
     pub fn new(rate_limiter: Arc<R>) -> Self {
-        Self { rate_limiter }
+        Self {
+            rate_limiter,
+            evaluation_mode: EvaluationMode::default(),
+            readiness: Readiness::ready(),
+            not_ready_policy: NotReadyPolicy::default(),
+            refused_drift: None,
+        }
+    }
+
+    /// Set how the descriptors of a request are evaluated.
+    pub fn with_evaluation_mode(mut self, evaluation_mode: EvaluationMode) -> Self {
+        self.evaluation_mode = evaluation_mode;
+        self
+    }
+
+    /// Set the readiness state and how requests are answered while not ready.
+    pub fn with_readiness(mut self, readiness: Readiness, policy: NotReadyPolicy) -> Self {
+        self.readiness = readiness;
+        self.not_ready_policy = policy;
+        self
+    }
+
+    /// Refuse requests counted across the cluster for domains whose rules
+    /// differ from the cluster majority.
+    pub fn with_drift_refusal(mut self, drift: ConfigDrift) -> Self {
+        self.refused_drift = Some(drift);
+        self
+    }
+
+    /// Check the rate limits of a request in the configured evaluation mode.
+    async fn evaluate(&self, req: &RateLimitRequest, hits: u32) -> Vec<DescriptorStatus> {
+        match self.evaluation_mode {
+            EvaluationMode::Independent => {
+                self.rate_limiter
+                    .check_rate_limits(&req.domain, &req.descriptors, hits)
+                    .await
+            }
+            EvaluationMode::Atomic => {
+                self.rate_limiter
+                    .check_rate_limits_atomic(&req.domain, &req.descriptors, hits)
+                    .await
+            }
+        }
     }
 }
 
@@ -63,26 +116,59 @@ impl<R: RateLimiterBackend + 'static> RateLimitService for RateLimitServiceImpl<
             return Err(Status::invalid_argument("at least one descriptor is required"));
         }
 
+        // Decisions on rules the rest of the cluster doesn't enforce would
+        // count against the same counters with different limits, which
+        // doesn't apply to descriptors this node counts alone
+        if self.refused_drift.as_ref().is_some_and(|drift| drift.is_drifted(&req.domain))
+            && self.rate_limiter.counts_across_cluster(&req.domain, &req.descriptors)
+        {
+            warn!(domain = %req.domain, "Refusing request for domain with drifted configuration");
+            return Err(Status::unavailable(format!(
+                "rate limit configuration for domain {:?} differs from the cluster",
+                req.domain
+            )));
+        }
+
         // Get the number of hits to add (default to 1 if not specified)
         let hits = if req.hits_addend == 0 { 1 } else { req.hits_addend };
 
-        // Check rate limits for each descriptor
-        let mut statuses = Vec::with_capacity(req.descriptors.len());
-        let mut overall_code = Code::Ok;
-
-        for descriptor in &req.descriptors {
-            let status = self.rate_limiter
-                .check_rate_limit(&req.domain, descriptor, hits)
-                .await;
+        // Until the node is ready its counts may be incomplete, but a
+        // draining node's counts are complete
+        let policy = if self.readiness.status() == ReadinessStatus::Starting {
+            debug!(policy = ?self.not_ready_policy, "Node not ready, applying not-ready policy");
+            self.not_ready_policy
+        } else {
+            NotReadyPolicy::Evaluate
+        };
 
-            // If any descriptor is over limit, the overall response is over limit
-            if status.code() == Code::OverLimit {
-                overall_code = Code::OverLimit;
+        // Check rate limits for each descriptor
+        let mut statuses = match policy {
+            NotReadyPolicy::Unavailable => {
+                return Err(Status::unavailable("rate limit service is not ready"));
+            }
+            NotReadyPolicy::Deny => req
+                .descriptors
+                .iter()
+                .map(|_| DescriptorStatus {
+                    code: Code::OverLimit.into(),
+                    ..Default::default()
+                })
+                .collect(),
+            NotReadyPolicy::Evaluate | NotReadyPolicy::Allow => self.evaluate(&req, hits).await,
+        };
+        if policy == NotReadyPolicy::Allow {
+            for status in &mut statuses {
+                status.set_code(Code::Ok);
             }
-
-            statuses.push(status);
         }
 
+        // If any descriptor is over limit, the overall response is over limit
+        let overall_code = if statuses.iter().any(|s| s.code() == Code::OverLimit) {
+            Code::OverLimit
+        } else {
+            Code::Ok
+        };
+
         let response = RateLimitResponse {
             overall_code: overall_code.into(),
             statuses,
@@ -173,4 +259,261 @@ mod tests {
         assert_eq!(response.overall_code, i32::from(Code::Ok));
         assert_eq!(response.statuses.len(), 1);
     }
+
+    /// Backend that blocks every check until all descriptors of a request
+    /// have started, so it only completes if they are evaluated concurrently.
+    struct BarrierBackend {
+        barrier: tokio::sync::Barrier,
+    }
+
+    #[tonic::async_trait]
+    impl RateLimiterBackend for BarrierBackend {
+        async fn check_rate_limit(
+            &self,
+            _domain: &str,
+            descriptor: &RateLimitDescriptor,
+            _hits: u32,
+        ) -> DescriptorStatus {
+            self.barrier.wait().await;
+            let code = if descriptor.entries[0].value == "over" {
+                Code::OverLimit
+            } else {
+                Code::Ok
+            };
+            DescriptorStatus {
+                code: code.into(),
+                ..Default::default()
+            }
+        }
+
+        async fn check_rate_limits_atomic(
+            &self,
+            domain: &str,
+            descriptors: &[RateLimitDescriptor],
+            hits: u32,
+        ) -> Vec<DescriptorStatus> {
+            // Checked together so every descriptor reaches the barrier
+            self.check_rate_limits(domain, descriptors, hits).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_descriptors_evaluated_concurrently_in_order() {
+        let values = ["ok", "over", "ok"];
+        let rate_limiter = Arc::new(BarrierBackend {
+            barrier: tokio::sync::Barrier::new(values.len()),
+        });
+
+        for mode in [EvaluationMode::Independent, EvaluationMode::Atomic] {
+            let service = RateLimitServiceImpl::new(rate_limiter.clone()).with_evaluation_mode(mode);
+            let request = Request::new(RateLimitRequest {
+                domain: "test".to_string(),
+                descriptors: values
+                    .iter()
+                    .map(|v| RateLimitDescriptor {
+                        entries: vec![Entry { key: "key".to_string(), value: v.to_string() }],
+                        limit: None,
+                    })
+                    .collect(),
+                hits_addend: 1,
+            });
+
+            let response = tokio::time::timeout(
+                std::time::Duration::from_secs(5),
+                service.should_rate_limit(request),
+            )
+            .await
+            .expect("descriptors were not evaluated concurrently")
+            .unwrap()
+            .into_inner();
+
+            assert_eq!(response.overall_code, i32::from(Code::OverLimit));
+            let codes: Vec<Code> = response.statuses.iter().map(|s| s.code()).collect();
+            assert_eq!(codes, vec![Code::Ok, Code::OverLimit, Code::Ok]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_atomic_mode_rejects_without_consuming_quota() {
+        use crate::ratelimit::RateLimitConfig;
+
+        let yaml = r#"
+domain: test
+descriptors:
+  - key: user
+    rate_limit:
+      requests_per_unit: 1
+      unit: second
+  - key: org
+    rate_limit:
+      requests_per_unit: 10
+      unit: second
+"#;
+        let config = RateLimitConfig::from_yaml(yaml).unwrap();
+        let rate_limiter = Arc::new(RateLimiter::with_config(config));
+        let service = RateLimitServiceImpl::new(rate_limiter.clone())
+            .with_evaluation_mode(EvaluationMode::Atomic);
+
+        let descriptors = vec![
+            RateLimitDescriptor {
+                entries: vec![Entry { key: "user".to_string(), value: "alice".to_string() }],
+                limit: None,
+            },
+            RateLimitDescriptor {
+                entries: vec![Entry { key: "org".to_string(), value: "acme".to_string() }],
+                limit: None,
+            },
+        ];
+
+        for expected in [Code::Ok, Code::OverLimit] {
+            let request = Request::new(RateLimitRequest {
+                domain: "test".to_string(),
+                descriptors: descriptors.clone(),
+                hits_addend: 1,
+            });
+            let response = service.should_rate_limit(request).await.unwrap().into_inner();
+            assert_eq!(response.overall_code, i32::from(expected));
+        }
+
+        assert_eq!(rate_limiter.get_counter_value("test", &descriptors[1]), Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_not_ready_policies() {
+        use crate::ratelimit::RateLimitConfig;
+
+        let yaml = r#"
+domain: test
+descriptors:
+  - key: user
+    rate_limit:
+      requests_per_unit: 1
+      unit: minute
+"#;
+        let descriptors = vec![RateLimitDescriptor {
+            entries: vec![Entry { key: "user".to_string(), value: "alice".to_string() }],
+            limit: None,
+        }];
+        let request = || {
+            Request::new(RateLimitRequest {
+                domain: "test".to_string(),
+                descriptors: descriptors.clone(),
+                hits_addend: 1,
+            })
+        };
+
+        let rate_limiter = Arc::new(RateLimiter::with_config(RateLimitConfig::from_yaml(yaml).unwrap()));
+        let readiness = Readiness::new();
+        let service = |policy| {
+            RateLimitServiceImpl::new(rate_limiter.clone()).with_readiness(readiness.clone(), policy)
+        };
+
+        // Deny rejects without counting
+        let response = service(NotReadyPolicy::Deny).should_rate_limit(request()).await.unwrap();
+        assert_eq!(response.into_inner().overall_code, i32::from(Code::OverLimit));
+        assert_eq!(rate_limiter.get_counter_value("test", &descriptors[0]), None);
+
+        // Unavailable leaves the decision to the client
+        let status = service(NotReadyPolicy::Unavailable).should_rate_limit(request()).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+
+        // Allow counts but admits requests over the limit
+        for _ in 0..2 {
+            let response = service(NotReadyPolicy::Allow).should_rate_limit(request()).await.unwrap();
+            assert_eq!(response.into_inner().overall_code, i32::from(Code::Ok));
+        }
+        assert_eq!(rate_limiter.get_counter_value("test", &descriptors[0]), Some(2));
+
+        // Once ready, and while draining, requests are evaluated whatever the policy
+        readiness.mark_ready();
+        let response = service(NotReadyPolicy::Deny).should_rate_limit(request()).await.unwrap();
+        assert_eq!(response.into_inner().overall_code, i32::from(Code::OverLimit));
+        assert_eq!(rate_limiter.get_counter_value("test", &descriptors[0]), Some(3));
+
+        readiness.mark_draining();
+        let response = service(NotReadyPolicy::Unavailable).should_rate_limit(request()).await.unwrap();
+        assert_eq!(response.into_inner().overall_code, i32::from(Code::OverLimit));
+        assert_eq!(rate_limiter.get_counter_value("test", &descriptors[0]), Some(4));
+    }
+
+    #[tokio::test]
+    async fn test_drifted_domain_refused() {
+        use crate::mesh::{Cluster, ClusterConfig, DomainDrift};
+        use crate::ratelimit::{DistributedRateLimiter, RateLimitConfig, ScopedRateLimiter};
+
+        let config = RateLimitConfig::from_yaml(
+            r#"
+domains:
+  stale:
+    domain: stale
+    descriptors:
+      - key: upstream
+        rate_limit:
+          requests_per_unit: 10
+          unit: second
+          scope: local
+      - key: user
+        rate_limit:
+          requests_per_unit: 10
+          unit: second
+"#,
+        )
+        .unwrap();
+        let request = |domain: &str, keys: &[&str]| {
+            Request::new(RateLimitRequest {
+                domain: domain.to_string(),
+                descriptors: keys
+                    .iter()
+                    .map(|key| RateLimitDescriptor {
+                        entries: vec![Entry { key: key.to_string(), value: "a".to_string() }],
+                        limit: None,
+                    })
+                    .collect(),
+                hits_addend: 1,
+            })
+        };
+        let drift = ConfigDrift::new();
+        drift.update(Vec::new(), vec![DomainDrift {
+            domain: "stale".to_string(),
+            local_hash: Some(1),
+            has_majority: true,
+            majority_hash: Some(2),
+            nodes: Default::default(),
+        }]);
+
+        let addr: std::net::SocketAddr = ([127, 0, 0, 1], 18975).into();
+        let cluster = Arc::new(
+            Cluster::start(ClusterConfig {
+                node_id: "test-node-18975".to_string(),
+                listen_addr: addr,
+                advertise_addr: addr,
+                cluster_id: "test-cluster".to_string(),
+                ..Default::default()
+            })
+            .await
+            .unwrap(),
+        );
+        {
+            let limiter = ScopedRateLimiter::new(DistributedRateLimiter::with_config(cluster.clone(), config));
+            let service = RateLimitServiceImpl::new(Arc::new(limiter)).with_drift_refusal(drift.clone());
+
+            // Requests with a descriptor counted across the cluster are refused
+            let status = service.should_rate_limit(request("stale", &["user"])).await.unwrap_err();
+            assert_eq!(status.code(), tonic::Code::Unavailable);
+            let status = service
+                .should_rate_limit(request("stale", &["upstream", "user"]))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::Unavailable);
+
+            // Descriptors counted by this node alone are still answered
+            assert!(service.should_rate_limit(request("stale", &["upstream"])).await.is_ok());
+            assert!(service.should_rate_limit(request("current", &["user"])).await.is_ok());
+        }
+        Arc::try_unwrap(cluster).unwrap().shutdown().await.unwrap();
+
+        // A local rate limiter counts every descriptor alone
+        let service = RateLimitServiceImpl::new(Arc::new(RateLimiter::new())).with_drift_refusal(drift);
+        assert!(service.should_rate_limit(request("stale", &["user"])).await.is_ok());
+    }
 }
@@ -4,8 +4,11 @@
 //! with Envoy Proxy's global rate limiting API. It uses a peer-to-peer mesh
 //! architecture for state synchronization without relying on centralized storage.
 
+pub mod admin;
 pub mod grpc;
 pub mod ratelimit;
 pub mod config;
+pub mod drift;
 pub mod error;
 pub mod mesh;
+pub mod readiness;
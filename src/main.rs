@@ -1,13 +1,21 @@
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
+use anyhow::Context;
 use clap::Parser;
 use tokio::signal;
 use tracing::{info, warn, Level};
-use tracing_subscriber;
 
+use hivemind::admin::AdminServer;
 use hivemind::config::HivemindConfig;
+use hivemind::drift::ConfigDrift;
 use hivemind::grpc::GrpcServer;
-use hivemind::mesh::{Cluster, ClusterConfig};
-use hivemind::ratelimit::{RateLimiter, RateLimitConfig, DistributedRateLimiter};
+use hivemind::mesh::{resolve_advertise_addr, Cluster, ClusterConfig};
+use hivemind::ratelimit::{
+    restore_counters, save_counters, spawn_snapshots, EvaluationMode, RateLimiter, RateLimitConfig,
+    DistributedRateLimiter, ScopedRateLimiter, SnapshotSource,
+};
+use hivemind::readiness::{NotReadyPolicy, Readiness};
 
 /// Hivemind - Distributed rate limiting service for Envoy Proxy
 #[derive(Parser, Debug)]
@@ -22,6 +30,14 @@ struct Args {
     #[arg(short = 'a', long = "addr", default_value = "127.0.0.1:8081")]
     addr: String,
 
+    /// Admin HTTP port for health and readiness probes, bound on the gRPC address's IP
+    #[arg(long = "admin-port")]
+    admin_port: Option<u16>,
+
+    /// Seconds to keep serving after a shutdown signal while reporting not serving
+    #[arg(long = "shutdown-drain")]
+    shutdown_drain: Option<u64>,
+
     /// Enable mesh networking for distributed rate limiting
     #[arg(long = "mesh", default_value = "false")]
     mesh_enabled: bool,
@@ -34,9 +50,51 @@ struct Args {
     #[arg(long = "mesh-addr", default_value = "0.0.0.0:7946")]
     mesh_addr: String,
 
+    /// Address advertised to peers: an IP or hostname, optionally with a port.
+    /// Detected from the pod IP or primary interface if the mesh address is a wildcard
+    #[arg(long = "advertise-addr")]
+    advertise_addr: Option<String>,
+
+    /// UDP address to push counters of rules with `broadcast: true` to peers on.
+    /// Peers reach it at the advertised IP. Datagrams are unauthenticated JSON,
+    /// only accepted from the address the sender gossips, so a host that can
+    /// spoof a member's address can inflate counts: bind it to a private network only
+    #[arg(long = "broadcast-addr")]
+    broadcast_addr: Option<std::net::SocketAddr>,
+
+    /// Address to serve the peer service for strongly consistent counters on.
+    /// Peers reach it at the advertised IP. Required by rules with
+    /// `consistency: strong`: bind it to a private network only
+    #[arg(long = "peer-addr")]
+    peer_addr: Option<std::net::SocketAddr>,
+
     /// Bootstrap peer addresses (comma-separated)
     #[arg(long = "peers")]
     bootstrap_peers: Option<String>,
+
+    /// Seconds to wait for cluster state to sync before reporting ready anyway
+    #[arg(long = "sync-timeout")]
+    sync_timeout: Option<u64>,
+
+    /// How requests are answered until the node is ready: evaluate, allow, deny or unavailable
+    #[arg(long = "not-ready-policy")]
+    not_ready_policy: Option<NotReadyPolicy>,
+
+    /// Refuse requests for domains whose rules differ from the cluster majority
+    #[arg(long = "refuse-config-drift", default_value = "false")]
+    refuse_config_drift: bool,
+
+    /// Only consume quota when every descriptor in a request is within its limit
+    #[arg(long = "atomic", default_value = "false")]
+    atomic: bool,
+
+    /// File to snapshot counters to, and restore them from on startup
+    #[arg(long = "snapshot-path")]
+    snapshot_path: Option<String>,
+
+    /// Counter snapshot interval in seconds
+    #[arg(long = "snapshot-interval")]
+    snapshot_interval: Option<u64>,
 }
 
 #[tokio::main]
@@ -64,11 +122,48 @@ async fn main() -> anyhow::Result<()> {
     if let Ok(addr) = args.addr.parse() {
         config.server.grpc_addr = addr;
     }
+    if args.atomic {
+        config.rate_limiting.evaluation_mode = EvaluationMode::Atomic;
+    }
+    if let Some(ref snapshot_path) = args.snapshot_path {
+        config.rate_limiting.snapshot_path = Some(snapshot_path.clone());
+    }
+    if let Some(interval) = args.snapshot_interval {
+        config.rate_limiting.snapshot_interval_secs = interval;
+    }
+    if let Some(admin_port) = args.admin_port {
+        config.server.admin_port = admin_port;
+    }
+    if let Some(shutdown_drain) = args.shutdown_drain {
+        config.server.shutdown_drain_secs = shutdown_drain;
+    }
+    if let Some(sync_timeout) = args.sync_timeout {
+        config.mesh.sync_timeout_secs = sync_timeout;
+    }
+    if let Some(policy) = args.not_ready_policy {
+        config.mesh.not_ready_policy = policy;
+    }
+    if let Some(ref advertise_addr) = args.advertise_addr {
+        config.mesh.advertise_addr = Some(advertise_addr.clone());
+    }
+    if let Some(broadcast_addr) = args.broadcast_addr {
+        config.mesh.broadcast_addr = Some(broadcast_addr);
+    }
+    if let Some(peer_addr) = args.peer_addr {
+        config.mesh.peer_addr = Some(peer_addr);
+    }
+    if args.refuse_config_drift {
+        config.mesh.refuse_config_drift = true;
+    }
 
-    info!(grpc_addr = %config.server.grpc_addr, "Configuration loaded");
+    info!(
+        grpc_addr = %config.server.grpc_addr,
+        evaluation_mode = ?config.rate_limiting.evaluation_mode,
+        "Configuration loaded"
+    );
 
     // Load rate limit rules from configuration file/directory
-    let rate_limit_config = load_rate_limit_config(&config);
+    let rate_limit_config = load_rate_limit_config(&config)?;
 
     // Initialize and run the gRPC server with the appropriate rate limiter
     if args.mesh_enabled {
@@ -83,41 +178,95 @@ async fn main() -> anyhow::Result<()> {
                 .collect())
             .unwrap_or_default();
 
+        let advertise_addr = resolve_advertise_addr(config.mesh.advertise_addr.as_deref(), mesh_addr).await?;
+
+        // Peers forward increments of counters this node owns to its peer service
+        let peer_addr = match config.mesh.peer_addr {
+            Some(bind) if bind.ip().is_unspecified() => {
+                Some(std::net::SocketAddr::new(advertise_addr.ip(), bind.port()))
+            }
+            Some(bind) if bind.ip().is_loopback() && !advertise_addr.ip().is_loopback() => {
+                anyhow::bail!("peer address {} is not reachable by peers at {}", bind, advertise_addr.ip());
+            }
+            peer_addr => peer_addr,
+        };
+        if peer_addr.is_none() && rate_limit_config.has_strong_limits() {
+            anyhow::bail!("rules with `consistency: strong` need a peer address (`--peer-addr`)");
+        }
+
         let cluster_config = ClusterConfig {
             node_id: args.node_id.clone().unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
             listen_addr: mesh_addr,
-            advertise_addr: mesh_addr,
+            advertise_addr,
             seed_nodes,
             cluster_id: "hivemind".to_string(),
+            broadcast_listen_addr: config.mesh.broadcast_addr,
+            peer_addr,
             ..Default::default()
         };
 
         let cluster = Arc::new(Cluster::start(cluster_config).await
             .expect("Failed to start cluster"));
 
-        let distributed_limiter = Arc::new(DistributedRateLimiter::with_config(
+        // Limits with local scope are counted in-process, the rest across the cluster
+        let scoped_limiter = Arc::new(ScopedRateLimiter::new(DistributedRateLimiter::with_config(
             cluster.clone(),
             rate_limit_config,
-        ));
+        )));
 
         info!(
             node_id = %cluster.node_id(),
             mesh_addr = %args.mesh_addr,
+            advertise_addr = %advertise_addr,
             "Distributed rate limiter initialized with cluster"
         );
 
-        let grpc_server = GrpcServer::with_distributed_limiter(config.server.grpc_addr, distributed_limiter);
+        let snapshots = start_snapshots(&config, scoped_limiter.clone()).await;
+
+        // Peers compare their rules with the ones published here
+        scoped_limiter.global().publish_config().await;
+        let drift = ConfigDrift::new();
+        spawn_drift_check(cluster.clone(), drift.clone(), &config);
+
+        let readiness = Readiness::new();
+        spawn_sync_wait(cluster.clone(), readiness.clone(), &config);
+        let admin_server = start_admin_server(&config, readiness.clone(), Some(drift.clone()));
+
+        let mut grpc_server = GrpcServer::with_scoped_limiter(config.server.grpc_addr, scoped_limiter)
+            .with_evaluation_mode(config.rate_limiting.evaluation_mode)
+            .with_readiness(readiness, config.mesh.not_ready_policy)
+            .with_drain_period(Duration::from_secs(config.server.shutdown_drain_secs));
+        if config.mesh.refuse_config_drift {
+            grpc_server = grpc_server.with_drift_refusal(drift);
+        }
+        if let Some(peer_addr) = config.mesh.peer_addr {
+            info!("Serving peer service on {}", peer_addr);
+            grpc_server = grpc_server.with_peer_addr(peer_addr);
+        }
 
         info!("Starting gRPC server on {}", config.server.grpc_addr);
         grpc_server.serve_with_shutdown(shutdown_signal()).await?;
+        admin_server.abort();
+        stop_snapshots(&config, snapshots).await;
     } else {
         let rate_limiter = Arc::new(RateLimiter::with_config(rate_limit_config));
         info!("Local rate limiter initialized");
 
-        let grpc_server = GrpcServer::new(config.server.grpc_addr, rate_limiter);
+        let snapshots = start_snapshots(&config, rate_limiter.clone()).await;
+
+        // The rules are loaded, so a local node is ready straight away
+        let readiness = Readiness::ready();
+        let admin_server = start_admin_server(&config, readiness.clone(), None);
+
+        let grpc_server = GrpcServer::new(config.server.grpc_addr, rate_limiter)
+            .with_evaluation_mode(config.rate_limiting.evaluation_mode)
+            .with_readiness(readiness, NotReadyPolicy::default())
+            .with_drain_period(Duration::from_secs(config.server.shutdown_drain_secs));
 
         info!("Starting gRPC server on {}", config.server.grpc_addr);
         grpc_server.serve_with_shutdown(shutdown_signal()).await?;
+        admin_server.abort();
+        stop_snapshots(&config, snapshots).await;
     }
 
     info!("Hivemind Rate Limiting Service stopped");
@@ -125,30 +274,99 @@ async fn main() -> anyhow::Result<()> {
 }
 
 /// Load rate limit configuration from the configured file path.
-fn load_rate_limit_config(config: &HivemindConfig) -> RateLimitConfig {
-    if let Some(ref config_path) = config.rate_limiting.config_path {
-        match RateLimitConfig::from_file(config_path) {
-            Ok(cfg) => {
-                info!(
-                    path = %config_path,
-                    domain_count = cfg.domains.len(),
-                    "Rate limit configuration loaded"
-                );
-                return cfg;
-            }
-            Err(e) => {
-                warn!(
-                    path = %config_path,
-                    error = %e,
-                    "Failed to load rate limit configuration, using defaults"
-                );
-            }
-        }
-    } else {
+///
+/// A configured file that fails to load stops startup, so the node never
+/// reports itself ready while enforcing rules other than the configured ones.
+fn load_rate_limit_config(config: &HivemindConfig) -> anyhow::Result<RateLimitConfig> {
+    let Some(ref config_path) = config.rate_limiting.config_path else {
         info!("No rate limit configuration path specified, using defaults");
+        return Ok(RateLimitConfig::new());
+    };
+
+    let cfg = RateLimitConfig::from_file(config_path)
+        .with_context(|| format!("failed to load rate limit configuration from {}", config_path))?;
+    info!(
+        path = %config_path,
+        domain_count = cfg.domains.len(),
+        "Rate limit configuration loaded"
+    );
+    Ok(cfg)
+}
+
+/// Restore counters from the configured snapshot file and start snapshotting them.
+async fn start_snapshots(
+    config: &HivemindConfig,
+    source: Arc<dyn SnapshotSource>,
+) -> Option<(Arc<dyn SnapshotSource>, tokio::task::JoinHandle<()>)> {
+    let path = config.rate_limiting.snapshot_path.as_ref()?;
+    restore_counters(source.as_ref(), Path::new(path)).await;
+
+    let interval = Duration::from_secs(config.rate_limiting.snapshot_interval_secs.max(1));
+    info!(path = %path, interval = ?interval, "Snapshotting rate limit counters");
+    let task = spawn_snapshots(source.clone(), path, interval);
+    Some((source, task))
+}
+
+/// Stop snapshotting counters and take a final snapshot.
+async fn stop_snapshots(
+    config: &HivemindConfig,
+    snapshots: Option<(Arc<dyn SnapshotSource>, tokio::task::JoinHandle<()>)>,
+) {
+    let (Some((source, task)), Some(path)) = (snapshots, &config.rate_limiting.snapshot_path) else {
+        return;
+    };
+    task.abort();
+    match save_counters(source.as_ref(), Path::new(path)).await {
+        Ok(()) => info!(path = %path, "Saved final counter snapshot"),
+        Err(e) => warn!(path = %path, error = %e, "Failed to save final counter snapshot"),
     }
+}
+
+/// Mark the node ready once it has caught up with cluster state, or the sync timeout elapses.
+fn spawn_sync_wait(cluster: Arc<Cluster>, readiness: Readiness, config: &HivemindConfig) {
+    let timeout = Duration::from_secs(config.mesh.sync_timeout_secs);
+    info!(
+        timeout = ?timeout,
+        policy = ?config.mesh.not_ready_policy,
+        "Waiting to catch up with cluster state"
+    );
+    tokio::spawn(async move {
+        if !cluster.wait_for_sync(timeout).await {
+            warn!(timeout = ?timeout, "Timed out catching up with cluster state, reporting ready");
+        }
+        readiness.mark_ready();
+    });
+}
 
-    RateLimitConfig::new()
+/// Periodically compare this node's rate limit rules with the other nodes'.
+fn spawn_drift_check(cluster: Arc<Cluster>, drift: ConfigDrift, config: &HivemindConfig) {
+    let interval = Duration::from_secs(config.mesh.config_check_interval_secs.max(1));
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            drift.check(&cluster).await;
+        }
+    });
+}
+
+/// Start the admin server for health and readiness probes, and config
+/// drift reports in mesh mode.
+fn start_admin_server(
+    config: &HivemindConfig,
+    readiness: Readiness,
+    drift: Option<ConfigDrift>,
+) -> tokio::task::JoinHandle<()> {
+    let addr = std::net::SocketAddr::new(config.server.grpc_addr.ip(), config.server.admin_port);
+    tokio::spawn(async move {
+        let mut admin_server = AdminServer::new(addr, readiness);
+        if let Some(drift) = drift {
+            admin_server = admin_server.with_config_drift(drift);
+        }
+        if let Err(e) = admin_server.serve().await {
+            warn!(addr = %addr, error = %e, "Admin server stopped");
+        }
+    })
 }
 
 /// Wait for a shutdown signal (Ctrl+C or SIGTERM).
@@ -0,0 +1,181 @@
+//! Resolution of the address a node advertises to its peers.
+//!
+//! Peers gossip with a node at the address it advertises, so that address
+//! must be routable from them. Binding the mesh to a wildcard address such
+//! as `0.0.0.0` is common, but the wildcard itself can't be advertised. When
+//! no advertise address is given, the node advertises the pod IP from the
+//! environment, or else the address of the interface its default route
+//! uses.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+
+use tracing::info;
+
+use super::cluster::ClusterError;
+
+/// Environment variable holding the pod IP, as set by the Kubernetes downward API.
+pub const POD_IP_ENV: &str = "POD_IP";
+
+/// Determine the address to advertise to peers.
+///
+/// `advertise` may be an IP address or a hostname, with or without a port;
+/// hostnames are resolved, and the mesh listen port is used if no port is
+/// given. Without `advertise`, the listen address is advertised unless it
+/// is a wildcard address, in which case the address is detected.
+///
+/// Returns an error if the address can't be resolved or detected, or if it
+/// is unspecified.
+pub async fn resolve_advertise_addr(
+    advertise: Option<&str>,
+    listen_addr: SocketAddr,
+) -> Result<SocketAddr, ClusterError> {
+    let addr = match advertise {
+        Some(advertise) => resolve(advertise, listen_addr.port()).await?,
+        None if listen_addr.ip().is_unspecified() => {
+            let pod_ip = std::env::var(POD_IP_ENV).ok();
+            let ip = detect_ip(pod_ip.as_deref(), listen_addr.is_ipv6())?;
+            info!(
+                listen_addr = %listen_addr,
+                advertise_ip = %ip,
+                "Detected mesh advertise address"
+            );
+            SocketAddr::new(ip, listen_addr.port())
+        }
+        None => listen_addr,
+    };
+
+    if addr.ip().is_unspecified() {
+        return Err(ClusterError::AdvertiseError(format!(
+            "{} is not routable; set --advertise-addr to an address peers can reach",
+            addr
+        )));
+    }
+    Ok(addr)
+}
+
+/// Resolve an advertise address given as an IP or hostname, with an optional port.
+async fn resolve(advertise: &str, default_port: u16) -> Result<SocketAddr, ClusterError> {
+    if let Ok(addr) = advertise.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    if let Ok(ip) = advertise.trim_matches(['[', ']']).parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, default_port));
+    }
+
+    // A hostname, with a port if it ends in one
+    let (host, port) = match advertise.rsplit_once(':') {
+        Some((host, port)) => {
+            let port = port.parse().map_err(|_| {
+                ClusterError::AdvertiseError(format!("invalid port in {:?}", advertise))
+            })?;
+            (host, port)
+        }
+        None => (advertise, default_port),
+    };
+
+    let mut addrs = tokio::net::lookup_host((host, port)).await.map_err(|e| {
+        ClusterError::AdvertiseError(format!("failed to resolve {:?}: {}", host, e))
+    })?;
+    let addr = addrs.next().ok_or_else(|| {
+        ClusterError::AdvertiseError(format!("{:?} did not resolve to any address", host))
+    })?;
+
+    info!(host = %host, advertise_addr = %addr, "Resolved mesh advertise address");
+    Ok(addr)
+}
+
+/// Detect the IP address of this host that peers can reach.
+///
+/// Prefers the pod IP, falling back to the address of the interface used to
+/// reach other hosts.
+fn detect_ip(pod_ip: Option<&str>, ipv6: bool) -> Result<IpAddr, ClusterError> {
+    if let Some(pod_ip) = pod_ip.filter(|ip| !ip.is_empty()) {
+        return pod_ip.parse().map_err(|_| {
+            ClusterError::AdvertiseError(format!("invalid {} {:?}", POD_IP_ENV, pod_ip))
+        });
+    }
+
+    // Connecting a UDP socket only selects a route and source address; no
+    // packets are sent, so the target doesn't need to be reachable.
+    let (bind, target): (SocketAddr, SocketAddr) = if ipv6 {
+        (
+            (Ipv6Addr::UNSPECIFIED, 0).into(),
+            (Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), 9).into(),
+        )
+    } else {
+        ((Ipv4Addr::UNSPECIFIED, 0).into(), (Ipv4Addr::new(192, 0, 2, 1), 9).into())
+    };
+    let detected = UdpSocket::bind(bind)
+        .and_then(|socket| {
+            socket.connect(target)?;
+            socket.local_addr()
+        })
+        .map_err(|e| {
+            ClusterError::AdvertiseError(format!(
+                "failed to detect the primary interface address: {}; set --advertise-addr",
+                e
+            ))
+        })?;
+    Ok(detected.ip())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listen(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_explicit_advertise_addr() {
+        let wildcard = listen("0.0.0.0:7946");
+
+        let addr = resolve_advertise_addr(Some("10.0.0.5:8000"), wildcard).await.unwrap();
+        assert_eq!(addr, listen("10.0.0.5:8000"));
+
+        // The listen port is used when no port is given
+        let addr = resolve_advertise_addr(Some("10.0.0.5"), wildcard).await.unwrap();
+        assert_eq!(addr, listen("10.0.0.5:7946"));
+        let addr = resolve_advertise_addr(Some("[fd00::5]"), wildcard).await.unwrap();
+        assert_eq!(addr, listen("[fd00::5]:7946"));
+
+        // Hostnames are resolved
+        let addr = resolve_advertise_addr(Some("localhost:8000"), wildcard).await.unwrap();
+        assert!(addr.ip().is_loopback());
+        assert_eq!(addr.port(), 8000);
+        let addr = resolve_advertise_addr(Some("localhost"), wildcard).await.unwrap();
+        assert_eq!(addr.port(), 7946);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_advertise_addr() {
+        let wildcard = listen("0.0.0.0:7946");
+
+        assert!(resolve_advertise_addr(Some("0.0.0.0:7946"), wildcard).await.is_err());
+        assert!(resolve_advertise_addr(Some("[::]"), wildcard).await.is_err());
+        assert!(resolve_advertise_addr(Some("localhost:port"), wildcard).await.is_err());
+        assert!(resolve_advertise_addr(Some("host.invalid"), wildcard).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_specific_listen_addr_is_advertised() {
+        let addr = resolve_advertise_addr(None, listen("127.0.0.1:7946")).await.unwrap();
+        assert_eq!(addr, listen("127.0.0.1:7946"));
+    }
+
+    #[test]
+    fn test_detect_ip() {
+        assert_eq!(
+            detect_ip(Some("10.1.2.3"), false).unwrap(),
+            "10.1.2.3".parse::<IpAddr>().unwrap()
+        );
+        assert!(detect_ip(Some("not-an-ip"), false).is_err());
+
+        // Without a pod IP the interface address is detected, which needs a
+        // route and may not exist in a sandboxed test environment
+        if let Ok(ip) = detect_ip(None, false) {
+            assert!(!ip.is_unspecified());
+        }
+    }
+}
@@ -0,0 +1,135 @@
+//! Over-limit block hints.
+//!
+//! Once a counter is at its limit, every later hit in its window is
+//! rejected, yet each node would still take the chitchat lock to increment
+//! and re-sum the counter on every request. Instead, a node that sees a
+//! counter reach its limit gossips a hint under the counter's key, and every
+//! node keeps the hints it hears of in a lock-free cache, rejecting hits on
+//! those counters without touching the cluster state until the window ends.
+//!
+//! A hint records the limit the counter reached, so a node whose
+//! configuration allows more hits, as after a limit is raised, ignores it.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use dashmap::DashMap;
+
+/// Prefix of every block hint key in the chitchat state, followed by the
+/// counter's chitchat key.
+pub(crate) const BLOCK_KEY_PREFIX: &str = "block|";
+
+/// A counter known to be at its limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct BlockHint {
+    /// The limit the counter reached
+    pub limit: u64,
+    /// End of the counter's window, in epoch seconds
+    pub expires_at: u64,
+}
+
+impl BlockHint {
+    /// Format the hint as a chitchat value: "{limit}:{expires_at}".
+    pub fn to_value(self) -> String {
+        format!("{}:{}", self.limit, self.expires_at)
+    }
+
+    /// Parse a hint from a chitchat value.
+    pub fn from_value(value: &str) -> Option<Self> {
+        let (limit, expires_at) = value.split_once(':')?;
+        Some(Self {
+            limit: limit.parse().ok()?,
+            expires_at: expires_at.parse().ok()?,
+        })
+    }
+}
+
+/// Block hints known to this node: counter chitchat key -> hint.
+#[derive(Debug, Default)]
+pub(crate) struct BlockHints {
+    hints: DashMap<String, BlockHint>,
+    /// When hints of ended windows are next dropped, in epoch seconds
+    next_eviction: AtomicU64,
+}
+
+impl BlockHints {
+    /// Record a hint for a counter, keeping the higher limit if one is known.
+    pub fn insert(&self, counter_key: &str, hint: BlockHint) {
+        self.hints
+            .entry(counter_key.to_string())
+            .and_modify(|known| {
+                known.limit = known.limit.max(hint.limit);
+                known.expires_at = known.expires_at.max(hint.expires_at);
+            })
+            .or_insert(hint);
+    }
+
+    /// Check whether a counter with `limit` is known to be at its limit.
+    pub fn is_blocked(&self, counter_key: &str, limit: u64, now: u64) -> bool {
+        self.evict_if_due(now);
+        self.hints
+            .get(counter_key)
+            .is_some_and(|hint| hint.expires_at > now && limit <= hint.limit)
+    }
+
+    /// Get the number of hints held.
+    pub fn len(&self) -> usize {
+        self.hints.len()
+    }
+
+    /// Drop hints whose window has ended, at most once a second.
+    fn evict_if_due(&self, now: u64) {
+        let next_eviction = self.next_eviction.load(Ordering::Relaxed);
+        if now < next_eviction
+            || self
+                .next_eviction
+                .compare_exchange(next_eviction, now + 1, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+        {
+            return;
+        }
+        self.hints.retain(|_, hint| hint.expires_at > now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::CounterKey;
+
+    #[test]
+    fn test_block_hint_value() {
+        let hint = BlockHint {
+            limit: 10,
+            expires_at: 1704067260,
+        };
+        assert_eq!(hint.to_value(), "10:1704067260");
+        assert_eq!(BlockHint::from_value(&hint.to_value()), Some(hint));
+        assert_eq!(BlockHint::from_value("10"), None);
+        assert_eq!(BlockHint::from_value("a:1"), None);
+    }
+
+    #[test]
+    fn test_block_hints() {
+        let hints = BlockHints::default();
+        let key = CounterKey::new("domain", "key", 60);
+        let other = CounterKey::new("domain", "other", 60);
+        let (key, other) = (key.as_chitchat_key(), other.as_chitchat_key());
+        hints.insert(
+            key,
+            BlockHint {
+                limit: 10,
+                expires_at: 120,
+            },
+        );
+
+        assert!(hints.is_blocked(key, 10, 61));
+        assert!(hints.is_blocked(key, 5, 61));
+        // A higher limit allows more hits than the counter reached
+        assert!(!hints.is_blocked(key, 20, 61));
+        assert!(!hints.is_blocked(other, 10, 61));
+
+        // Hints are dropped once their window ends
+        assert!(!hints.is_blocked(key, 10, 120));
+        assert_eq!(hints.len(), 0);
+    }
+}
@@ -0,0 +1,336 @@
+//! Eager broadcast of counter increments to peers.
+//!
+//! Gossip delivers a node's increments to its peers at the next gossip
+//! round, so for a low limit every node can admit close to the whole limit
+//! before hearing about the others. For counters of rules that opt in, a
+//! node also sends its new local value straight to every live peer over a
+//! dedicated UDP socket.
+//!
+//! Receivers keep the broadcast values in an overlay next to the gossiped
+//! state, and count the higher of the two for each peer. A node's share of a
+//! counter only grows within a generation, so taking the maximum never
+//! undercounts, and an overlay entry is redundant once gossip catches up,
+//! after which it expires.
+//!
+//! A datagram is only accepted from the broadcast address its sender gossips
+//! for its current generation, so hosts outside the cluster can't inflate
+//! counters by naming a member in a datagram. The overlay holds a bounded
+//! number of counters, and broadcasts for further counters are left to gossip.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use tokio::net::UdpSocket;
+use tracing::{debug, trace};
+
+use super::cluster::ClusterError;
+
+/// How long a broadcast value is kept, by which time gossip has delivered it.
+const EAGER_COUNT_TTL: Duration = Duration::from_secs(30);
+
+/// Largest broadcast datagram accepted.
+const MAX_DATAGRAM_SIZE: usize = 65_507;
+
+/// Most counters kept in the overlay of broadcast values.
+const MAX_EAGER_COUNTERS: usize = 100_000;
+
+/// A node's new local values for some counters.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct BroadcastMessage {
+    /// Cluster of the sending node, so clusters sharing a network stay apart
+    pub cluster_id: String,
+    /// ID of the sending node
+    pub node_id: String,
+    /// Generation of the sending node
+    pub generation_id: u64,
+    /// Chitchat counter keys and the sender's local value of each
+    pub counters: Vec<(String, u64)>,
+}
+
+/// A peer's broadcast value for a counter.
+#[derive(Debug, Clone)]
+struct EagerCount {
+    generation_id: u64,
+    value: u64,
+    received_at: Instant,
+}
+
+/// Broadcast values received from peers: chitchat key -> node ID -> value.
+#[derive(Debug, Default)]
+pub(crate) struct EagerCounts {
+    counts: DashMap<String, HashMap<String, EagerCount>>,
+}
+
+impl EagerCounts {
+    /// Record the values of a broadcast message.
+    ///
+    /// Values of counters not yet in the overlay are dropped once it holds
+    /// `MAX_EAGER_COUNTERS` counters.
+    pub fn record(&self, message: BroadcastMessage, now: Instant) {
+        self.record_within(message, now, MAX_EAGER_COUNTERS);
+    }
+
+    /// Record the values of a broadcast message, keeping at most `capacity` counters.
+    fn record_within(&self, message: BroadcastMessage, now: Instant, capacity: usize) {
+        let mut room = capacity.saturating_sub(self.counts.len());
+        for (key, value) in message.counters {
+            let mut nodes = match self.counts.get_mut(&key) {
+                Some(nodes) => nodes,
+                None if room > 0 => {
+                    room -= 1;
+                    self.counts.entry(key).or_default()
+                }
+                None => {
+                    trace!(key = %key, "Eager counts are full, leaving counter to gossip");
+                    continue;
+                }
+            };
+            let count = nodes.entry(message.node_id.clone()).or_insert(EagerCount {
+                generation_id: message.generation_id,
+                value: 0,
+                received_at: now,
+            });
+            if message.generation_id > count.generation_id {
+                count.generation_id = message.generation_id;
+                count.value = 0;
+            }
+            if message.generation_id == count.generation_id {
+                count.value = count.value.max(value);
+                count.received_at = now;
+            }
+        }
+    }
+
+    /// Get a node generation's broadcast value for a counter.
+    pub fn get(&self, key: &str, node_id: &str, generation_id: u64) -> Option<u64> {
+        let nodes = self.counts.get(key)?;
+        nodes
+            .get(node_id)
+            .filter(|count| count.generation_id == generation_id)
+            .map(|count| count.value)
+    }
+
+    /// Drop values received more than `ttl` ago.
+    pub fn evict_older_than(&self, ttl: Duration, now: Instant) {
+        self.counts.retain(|_, nodes| {
+            nodes.retain(|_, count| now.duration_since(count.received_at) < ttl);
+            !nodes.is_empty()
+        });
+    }
+}
+
+/// The broadcast addresses peers gossip: node ID -> generation and address.
+#[derive(Debug, Default)]
+pub(crate) struct PeerAddrs {
+    addrs: DashMap<String, (u64, SocketAddr)>,
+}
+
+impl PeerAddrs {
+    /// Record the broadcast address gossiped by a node generation.
+    ///
+    /// Addresses of generations older than the latest known are ignored.
+    pub fn insert(&self, node_id: &str, generation_id: u64, addr: SocketAddr) {
+        let mut entry = self.addrs.entry(node_id.to_string()).or_insert((generation_id, addr));
+        if generation_id >= entry.0 {
+            *entry = (generation_id, addr);
+        }
+    }
+
+    /// Check whether a message was sent from the broadcast address its
+    /// sender gossips for the message's generation.
+    fn is_sender(&self, message: &BroadcastMessage, source: SocketAddr) -> bool {
+        self.addrs
+            .get(&message.node_id)
+            .is_some_and(|entry| *entry == (message.generation_id, source))
+    }
+}
+
+/// Sends this node's counter values to peers and records theirs.
+pub(crate) struct Broadcaster {
+    socket: Arc<UdpSocket>,
+    eager_counts: Arc<EagerCounts>,
+    peer_addrs: Arc<PeerAddrs>,
+}
+
+impl Broadcaster {
+    /// Bind the broadcast socket and start receiving peers' broadcasts.
+    ///
+    /// Returns the broadcaster and the receiving task.
+    pub async fn start(
+        listen_addr: SocketAddr,
+        cluster_id: String,
+        node_id: String,
+    ) -> Result<(Self, tokio::task::JoinHandle<()>), ClusterError> {
+        let socket = UdpSocket::bind(listen_addr).await.map_err(|e| {
+            ClusterError::StartError(format!("failed to bind broadcast socket {}: {}", listen_addr, e))
+        })?;
+        let socket = Arc::new(socket);
+        let eager_counts = Arc::new(EagerCounts::default());
+        let peer_addrs = Arc::new(PeerAddrs::default());
+
+        let task = tokio::spawn(Self::receive(
+            socket.clone(),
+            eager_counts.clone(),
+            peer_addrs.clone(),
+            cluster_id,
+            node_id,
+        ));
+        Ok((
+            Self {
+                socket,
+                eager_counts,
+                peer_addrs,
+            },
+            task,
+        ))
+    }
+
+    /// Get the address the broadcast socket is bound to.
+    pub fn local_addr(&self) -> SocketAddr {
+        // A bound socket always has a local address
+        self.socket.local_addr().expect("broadcast socket is bound")
+    }
+
+    /// Get the broadcast values received from peers.
+    pub fn eager_counts(&self) -> &EagerCounts {
+        &self.eager_counts
+    }
+
+    /// Get the broadcast addresses broadcasts are accepted from.
+    pub fn peer_addrs(&self) -> Arc<PeerAddrs> {
+        self.peer_addrs.clone()
+    }
+
+    /// Send a message to every peer.
+    pub async fn send(&self, message: &BroadcastMessage, peers: &[SocketAddr]) {
+        // Serializing a message of strings and integers can't fail
+        let Ok(payload) = serde_json::to_vec(message) else {
+            return;
+        };
+        for peer in peers {
+            if let Err(e) = self.socket.send_to(&payload, peer).await {
+                debug!(peer = %peer, error = %e, "Failed to broadcast counters");
+            }
+        }
+        trace!(
+            peers = peers.len(),
+            key_count = message.counters.len(),
+            "Broadcast counters"
+        );
+    }
+
+    /// Record peers' broadcasts until the task is aborted.
+    async fn receive(
+        socket: Arc<UdpSocket>,
+        eager_counts: Arc<EagerCounts>,
+        peer_addrs: Arc<PeerAddrs>,
+        cluster_id: String,
+        node_id: String,
+    ) {
+        let mut buffer = vec![0u8; MAX_DATAGRAM_SIZE];
+        let mut evictions = tokio::time::interval(EAGER_COUNT_TTL);
+        loop {
+            tokio::select! {
+                received = socket.recv_from(&mut buffer) => {
+                    let (len, peer) = match received {
+                        Ok(received) => received,
+                        Err(e) => {
+                            debug!(error = %e, "Failed to receive broadcast");
+                            continue;
+                        }
+                    };
+                    match serde_json::from_slice::<BroadcastMessage>(&buffer[..len]) {
+                        Ok(message) if message.cluster_id == cluster_id && message.node_id != node_id => {
+                            if peer_addrs.is_sender(&message, peer) {
+                                eager_counts.record(message, Instant::now());
+                            } else {
+                                debug!(peer = %peer, node_id = %message.node_id, "Ignoring broadcast from an unknown address");
+                            }
+                        }
+                        Ok(_) => {}
+                        Err(e) => debug!(peer = %peer, error = %e, "Ignoring invalid broadcast"),
+                    }
+                }
+                _ = evictions.tick() => {
+                    eager_counts.evict_older_than(EAGER_COUNT_TTL, Instant::now());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(node_id: &str, generation_id: u64, counters: &[(&str, u64)]) -> BroadcastMessage {
+        BroadcastMessage {
+            cluster_id: "test".to_string(),
+            node_id: node_id.to_string(),
+            generation_id,
+            counters: counters.iter().map(|(k, v)| (k.to_string(), *v)).collect(),
+        }
+    }
+
+    #[test]
+    fn test_eager_counts_keep_highest_value_of_latest_generation() {
+        let counts = EagerCounts::default();
+        let now = Instant::now();
+
+        counts.record(message("a", 1, &[("k", 5)]), now);
+        // Datagrams may arrive out of order
+        counts.record(message("a", 1, &[("k", 3)]), now);
+        assert_eq!(counts.get("k", "a", 1), Some(5));
+        assert_eq!(counts.get("k", "b", 1), None);
+
+        // A restarted node starts over, and the old generation is ignored
+        counts.record(message("a", 2, &[("k", 1)]), now);
+        counts.record(message("a", 1, &[("k", 9)]), now);
+        assert_eq!(counts.get("k", "a", 2), Some(1));
+        assert_eq!(counts.get("k", "a", 1), None);
+
+        counts.evict_older_than(Duration::from_secs(1), now + Duration::from_secs(2));
+        assert_eq!(counts.get("k", "a", 2), None);
+        assert!(counts.counts.is_empty());
+    }
+
+    #[test]
+    fn test_eager_counts_capped() {
+        let counts = EagerCounts::default();
+        let now = Instant::now();
+
+        counts.record_within(message("a", 1, &[("k1", 1), ("k2", 1), ("k3", 1)]), now, 2);
+        assert_eq!(counts.get("k1", "a", 1), Some(1));
+        assert_eq!(counts.get("k2", "a", 1), Some(1));
+        assert_eq!(counts.get("k3", "a", 1), None);
+
+        // Counters already kept are still updated
+        counts.record_within(message("b", 1, &[("k3", 4), ("k2", 4)]), now, 2);
+        assert_eq!(counts.get("k2", "b", 1), Some(4));
+        assert_eq!(counts.get("k3", "b", 1), None);
+    }
+
+    #[test]
+    fn test_peer_addrs_accept_gossiped_sender() {
+        let peer_addrs = PeerAddrs::default();
+        let addr: SocketAddr = "10.0.0.1:7947".parse().unwrap();
+        peer_addrs.insert("a", 1, addr);
+
+        assert!(peer_addrs.is_sender(&message("a", 1, &[]), addr));
+        assert!(!peer_addrs.is_sender(&message("a", 1, &[]), "10.0.0.2:7947".parse().unwrap()));
+        assert!(!peer_addrs.is_sender(&message("a", 2, &[]), addr));
+        assert!(!peer_addrs.is_sender(&message("b", 1, &[]), addr));
+
+        // A restarted node is accepted from its new address, and an older
+        // generation can't take it back
+        let moved: SocketAddr = "10.0.0.3:7947".parse().unwrap();
+        peer_addrs.insert("a", 2, moved);
+        peer_addrs.insert("a", 1, addr);
+        assert!(peer_addrs.is_sender(&message("a", 2, &[]), moved));
+        assert!(!peer_addrs.is_sender(&message("a", 1, &[]), addr));
+    }
+}
@@ -12,20 +12,73 @@
 //!
 //! - **Writes** (`increment_counter`): Short lock to update local state, then cache refresh
 //! - **Reads** (`get_count`): Lock-free cache lookup; falls back to Chitchat on cache miss
-
-use std::net::SocketAddr;
+//!
+//! ## Initial Sync
+//!
+//! Every node periodically refreshes a sync marker key in its own state.
+//! Chitchat delivers each node's key-values in version order, so once a
+//! joining node has received a peer's marker it also has all of that peer's
+//! counters as of when the marker was last refreshed. `wait_for_sync` waits
+//! for the markers of all live peers.
+//!
+//! ## Eager Broadcast
+//!
+//! With a broadcast address configured, counters of rules that opt in are
+//! also pushed straight to peers (see the `broadcast` module), and sums count
+//! the higher of each peer's gossiped and broadcast values.
+//!
+//! ## Strong Consistency
+//!
+//! Counters of rules with `consistency: strong` are not summed across nodes.
+//! Each is owned by one live node (see the `ownership` module), and nodes
+//! advertising a peer service address can be chosen as owners. Owners gossip
+//! their counts as floors, which the next owner of a key continues from.
+//!
+//! ## Block Hints
+//!
+//! A node that sees a gossiped counter reach its limit gossips a block hint
+//! for it (see the `block` module). Hints are kept in a lock-free cache, so
+//! requests on an exhausted counter are rejected without taking the chitchat
+//! lock for the rest of its window.
+//!
+//! ## Configuration Drift
+//!
+//! Every node publishes a hash of its rate limit configuration and of each
+//! domain's rules, so nodes can tell when their rules differ from those most
+//! of the cluster runs (see the `config_sync` module).
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Write};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::time::{Duration, Instant};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use chitchat::transport::UdpTransport;
 use chitchat::{
     spawn_chitchat, ChitchatConfig, ChitchatHandle, ChitchatId, FailureDetectorConfig,
+    ListenerHandle,
 };
 use dashmap::DashMap;
-use serde::{Deserialize, Serialize};
+use smallvec::SmallVec;
 use thiserror::Error;
 use tracing::{debug, info, trace};
 
+use super::block::{BlockHint, BlockHints, BLOCK_KEY_PREFIX};
+use super::broadcast::{BroadcastMessage, Broadcaster, PeerAddrs};
+use super::config_sync::NodeConfig;
+use super::ownership::{
+    rendezvous_owner, KeyOwner, OwnedCount, OwnedCounters, OwnedIncrement, OWNED_KEY_PREFIX,
+};
+use crate::ratelimit::{split_escaped, DescriptorKeyRef, Escaped};
+
+thread_local! {
+    /// Buffer reused to format the chitchat keys of borrowed counter keys.
+    static COUNTER_KEY_BUFFER: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
 /// Errors that can occur in cluster operations.
 #[derive(Debug, Error)]
 pub enum ClusterError {
@@ -33,11 +86,31 @@ pub enum ClusterError {
     StartError(String),
     #[error("Failed to join cluster: {0}")]
     JoinError(String),
+    #[error("Invalid advertise address: {0}")]
+    AdvertiseError(String),
 }
 
 /// Default cache TTL for distributed counter sums
 const DEFAULT_CACHE_TTL: Duration = Duration::from_millis(500);
 
+/// Key of the sync marker in each node's state.
+const SYNC_MARKER_KEY: &str = "sync_marker";
+
+/// How often each node refreshes its sync marker.
+const SYNC_MARKER_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Key of the address each node receives broadcasts on, if it has one.
+const BROADCAST_ADDR_KEY: &str = "broadcast_addr";
+
+/// Key of the address each node serves the peer service on, if it has one.
+const PEER_ADDR_KEY: &str = "peer_addr";
+
+/// Key of the hash and version of each node's rate limit configuration.
+const CONFIG_VERSION_KEY: &str = "config_version";
+
+/// Prefix of the keys holding the hash of each domain's rules, followed by the domain.
+const CONFIG_DOMAIN_PREFIX: &str = "config|";
+
 /// Configuration for the cluster.
 #[derive(Debug, Clone)]
 pub struct ClusterConfig {
@@ -59,6 +132,13 @@ pub struct ClusterConfig {
     /// Lower values = more accurate but more lock contention.
     /// Higher values = less accurate but better throughput.
     pub cache_ttl: Duration,
+    /// UDP address to send and receive eager counter broadcasts on.
+    /// Peers reach it at the advertised IP. Broadcast is disabled if unset.
+    pub broadcast_listen_addr: Option<SocketAddr>,
+    /// Address peers reach this node's peer service at, to forward increments
+    /// of strongly consistent counters. Without it, the node never owns counters
+    /// for its peers.
+    pub peer_addr: Option<SocketAddr>,
 }
 
 impl Default for ClusterConfig {
@@ -73,66 +153,174 @@ impl Default for ClusterConfig {
             gossip_interval: Duration::from_millis(100),
             dead_node_grace_period: Duration::from_secs(3600), // 1 hour
             cache_ttl: DEFAULT_CACHE_TTL,
+            broadcast_listen_addr: None,
+            peer_addr: None,
         }
     }
 }
 
 /// Key identifying a rate limit counter in the cluster state.
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+///
+/// The key holds its chitchat key string, formatted once when the key is
+/// built, so reading or writing the counter doesn't format it again.
+///
+/// The domain is escaped the same way as descriptor keys, so it never
+/// contains an unescaped `|`. The window is the last `|`-separated field, so
+/// the descriptor between them can be embedded as-is.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct CounterKey {
-    /// The rate limit domain.
-    pub domain: String,
-    /// The descriptor key (serialized).
-    pub descriptor: String,
-    /// The time window (epoch seconds, floored to window boundary).
-    pub window: u64,
+    /// The chitchat key: "counter|{domain}|{descriptor}|{window}"
+    key: String,
+    /// Byte offset of the separator between the domain and the descriptor
+    domain_end: usize,
+    /// Byte offset of the separator between the descriptor and the window
+    descriptor_end: usize,
 }
 
 impl CounterKey {
+    /// Prefix of every counter key in the chitchat state.
+    const PREFIX: &'static str = "counter|";
+
     /// Create a new counter key.
-    pub fn new(domain: &str, descriptor: &str, window: u64) -> Self {
+    ///
+    /// The descriptor is formatted straight into the key, so a borrowed
+    /// descriptor key doesn't need to be converted to a string first.
+    pub fn new(domain: &str, descriptor: impl fmt::Display, window: u64) -> Self {
+        let mut key = String::with_capacity(Self::PREFIX.len() + domain.len() + 32);
+        let (domain_end, descriptor_end) = Self::write_key(&mut key, domain, descriptor, window);
         Self {
-            domain: domain.to_string(),
-            descriptor: descriptor.to_string(),
-            window,
+            key,
+            domain_end,
+            descriptor_end,
         }
     }
 
-    /// Convert to a chitchat key string.
+    /// Append a chitchat key to `buffer`, returning the byte offsets of the
+    /// separators after the domain and after the descriptor.
+    fn write_key(buffer: &mut String, domain: &str, descriptor: impl fmt::Display, window: u64) -> (usize, usize) {
+        buffer.push_str(Self::PREFIX);
+        // Writing to a String never fails
+        let _ = write!(buffer, "{}", Escaped::domain(domain));
+        let domain_end = buffer.len();
+        let _ = write!(buffer, "|{}", descriptor);
+        let descriptor_end = buffer.len();
+        let _ = write!(buffer, "|{}", window);
+        (domain_end, descriptor_end)
+    }
+
+    /// The rate limit domain.
+    pub fn domain(&self) -> Cow<'_, str> {
+        // Validated when the key was built or parsed
+        split_escaped(&self.key[Self::PREFIX.len()..self.domain_end], b"")
+            .map(|(domain, _, _)| domain)
+            .unwrap_or_default()
+    }
+
+    /// The descriptor key (serialized).
+    pub fn descriptor(&self) -> &str {
+        &self.key[self.domain_end + 1..self.descriptor_end]
+    }
+
+    /// The time window (epoch seconds, floored to window boundary).
+    pub fn window(&self) -> u64 {
+        // Validated when the key was built or parsed
+        self.key[self.descriptor_end + 1..].parse().unwrap_or_default()
+    }
+
+    /// Get the chitchat key string.
     /// Format: "counter|{domain}|{descriptor}|{window}"
-    /// We use | as delimiter since it's less common in domain/descriptor values
-    pub fn to_chitchat_key(&self) -> String {
-        format!("counter|{}|{}|{}", self.domain, self.descriptor, self.window)
+    pub fn as_chitchat_key(&self) -> &str {
+        &self.key
     }
 
     /// Parse from a chitchat key string.
     pub fn from_chitchat_key(key: &str) -> Option<Self> {
-        // Split from the right to handle descriptors containing the delimiter
-        if !key.starts_with("counter|") {
-            return None;
-        }
+        let rest = key.strip_prefix(Self::PREFIX)?;
 
-        let rest = &key[8..]; // Skip "counter|"
-
-        // Find the last | for window
+        // The window is the last field, so the descriptor may contain the delimiter
         let last_sep = rest.rfind('|')?;
-        let window: u64 = rest[last_sep + 1..].parse().ok()?;
+        let _window: u64 = rest[last_sep + 1..].parse().ok()?;
 
+        // The domain ends at its first unescaped delimiter
         let before_window = &rest[..last_sep];
-
-        // Find the first | for domain
-        let first_sep = before_window.find('|')?;
-        let domain = &before_window[..first_sep];
-        let descriptor = &before_window[first_sep + 1..];
+        let (_, delimiter, descriptor) = split_escaped(before_window, b"|")?;
+        delimiter?;
+        let domain_end = Self::PREFIX.len() + before_window.len() - descriptor.len() - 1;
 
         Some(Self {
-            domain: domain.to_string(),
-            descriptor: descriptor.to_string(),
+            key: key.to_string(),
+            domain_end,
+            descriptor_end: Self::PREFIX.len() + last_sep,
+        })
+    }
+}
+
+/// A counter key borrowed from a resolved limit.
+///
+/// Its chitchat key is formatted into a reused per-thread buffer whenever it
+/// is read or written, so counting against an existing counter doesn't build
+/// a key. An owned [`CounterKey`] is only needed to keep the key beyond the
+/// request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CounterKeyRef<'a> {
+    /// The rate limit domain
+    domain: &'a str,
+    /// The descriptor key
+    descriptor: &'a DescriptorKeyRef<'a>,
+    /// The time window (epoch seconds, floored to window boundary)
+    window: u64,
+}
+
+impl<'a> CounterKeyRef<'a> {
+    /// Create a counter key borrowing a domain and descriptor key.
+    pub fn new(domain: &'a str, descriptor: &'a DescriptorKeyRef<'a>, window: u64) -> Self {
+        Self {
+            domain,
+            descriptor,
             window,
+        }
+    }
+
+    /// Build the owned key.
+    pub fn to_key(&self) -> CounterKey {
+        CounterKey::new(self.domain, self.descriptor, self.window)
+    }
+}
+
+/// A key of a counter in the cluster state.
+pub trait AsCounterKey {
+    /// Run `f` with the counter's chitchat key.
+    fn with_chitchat_key<R>(&self, f: impl FnOnce(&str) -> R) -> R;
+}
+
+impl AsCounterKey for CounterKey {
+    fn with_chitchat_key<R>(&self, f: impl FnOnce(&str) -> R) -> R {
+        f(&self.key)
+    }
+}
+
+impl AsCounterKey for CounterKeyRef<'_> {
+    /// The key is formatted into a per-thread buffer, so this doesn't
+    /// allocate once the buffer has grown to fit.
+    fn with_chitchat_key<R>(&self, f: impl FnOnce(&str) -> R) -> R {
+        COUNTER_KEY_BUFFER.with(|buffer| match buffer.try_borrow_mut() {
+            Ok(mut buffer) => {
+                buffer.clear();
+                CounterKey::write_key(&mut buffer, self.domain, self.descriptor, self.window);
+                f(&buffer)
+            }
+            // Only reachable if `f` formats another key
+            Err(_) => f(self.to_key().as_chitchat_key()),
         })
     }
 }
 
+impl<K: AsCounterKey + ?Sized> AsCounterKey for &K {
+    fn with_chitchat_key<R>(&self, f: impl FnOnce(&str) -> R) -> R {
+        (**self).with_chitchat_key(f)
+    }
+}
+
 /// Cached counter entry with timestamp for TTL-based expiration.
 struct CachedCount {
     /// The cached total count across all nodes.
@@ -176,6 +364,8 @@ impl CachedCount {
 pub struct Cluster {
     /// Our node ID.
     node_id: String,
+    /// Our generation, which distinguishes this process from earlier runs of the node.
+    generation_id: u64,
     /// Chitchat handle.
     handle: ChitchatHandle,
     /// Configuration.
@@ -184,6 +374,33 @@ pub struct Cluster {
     cached_counts: DashMap<String, CachedCount>,
     /// Fixed epoch for computing relative timestamps in cache entries.
     cache_epoch: Instant,
+    /// Number of times chitchat has reset a node's state because we fell behind.
+    catchups: Arc<AtomicU64>,
+    /// Task refreshing our sync marker.
+    sync_marker_task: AbortOnDrop,
+    /// Eager broadcast of counters to peers, if enabled.
+    broadcaster: Option<Broadcaster>,
+    /// Task receiving peers' broadcasts.
+    broadcast_task: Option<AbortOnDrop>,
+    /// Strongly consistent counters owned by this node.
+    owned_counters: Arc<OwnedCounters>,
+    /// Task gossiping our owned counts and raising them to our peers'.
+    owned_sync_task: AbortOnDrop,
+    /// Counters known to be at their limit, from this node and its peers.
+    block_hints: Arc<BlockHints>,
+    /// Subscription feeding peers' block hints into the cache.
+    _block_listener: ListenerHandle,
+    /// Subscription recording the addresses peers broadcast from, if broadcast is enabled.
+    _broadcast_addr_listener: Option<ListenerHandle>,
+}
+
+/// Aborts a background task when dropped.
+pub(super) struct AbortOnDrop(pub(super) tokio::task::JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
 }
 
 impl std::fmt::Debug for Cluster {
@@ -192,6 +409,8 @@ impl std::fmt::Debug for Cluster {
             .field("node_id", &self.node_id)
             .field("config", &self.config)
             .field("cached_entries", &self.cached_counts.len())
+            .field("owned_counters", &self.owned_counters.len())
+            .field("block_hints", &self.block_hints.len())
             .finish()
     }
 }
@@ -208,9 +427,13 @@ impl Cluster {
             "Starting cluster node"
         );
 
+        // A node restarting with the same ID comes back as a new generation,
+        // so peers don't mistake its fresh state for stale updates of the old one.
+        let generation_id = Self::new_generation_id();
+        let catchups = Arc::new(AtomicU64::new(0));
         let chitchat_id = ChitchatId {
             node_id: config.node_id.clone(),
-            generation_id: 0,
+            generation_id,
             gossip_advertise_addr: config.advertise_addr,
         };
 
@@ -225,26 +448,268 @@ impl Cluster {
                 ..Default::default()
             },
             marked_for_deletion_grace_period: config.dead_node_grace_period,
-            catchup_callback: None,
+            catchup_callback: Some(Box::new({
+                let catchups = catchups.clone();
+                move || {
+                    catchups.fetch_add(1, Ordering::Relaxed);
+                }
+            })),
             extra_liveness_predicate: None,
         };
 
+        // Peers find our broadcast socket through our node state
+        let mut initial_key_values = Vec::new();
+        let (broadcaster, broadcast_task) = match config.broadcast_listen_addr {
+            Some(listen_addr) => {
+                let (broadcaster, task) = Broadcaster::start(
+                    listen_addr,
+                    config.cluster_id.clone(),
+                    config.node_id.clone(),
+                )
+                .await?;
+                let broadcast_addr =
+                    SocketAddr::new(config.advertise_addr.ip(), broadcaster.local_addr().port());
+                info!(broadcast_addr = %broadcast_addr, "Eager counter broadcast enabled");
+                initial_key_values.push((BROADCAST_ADDR_KEY.to_string(), broadcast_addr.to_string()));
+                (Some(broadcaster), Some(AbortOnDrop(task)))
+            }
+            None => (None, None),
+        };
+        if let Some(peer_addr) = config.peer_addr {
+            initial_key_values.push((PEER_ADDR_KEY.to_string(), peer_addr.to_string()));
+        }
+
         let transport = UdpTransport;
-        let handle = spawn_chitchat(chitchat_config, Vec::new(), &transport)
+        let handle = spawn_chitchat(chitchat_config, initial_key_values, &transport)
             .await
             .map_err(|e| ClusterError::StartError(e.to_string()))?;
 
-        info!("Cluster node started successfully");
+        info!(generation_id = generation_id, "Cluster node started successfully");
+
+        let sync_marker_task = AbortOnDrop(Self::spawn_sync_marker(&handle));
+        let owned_counters = Arc::new(OwnedCounters::default());
+        let owned_sync_task = AbortOnDrop(Self::spawn_owned_sync(
+            &handle,
+            owned_counters.clone(),
+            config.gossip_interval,
+        ));
+        let block_hints = Arc::new(BlockHints::default());
+        let block_listener = Self::subscribe_block_hints(&handle, block_hints.clone()).await;
+        let broadcast_addr_listener = match &broadcaster {
+            Some(broadcaster) => Some(Self::subscribe_broadcast_addrs(&handle, broadcaster.peer_addrs()).await),
+            None => None,
+        };
 
         Ok(Self {
             node_id: config.node_id.clone(),
+            generation_id,
             handle,
             config,
             cached_counts: DashMap::new(),
             cache_epoch: Instant::now(),
+            catchups,
+            sync_marker_task,
+            broadcaster,
+            broadcast_task,
+            owned_counters,
+            owned_sync_task,
+            block_hints,
+            _block_listener: block_listener,
+            _broadcast_addr_listener: broadcast_addr_listener,
+        })
+    }
+
+    /// Subscribe to the broadcast addresses gossiped by peers, so broadcasts
+    /// are only accepted from them.
+    async fn subscribe_broadcast_addrs(handle: &ChitchatHandle, peer_addrs: Arc<PeerAddrs>) -> ListenerHandle {
+        let chitchat_arc = handle.chitchat();
+        let chitchat = chitchat_arc.lock().await;
+
+        // Peers may have been heard from before subscribing
+        for id in chitchat.live_nodes() {
+            let addr = chitchat.node_state(id).and_then(|state| state.get(BROADCAST_ADDR_KEY)?.parse().ok());
+            if let Some(addr) = addr {
+                peer_addrs.insert(&id.node_id, id.generation_id, addr);
+            }
+        }
+
+        chitchat.subscribe_event(BROADCAST_ADDR_KEY, move |event| {
+            if let Ok(addr) = event.value.parse() {
+                trace!(node = %event.node.node_id, broadcast_addr = %addr, "Received broadcast address");
+                peer_addrs.insert(&event.node.node_id, event.node.generation_id, addr);
+            }
         })
     }
 
+    /// Subscribe to block hints gossiped by peers.
+    async fn subscribe_block_hints(handle: &ChitchatHandle, block_hints: Arc<BlockHints>) -> ListenerHandle {
+        handle
+            .chitchat()
+            .lock()
+            .await
+            .subscribe_event(BLOCK_KEY_PREFIX, move |event| {
+                if let Some(hint) = BlockHint::from_value(event.value) {
+                    trace!(key = %event.key, node = %event.node.node_id, "Received block hint");
+                    block_hints.insert(event.key, hint);
+                }
+            })
+    }
+
+    /// Spawn a task that refreshes our sync marker.
+    fn spawn_sync_marker(handle: &ChitchatHandle) -> tokio::task::JoinHandle<()> {
+        let chitchat_arc = handle.chitchat();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(SYNC_MARKER_INTERVAL);
+            loop {
+                ticker.tick().await;
+                let now_ms = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |elapsed| elapsed.as_millis() as u64);
+                chitchat_arc
+                    .lock()
+                    .await
+                    .self_node_state()
+                    .set(SYNC_MARKER_KEY, now_ms.to_string());
+            }
+        })
+    }
+
+    /// Spawn a task that gossips the counts this node owns as floors, and
+    /// raises them to the floors gossiped by other nodes.
+    ///
+    /// Other nodes' floors are ahead of ours for keys that moved away from
+    /// this node and back, or that were counted on both sides of a partition.
+    fn spawn_owned_sync(
+        handle: &ChitchatHandle,
+        owned_counters: Arc<OwnedCounters>,
+        interval: Duration,
+    ) -> tokio::task::JoinHandle<()> {
+        let chitchat_arc = handle.chitchat();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |elapsed| elapsed.as_secs());
+                let mut chitchat = chitchat_arc.lock().await;
+
+                let held = owned_counters.counts();
+                let self_id = chitchat.self_chitchat_id().clone();
+                let floors = Self::owned_floors(
+                    &chitchat,
+                    held.iter().map(|(key, _)| key.as_str()),
+                    Some(&self_id),
+                    now,
+                );
+                owned_counters.raise(&floors);
+
+                let held: HashMap<String, OwnedCount> = owned_counters
+                    .counts()
+                    .into_iter()
+                    .filter(|(_, owned)| owned.expires_at > now)
+                    .collect();
+                let node_state = chitchat.self_node_state();
+                let stale: Vec<String> = node_state
+                    .iter_prefix(OWNED_KEY_PREFIX)
+                    .filter(|(key, _)| !held.contains_key(&key[OWNED_KEY_PREFIX.len()..]))
+                    .map(|(key, _)| key.to_string())
+                    .collect();
+                for key in stale {
+                    node_state.delete(&key);
+                }
+                for (key, owned) in held {
+                    node_state.set(format!("{}{}", OWNED_KEY_PREFIX, key), owned.to_value());
+                }
+            }
+        })
+    }
+
+    /// Find the highest unexpired count any node has gossiped for each
+    /// owned counter, skipping the `excluded` node.
+    ///
+    /// Nodes that have died or restarted are included, since their counts
+    /// are what a new owner continues from. Must be called while holding the
+    /// Chitchat lock.
+    fn owned_floors<'k>(
+        chitchat: &chitchat::Chitchat,
+        chitchat_keys: impl IntoIterator<Item = &'k str>,
+        excluded: Option<&ChitchatId>,
+        now: u64,
+    ) -> Vec<(String, OwnedCount)> {
+        chitchat_keys
+            .into_iter()
+            .filter_map(|key| {
+                let floor_key = format!("{}{}", OWNED_KEY_PREFIX, key);
+                let floor = chitchat
+                    .node_states()
+                    .iter()
+                    .filter(|(id, _)| Some(*id) != excluded)
+                    .filter_map(|(_, node_state)| OwnedCount::from_value(node_state.get(&floor_key)?))
+                    .filter(|floor| floor.expires_at > now)
+                    .max_by_key(|floor| floor.count)?;
+                Some((key.to_string(), floor))
+            })
+            .collect()
+    }
+
+    /// Wait until this node has caught up with the state of its peers.
+    ///
+    /// A node without seed nodes starts a new cluster and is synced
+    /// immediately. Otherwise the node is synced once it sees at least one
+    /// live peer and has received the sync marker of every live peer, on two
+    /// consecutive checks with no catch-up in between.
+    ///
+    /// Returns `false` if the node is still not synced after `timeout`.
+    pub async fn wait_for_sync(&self, timeout: Duration) -> bool {
+        if self.config.seed_nodes.is_empty() {
+            return true;
+        }
+
+        let started = Instant::now();
+        let mut ticker = tokio::time::interval(self.config.gossip_interval);
+        let mut previous: Option<(usize, u64)> = None;
+        loop {
+            ticker.tick().await;
+
+            let catchups = self.catchups.load(Ordering::Relaxed);
+            let synced_peers = self.synced_peer_count().await;
+            if let Some(peers) = synced_peers {
+                if previous == Some((peers, catchups)) {
+                    info!(
+                        peers = peers,
+                        elapsed = ?started.elapsed(),
+                        "Caught up with cluster state"
+                    );
+                    return true;
+                }
+            }
+            previous = synced_peers.map(|peers| (peers, catchups));
+
+            if started.elapsed() >= timeout {
+                return false;
+            }
+        }
+    }
+
+    /// Get the number of live peers if we have the sync marker of every one of them.
+    async fn synced_peer_count(&self) -> Option<usize> {
+        let chitchat_arc = self.handle.chitchat();
+        let chitchat = chitchat_arc.lock().await;
+        let peers: Vec<&ChitchatId> = Self::current_generations(&chitchat)
+            .into_iter()
+            .filter(|id| id.node_id != self.node_id)
+            .collect();
+
+        let synced = !peers.is_empty()
+            && peers.iter().all(|id| {
+                chitchat
+                    .node_state(id)
+                    .is_some_and(|state| state.get(SYNC_MARKER_KEY).is_some())
+            });
+        synced.then_some(peers.len())
+    }
+
     /// Get our node ID.
     pub fn node_id(&self) -> &str {
         &self.node_id
@@ -261,70 +726,485 @@ impl Cluster {
     /// 1. Taking a short lock to update our local value
     /// 2. Releasing the lock before computing the distributed sum
     /// 3. Updating the cache with the fresh sum
-    pub async fn increment_counter(&self, key: &CounterKey, amount: u64) -> u64 {
-        let chitchat_key = key.to_chitchat_key();
+    pub async fn increment_counter<K: AsCounterKey + Sync>(&self, key: &K, amount: u64) -> u64 {
         let chitchat_arc = self.handle.chitchat();
 
         // Phase 1: Short lock to update our local value
         {
             let mut chitchat = chitchat_arc.lock().await;
-            let current_local: u64 = chitchat
-                .self_node_state()
-                .get(&chitchat_key)
-                .and_then(|v| v.parse().ok())
-                .unwrap_or(0);
+            key.with_chitchat_key(|chitchat_key| {
+                let new_local = Self::increment_own(&mut chitchat, chitchat_key, amount);
+                debug!(
+                    key = %chitchat_key,
+                    local_value = new_local,
+                    "Incremented local counter"
+                );
+            });
+        } // Lock released here
+
+        // Phase 2: Refresh cache with fresh distributed sum
+        // This takes a separate lock acquisition but ensures we return accurate data
+        self.refresh_cache_for_key(key).await
+    }
 
-            let new_local = current_local + amount;
-            chitchat
-                .self_node_state()
-                .set(&chitchat_key, new_local.to_string());
+    /// Add to this node's share of a counter, returning the new share.
+    /// Must be called while holding the Chitchat lock.
+    fn increment_own(chitchat: &mut chitchat::Chitchat, chitchat_key: &str, amount: u64) -> u64 {
+        let own_state = chitchat.self_node_state();
+        let current_local: u64 = own_state
+            .get(chitchat_key)
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0);
+        let new_local = current_local.saturating_add(amount);
+        own_state.set(chitchat_key, new_local);
+        new_local
+    }
+
+    /// Increment several counters and return the total across all nodes for each.
+    ///
+    /// All increments are applied and summed under a single Chitchat lock,
+    /// so a multi-descriptor request only contends for the lock once.
+    pub async fn increment_counters<K: AsCounterKey + Sync>(&self, keys: &[K], amount: u64) -> Vec<u64> {
+        let chitchat_arc = self.handle.chitchat();
+        let mut chitchat = chitchat_arc.lock().await;
+
+        // Sum each key right after incrementing it, so a key repeated in the
+        // batch reports its running total at each position.
+        let mut totals = Vec::with_capacity(keys.len());
+        for key in keys {
+            totals.push(key.with_chitchat_key(|key| {
+                Self::increment_own(&mut chitchat, key, amount);
+                self.sum_counter_internal(&chitchat, key)
+            }));
+        }
+
+        debug!(
+            key_count = keys.len(),
+            amount = amount,
+            "Incremented local counters"
+        );
+        drop(chitchat); // Release lock before cache update
+
+        self.update_cached_counts(keys, &totals);
+        totals
+    }
+
+    /// Increment several counters only if every one stays within its limit.
+    ///
+    /// Each entry pairs a counter key with its limit. The distributed sums are
+    /// checked and the increments applied under a single Chitchat lock, so
+    /// either all counters are incremented or none are. Returns whether the
+    /// increments were committed, along with the total for each entry.
+    pub async fn increment_counters_if_within<K: AsCounterKey + PartialEq + Sync>(
+        &self,
+        counters: &[(K, u64)],
+        amount: u64,
+    ) -> (bool, Vec<u64>) {
+        // The same key may appear more than once, so check the combined
+        // amount. Requests carry a handful of counters, so a linear scan
+        // finds repeated keys.
+        let required: SmallVec<[u64; 8]> = counters
+            .iter()
+            .map(|(key, _)| {
+                let repeats = counters.iter().filter(|(other, _)| other == key).count() as u64;
+                amount.saturating_mul(repeats)
+            })
+            .collect();
+
+        let chitchat_arc = self.handle.chitchat();
+        let mut chitchat = chitchat_arc.lock().await;
+
+        let committed = counters.iter().zip(&required).all(|((key, limit), required)| {
+            key.with_chitchat_key(|key| self.sum_counter_internal(&chitchat, key).saturating_add(*required) <= *limit)
+        });
+
+        if committed {
+            for (key, _) in counters {
+                key.with_chitchat_key(|key| Self::increment_own(&mut chitchat, key, amount));
+            }
 
             debug!(
-                key = %chitchat_key,
-                local_value = new_local,
-                "Incremented local counter"
+                key_count = counters.len(),
+                amount = amount,
+                "Incremented local counters atomically"
             );
-        } // Lock released here
+        }
 
-        // Phase 2: Refresh cache with fresh distributed sum
-        // This takes a separate lock acquisition but ensures we return accurate data
-        self.refresh_cache_for_key(&chitchat_key).await
+        let totals: Vec<u64> = counters
+            .iter()
+            .map(|(key, _)| key.with_chitchat_key(|key| self.sum_counter_internal(&chitchat, key)))
+            .collect();
+        drop(chitchat); // Release lock before cache update
+
+        let keys: SmallVec<[&K; 8]> = counters.iter().map(|(key, _)| key).collect();
+        self.update_cached_counts(&keys, &totals);
+        (committed, totals)
+    }
+
+    /// Check whether counters can be broadcast to peers.
+    pub fn broadcast_enabled(&self) -> bool {
+        self.broadcaster.is_some()
+    }
+
+    /// Send this node's current share of some counters straight to every live peer.
+    ///
+    /// Does nothing if broadcast is disabled. Peers without a broadcast
+    /// address still receive the counters by gossip.
+    pub async fn broadcast_counters(&self, keys: &[&CounterKey]) {
+        let Some(broadcaster) = &self.broadcaster else {
+            return;
+        };
+        if keys.is_empty() {
+            return;
+        }
+
+        let chitchat_arc = self.handle.chitchat();
+        let (counters, peers) = {
+            let mut chitchat = chitchat_arc.lock().await;
+            let peers: Vec<SocketAddr> = Self::current_generations(&chitchat)
+                .into_iter()
+                .filter(|id| id.node_id != self.node_id)
+                .filter_map(|id| chitchat.node_state(id)?.get(BROADCAST_ADDR_KEY)?.parse().ok())
+                .collect();
+            let own_state = chitchat.self_node_state();
+            let counters: Vec<(String, u64)> = keys
+                .iter()
+                .map(|key| {
+                    let value = own_state
+                        .get(key.as_chitchat_key())
+                        .and_then(|v| v.parse().ok())
+                        .unwrap_or(0);
+                    (key.as_chitchat_key().to_string(), value)
+                })
+                .collect();
+            (counters, peers)
+        }; // Lock released here
+
+        if peers.is_empty() {
+            return;
+        }
+        let message = BroadcastMessage {
+            cluster_id: self.config.cluster_id.clone(),
+            node_id: self.node_id.clone(),
+            generation_id: self.generation_id,
+            counters,
+        };
+        broadcaster.send(&message, &peers).await;
+    }
+
+    /// Check whether a counter with `limit` is known to be at its limit.
+    ///
+    /// This is a lock-free cache lookup. `now` is in epoch seconds.
+    pub fn is_blocked(&self, key: &impl AsCounterKey, limit: u64, now: u64) -> bool {
+        key.with_chitchat_key(|key| self.block_hints.is_blocked(key, limit, now))
+    }
+
+    /// Mark counters as at their limit until their window ends, and gossip
+    /// the hints to peers.
+    ///
+    /// Each entry is a counter key, the limit it reached and the end of its
+    /// window in epoch seconds. Gossiped hints are deleted after the dead node
+    /// grace period, by which time nodes have cached them.
+    pub async fn block_counters<K: AsCounterKey + Sync>(&self, hints: &[(K, u64, u64)]) {
+        if hints.is_empty() {
+            return;
+        }
+        let hints: Vec<(&K, BlockHint)> = hints
+            .iter()
+            .map(|(key, limit, expires_at)| {
+                let hint = BlockHint {
+                    limit: *limit,
+                    expires_at: *expires_at,
+                };
+                key.with_chitchat_key(|key| self.block_hints.insert(key, hint));
+                (key, hint)
+            })
+            .collect();
+
+        let chitchat_arc = self.handle.chitchat();
+        let mut chitchat = chitchat_arc.lock().await;
+        let own_state = chitchat.self_node_state();
+        for (key, hint) in &hints {
+            key.with_chitchat_key(|key| {
+                own_state.set_with_ttl(format!("{}{}", BLOCK_KEY_PREFIX, key), hint.to_value())
+            });
+        }
+        debug!(key_count = hints.len(), "Gossiped block hints");
+    }
+
+    /// Publish the hash and version of this node's rate limit configuration.
+    ///
+    /// `domains` holds the hash of each domain's rules. Domains published
+    /// before but missing from `domains` are withdrawn.
+    pub async fn publish_config(&self, hash: u64, version: u64, domains: &BTreeMap<String, u64>) {
+        let chitchat_arc = self.handle.chitchat();
+        let mut chitchat = chitchat_arc.lock().await;
+        let own_state = chitchat.self_node_state();
+
+        let withdrawn: Vec<String> = own_state
+            .iter_prefix(CONFIG_DOMAIN_PREFIX)
+            .map(|(key, _)| key)
+            .filter(|key| !domains.contains_key(&key[CONFIG_DOMAIN_PREFIX.len()..]))
+            .map(str::to_string)
+            .collect();
+        for key in &withdrawn {
+            own_state.delete(key);
+        }
+        for (domain, domain_hash) in domains {
+            own_state.set(format!("{}{}", CONFIG_DOMAIN_PREFIX, domain), format!("{:016x}", domain_hash));
+        }
+        own_state.set(CONFIG_VERSION_KEY, format!("{:016x}:{}", hash, version));
+
+        info!(
+            config_hash = %format!("{:016x}", hash),
+            version = version,
+            domain_count = domains.len(),
+            "Published rate limit configuration"
+        );
+    }
+
+    /// Get the rate limit configuration published by every live node, this one included.
+    ///
+    /// Nodes that haven't published a configuration are left out.
+    pub async fn node_configs(&self) -> Vec<NodeConfig> {
+        let chitchat_arc = self.handle.chitchat();
+        let chitchat = chitchat_arc.lock().await;
+        Self::current_generations(&chitchat)
+            .into_iter()
+            .filter_map(|id| {
+                let state = chitchat.node_state(id)?;
+                let (hash, version) = state.get(CONFIG_VERSION_KEY)?.split_once(':')?;
+                let domains = state
+                    .iter_prefix(CONFIG_DOMAIN_PREFIX)
+                    .filter_map(|(key, value)| {
+                        let domain_hash = u64::from_str_radix(&value.value, 16).ok()?;
+                        Some((key[CONFIG_DOMAIN_PREFIX.len()..].to_string(), domain_hash))
+                    })
+                    .collect();
+                Some(NodeConfig {
+                    node_id: id.node_id.clone(),
+                    hash: u64::from_str_radix(hash, 16).ok()?,
+                    version: version.parse().ok()?,
+                    domains,
+                })
+            })
+            .collect()
+    }
+
+    /// Find the owner of each strongly consistent counter.
+    ///
+    /// Owners are chosen among the live nodes that advertise a peer service
+    /// address, this node included only if it advertises one, since a node
+    /// peers can't reach would otherwise count its share of the keys alone.
+    /// Nodes in `excluded`, typically ones that just failed to answer, are
+    /// skipped. When no node is eligible, counters are kept locally.
+    pub async fn counter_owners(&self, keys: &[&CounterKey], excluded: &[String]) -> Vec<KeyOwner> {
+        let chitchat_arc = self.handle.chitchat();
+        let peers: HashMap<String, SocketAddr> = {
+            let chitchat = chitchat_arc.lock().await;
+            Self::current_generations(&chitchat)
+                .into_iter()
+                .filter(|id| id.node_id != self.node_id && !excluded.contains(&id.node_id))
+                .filter_map(|id| {
+                    let addr = chitchat.node_state(id)?.get(PEER_ADDR_KEY)?.parse().ok()?;
+                    Some((id.node_id.clone(), addr))
+                })
+                .collect()
+        }; // Lock released here
+
+        let candidates: Vec<&str> = peers
+            .keys()
+            .map(String::as_str)
+            .chain(self.config.peer_addr.map(|_| self.node_id.as_str()))
+            .collect();
+        keys.iter()
+            .map(|key| {
+                match rendezvous_owner(key.as_chitchat_key(), &candidates)
+                    .and_then(|owner| peers.get_key_value(owner))
+                {
+                    Some((node_id, addr)) => KeyOwner::Peer {
+                        node_id: node_id.clone(),
+                        addr: *addr,
+                    },
+                    None => KeyOwner::Local,
+                }
+            })
+            .collect()
+    }
+
+    /// Check whether a live node of the cluster gossips from an address with this IP.
+    pub async fn is_member_ip(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        let chitchat_arc = self.handle.chitchat();
+        let chitchat = chitchat_arc.lock().await;
+        let is_member = chitchat
+            .live_nodes()
+            .any(|id| id.gossip_advertise_addr.ip().to_canonical() == ip);
+        is_member
+    }
+
+    /// Increment strongly consistent counters owned by this node.
+    ///
+    /// Counters this node doesn't hold yet start from the highest count
+    /// gossiped for them, so a key that moved here keeps its previous
+    /// owner's hits. Returns whether the increments were committed, along
+    /// with the count of each counter. See [`OwnedIncrement`].
+    pub async fn increment_owned(&self, counters: &[OwnedIncrement], amount: u64, atomic: bool) -> (bool, Vec<u64>) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        let missing = self.owned_counters.missing(counters);
+        if !missing.is_empty() {
+            let chitchat_arc = self.handle.chitchat();
+            let floors = {
+                let chitchat = chitchat_arc.lock().await;
+                Self::owned_floors(
+                    &chitchat,
+                    missing.iter().map(|counter| counter.key.as_chitchat_key()),
+                    None,
+                    now,
+                )
+            }; // Lock released here
+            if !floors.is_empty() {
+                debug!(key_count = floors.len(), "Seeded owned counters from gossiped counts");
+                self.owned_counters.raise(&floors);
+            }
+        }
+        let (committed, totals) = self.owned_counters.increment(counters, amount, atomic, now);
+        debug!(
+            key_count = counters.len(),
+            amount = amount,
+            committed = committed,
+            "Incremented owned counters"
+        );
+        (committed, totals)
+    }
+
+    /// Take back hits from strongly consistent counters owned by this node.
+    pub fn release_owned(&self, keys: &[CounterKey], amount: u64) {
+        self.owned_counters.release(keys, amount);
+        debug!(key_count = keys.len(), amount = amount, "Released owned counters");
+    }
+
+    /// Get the counts of the strongly consistent counters this node holds,
+    /// with the end of each counter's window in epoch seconds.
+    pub fn owned_counts(&self) -> Vec<(CounterKey, u64, u64)> {
+        self.owned_counters
+            .counts()
+            .into_iter()
+            .filter_map(|(key, owned)| Some((CounterKey::from_chitchat_key(&key)?, owned.count, owned.expires_at)))
+            .collect()
+    }
+
+    /// Restore strongly consistent counters, typically after a restart.
+    ///
+    /// Counters are held again, and their counts gossiped, whether or not
+    /// this node still owns them, so their owner continues from them.
+    pub fn restore_owned(&self, counters: &[(CounterKey, u64, u64)]) {
+        let floors: Vec<(String, OwnedCount)> = counters
+            .iter()
+            .map(|(key, count, expires_at)| {
+                let owned = OwnedCount {
+                    count: *count,
+                    expires_at: *expires_at,
+                };
+                (key.as_chitchat_key().to_string(), owned)
+            })
+            .collect();
+        self.owned_counters.raise(&floors);
+        debug!(key_count = counters.len(), "Restored owned counters");
+    }
+
+    /// Store freshly computed totals in the cache.
+    ///
+    /// Cached entries are updated in place, so only new keys are copied.
+    fn update_cached_counts<K: AsCounterKey>(&self, keys: &[K], totals: &[u64]) {
+        let now = Instant::now();
+        for (key, total) in keys.iter().zip(totals) {
+            key.with_chitchat_key(|key| match self.cached_counts.get(key) {
+                Some(cached) => cached.update(*total, now, self.cache_epoch),
+                None => {
+                    self.cached_counts
+                        .entry(key.to_string())
+                        .and_modify(|cached| cached.update(*total, now, self.cache_epoch))
+                        .or_insert_with(|| CachedCount::new(*total, now, self.cache_epoch));
+                }
+            });
+        }
+    }
+
+    /// Get this node's own share of every counter.
+    pub async fn own_counters(&self) -> Vec<(CounterKey, u64)> {
+        let chitchat_arc = self.handle.chitchat();
+        let mut chitchat = chitchat_arc.lock().await;
+        chitchat
+            .self_node_state()
+            .iter_prefix(CounterKey::PREFIX)
+            .filter_map(|(key, value)| {
+                Some((CounterKey::from_chitchat_key(key)?, value.value.parse().ok()?))
+            })
+            .collect()
+    }
+
+    /// Restore this node's share of counters, typically after a restart.
+    ///
+    /// A counter this node has already counted more hits on keeps its value.
+    pub async fn restore_counters(&self, counters: &[(CounterKey, u64)]) {
+        let chitchat_arc = self.handle.chitchat();
+        let mut chitchat = chitchat_arc.lock().await;
+        for (key, count) in counters {
+            let node_state = chitchat.self_node_state();
+            let current: u64 = node_state
+                .get(key.as_chitchat_key())
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0);
+            if *count > current {
+                node_state.set(key.as_chitchat_key(), count.to_string());
+            }
+        }
+        debug!(key_count = counters.len(), "Restored local counters");
     }
 
     /// Get the total count for a key across all nodes.
     ///
     /// Uses a TTL-based cache to minimize lock contention. Cache hits are
     /// lock-free; cache misses fall back to querying Chitchat state.
-    pub async fn get_count(&self, key: &CounterKey) -> u64 {
-        let chitchat_key = key.to_chitchat_key();
+    pub async fn get_count<K: AsCounterKey + Sync>(&self, key: &K) -> u64 {
         let now = Instant::now();
 
         // Fast path: check cache (lock-free DashMap read)
-        if let Some(cached) = self.cached_counts.get(&chitchat_key) {
-            if !cached.is_expired(now, self.cache_epoch, self.config.cache_ttl) {
-                trace!(key = %chitchat_key, "Cache hit for counter");
-                return cached.get();
+        let cached = key.with_chitchat_key(|chitchat_key| {
+            let cached = self
+                .cached_counts
+                .get(chitchat_key)
+                .filter(|cached| !cached.is_expired(now, self.cache_epoch, self.config.cache_ttl));
+            match cached {
+                Some(cached) => {
+                    trace!(key = %chitchat_key, "Cache hit for counter");
+                    Some(cached.get())
+                }
+                None => {
+                    trace!(key = %chitchat_key, "Cache miss for counter, fetching from cluster");
+                    None
+                }
             }
-        }
+        });
 
         // Slow path: cache miss or expired, refresh from Chitchat
-        trace!(key = %chitchat_key, "Cache miss for counter, fetching from cluster");
-        self.refresh_cache_for_key(&chitchat_key).await
+        match cached {
+            Some(total) => total,
+            None => self.refresh_cache_for_key(key).await,
+        }
     }
 
     /// Refresh the cache for a specific key and return the fresh total.
-    async fn refresh_cache_for_key(&self, chitchat_key: &str) -> u64 {
+    async fn refresh_cache_for_key<K: AsCounterKey + Sync>(&self, key: &K) -> u64 {
         let chitchat_arc = self.handle.chitchat();
         let chitchat = chitchat_arc.lock().await;
-        let total = self.sum_counter_internal(&chitchat, chitchat_key);
+        let total = key.with_chitchat_key(|key| self.sum_counter_internal(&chitchat, key));
         drop(chitchat); // Release lock before cache update
 
-        let now = Instant::now();
-        self.cached_counts
-            .entry(chitchat_key.to_string())
-            .and_modify(|cached| cached.update(total, now, self.cache_epoch))
-            .or_insert_with(|| CachedCount::new(total, now, self.cache_epoch));
-
+        self.update_cached_counts(std::slice::from_ref(key), &[total]);
         total
     }
 
@@ -333,15 +1213,24 @@ impl Cluster {
     fn sum_counter_internal(&self, chitchat: &chitchat::Chitchat, key: &str) -> u64 {
         let mut total: u64 = 0;
 
-        // Sum from all live nodes
-        for node_id in chitchat.live_nodes() {
-            if let Some(node_state) = chitchat.node_state(node_id) {
-                if let Some(value) = node_state.get(key) {
-                    if let Ok(count) = value.parse::<u64>() {
-                        total += count;
-                    }
+        // Sum from the current generation of all live nodes
+        for node_id in Self::current_generations(chitchat) {
+            let mut count = chitchat
+                .node_state(node_id)
+                .and_then(|node_state| node_state.get(key))
+                .and_then(|value| value.parse::<u64>().ok())
+                .unwrap_or(0);
+
+            // A peer's broadcast value may be ahead of its gossiped one
+            if let Some(broadcaster) = &self.broadcaster {
+                if node_id.node_id != self.node_id {
+                    let eager = broadcaster
+                        .eager_counts()
+                        .get(key, &node_id.node_id, node_id.generation_id);
+                    count = count.max(eager.unwrap_or(0));
                 }
             }
+            total = total.saturating_add(count);
         }
 
         total
@@ -365,22 +1254,60 @@ impl Cluster {
     pub async fn live_node_count(&self) -> usize {
         let chitchat_arc = self.handle.chitchat();
         let chitchat = chitchat_arc.lock().await;
-        chitchat.live_nodes().count()
+        Self::current_generations(&chitchat).len()
     }
 
     /// Get the IDs of all live nodes.
     pub async fn live_nodes(&self) -> Vec<String> {
         let chitchat_arc = self.handle.chitchat();
         let chitchat = chitchat_arc.lock().await;
-        chitchat
-            .live_nodes()
+        Self::current_generations(&chitchat)
+            .into_iter()
             .map(|id| id.node_id.clone())
             .collect()
     }
 
+    /// Get a generation ID for a node starting now.
+    ///
+    /// Generations must increase across restarts of a node, so the startup
+    /// time in microseconds is used.
+    fn new_generation_id() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_micros() as u64)
+    }
+
+    /// Get the latest generation of every live node.
+    ///
+    /// A restarted node's previous generation stays live until the failure
+    /// detector notices it stopped heartbeating. Its state was superseded by
+    /// the restart, so only the newest generation of each node is counted.
+    fn current_generations(chitchat: &chitchat::Chitchat) -> Vec<&ChitchatId> {
+        let mut latest: HashMap<&str, &ChitchatId> = HashMap::new();
+        for id in chitchat.live_nodes() {
+            latest
+                .entry(id.node_id.as_str())
+                .and_modify(|current| {
+                    if id.generation_id > current.generation_id {
+                        *current = id;
+                    }
+                })
+                .or_insert(id);
+        }
+        latest.into_values().collect()
+    }
+
+    /// Get this node's generation ID.
+    pub fn generation_id(&self) -> u64 {
+        self.generation_id
+    }
+
     /// Shutdown the cluster node gracefully.
     pub async fn shutdown(self) -> Result<(), ClusterError> {
         info!(node_id = %self.node_id, "Shutting down cluster node");
+        drop(self.sync_marker_task);
+        drop(self.broadcast_task);
+        drop(self.owned_sync_task);
         self.handle
             .shutdown()
             .await
@@ -392,6 +1319,7 @@ impl Cluster {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
     use std::time::Duration;
 
     fn test_config(port: u16) -> ClusterConfig {
@@ -405,19 +1333,56 @@ mod tests {
             gossip_interval: Duration::from_millis(50),
             dead_node_grace_period: Duration::from_secs(60),
             cache_ttl: Duration::from_millis(100), // Short TTL for tests
+            broadcast_listen_addr: None,
+            peer_addr: None,
         }
     }
 
     #[test]
     fn test_counter_key() {
         let key = CounterKey::new("my_domain", "user:123", 1704067200);
-        let chitchat_key = key.to_chitchat_key();
+        let chitchat_key = key.as_chitchat_key();
         assert_eq!(chitchat_key, "counter|my_domain|user:123|1704067200");
+        assert_eq!(key.domain(), "my_domain");
+        assert_eq!(key.descriptor(), "user:123");
+        assert_eq!(key.window(), 1704067200);
+
+        let parsed = CounterKey::from_chitchat_key(chitchat_key).unwrap();
+        assert_eq!(parsed, key);
+        assert_eq!(parsed.domain(), "my_domain");
+        assert_eq!(parsed.descriptor(), "user:123");
+        assert_eq!(parsed.window(), 1704067200);
+    }
 
-        let parsed = CounterKey::from_chitchat_key(&chitchat_key).unwrap();
-        assert_eq!(parsed.domain, "my_domain");
-        assert_eq!(parsed.descriptor, "user:123");
-        assert_eq!(parsed.window, 1704067200);
+    #[test]
+    fn test_counter_key_escapes_domain() {
+        let a = CounterKey::new("a|b", "c", 1000);
+        let b = CounterKey::new("a", "b|c", 1000);
+        assert_ne!(a.as_chitchat_key(), b.as_chitchat_key());
+        assert_eq!(a.as_chitchat_key(), r"counter|a\|b|c|1000");
+
+        let parsed = CounterKey::from_chitchat_key(a.as_chitchat_key()).unwrap();
+        assert_eq!(parsed.domain(), "a|b");
+        assert_eq!(parsed.descriptor(), "c");
+        let parsed = CounterKey::from_chitchat_key(b.as_chitchat_key()).unwrap();
+        assert_eq!(parsed.domain(), "a");
+        assert_eq!(parsed.descriptor(), "b|c");
+    }
+
+    proptest! {
+        #[test]
+        fn prop_counter_key_round_trips(
+            domain in any::<String>(),
+            descriptor in any::<String>(),
+            window in any::<u64>(),
+        ) {
+            let key = CounterKey::new(&domain, &descriptor, window);
+            let parsed = CounterKey::from_chitchat_key(key.as_chitchat_key()).unwrap();
+            prop_assert_eq!(parsed.domain(), domain.as_str());
+            prop_assert_eq!(parsed.descriptor(), descriptor.as_str());
+            prop_assert_eq!(parsed.window(), window);
+            prop_assert_eq!(parsed, key);
+        }
     }
 
     #[test]
@@ -501,4 +1466,209 @@ mod tests {
         cluster1.shutdown().await.unwrap();
         cluster2.shutdown().await.unwrap();
     }
+
+    #[tokio::test]
+    async fn test_cluster_node_restart_mid_window() {
+        let mut config1 = test_config(17952);
+        config1.node_id = "restarting-node".to_string();
+        let cluster1 = Cluster::start(config1.clone()).await.unwrap();
+
+        let mut config2 = test_config(17953);
+        config2.seed_nodes = vec!["127.0.0.1:17952".to_string()];
+        let cluster2 = Cluster::start(config2).await.unwrap();
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let key = CounterKey::new("test", "restart", 1000);
+        cluster1.increment_counter(&key, 3).await;
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(cluster2.get_count(&key).await, 3);
+
+        // Restart node 1 with the same node ID in the same window
+        let old_generation = cluster1.generation_id();
+        cluster1.shutdown().await.unwrap();
+        config1.seed_nodes = vec!["127.0.0.1:17953".to_string()];
+        let cluster1 = Cluster::start(config1).await.unwrap();
+        assert!(cluster1.generation_id() > old_generation);
+
+        cluster1.increment_counter(&key, 2).await;
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        // Peers count the new generation's increments and retire the old
+        // generation's state
+        assert_eq!(cluster2.get_count(&key).await, 2);
+        assert_eq!(cluster1.get_count(&key).await, 2);
+        assert_eq!(cluster2.live_node_count().await, 2);
+
+        cluster1.shutdown().await.unwrap();
+        cluster2.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cluster_wait_for_sync() {
+        // A node without seeds starts the cluster and is synced immediately
+        let config1 = test_config(17954);
+        let cluster1 = Cluster::start(config1).await.unwrap();
+        assert!(cluster1.wait_for_sync(Duration::ZERO).await);
+
+        let key = CounterKey::new("test", "sync", 1000);
+        cluster1.increment_counter(&key, 7).await;
+
+        // A joining node is synced once it has the seed's state
+        let mut config2 = test_config(17955);
+        config2.seed_nodes = vec!["127.0.0.1:17954".to_string()];
+        let cluster2 = Cluster::start(config2).await.unwrap();
+        assert!(cluster2.wait_for_sync(Duration::from_secs(5)).await);
+        assert_eq!(cluster2.get_count(&key).await, 7);
+
+        // A node whose seeds are unreachable times out
+        let mut config3 = test_config(17956);
+        config3.seed_nodes = vec!["127.0.0.1:17999".to_string()];
+        let cluster3 = Cluster::start(config3).await.unwrap();
+        assert!(!cluster3.wait_for_sync(Duration::from_millis(300)).await);
+
+        cluster1.shutdown().await.unwrap();
+        cluster2.shutdown().await.unwrap();
+        cluster3.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cluster_broadcast_counters() {
+        let mut config1 = test_config(17957);
+        config1.broadcast_listen_addr = Some("127.0.0.1:0".parse().unwrap());
+        let cluster1 = Cluster::start(config1).await.unwrap();
+        let mut config2 = test_config(17958);
+        config2.broadcast_listen_addr = Some("127.0.0.1:0".parse().unwrap());
+        config2.seed_nodes = vec!["127.0.0.1:17957".to_string()];
+        let cluster2 = Cluster::start(config2).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let key = CounterKey::new("test", "broadcast", 1000);
+        cluster1.increment_counter(&key, 4).await;
+        cluster1.broadcast_counters(&[&key]).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // The peer records the broadcast value next to the gossiped state
+        let eager_counts = cluster2.broadcaster.as_ref().unwrap().eager_counts();
+        let eager = eager_counts.get(key.as_chitchat_key(), "test-node-17957", cluster1.generation_id());
+        assert_eq!(eager, Some(4));
+        assert_eq!(cluster2.get_count(&key).await, 4);
+
+        // Datagrams naming the peer from another address are ignored
+        let spoofer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let spoofed = BroadcastMessage {
+            cluster_id: "test-cluster".to_string(),
+            node_id: "test-node-17957".to_string(),
+            generation_id: cluster1.generation_id(),
+            counters: vec![(key.as_chitchat_key().to_string(), 1000)],
+        };
+        let target = cluster2.broadcaster.as_ref().unwrap().local_addr();
+        spoofer.send_to(&serde_json::to_vec(&spoofed).unwrap(), target).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let eager = eager_counts.get(key.as_chitchat_key(), "test-node-17957", cluster1.generation_id());
+        assert_eq!(eager, Some(4));
+
+        // A node without broadcast enabled ignores the request
+        let cluster3 = Cluster::start(test_config(17959)).await.unwrap();
+        assert!(!cluster3.broadcast_enabled());
+        cluster3.broadcast_counters(&[&key]).await;
+
+        cluster1.shutdown().await.unwrap();
+        cluster2.shutdown().await.unwrap();
+        cluster3.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_counter_owners_need_peer_addr() {
+        let cluster1 = Cluster::start(test_config(17961)).await.unwrap();
+        let mut config2 = test_config(17962);
+        config2.seed_nodes = vec!["127.0.0.1:17961".to_string()];
+        let peer_addr: SocketAddr = "127.0.0.1:17963".parse().unwrap();
+        config2.peer_addr = Some(peer_addr);
+        let cluster2 = Cluster::start(config2).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        // A node peers can't reach never owns counters
+        let keys: Vec<CounterKey> = (0..20).map(|i| CounterKey::new("test", format!("key{}", i), 1000)).collect();
+        let keys: Vec<&CounterKey> = keys.iter().collect();
+        for owner in cluster1.counter_owners(&keys, &[]).await {
+            assert_eq!(
+                owner,
+                KeyOwner::Peer {
+                    node_id: "test-node-17962".to_string(),
+                    addr: peer_addr,
+                }
+            );
+        }
+        assert!(cluster2
+            .counter_owners(&keys, &[])
+            .await
+            .iter()
+            .all(|owner| *owner == KeyOwner::Local));
+
+        cluster1.shutdown().await.unwrap();
+        cluster2.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cluster_publish_config() {
+        let cluster = Cluster::start(test_config(17960)).await.unwrap();
+        assert!(cluster.node_configs().await.is_empty());
+
+        let domains = BTreeMap::from([("api".to_string(), 1), ("web".to_string(), 2)]);
+        cluster.publish_config(7, 1700000000, &domains).await;
+        let configs = cluster.node_configs().await;
+        assert_eq!(configs.len(), 1);
+        assert_eq!(configs[0].node_id, "test-node-17960");
+        assert_eq!((configs[0].hash, configs[0].version), (7, 1700000000));
+        assert_eq!(configs[0].domains, domains);
+
+        // Domains missing from a later configuration are withdrawn
+        let domains = BTreeMap::from([("api".to_string(), 3)]);
+        cluster.publish_config(8, 1700000060, &domains).await;
+        assert_eq!(cluster.node_configs().await[0].domains, domains);
+
+        cluster.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cluster_increment_counters_batch() {
+        let config = test_config(17951);
+        let cluster = Cluster::start(config).await.unwrap();
+
+        let a = CounterKey::new("test", "a", 1000);
+        let b = CounterKey::new("test", "b", 1000);
+
+        let totals = cluster.increment_counters(&[a.clone(), b.clone()], 2).await;
+        assert_eq!(totals, vec![2, 2]);
+
+        // A repeated key reports its running total at each position
+        let totals = cluster.increment_counters(&[a.clone(), b.clone(), a.clone()], 1).await;
+        assert_eq!(totals, vec![3, 3, 4]);
+        assert_eq!(cluster.get_count(&a).await, 4);
+
+        cluster.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cluster_increment_counters_if_within() {
+        let config = test_config(17950);
+        let cluster = Cluster::start(config).await.unwrap();
+
+        let tight = CounterKey::new("test", "tight", 1000);
+        let loose = CounterKey::new("test", "loose", 1000);
+        let counters = vec![(tight.clone(), 2), (loose.clone(), 100)];
+
+        let (committed, totals) = cluster.increment_counters_if_within(&counters, 2).await;
+        assert!(committed);
+        assert_eq!(totals, vec![2, 2]);
+
+        // The tight counter would exceed its limit, so nothing is incremented
+        let (committed, totals) = cluster.increment_counters_if_within(&counters, 1).await;
+        assert!(!committed);
+        assert_eq!(totals, vec![2, 2]);
+        assert_eq!(cluster.get_count(&loose).await, 2);
+
+        cluster.shutdown().await.unwrap();
+    }
 }
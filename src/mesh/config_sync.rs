@@ -0,0 +1,154 @@
+//! Comparing rate limit configurations across the cluster.
+//!
+//! Nodes count against shared counters, so a node running stale rules
+//! enforces a different limit on the same counter than its peers. Every node
+//! gossips a hash of each domain's rules, and drift is detected by comparing
+//! a domain's hash on this node with the hash more than half the nodes agree on.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// The rate limit configuration a node has published.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeConfig {
+    /// ID of the node
+    pub node_id: String,
+    /// Hash of the whole configuration
+    pub hash: u64,
+    /// When the configuration was published, in epoch seconds
+    pub version: u64,
+    /// Hash of each domain's rules, by domain
+    pub domains: BTreeMap<String, u64>,
+}
+
+/// How a domain's rules compare across the cluster.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DomainDrift {
+    /// The rate limit domain
+    pub domain: String,
+    /// Hash of the domain's rules on this node, or `None` if it doesn't have them
+    pub local_hash: Option<u64>,
+    /// Whether more than half the nodes have the same version of the rules
+    pub has_majority: bool,
+    /// Hash of the rules more than half the nodes have, or `None` if there is
+    /// no majority or the majority doesn't have the domain
+    pub majority_hash: Option<u64>,
+    /// Hash of the domain's rules on each node, by node ID
+    pub nodes: BTreeMap<String, Option<u64>>,
+}
+
+impl DomainDrift {
+    /// Check whether this node's rules differ from those a majority of nodes have.
+    ///
+    /// Without a strict majority, as when half the nodes have rolled out new
+    /// rules or three versions are spread over the cluster, no node is
+    /// considered drifted.
+    pub fn drifted(&self) -> bool {
+        self.has_majority && self.local_hash != self.majority_hash
+    }
+}
+
+/// Compare every domain's rules on `local_node_id` with the other nodes'.
+///
+/// A node without a domain counts as a node with different rules. Domains are
+/// returned in order.
+pub fn detect_drift(local_node_id: &str, nodes: &[NodeConfig]) -> Vec<DomainDrift> {
+    let domains: BTreeSet<&String> = nodes.iter().flat_map(|node| node.domains.keys()).collect();
+    let local = nodes.iter().find(|node| node.node_id == local_node_id);
+
+    domains
+        .into_iter()
+        .map(|domain| {
+            let hashes: BTreeMap<String, Option<u64>> = nodes
+                .iter()
+                .map(|node| (node.node_id.clone(), node.domains.get(domain).copied()))
+                .collect();
+
+            let mut votes: HashMap<Option<u64>, usize> = HashMap::new();
+            for hash in hashes.values() {
+                *votes.entry(*hash).or_default() += 1;
+            }
+            // A strict majority has more votes than all other versions together
+            let majority = votes.into_iter().find(|(_, count)| count * 2 > nodes.len());
+            let (has_majority, majority_hash) = match majority {
+                Some((hash, _)) => (true, hash),
+                None => (false, None),
+            };
+
+            DomainDrift {
+                domain: domain.clone(),
+                local_hash: local.and_then(|node| node.domains.get(domain).copied()),
+                has_majority,
+                majority_hash,
+                nodes: hashes,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(node_id: &str, domains: &[(&str, u64)]) -> NodeConfig {
+        NodeConfig {
+            node_id: node_id.to_string(),
+            hash: 0,
+            version: 0,
+            domains: domains.iter().map(|(domain, hash)| (domain.to_string(), *hash)).collect(),
+        }
+    }
+
+    #[test]
+    fn test_detect_drift() {
+        let nodes = [
+            node("a", &[("api", 1), ("web", 5)]),
+            node("b", &[("api", 1), ("web", 5)]),
+            node("c", &[("api", 2), ("web", 5), ("new", 9)]),
+        ];
+
+        let drift = detect_drift("c", &nodes);
+        let domains: Vec<&str> = drift.iter().map(|d| d.domain.as_str()).collect();
+        assert_eq!(domains, ["api", "new", "web"]);
+
+        // c has stale api rules and a domain the others don't have
+        assert_eq!(drift[0].local_hash, Some(2));
+        assert_eq!(drift[0].majority_hash, Some(1));
+        assert!(drift[0].drifted());
+        assert!(drift[1].has_majority);
+        assert_eq!(drift[1].majority_hash, None);
+        assert!(drift[1].drifted());
+        assert!(!drift[2].drifted());
+
+        // The nodes that agree aren't drifted
+        assert!(detect_drift("a", &nodes).iter().all(|d| !d.drifted()));
+    }
+
+    #[test]
+    fn test_detect_drift_without_majority() {
+        let nodes = [node("a", &[("api", 1)]), node("b", &[("api", 2)])];
+        let drift = detect_drift("a", &nodes);
+        assert!(!drift[0].has_majority);
+        assert!(!drift[0].drifted());
+
+        // The most common version isn't a majority unless more than half the nodes have it
+        let nodes = [
+            node("a", &[("api", 1)]),
+            node("b", &[("api", 1)]),
+            node("c", &[("api", 2)]),
+            node("d", &[("api", 3)]),
+        ];
+        let drift = detect_drift("c", &nodes);
+        assert!(!drift[0].has_majority);
+        assert_eq!(drift[0].majority_hash, None);
+        assert!(!drift[0].drifted());
+
+        let nodes = [
+            node("a", &[("api", 1)]),
+            node("b", &[("api", 1)]),
+            node("c", &[("api", 1)]),
+            node("d", &[("api", 2)]),
+            node("e", &[("api", 3)]),
+        ];
+        assert!(detect_drift("d", &nodes)[0].drifted());
+    }
+}
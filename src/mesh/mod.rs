@@ -5,6 +5,14 @@
 //! It uses the chitchat library for gossip-based cluster membership
 //! and state dissemination.
 
+mod advertise;
+mod block;
+mod broadcast;
 mod cluster;
+mod config_sync;
+mod ownership;
 
-pub use cluster::{Cluster, ClusterConfig, CounterKey};
+pub use advertise::{resolve_advertise_addr, POD_IP_ENV};
+pub use cluster::{AsCounterKey, Cluster, ClusterConfig, ClusterError, CounterKey, CounterKeyRef};
+pub use config_sync::{detect_drift, DomainDrift, NodeConfig};
+pub use ownership::{KeyOwner, OwnedIncrement};
@@ -0,0 +1,318 @@
+//! Key ownership for strongly consistent counters.
+//!
+//! Gossiped counters are summed from every node's share, so two nodes can
+//! both admit the last hit of a limit before hearing about each other. A
+//! strongly consistent counter is instead kept by a single owner node, which
+//! every other node forwards its increments to, so the owner's count is the
+//! only count and is checked under a lock.
+//!
+//! Owners are chosen by rendezvous hashing over the live nodes: each node
+//! scores each key, and the highest score wins. A node joining or leaving
+//! only moves the keys it wins or was winning, and a node that can't reach
+//! an owner picks the next-highest scoring node instead.
+//!
+//! Owners gossip their counts as floors. A node that takes a key over, as
+//! when a node joins or its owner stops answering, seeds the count with the
+//! highest floor any node has gossiped for it, and held counts are raised to
+//! other nodes' floors at every sync, so a key that moves back to a previous
+//! owner picks up the hits counted meanwhile. Hits counted since the last
+//! sync can be missed by a new owner, and a floor gossiped before a release
+//! undoes it, so handoffs can lose a sync interval's worth of hits or
+//! overcount released ones.
+//!
+//! While a node is partitioned from an owner it counts the owner's keys on
+//! its own. Once the partition heals, the owner continues from the higher of
+//! the two counts.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use parking_lot::Mutex;
+
+use super::cluster::CounterKey;
+
+/// Prefix of every owned count floor key in the chitchat state, followed by
+/// the counter's chitchat key.
+pub(crate) const OWNED_KEY_PREFIX: &str = "owned|";
+
+/// The node that owns a counter.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum KeyOwner {
+    /// This node
+    Local,
+    /// A peer, reached at its peer service address
+    Peer { node_id: String, addr: SocketAddr },
+}
+
+impl KeyOwner {
+    /// Get the owner's node ID, or `None` for this node.
+    pub fn node_id(&self) -> Option<&str> {
+        match self {
+            KeyOwner::Local => None,
+            KeyOwner::Peer { node_id, .. } => Some(node_id),
+        }
+    }
+}
+
+/// An increment of a strongly consistent counter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OwnedIncrement {
+    /// The counter key, which includes the window start
+    pub key: CounterKey,
+    /// Maximum hits allowed in the window
+    pub limit: u64,
+    /// Length of the counter's window in seconds
+    pub window_secs: u64,
+}
+
+/// Choose the owner of a key among candidate node IDs.
+///
+/// Every node computes the same owner from the same candidates, whatever
+/// their order.
+pub(crate) fn rendezvous_owner<'a>(key: &str, candidates: &'a [&'a str]) -> Option<&'a str> {
+    candidates
+        .iter()
+        .copied()
+        .max_by_key(|node_id| (rendezvous_score(node_id, key), *node_id))
+}
+
+/// Score a node for a key.
+///
+/// FNV-1a over the node ID and key, finished with the splitmix64 mixer so
+/// similar node IDs don't produce similar scores. The hash must not change
+/// between releases, since nodes of different versions must agree on owners.
+fn rendezvous_score(node_id: &str, key: &str) -> u64 {
+    const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    let mut hash = FNV_OFFSET;
+    // The separator can't appear in UTF-8, so (node, key) pairs can't collide by shifting bytes
+    for byte in node_id.bytes().chain([0xff]).chain(key.bytes()) {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+
+    hash ^= hash >> 30;
+    hash = hash.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash ^= hash >> 27;
+    hash = hash.wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+/// A count held by an owner, as gossiped to the other nodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct OwnedCount {
+    /// Hits counted in the window
+    pub count: u64,
+    /// End of the counter's window, in epoch seconds
+    pub expires_at: u64,
+}
+
+impl OwnedCount {
+    /// Format the count as a chitchat value: "{count}:{expires_at}".
+    pub fn to_value(self) -> String {
+        format!("{}:{}", self.count, self.expires_at)
+    }
+
+    /// Parse a count from a chitchat value.
+    pub fn from_value(value: &str) -> Option<Self> {
+        let (count, expires_at) = value.split_once(':')?;
+        Some(Self {
+            count: count.parse().ok()?,
+            expires_at: expires_at.parse().ok()?,
+        })
+    }
+}
+
+/// Counters owned by this node: chitchat key -> count.
+///
+/// A single lock covers every counter, so a batch is checked and applied
+/// atomically.
+#[derive(Debug, Default)]
+pub(crate) struct OwnedCounters {
+    counts: Mutex<HashMap<String, OwnedCount>>,
+    /// When counters of ended windows are next dropped, in epoch seconds
+    next_eviction: Mutex<u64>,
+}
+
+impl OwnedCounters {
+    /// Add `hits` to every counter and return each count, in order.
+    ///
+    /// With `atomic`, nothing is added unless every counter stays within its
+    /// limit. Returns whether the hits were added. Adding no hits reads the
+    /// counts without creating counters.
+    pub fn increment(&self, counters: &[OwnedIncrement], hits: u64, atomic: bool, now: u64) -> (bool, Vec<u64>) {
+        self.evict_if_due(now);
+        let mut counts = self.counts.lock();
+
+        let committed = !atomic || {
+            // The same key may appear more than once, so check the combined hits
+            let mut required: HashMap<&str, u64> = HashMap::new();
+            for counter in counters {
+                let total = required.entry(counter.key.as_chitchat_key()).or_insert(0);
+                *total = total.saturating_add(hits);
+            }
+            counters.iter().all(|counter| {
+                let key = counter.key.as_chitchat_key();
+                let count = counts.get(key).map_or(0, |owned| owned.count);
+                count.saturating_add(required[key]) <= counter.limit
+            })
+        };
+
+        let totals = counters
+            .iter()
+            .map(|counter| {
+                let key = counter.key.as_chitchat_key();
+                if !committed || hits == 0 {
+                    return counts.get(key).map_or(0, |owned| owned.count);
+                }
+                let owned = counts.entry(key.to_string()).or_insert_with(|| OwnedCount {
+                    count: 0,
+                    expires_at: counter.key.window().saturating_add(counter.window_secs),
+                });
+                owned.count = owned.count.saturating_add(hits);
+                owned.count
+            })
+            .collect();
+        (committed, totals)
+    }
+
+    /// Find the counters not held by this node, which a new owner seeds.
+    pub fn missing<'a>(&self, counters: &'a [OwnedIncrement]) -> Vec<&'a OwnedIncrement> {
+        let counts = self.counts.lock();
+        counters
+            .iter()
+            .filter(|counter| !counts.contains_key(counter.key.as_chitchat_key()))
+            .collect()
+    }
+
+    /// Raise each counter to at least the given count, creating it if needed.
+    pub fn raise(&self, floors: &[(String, OwnedCount)]) {
+        let mut counts = self.counts.lock();
+        for (key, floor) in floors {
+            counts
+                .entry(key.clone())
+                .and_modify(|owned| owned.count = owned.count.max(floor.count))
+                .or_insert(*floor);
+        }
+    }
+
+    /// Get every counter held, by chitchat key.
+    pub fn counts(&self) -> Vec<(String, OwnedCount)> {
+        self.counts
+            .lock()
+            .iter()
+            .map(|(key, owned)| (key.clone(), *owned))
+            .collect()
+    }
+
+    /// Take `hits` back from each counter.
+    pub fn release(&self, keys: &[CounterKey], hits: u64) {
+        let mut counts = self.counts.lock();
+        for key in keys {
+            if let Some(owned) = counts.get_mut(key.as_chitchat_key()) {
+                owned.count = owned.count.saturating_sub(hits);
+            }
+        }
+    }
+
+    /// Get the number of counters held.
+    pub fn len(&self) -> usize {
+        self.counts.lock().len()
+    }
+
+    /// Drop counters whose window has ended, at most once a second.
+    fn evict_if_due(&self, now: u64) {
+        {
+            let mut next_eviction = self.next_eviction.lock();
+            if now < *next_eviction {
+                return;
+            }
+            *next_eviction = now + 1;
+        }
+        self.counts.lock().retain(|_, owned| owned.expires_at > now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn increment(key: &str, limit: u64, window: u64) -> OwnedIncrement {
+        OwnedIncrement {
+            key: CounterKey::new("domain", key, window),
+            limit,
+            window_secs: 60,
+        }
+    }
+
+    #[test]
+    fn test_rendezvous_owner() {
+        let nodes = ["node-a", "node-b", "node-c"];
+        let owner = rendezvous_owner("counter|d|k|0", &nodes).unwrap();
+
+        // The owner doesn't depend on the order of candidates
+        let reversed = ["node-c", "node-b", "node-a"];
+        assert_eq!(rendezvous_owner("counter|d|k|0", &reversed), Some(owner));
+
+        // Removing another node doesn't move the key
+        let others: Vec<&str> = nodes.iter().copied().filter(|n| *n != owner).collect();
+        let remaining = [owner, others[0]];
+        assert_eq!(rendezvous_owner("counter|d|k|0", &remaining), Some(owner));
+
+        // Keys are spread across nodes
+        let mut owned: HashMap<&str, usize> = HashMap::new();
+        for i in 0..300 {
+            let key = format!("counter|d|k{}|0", i);
+            *owned.entry(rendezvous_owner(&key, &nodes).unwrap()).or_default() += 1;
+        }
+        assert_eq!(owned.len(), 3);
+        assert!(owned.values().all(|count| *count > 50), "{:?}", owned);
+
+        assert_eq!(rendezvous_owner("counter|d|k|0", &[]), None);
+    }
+
+    #[test]
+    fn test_owned_counters() {
+        let counters = OwnedCounters::default();
+        let a = increment("a", 3, 60);
+        let b = increment("b", 1, 60);
+
+        assert_eq!(counters.increment(&[a.clone(), b.clone()], 1, true, 61), (true, vec![1, 1]));
+        // b is at its limit, so neither counter is incremented
+        assert_eq!(counters.increment(&[a.clone(), b.clone()], 1, true, 61), (false, vec![1, 1]));
+        // Independent increments always count
+        assert_eq!(counters.increment(&[a.clone(), b.clone()], 1, false, 61), (true, vec![2, 2]));
+
+        counters.release(std::slice::from_ref(&b.key), 1);
+        assert_eq!(counters.increment(std::slice::from_ref(&b), 0, false, 61), (true, vec![1]));
+        assert_eq!(counters.increment(&[increment("c", 1, 60)], 0, false, 61), (true, vec![0]));
+
+        // Counters are dropped once their window ends
+        assert_eq!(counters.len(), 2);
+        counters.increment(&[], 0, false, 120);
+        assert_eq!(counters.len(), 0);
+    }
+
+    #[test]
+    fn test_owned_counters_raise() {
+        let counters = OwnedCounters::default();
+        let a = increment("a", 10, 60);
+        let b = increment("b", 10, 60);
+        counters.increment(std::slice::from_ref(&a), 3, false, 61);
+        assert_eq!(counters.missing(&[a.clone(), b.clone()]), vec![&b]);
+
+        // Floors raise held counts and seed missing ones, but never lower them
+        let floor = |count| OwnedCount { count, expires_at: 120 };
+        counters.raise(&[
+            (a.key.as_chitchat_key().to_string(), floor(2)),
+            (b.key.as_chitchat_key().to_string(), floor(4)),
+        ]);
+        assert_eq!(counters.increment(&[a.clone(), b.clone()], 1, false, 61), (true, vec![4, 5]));
+        assert!(counters.missing(&[a, b]).is_empty());
+
+        assert_eq!(floor(5).to_value(), "5:120");
+        assert_eq!(OwnedCount::from_value("5:120"), Some(floor(5)));
+        assert_eq!(OwnedCount::from_value("5"), None);
+    }
+}
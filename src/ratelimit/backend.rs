@@ -1,10 +1,25 @@
 //! Rate limiter trait for abstracting local and distributed implementations.
 
 use async_trait::async_trait;
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
 
 use crate::grpc::proto::envoy::extensions::common::ratelimit::v3::RateLimitDescriptor;
 use crate::grpc::proto::envoy::service::ratelimit::v3::rate_limit_response::DescriptorStatus;
 
+/// How the descriptors of a single request are evaluated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EvaluationMode {
+    /// Each descriptor is checked and counted on its own, so a rejected
+    /// request still consumes quota on the descriptors that were within limit.
+    #[default]
+    Independent,
+    /// All descriptors are checked first and increments are only committed
+    /// if every descriptor is within its limit.
+    Atomic,
+}
+
 /// Trait for rate limiter implementations.
 ///
 /// This trait abstracts over both the local `RateLimiter` and the
@@ -18,4 +33,43 @@ pub trait RateLimiterBackend: Send + Sync {
         descriptor: &RateLimitDescriptor,
         hits: u32,
     ) -> DescriptorStatus;
+
+    /// Check the rate limits for all descriptors of a request.
+    ///
+    /// Each descriptor is counted independently. Statuses are returned in
+    /// the same order as the descriptors. The default implementation checks
+    /// the descriptors concurrently; backends should override it to share
+    /// locks and rule lookups across the batch.
+    async fn check_rate_limits(
+        &self,
+        domain: &str,
+        descriptors: &[RateLimitDescriptor],
+        hits: u32,
+    ) -> Vec<DescriptorStatus> {
+        join_all(
+            descriptors
+                .iter()
+                .map(|descriptor| self.check_rate_limit(domain, descriptor, hits)),
+        )
+        .await
+    }
+
+    /// Check the rate limits for all descriptors of a request atomically.
+    ///
+    /// Counters are only incremented if every descriptor is within its limit.
+    /// Statuses are returned in the same order as the descriptors.
+    async fn check_rate_limits_atomic(
+        &self,
+        domain: &str,
+        descriptors: &[RateLimitDescriptor],
+        hits: u32,
+    ) -> Vec<DescriptorStatus>;
+
+    /// Whether any descriptor of a request is counted with the rest of the
+    /// cluster, and so relies on every node enforcing the same rules.
+    ///
+    /// The default assumes every descriptor is.
+    fn counts_across_cluster(&self, _domain: &str, _descriptors: &[RateLimitDescriptor]) -> bool {
+        true
+    }
 }
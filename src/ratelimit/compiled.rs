@@ -0,0 +1,753 @@
+//! Precompiled rate limit rules.
+//!
+//! A [`RateLimitConfig`] is compiled once, when it is loaded or replaced, into
+//! a tree indexed by descriptor key and exact value at each level. Lookups
+//! then cost a hash lookup per descriptor entry instead of a scan over every
+//! configured node, and limiters share the compiled rules through an
+//! `ArcSwap` so checks never take a lock to read them.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+use smallvec::SmallVec;
+use tracing::warn;
+
+use crate::grpc::proto::envoy::extensions::common::ratelimit::v3::rate_limit_descriptor::Entry;
+use crate::grpc::proto::envoy::extensions::common::ratelimit::v3::RateLimitDescriptor;
+
+use super::rules::{
+    DescriptorConfig, IpAggregation, MatchingMode, RateLimitConfig, RateLimitRule, ValueMatcher,
+};
+
+/// A rate limit configuration compiled for fast descriptor lookup.
+#[derive(Debug, Default)]
+pub struct CompiledConfig {
+    /// The configuration this was compiled from
+    config: RateLimitConfig,
+    /// Compiled descriptor trees by domain name
+    domains: HashMap<String, CompiledDomain>,
+}
+
+/// The compiled descriptor tree of a domain.
+#[derive(Debug)]
+struct CompiledDomain {
+    /// How descriptors are matched against the tree
+    matching: MatchingMode,
+    /// The top-level descriptor nodes
+    root: NodeIndex,
+}
+
+/// The descriptor nodes at one level of the tree, indexed for lookup.
+#[derive(Debug, Default)]
+struct NodeIndex {
+    /// Nodes by the descriptor key they match
+    keys: HashMap<String, KeyNodes>,
+}
+
+/// The nodes matching one descriptor key.
+#[derive(Debug, Default)]
+struct KeyNodes {
+    /// Nodes matching an exact value, by value, in configuration order
+    exact: HashMap<String, Vec<CompiledNode>>,
+    /// All other nodes, in order of precedence, specificity and then
+    /// configuration order
+    patterns: Vec<CompiledNode>,
+}
+
+/// A compiled descriptor node.
+#[derive(Debug)]
+pub struct CompiledNode {
+    /// Matcher for entry values
+    matcher: ValueMatcher,
+    /// Rate limits applied at this level
+    rules: Vec<RateLimitRule>,
+    /// Aggregation of the matched entry's value
+    aggregate: Option<IpAggregation>,
+    /// Child nodes for more specific matching
+    children: NodeIndex,
+}
+
+/// The rules matched for a descriptor.
+#[derive(Debug, Clone)]
+pub struct LimitMatch<'a> {
+    /// The node providing the rules
+    pub node: &'a CompiledNode,
+    /// Aggregation to apply to descriptor entries, by entry index
+    pub aggregations: SmallVec<[(usize, &'a IpAggregation); 2]>,
+}
+
+impl CompiledNode {
+    /// Get the rate limits applied at this level.
+    pub fn rules(&self) -> &[RateLimitRule] {
+        &self.rules
+    }
+}
+
+impl<'a> LimitMatch<'a> {
+    fn new(node: &'a CompiledNode) -> Self {
+        Self {
+            node,
+            aggregations: SmallVec::new(),
+        }
+    }
+
+    /// Get the matched rate limits.
+    pub fn rules(&self) -> &'a [RateLimitRule] {
+        self.node.rules()
+    }
+
+    /// Record the node that matched the entry at `entry_index`.
+    fn record(&mut self, entry_index: usize, node: &'a CompiledNode) {
+        if let Some(ref aggregate) = node.aggregate {
+            self.aggregations.push((entry_index, aggregate));
+        }
+    }
+}
+
+impl CompiledConfig {
+    /// Compile a rate limit configuration.
+    ///
+    /// Descriptor nodes with invalid matchers are skipped with a warning, along
+    /// with their children. Configuration loaded from YAML is validated up
+    /// front, so this only affects configuration built in code.
+    pub fn new(config: RateLimitConfig) -> Self {
+        let domains = config
+            .domains
+            .iter()
+            .map(|(name, domain_config)| {
+                let compiled = CompiledDomain {
+                    matching: domain_config.matching,
+                    root: NodeIndex::compile(&domain_config.descriptors),
+                };
+                (name.clone(), compiled)
+            })
+            .collect();
+
+        Self { config, domains }
+    }
+
+    /// Get the configuration this was compiled from.
+    pub fn config(&self) -> &RateLimitConfig {
+        &self.config
+    }
+
+    /// Find the matching rate limit rule for a descriptor within a domain.
+    ///
+    /// This performs hierarchical matching, where more specific matches take precedence.
+    /// If the matching node has several limits, the first one is returned.
+    pub fn find_limit(
+        &self,
+        domain: &str,
+        descriptor: &RateLimitDescriptor,
+    ) -> Option<&RateLimitRule> {
+        self.find_match(domain, descriptor)
+            .and_then(|m| m.rules().first())
+    }
+
+    /// Find the matching rules for a descriptor within a domain, along with
+    /// how its entries are aggregated.
+    pub fn find_match(
+        &self,
+        domain: &str,
+        descriptor: &RateLimitDescriptor,
+    ) -> Option<LimitMatch<'_>> {
+        let compiled = self.domains.get(domain)?;
+        match compiled.matching {
+            MatchingMode::Lenient => compiled.root.find_match(&descriptor.entries, 0),
+            MatchingMode::Strict => compiled.root.find_match_strict(&descriptor.entries),
+        }
+    }
+}
+
+impl From<RateLimitConfig> for CompiledConfig {
+    fn from(config: RateLimitConfig) -> Self {
+        Self::new(config)
+    }
+}
+
+impl NodeIndex {
+    /// Compile the nodes of one level of the descriptor tree.
+    fn compile(configs: &[DescriptorConfig]) -> Self {
+        let mut index = NodeIndex::default();
+
+        for config in configs {
+            let matcher = match ValueMatcher::compile(config) {
+                Ok(matcher) => matcher,
+                Err(e) => {
+                    warn!(key = %config.key, error = %e, "Ignoring invalid descriptor");
+                    continue;
+                }
+            };
+
+            // Each range is ordered by its own prefix length, so a node
+            // listing several ranges is compiled once per range
+            let matchers = match matcher {
+                ValueMatcher::Cidr(networks) => networks
+                    .into_iter()
+                    .map(|network| ValueMatcher::Cidr(vec![network]))
+                    .collect(),
+                matcher => vec![matcher],
+            };
+
+            let key_nodes = index.keys.entry(config.key.clone()).or_default();
+            for matcher in matchers {
+                let node = CompiledNode {
+                    rules: config.rules().cloned().collect(),
+                    aggregate: config.aggregate,
+                    children: NodeIndex::compile(&config.descriptors),
+                    matcher,
+                };
+                match node.matcher {
+                    ValueMatcher::Exact(ref value) => {
+                        key_nodes.exact.entry(value.clone()).or_default().push(node)
+                    }
+                    _ => key_nodes.patterns.push(node),
+                }
+            }
+        }
+
+        // Stable, so nodes of the same precedence and specificity keep their
+        // configuration order
+        for key_nodes in index.keys.values_mut() {
+            key_nodes
+                .patterns
+                .sort_by_key(|node| (node.matcher.precedence(), Reverse(node.matcher.specificity())));
+        }
+
+        index
+    }
+
+    /// Iterate over the nodes matching an entry, in order of precedence.
+    ///
+    /// Nodes with the same precedence are returned most specific first, and
+    /// then in configuration order.
+    fn matching<'a: 'e, 'e>(&'a self, entry: &'e Entry) -> impl Iterator<Item = &'a CompiledNode> + 'e {
+        let key_nodes = self.keys.get(&entry.key);
+        let exact = key_nodes
+            .and_then(|nodes| nodes.exact.get(&entry.value))
+            .into_iter()
+            .flatten();
+        let patterns = key_nodes
+            .into_iter()
+            .flat_map(|nodes| &nodes.patterns)
+            .filter(move |node| node.matcher.matches(&entry.value));
+
+        exact.chain(patterns)
+    }
+
+    /// Find the rate limit for a full descriptor path, as Envoy does.
+    ///
+    /// Each entry selects the highest-precedence node matching it. There is
+    /// no backtracking, and only the node matched by the last entry may
+    /// provide the limit.
+    fn find_match_strict(&self, entries: &[Entry]) -> Option<LimitMatch<'_>> {
+        let mut index = self;
+        let mut path = Vec::with_capacity(entries.len());
+
+        for entry in entries {
+            let next = index.matching(entry).next()?;
+            index = &next.children;
+            path.push(next);
+        }
+
+        let node = path.last().copied().filter(|node| !node.rules.is_empty())?;
+        let mut limit_match = LimitMatch::new(node);
+        for (entry_index, node) in path.into_iter().enumerate() {
+            limit_match.record(entry_index, node);
+        }
+        Some(limit_match)
+    }
+
+    /// Recursively find a matching rate limit in the descriptor tree.
+    fn find_match(&self, entries: &[Entry], entry_index: usize) -> Option<LimitMatch<'_>> {
+        let entry = entries.get(entry_index)?;
+        let mut best_match: Option<LimitMatch<'_>> = None;
+
+        for node in self.matching(entry) {
+            // This node matches - check for more specific matches in children
+            if entry_index + 1 < entries.len() && !node.children.keys.is_empty() {
+                if let Some(mut child_match) = node.children.find_match(entries, entry_index + 1) {
+                    // Found a more specific match
+                    child_match.record(entry_index, node);
+                    return Some(child_match);
+                }
+            }
+
+            // Use the highest-precedence rate limits at this level if there's no more specific one
+            if best_match.is_none() && !node.rules.is_empty() {
+                let mut limit_match = LimitMatch::new(node);
+                limit_match.record(entry_index, node);
+                best_match = Some(limit_match);
+            }
+        }
+
+        best_match
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ratelimit::{DomainConfig, TimeUnit};
+
+    fn create_descriptor(entries: &[(&str, &str)]) -> RateLimitDescriptor {
+        RateLimitDescriptor {
+            entries: entries
+                .iter()
+                .map(|(k, v)| Entry {
+                    key: k.to_string(),
+                    value: v.to_string(),
+                })
+                .collect(),
+            limit: None,
+        }
+    }
+
+    fn compile(yaml: &str) -> CompiledConfig {
+        CompiledConfig::new(RateLimitConfig::from_yaml(yaml).unwrap())
+    }
+
+    /// Compile a single-domain config after editing the `test_domain` domain.
+    fn compile_with(yaml: &str, edit: impl FnOnce(&mut DomainConfig)) -> CompiledConfig {
+        let mut config = RateLimitConfig::from_yaml(yaml).unwrap();
+        edit(config.domains.get_mut("test_domain").unwrap());
+        CompiledConfig::new(config)
+    }
+
+    #[test]
+    fn test_find_limit_simple() {
+        let yaml = r#"
+domain: test_domain
+descriptors:
+  - key: api_key
+    rate_limit:
+      requests_per_unit: 1000
+      unit: minute
+"#;
+        let config = compile(yaml);
+        let descriptor = create_descriptor(&[("api_key", "some_key")]);
+
+        let limit = config.find_limit("test_domain", &descriptor);
+        assert!(limit.is_some());
+        let limit = limit.unwrap();
+        assert_eq!(limit.requests_per_unit, 1000);
+        assert_eq!(limit.unit, TimeUnit::Minute);
+    }
+
+    #[test]
+    fn test_find_limit_with_value_match() {
+        let yaml = r#"
+domain: test_domain
+descriptors:
+  - key: source_cluster
+    value: premium
+    rate_limit:
+      requests_per_unit: 10000
+      unit: second
+  - key: source_cluster
+    value: basic
+    rate_limit:
+      requests_per_unit: 100
+      unit: second
+"#;
+        let config = compile(yaml);
+
+        // Premium tier
+        let descriptor = create_descriptor(&[("source_cluster", "premium")]);
+        let limit = config.find_limit("test_domain", &descriptor).unwrap();
+        assert_eq!(limit.requests_per_unit, 10000);
+
+        // Basic tier
+        let descriptor = create_descriptor(&[("source_cluster", "basic")]);
+        let limit = config.find_limit("test_domain", &descriptor).unwrap();
+        assert_eq!(limit.requests_per_unit, 100);
+    }
+
+    #[test]
+    fn test_find_limit_hierarchical() {
+        let yaml = r#"
+domain: test_domain
+descriptors:
+  - key: source_cluster
+    rate_limit:
+      requests_per_unit: 1000
+      unit: second
+    descriptors:
+      - key: destination_cluster
+        value: critical_service
+        rate_limit:
+          requests_per_unit: 100
+          unit: second
+"#;
+        let config = compile(yaml);
+
+        // Just source_cluster - should get top-level limit
+        let descriptor = create_descriptor(&[("source_cluster", "any")]);
+        let limit = config.find_limit("test_domain", &descriptor).unwrap();
+        assert_eq!(limit.requests_per_unit, 1000);
+
+        // source_cluster + destination_cluster - should get more specific limit
+        let descriptor = create_descriptor(&[
+            ("source_cluster", "any"),
+            ("destination_cluster", "critical_service"),
+        ]);
+        let limit = config.find_limit("test_domain", &descriptor).unwrap();
+        assert_eq!(limit.requests_per_unit, 100);
+    }
+
+    #[test]
+    fn test_find_limit_no_match() {
+        let yaml = r#"
+domain: test_domain
+descriptors:
+  - key: api_key
+    rate_limit:
+      requests_per_unit: 1000
+      unit: minute
+"#;
+        let config = compile(yaml);
+
+        // Different key - no match
+        let descriptor = create_descriptor(&[("other_key", "value")]);
+        let limit = config.find_limit("test_domain", &descriptor);
+        assert!(limit.is_none());
+
+        // Different domain - no match
+        let descriptor = create_descriptor(&[("api_key", "value")]);
+        let limit = config.find_limit("other_domain", &descriptor);
+        assert!(limit.is_none());
+    }
+
+    #[test]
+    fn test_find_limit_any_value() {
+        let yaml = r#"
+domain: test_domain
+descriptors:
+  - key: remote_address
+    rate_limit:
+      requests_per_unit: 50
+      unit: second
+"#;
+        let config = compile(yaml);
+
+        // Should match any value for remote_address
+        let descriptor = create_descriptor(&[("remote_address", "192.168.1.1")]);
+        let limit = config.find_limit("test_domain", &descriptor).unwrap();
+        assert_eq!(limit.requests_per_unit, 50);
+
+        let descriptor = create_descriptor(&[("remote_address", "10.0.0.1")]);
+        let limit = config.find_limit("test_domain", &descriptor).unwrap();
+        assert_eq!(limit.requests_per_unit, 50);
+    }
+
+    const DIVERGENCE_CONFIG: &str = r#"
+domain: test_domain
+descriptors:
+  - key: source_cluster
+    rate_limit:
+      requests_per_unit: 100
+      unit: second
+    descriptors:
+      - key: path
+        value: /bar
+        rate_limit:
+          requests_per_unit: 10
+          unit: second
+  - key: source_cluster
+    value: x
+    rate_limit:
+      requests_per_unit: 50
+      unit: second
+"#;
+
+    fn config_with_matching(matching: MatchingMode) -> CompiledConfig {
+        compile_with(DIVERGENCE_CONFIG, |domain| domain.matching = matching)
+    }
+
+    #[test]
+    fn test_matching_trailing_entries() {
+        let descriptor = create_descriptor(&[("source_cluster", "y"), ("path", "/foo")]);
+
+        // Lenient matching falls back to the parent's limit
+        let config = config_with_matching(MatchingMode::Lenient);
+        let limit = config.find_limit("test_domain", &descriptor).unwrap();
+        assert_eq!(limit.requests_per_unit, 100);
+
+        // Strict matching requires the full descriptor to match
+        let config = config_with_matching(MatchingMode::Strict);
+        assert!(config.find_limit("test_domain", &descriptor).is_none());
+    }
+
+    #[test]
+    fn test_matching_prefers_exact_value() {
+        let descriptor = create_descriptor(&[("source_cluster", "x")]);
+
+        // Both modes prefer the exact value over the any-value node, regardless of order
+        for matching in [MatchingMode::Lenient, MatchingMode::Strict] {
+            let config = config_with_matching(matching);
+            let limit = config.find_limit("test_domain", &descriptor).unwrap();
+            assert_eq!(limit.requests_per_unit, 50);
+
+            let config = compile_with(DIVERGENCE_CONFIG, |domain| {
+                domain.matching = matching;
+                domain.descriptors.reverse();
+            });
+            let limit = config.find_limit("test_domain", &descriptor).unwrap();
+            assert_eq!(limit.requests_per_unit, 50);
+        }
+    }
+
+    #[test]
+    fn test_matching_does_not_backtrack() {
+        let descriptor = create_descriptor(&[("source_cluster", "x"), ("path", "/bar")]);
+
+        // Lenient matching finds the child of the any-value sibling
+        let config = config_with_matching(MatchingMode::Lenient);
+        let limit = config.find_limit("test_domain", &descriptor).unwrap();
+        assert_eq!(limit.requests_per_unit, 10);
+
+        // Strict matching commits to the exact value node, which has no children
+        let config = config_with_matching(MatchingMode::Strict);
+        assert!(config.find_limit("test_domain", &descriptor).is_none());
+
+        // Both modes agree on full paths through any-value nodes
+        let descriptor = create_descriptor(&[("source_cluster", "y"), ("path", "/bar")]);
+        for matching in [MatchingMode::Lenient, MatchingMode::Strict] {
+            let config = config_with_matching(matching);
+            let limit = config.find_limit("test_domain", &descriptor).unwrap();
+            assert_eq!(limit.requests_per_unit, 10);
+        }
+    }
+
+    #[test]
+    fn test_find_limit_cidr() {
+        let yaml = r#"
+domain: test_domain
+descriptors:
+  - key: remote_address
+    rate_limit:
+      requests_per_unit: 10
+      unit: second
+  - key: remote_address
+    value_cidr: [203.0.113.0/24, "2001:db8::/32"]
+    rate_limit:
+      requests_per_unit: 1000
+      unit: second
+  - key: remote_address
+    value: 203.0.113.7
+    rate_limit:
+      requests_per_unit: 1
+      unit: second
+"#;
+        let config = compile(yaml);
+        let limit_for = |address: &str| {
+            config
+                .find_limit("test_domain", &create_descriptor(&[("remote_address", address)]))
+                .unwrap()
+                .requests_per_unit
+        };
+
+        assert_eq!(limit_for("203.0.113.7"), 1);
+        assert_eq!(limit_for("203.0.113.8"), 1000);
+        assert_eq!(limit_for("2001:db8::1"), 1000);
+        assert_eq!(limit_for("198.51.100.1"), 10);
+    }
+
+    #[test]
+    fn test_find_limit_narrowest_cidr_first() {
+        let yaml = r#"
+domain: test_domain
+descriptors:
+  - key: remote_address
+    value_cidr: [10.0.0.0/8, 192.168.1.0/24]
+    rate_limit:
+      requests_per_unit: 1
+      unit: second
+  - key: remote_address
+    value_cidr: [10.1.0.0/16]
+    rate_limit:
+      requests_per_unit: 2
+      unit: second
+"#;
+        for matching in [MatchingMode::Lenient, MatchingMode::Strict] {
+            let config = compile_with(yaml, |domain| domain.matching = matching);
+            let limit_for = |address: &str| {
+                config
+                    .find_limit("test_domain", &create_descriptor(&[("remote_address", address)]))
+                    .unwrap()
+                    .requests_per_unit
+            };
+
+            // The narrower range wins though it is listed second, even over
+            // a node that also lists a narrower, non-matching range
+            assert_eq!(limit_for("10.1.2.3"), 2);
+            assert_eq!(limit_for("10.2.0.1"), 1);
+            assert_eq!(limit_for("192.168.1.1"), 1);
+        }
+    }
+
+    #[test]
+    fn test_find_limit_value_matcher_precedence() {
+        let yaml = r#"
+domain: test_domain
+descriptors:
+  - key: path
+    rate_limit:
+      requests_per_unit: 1
+      unit: second
+  - key: path
+    value_regex: "/api/v1/users/[^/]+/orders"
+    rate_limit:
+      requests_per_unit: 2
+      unit: second
+  - key: path
+    value: /api/*
+    rate_limit:
+      requests_per_unit: 3
+      unit: second
+  - key: path
+    value: /api/v1/users/admin/orders
+    rate_limit:
+      requests_per_unit: 4
+      unit: second
+"#;
+        for matching in [MatchingMode::Lenient, MatchingMode::Strict] {
+            let config = compile_with(yaml, |domain| domain.matching = matching);
+            let limit_for = |path: &str| {
+                config
+                    .find_limit("test_domain", &create_descriptor(&[("path", path)]))
+                    .unwrap()
+                    .requests_per_unit
+            };
+
+            // exact > prefix > regex > any
+            assert_eq!(limit_for("/api/v1/users/admin/orders"), 4);
+            assert_eq!(limit_for("/api/v1/users/42/orders"), 3);
+            assert_eq!(limit_for("/other/v1/users/42/orders"), 1);
+            assert_eq!(limit_for("/health"), 1);
+        }
+
+        // Without the prefix node, the regex takes precedence over any value
+        let config = compile_with(yaml, |domain| {
+            domain.descriptors.remove(2);
+        });
+        let descriptor = create_descriptor(&[("path", "/api/v1/users/42/orders")]);
+        assert_eq!(config.find_limit("test_domain", &descriptor).unwrap().requests_per_unit, 2);
+    }
+
+    #[test]
+    fn test_find_limit_longest_prefix_first() {
+        let yaml = r#"
+domain: test_domain
+descriptors:
+  - key: path
+    value: /api/*
+    rate_limit:
+      requests_per_unit: 1
+      unit: second
+  - key: path
+    value: /api/v1/*
+    rate_limit:
+      requests_per_unit: 2
+      unit: second
+"#;
+        for matching in [MatchingMode::Lenient, MatchingMode::Strict] {
+            let config = compile_with(yaml, |domain| domain.matching = matching);
+            let limit_for = |path: &str| {
+                config
+                    .find_limit("test_domain", &create_descriptor(&[("path", path)]))
+                    .unwrap()
+                    .requests_per_unit
+            };
+
+            // The longer prefix wins though it is listed second
+            assert_eq!(limit_for("/api/v1/users"), 2);
+            assert_eq!(limit_for("/api/v2/users"), 1);
+        }
+    }
+
+    #[test]
+    fn test_find_match_multiple_rules() {
+        let yaml = r#"
+domain: test_domain
+descriptors:
+  - key: api_key
+    rate_limit:
+      requests_per_unit: 10
+      unit: second
+    rate_limits:
+      - requests_per_unit: 10000
+        unit: day
+        name: daily
+  - key: tenant
+    rate_limits:
+      - requests_per_unit: 5
+        unit: minute
+"#;
+        let config = compile(yaml);
+
+        let descriptor = create_descriptor(&[("api_key", "a")]);
+        let limits: Vec<_> = config
+            .find_match("test_domain", &descriptor)
+            .unwrap()
+            .rules()
+            .iter()
+            .map(|rule| (rule.requests_per_unit, rule.unit))
+            .collect();
+        assert_eq!(limits, vec![(10, TimeUnit::Second), (10000, TimeUnit::Day)]);
+        assert_eq!(config.find_limit("test_domain", &descriptor).unwrap().requests_per_unit, 10);
+
+        // A node with only `rate_limits` is still a match
+        let descriptor = create_descriptor(&[("tenant", "t")]);
+        assert_eq!(config.find_limit("test_domain", &descriptor).unwrap().requests_per_unit, 5);
+    }
+
+    #[test]
+    fn test_exact_values_indexed() {
+        let mut yaml = String::from("domain: test_domain\ndescriptors:\n");
+        for customer in 0..5000 {
+            yaml.push_str(&format!(
+                "  - key: customer\n    value: c{}\n    rate_limit:\n      requests_per_unit: {}\n      unit: minute\n",
+                customer, customer + 1
+            ));
+        }
+        yaml.push_str("  - key: customer\n    rate_limit:\n      requests_per_unit: 1\n      unit: second\n");
+        let config = compile(&yaml);
+
+        let limit = config
+            .find_limit("test_domain", &create_descriptor(&[("customer", "c4321")]))
+            .unwrap();
+        assert_eq!(limit.requests_per_unit, 4322);
+        assert_eq!(limit.unit, TimeUnit::Minute);
+
+        let limit = config
+            .find_limit("test_domain", &create_descriptor(&[("customer", "unknown")]))
+            .unwrap();
+        assert_eq!(limit.unit, TimeUnit::Second);
+    }
+
+    #[test]
+    fn test_invalid_nodes_skipped() {
+        let mut config = RateLimitConfig::from_yaml(
+            r#"
+domain: test_domain
+descriptors:
+  - key: path
+    rate_limit:
+      requests_per_unit: 1
+      unit: second
+"#,
+        )
+        .unwrap();
+        let mut invalid = config.domains["test_domain"].descriptors[0].clone();
+        invalid.value_regex = Some("(unclosed".to_string());
+        invalid.rate_limit.as_mut().unwrap().requests_per_unit = 2;
+        config.domains.get_mut("test_domain").unwrap().descriptors.insert(0, invalid);
+
+        let config = CompiledConfig::new(config);
+        let limit = config
+            .find_limit("test_domain", &create_descriptor(&[("path", "/")]))
+            .unwrap();
+        assert_eq!(limit.requests_per_unit, 1);
+    }
+}
@@ -1,23 +1,24 @@
 //! Rate limit counter implementation.
 //!
 //! This module provides a lock-free rate limit counter using epoch-based windows.
-//! The counter packs the window epoch and count into a single atomic u64, enabling
+//! The counter packs the window epoch and count into a single atomic u128, enabling
 //! fully lock-free operations using compare-and-swap (CAS).
 //!
 //! ## Design
 //!
 //! Instead of storing a mutable `window_start` timestamp that requires locking,
 //! we compute the current window epoch from a fixed reference point. The state
-//! is packed as: `[32-bit epoch][32-bit count]`.
+//! is packed as: `[64-bit epoch][64-bit count]`, so counts and limits use the
+//! full `u64` range. Counts saturate at `u64::MAX` rather than wrapping.
 //!
-//! ## Limitations
-//!
-//! - **Maximum count per window**: 4,294,967,295 (u32::MAX). This is sufficient
-//!   for most rate limiting use cases. If you need higher limits, consider using
-//!   longer time windows or distributing across multiple keys.
+//! The reference point is placed on a wall-clock window boundary, so windows
+//! roll over on the second, minute, hour or day (UTC) rather than a window
+//! after the counter's first hit, as they do in distributed mode.
+
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::time::{Duration, Instant};
+use portable_atomic::AtomicU128;
 
 /// Time window for rate limiting.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -43,6 +44,17 @@ impl TimeWindow {
         }
     }
 
+    /// Get the time window lasting `secs` seconds, if there is one.
+    pub fn from_secs(secs: u64) -> Option<Self> {
+        match secs {
+            1 => Some(TimeWindow::Second),
+            60 => Some(TimeWindow::Minute),
+            3600 => Some(TimeWindow::Hour),
+            86400 => Some(TimeWindow::Day),
+            _ => None,
+        }
+    }
+
     /// Convert from the proto enum value.
     pub fn from_proto(unit: i32) -> Option<Self> {
         match unit {
@@ -68,29 +80,29 @@ impl TimeWindow {
 /// A rate limit counter that tracks requests within a time window.
 ///
 /// This counter is fully lock-free, using atomic compare-and-swap operations
-/// to update state. The window epoch and count are packed into a single `u64`:
-/// - Upper 32 bits: window epoch (computed from elapsed time)
-/// - Lower 32 bits: request count within the current window
+/// to update state. The window epoch and count are packed into a single `u128`:
+/// - Upper 64 bits: window epoch (computed from elapsed time)
+/// - Lower 64 bits: request count within the current window
+///
+/// Windows are aligned to wall-clock boundaries when the counter is created
+/// and then follow the monotonic clock.
 ///
 /// # Performance
 ///
 /// - **Reads**: Always lock-free, single atomic load
 /// - **Writes**: Lock-free CAS loop, only retries on concurrent modification
 /// - **Cache-friendly**: Single cache line for all mutable state
-///
-/// # Limitations
-///
-/// The maximum count per window is `u32::MAX` (4,294,967,295). For rate limits
-/// exceeding this, consider using longer time windows.
 pub struct RateLimitCounter {
-    /// Packed state: upper 32 bits = window epoch, lower 32 bits = count
-    state: AtomicU64,
-    /// The limit for this counter (capped at u32::MAX for comparison)
+    /// Packed state: upper 64 bits = window epoch, lower 64 bits = count
+    state: AtomicU128,
+    /// The limit for this counter
     limit: u64,
     /// Time window for this counter
     window: TimeWindow,
-    /// Fixed reference point for computing window epochs
-    epoch_start: Instant,
+    /// When the counter was created
+    created: Instant,
+    /// Time from the start of the wall-clock window to the counter's creation
+    created_in_window: Duration,
 }
 
 impl RateLimitCounter {
@@ -98,38 +110,83 @@ impl RateLimitCounter {
     ///
     /// # Arguments
     ///
-    /// * `limit` - Maximum requests allowed per window. Values above `u32::MAX`
-    ///   will be capped to `u32::MAX` for internal comparisons.
+    /// * `limit` - Maximum requests allowed per window.
     /// * `window` - The time window for rate limiting.
     pub fn new(limit: u64, window: TimeWindow) -> Self {
+        let created = Instant::now();
+        let since_unix_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO);
         Self {
-            state: AtomicU64::new(0),
+            state: AtomicU128::new(0),
             limit,
             window,
-            epoch_start: Instant::now(),
+            created,
+            created_in_window: Duration::from_nanos(
+                (since_unix_epoch.as_nanos() % window.duration().as_nanos()) as u64,
+            ),
+        }
+    }
+
+    /// Restore a counter whose window started at `window_start`.
+    ///
+    /// The `count` hits are counted in the current window, which ends on the
+    /// next wall-clock boundary like the original counter's. Returns `None`
+    /// if the window has already ended.
+    pub fn restore(limit: u64, window: TimeWindow, window_start: SystemTime, count: u64) -> Option<Self> {
+        // A window starting in the future (clock skew) is still current
+        let elapsed = SystemTime::now()
+            .duration_since(window_start)
+            .unwrap_or(Duration::ZERO);
+        if elapsed >= window.duration() {
+            return None;
+        }
+
+        let counter = Self::new(limit, window);
+        counter
+            .state
+            .store(Self::pack(counter.current_epoch(), count), Ordering::Release);
+        Some(counter)
+    }
+
+    /// Get the wall-clock start of the current window and the hits counted in it.
+    ///
+    /// Returns `None` if no hits have been counted in the current window.
+    pub fn snapshot(&self) -> Option<(SystemTime, u64)> {
+        let count = self.current_count();
+        if count == 0 {
+            return None;
         }
+
+        let elapsed_in_window = self.window.duration() - self.duration_until_reset();
+        Some((SystemTime::now() - elapsed_in_window, count))
+    }
+
+    /// Get the time elapsed since the start of the wall-clock window the
+    /// counter was created in.
+    #[inline]
+    fn elapsed(&self) -> Duration {
+        self.created.elapsed() + self.created_in_window
     }
 
     /// Compute the current window epoch based on elapsed time from the fixed start.
     #[inline]
-    fn current_epoch(&self) -> u32 {
-        let elapsed = self.epoch_start.elapsed();
-        let window_nanos = self.window.duration().as_nanos() as u64;
-        // Safe: window_nanos is always > 0 for valid TimeWindow values
-        ((elapsed.as_nanos() as u64) / window_nanos) as u32
+    fn current_epoch(&self) -> u64 {
+        // Safe: the window is always > 0 for valid TimeWindow values
+        (self.elapsed().as_nanos() / self.window.duration().as_nanos()) as u64
     }
 
-    /// Pack epoch and count into a single u64.
+    /// Pack epoch and count into a single u128.
     #[inline]
-    const fn pack(epoch: u32, count: u32) -> u64 {
-        ((epoch as u64) << 32) | (count as u64)
+    const fn pack(epoch: u64, count: u64) -> u128 {
+        ((epoch as u128) << 64) | (count as u128)
     }
 
-    /// Unpack epoch and count from a u64.
+    /// Unpack epoch and count from a u128.
     #[inline]
-    const fn unpack(value: u64) -> (u32, u32) {
-        let epoch = (value >> 32) as u32;
-        let count = value as u32;
+    const fn unpack(value: u128) -> (u64, u64) {
+        let epoch = (value >> 64) as u64;
+        let count = value as u64;
         (epoch, count)
     }
 
@@ -138,7 +195,7 @@ impl RateLimitCounter {
     /// This operation is lock-free and uses a CAS loop to handle concurrent updates.
     ///
     /// Returns `true` if the request is within the limit, `false` if over limit.
-    pub fn increment(&self, hits: u32) -> bool {
+    pub fn increment(&self, hits: u64) -> bool {
         let current_epoch = self.current_epoch();
 
         loop {
@@ -161,25 +218,66 @@ impl RateLimitCounter {
                 Ordering::AcqRel,
                 Ordering::Acquire,
             ) {
-                Ok(_) => return (new_count as u64) <= self.limit,
+                Ok(_) => return new_count <= self.limit,
                 Err(_) => continue, // CAS failed, retry
             }
         }
     }
 
+    /// Increment the counter only if the hits fit within the limit.
+    ///
+    /// Returns the window epoch the hits were counted in, which is needed to
+    /// [`release`](Self::release) them, or `None` if they would exceed the limit.
+    pub fn try_increment(&self, hits: u64) -> Option<u64> {
+        let current_epoch = self.current_epoch();
+
+        loop {
+            let state = self.state.load(Ordering::Acquire);
+            let (stored_epoch, count) = Self::unpack(state);
+
+            let count = if stored_epoch == current_epoch { count } else { 0 };
+            let new_count = count.checked_add(hits)?;
+            if new_count > self.limit {
+                return None;
+            }
+
+            let new_state = Self::pack(current_epoch, new_count);
+
+            match self.state.compare_exchange_weak(
+                state,
+                new_state,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return Some(current_epoch),
+                Err(_) => continue, // CAS failed, retry
+            }
+        }
+    }
+
+    /// Give back hits counted by [`try_increment`](Self::try_increment).
+    ///
+    /// Does nothing if the window the hits were counted in has ended.
+    pub fn release(&self, epoch: u64, hits: u64) {
+        let _ = self.state.fetch_update(Ordering::AcqRel, Ordering::Acquire, |state| {
+            let (stored_epoch, count) = Self::unpack(state);
+            (stored_epoch == epoch).then(|| Self::pack(epoch, count.saturating_sub(hits)))
+        });
+    }
+
     /// Check if adding hits would exceed the limit without incrementing.
-    pub fn would_exceed(&self, hits: u32) -> bool {
+    pub fn would_exceed(&self, hits: u64) -> bool {
         let current_epoch = self.current_epoch();
         let state = self.state.load(Ordering::Acquire);
         let (stored_epoch, count) = Self::unpack(state);
 
         let effective_count = if stored_epoch == current_epoch {
-            count as u64
+            count
         } else {
             0 // Window has rolled over
         };
 
-        effective_count + hits as u64 > self.limit
+        effective_count.saturating_add(hits) > self.limit
     }
 
     /// Get the current count.
@@ -191,7 +289,7 @@ impl RateLimitCounter {
         let (stored_epoch, count) = Self::unpack(state);
 
         if stored_epoch == current_epoch {
-            count as u64
+            count
         } else {
             0 // Window has rolled over
         }
@@ -214,10 +312,10 @@ impl RateLimitCounter {
 
     /// Get the duration until the current window resets.
     pub fn duration_until_reset(&self) -> Duration {
-        let elapsed = self.epoch_start.elapsed();
+        let elapsed = self.elapsed();
         let window_duration = self.window.duration();
         let elapsed_in_window = Duration::from_nanos(
-            (elapsed.as_nanos() as u64) % (window_duration.as_nanos() as u64)
+            (elapsed.as_nanos() % window_duration.as_nanos()) as u64
         );
 
         window_duration - elapsed_in_window
@@ -275,4 +373,94 @@ mod tests {
         assert_eq!(counter.current_count(), 5);
         assert_eq!(counter.remaining(), 5);
     }
+
+    #[test]
+    fn test_counter_try_increment_and_release() {
+        let counter = RateLimitCounter::new(10, TimeWindow::Minute);
+
+        let epoch = counter.try_increment(8).unwrap();
+        assert_eq!(counter.current_count(), 8);
+
+        // Hits that don't fit are not counted
+        assert!(counter.try_increment(3).is_none());
+        assert_eq!(counter.current_count(), 8);
+
+        counter.release(epoch, 8);
+        assert_eq!(counter.current_count(), 0);
+
+        // Releasing hits from an earlier window does nothing
+        counter.try_increment(2).unwrap();
+        counter.release(epoch.wrapping_sub(1), 2);
+        assert_eq!(counter.current_count(), 2);
+    }
+
+    #[test]
+    fn test_counter_counts_beyond_u32() {
+        let limit = u32::MAX as u64 * 3;
+        let counter = RateLimitCounter::new(limit, TimeWindow::Day);
+
+        assert!(counter.increment(u32::MAX as u64));
+        assert!(counter.increment(u32::MAX as u64));
+        assert_eq!(counter.current_count(), u32::MAX as u64 * 2);
+        assert_eq!(counter.remaining(), u32::MAX as u64);
+
+        assert!(!counter.would_exceed(u32::MAX as u64));
+        assert!(counter.would_exceed(u32::MAX as u64 + 1));
+        assert!(counter.try_increment(u32::MAX as u64 + 1).is_none());
+        assert!(counter.increment(u32::MAX as u64));
+        assert!(!counter.increment(1));
+        assert_eq!(counter.current_count(), limit + 1);
+    }
+
+    #[test]
+    fn test_counter_saturates_at_u64_max() {
+        let counter = RateLimitCounter::new(u64::MAX - 1, TimeWindow::Minute);
+
+        assert!(counter.increment(u64::MAX - 1));
+        assert!(counter.would_exceed(1));
+        assert!(counter.try_increment(1).is_none());
+
+        // Over-limit hits saturate instead of wrapping to a low count
+        assert!(!counter.increment(u64::MAX));
+        assert!(!counter.increment(1));
+        assert_eq!(counter.current_count(), u64::MAX);
+        assert_eq!(counter.remaining(), 0);
+    }
+
+    #[test]
+    fn test_counter_snapshot_and_restore() {
+        let counter = RateLimitCounter::new(10, TimeWindow::Day);
+        assert!(counter.snapshot().is_none());
+
+        counter.increment(7);
+        let (window_start, count) = counter.snapshot().unwrap();
+        assert_eq!(count, 7);
+
+        let restored = RateLimitCounter::restore(10, TimeWindow::Day, window_start, count).unwrap();
+        assert_eq!(restored.current_count(), 7);
+        assert!(restored.increment(3));
+        assert!(!restored.increment(1));
+
+        // The restored window ends when the original one does
+        let drift = counter
+            .duration_until_reset()
+            .abs_diff(restored.duration_until_reset());
+        assert!(drift < Duration::from_millis(100));
+
+        // Windows that have ended are not restored
+        let ended = SystemTime::now() - Duration::from_secs(61);
+        assert!(RateLimitCounter::restore(10, TimeWindow::Minute, ended, 5).is_none());
+    }
+
+    #[test]
+    fn test_counter_window_aligned_to_wall_clock() {
+        let counter = RateLimitCounter::new(10, TimeWindow::Minute);
+        let since_unix_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let until_minute = Duration::from_secs(60) - Duration::from_nanos((since_unix_epoch.as_nanos() % 60_000_000_000) as u64);
+
+        // The window ends on the next minute, not a minute after creation
+        let drift = counter.duration_until_reset().abs_diff(until_minute);
+        let drift = drift.min(Duration::from_secs(60) - drift);
+        assert!(drift < Duration::from_millis(100));
+    }
 }
@@ -1,58 +1,401 @@
 //! Descriptor key generation and handling.
+//!
+//! Counters are keyed by an encoding of the domain and descriptor entries.
+//! A check builds a [`DescriptorKeyRef`] that borrows from the request and
+//! encodes it into a reused per-thread buffer, so looking up an existing
+//! counter doesn't allocate. Values rewritten by IP aggregation are kept as
+//! networks and only formatted into that buffer. An owned [`DescriptorKey`]
+//! is only built when a new counter is created.
+//!
+//! ## Encoding
+//!
+//! A key is encoded as `domain:key=value,key=value`, followed by
+//! `@{limit}/{secs}s` for a request-time override and `#{limit}/{secs}s` for
+//! one of several rule limits. Any `\`, `=`, `,`, `@`, `#` or `|` in the
+//! domain, entry keys or values is escaped with a backslash, as is `:` in the
+//! domain, so distinct descriptors never share an encoding. `|` is escaped
+//! too so the encoding can be embedded in cluster counter keys.
 
+use std::borrow::{Borrow, Cow};
+use std::cell::RefCell;
+use std::fmt::{self, Write};
+
+use ipnet::IpNet;
+use smallvec::SmallVec;
+
+use crate::grpc::proto::envoy::extensions::common::ratelimit::v3::rate_limit_descriptor::Entry;
 use crate::grpc::proto::envoy::extensions::common::ratelimit::v3::RateLimitDescriptor;
 
+use super::counter::TimeWindow;
+
+thread_local! {
+    /// Buffer reused to encode keys for counter lookups.
+    static KEY_BUFFER: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
 /// A key that uniquely identifies a rate limit descriptor.
 ///
-/// The key is composed of the domain and all descriptor entries,
-/// serialized in a consistent order.
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-pub struct DescriptorKey {
-    /// The domain this descriptor belongs to
-    pub domain: String,
-    /// Serialized key-value pairs from the descriptor
-    pub entries: Vec<(String, String)>,
-}
+/// The key is the encoding of the domain and all descriptor entries,
+/// serialized in a consistent order. Descriptors limited by a request-time
+/// override also carry the override, so they never share a counter with
+/// rule-based limits or with a different override. Descriptors matching a
+/// rule with several limits carry the limit each key after the first is
+/// counted against.
+///
+/// The key borrows as its encoded `str`, so maps keyed by it can be searched
+/// with an encoding built in a reused buffer.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct DescriptorKey(Box<str>);
 
 impl DescriptorKey {
     /// Create a new descriptor key from a domain and descriptor.
     pub fn new(domain: &str, descriptor: &RateLimitDescriptor) -> Self {
-        let entries: Vec<(String, String)> = descriptor
-            .entries
-            .iter()
-            .map(|e| (e.key.clone(), e.value.clone()))
-            .collect();
+        DescriptorKeyRef::new(domain, descriptor).to_key()
+    }
 
-        Self {
-            domain: domain.to_string(),
-            entries,
-        }
+    /// Get the encoded key.
+    pub fn as_str(&self) -> &str {
+        &self.0
     }
 
     /// Convert the descriptor key to a string representation.
     ///
     /// This is useful for logging and debugging.
     pub fn to_string_key(&self) -> String {
-        let entries_str: Vec<String> = self
-            .entries
-            .iter()
-            .map(|(k, v)| format!("{}={}", k, v))
-            .collect();
-        
-        format!("{}:{}", self.domain, entries_str.join(","))
+        self.0.to_string()
+    }
+
+    /// Decode the key into its parts.
+    ///
+    /// Returns `None` if the key is not a valid encoding.
+    pub fn decode(&self) -> Option<DescriptorKeyParts> {
+        DescriptorKeyParts::decode(&self.0)
+    }
+}
+
+impl From<&str> for DescriptorKey {
+    fn from(encoded: &str) -> Self {
+        Self(encoded.into())
+    }
+}
+
+impl Borrow<str> for DescriptorKey {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for DescriptorKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// A descriptor key borrowed from a request.
+///
+/// This is what the resolver produces for every limit of a check. Values
+/// rewritten by IP aggregation are the only parts it owns, and they are
+/// held inline, so cloning a key doesn't allocate.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DescriptorKeyRef<'a> {
+    /// The domain this descriptor belongs to
+    domain: &'a str,
+    /// The descriptor entries, in request order
+    entries: &'a [Entry],
+    /// Entry values replaced by IP aggregation, by entry index
+    aggregated: SmallVec<[(usize, IpNet); 2]>,
+    /// The enforced limit override (requests per unit and window), if any
+    limit_override: Option<(u64, TimeWindow)>,
+    /// The rule limit (requests per unit and window) for limits after a rule's first
+    rule_limit: Option<(u64, TimeWindow)>,
+}
+
+impl<'a> DescriptorKeyRef<'a> {
+    /// Create a key borrowing a domain and descriptor.
+    pub fn new(domain: &'a str, descriptor: &'a RateLimitDescriptor) -> Self {
+        Self {
+            domain,
+            entries: &descriptor.entries,
+            aggregated: SmallVec::new(),
+            limit_override: None,
+            rule_limit: None,
+        }
+    }
+
+    /// Mark this key as limited by a request-time override.
+    pub fn with_override(mut self, requests_per_unit: u64, window: TimeWindow) -> Self {
+        self.limit_override = Some((requests_per_unit, window));
+        self
+    }
+
+    /// Mark this key as counting a limit after the first of a rule.
+    pub fn with_rule_limit(mut self, requests_per_unit: u64, window: TimeWindow) -> Self {
+        self.rule_limit = Some((requests_per_unit, window));
+        self
+    }
+
+    /// Count the entry at `index` under `network` instead of its request value.
+    pub fn with_aggregated_value(mut self, index: usize, network: IpNet) -> Self {
+        self.aggregated.retain(|(i, _)| *i != index);
+        self.aggregated.push((index, network));
+        self
+    }
+
+    /// Get the domain.
+    pub fn domain(&self) -> &'a str {
+        self.domain
+    }
+
+    /// Get the key-value pairs the descriptor is counted under.
+    pub fn entries(&self) -> impl Iterator<Item = (&str, EntryValue<'_>)> + '_ {
+        self.entries.iter().enumerate().map(|(index, entry)| {
+            let value = self
+                .aggregated
+                .iter()
+                .find(|(i, _)| *i == index)
+                .map_or(EntryValue::Request(&entry.value), |(_, network)| EntryValue::Network(*network));
+            (entry.key.as_str(), value)
+        })
+    }
+
+    /// Run `f` with the encoded key.
+    ///
+    /// The key is encoded into a per-thread buffer that is reused across
+    /// calls, so this doesn't allocate once the buffer has grown to fit.
+    pub fn with_encoded<R>(&self, f: impl FnOnce(&str) -> R) -> R {
+        KEY_BUFFER.with(|buffer| match buffer.try_borrow_mut() {
+            Ok(mut buffer) => {
+                buffer.clear();
+                self.encode_into(&mut buffer);
+                f(&buffer)
+            }
+            // Only reachable if `f` encodes another key
+            Err(_) => f(&self.to_string()),
+        })
+    }
+
+    /// Append the encoded key to `buffer`.
+    pub fn encode_into(&self, buffer: &mut String) {
+        // Writing to a String never fails
+        let _ = write!(buffer, "{}", self);
+    }
+
+    /// Build the owned key.
+    pub fn to_key(&self) -> DescriptorKey {
+        DescriptorKey(self.to_string().into_boxed_str())
+    }
+}
+
+impl fmt::Display for DescriptorKeyRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:", Escaped::domain(self.domain))?;
+        for (index, (key, value)) in self.entries().enumerate() {
+            if index > 0 {
+                f.write_char(',')?;
+            }
+            write!(f, "{}={}", Escaped::entry(key), value)?;
+        }
+        if let Some((limit, window)) = self.limit_override {
+            write!(f, "@{}/{}s", limit, window.duration().as_secs())?;
+        }
+        if let Some((limit, window)) = self.rule_limit {
+            write!(f, "#{}/{}s", limit, window.duration().as_secs())?;
+        }
+        Ok(())
+    }
+}
+
+/// The value a descriptor entry is counted under.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryValue<'a> {
+    /// The value sent in the request
+    Request(&'a str),
+    /// The network an IP address value is aggregated to
+    Network(IpNet),
+}
+
+impl fmt::Display for EntryValue<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EntryValue::Request(value) => write!(f, "{}", Escaped::entry(value)),
+            // Networks contain no reserved characters
+            EntryValue::Network(network) => write!(f, "{}", network),
+        }
+    }
+}
+
+/// The parts of a decoded descriptor key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DescriptorKeyParts {
+    /// The domain the descriptor belongs to
+    pub domain: String,
+    /// The key-value pairs the descriptor is counted under
+    pub entries: Vec<(String, String)>,
+    /// The enforced limit override, if any
+    pub limit_override: Option<(u64, TimeWindow)>,
+    /// The rule limit for limits after a rule's first
+    pub rule_limit: Option<(u64, TimeWindow)>,
+}
+
+impl DescriptorKeyParts {
+    /// Decode an encoded descriptor key.
+    fn decode(encoded: &str) -> Option<Self> {
+        let (domain, delimiter, mut rest) = split_escaped(encoded, b":")?;
+        if delimiter != Some(b':') {
+            return None;
+        }
+
+        let mut entries = Vec::new();
+        let mut delimiter = rest.bytes().next().filter(|b| matches!(b, b'@' | b'#'));
+        if delimiter.is_some() {
+            rest = &rest[1..];
+        } else if !rest.is_empty() {
+            loop {
+                let (key, separator, after_key) = split_escaped(rest, b"=")?;
+                if separator != Some(b'=') {
+                    return None;
+                }
+                let (value, next, after_value) = split_escaped(after_key, b",@#")?;
+                entries.push((key.into_owned(), value.into_owned()));
+                rest = after_value;
+                if next != Some(b',') {
+                    delimiter = next;
+                    break;
+                }
+            }
+        }
+
+        let mut parts = Self {
+            domain: domain.into_owned(),
+            entries,
+            limit_override: None,
+            rule_limit: None,
+        };
+        if delimiter == Some(b'@') {
+            let (limit, after) = rest.split_once('#').map_or((rest, None), |(l, r)| (l, Some(r)));
+            parts.limit_override = Some(parse_limit(limit)?);
+            delimiter = after.map(|_| b'#');
+            rest = after.unwrap_or_default();
+        }
+        if delimiter == Some(b'#') {
+            parts.rule_limit = Some(parse_limit(rest)?);
+        }
+        Some(parts)
+    }
+}
+
+/// Parse a `{limit}/{secs}s` key suffix.
+fn parse_limit(suffix: &str) -> Option<(u64, TimeWindow)> {
+    let (limit, secs) = suffix.strip_suffix('s')?.split_once('/')?;
+    Some((limit.parse().ok()?, TimeWindow::from_secs(secs.parse().ok()?)?))
+}
+
+/// Characters with a meaning in encoded domains, escaped with a backslash.
+const RESERVED: &[u8] = b"\\:=,@#|";
+
+/// Characters with a meaning in encoded entry keys and values. The domain is
+/// the only component ending at a `:`, so IPv6 addresses stay readable.
+const ENTRY_RESERVED: &[u8] = b"\\=,@#|";
+
+/// Formats a key component with its reserved characters escaped.
+pub(crate) struct Escaped<'a> {
+    component: &'a str,
+    reserved: &'static [u8],
+}
+
+impl<'a> Escaped<'a> {
+    /// Escape a domain.
+    pub(crate) fn domain(domain: &'a str) -> Self {
+        Self {
+            component: domain,
+            reserved: RESERVED,
+        }
+    }
+
+    /// Escape an entry key or value.
+    fn entry(component: &'a str) -> Self {
+        Self {
+            component,
+            reserved: ENTRY_RESERVED,
+        }
+    }
+}
+
+impl fmt::Display for Escaped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut rest = self.component;
+        // Reserved characters are ASCII, so byte positions are char boundaries
+        while let Some(index) = rest.bytes().position(|b| self.reserved.contains(&b)) {
+            f.write_str(&rest[..index])?;
+            f.write_char('\\')?;
+            f.write_str(&rest[index..index + 1])?;
+            rest = &rest[index + 1..];
+        }
+        f.write_str(rest)
     }
 }
 
-impl std::fmt::Display for DescriptorKey {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.to_string_key())
+/// Split an escaped component off the front of `encoded`.
+///
+/// The component ends at the first unescaped byte in `delimiters`. Returns the
+/// unescaped component, the delimiter it ended at (`None` at the end of the
+/// input) and the input following the delimiter. Returns `None` if the input
+/// ends in the middle of an escape.
+pub(crate) fn split_escaped<'s>(
+    encoded: &'s str,
+    delimiters: &[u8],
+) -> Option<(Cow<'s, str>, Option<u8>, &'s str)> {
+    let bytes = encoded.as_bytes();
+    let mut unescaped: Option<String> = None;
+    let mut start = 0;
+    let mut index = 0;
+    while index < bytes.len() {
+        let byte = bytes[index];
+        if byte == b'\\' {
+            let escaped = *bytes.get(index + 1)?;
+            if !RESERVED.contains(&escaped) {
+                return None;
+            }
+            let buffer = unescaped.get_or_insert_with(String::new);
+            buffer.push_str(&encoded[start..index]);
+            start = index + 1;
+            index += 2;
+        } else if delimiters.contains(&byte) {
+            break;
+        } else {
+            index += 1;
+        }
     }
+
+    let component = match unescaped {
+        Some(mut buffer) => {
+            buffer.push_str(&encoded[start..index]);
+            Cow::Owned(buffer)
+        }
+        None => Cow::Borrowed(&encoded[..index]),
+    };
+    let delimiter = bytes.get(index).copied();
+    let rest = if delimiter.is_some() { &encoded[index + 1..] } else { "" };
+    Some((component, delimiter, rest))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::grpc::proto::envoy::extensions::common::ratelimit::v3::rate_limit_descriptor::Entry;
+    use proptest::prelude::*;
+
+    fn descriptor(entries: &[(&str, &str)]) -> RateLimitDescriptor {
+        RateLimitDescriptor {
+            entries: entries
+                .iter()
+                .map(|(key, value)| Entry {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                })
+                .collect(),
+            limit: None,
+        }
+    }
 
     #[test]
     fn test_descriptor_key_creation() {
@@ -70,12 +413,13 @@ mod tests {
             limit: None,
         };
 
-        let key = DescriptorKey::new("test_domain", &descriptor);
-        
-        assert_eq!(key.domain, "test_domain");
-        assert_eq!(key.entries.len(), 2);
-        assert_eq!(key.entries[0], ("source".to_string(), "client_a".to_string()));
-        assert_eq!(key.entries[1], ("destination".to_string(), "service_b".to_string()));
+        let key = DescriptorKeyRef::new("test_domain", &descriptor);
+        let entries: Vec<_> = key.entries().collect();
+
+        assert_eq!(key.domain(), "test_domain");
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0], ("source", EntryValue::Request("client_a")));
+        assert_eq!(entries[1], ("destination", EntryValue::Request("service_b")));
     }
 
     #[test]
@@ -109,4 +453,189 @@ mod tests {
         
         assert_eq!(key1, key2);
     }
+
+    #[test]
+    fn test_descriptor_key_with_override() {
+        let descriptor = RateLimitDescriptor {
+            entries: vec![Entry {
+                key: "key1".to_string(),
+                value: "value1".to_string(),
+            }],
+            limit: None,
+        };
+
+        let rule_key = DescriptorKey::new("domain", &descriptor);
+        let override_key = DescriptorKeyRef::new("domain", &descriptor)
+            .with_override(5, TimeWindow::Minute)
+            .to_key();
+        let other_override_key = DescriptorKeyRef::new("domain", &descriptor)
+            .with_override(5, TimeWindow::Second)
+            .to_key();
+
+        assert_ne!(rule_key, override_key);
+        assert_ne!(override_key, other_override_key);
+        assert_eq!(override_key.to_string_key(), "domain:key1=value1@5/60s");
+    }
+
+    #[test]
+    fn test_descriptor_key_with_rule_limit() {
+        let descriptor = RateLimitDescriptor {
+            entries: vec![Entry {
+                key: "key1".to_string(),
+                value: "value1".to_string(),
+            }],
+            limit: None,
+        };
+
+        let per_second = DescriptorKeyRef::new("domain", &descriptor)
+            .with_rule_limit(10, TimeWindow::Second)
+            .to_key();
+        let per_day = DescriptorKeyRef::new("domain", &descriptor)
+            .with_rule_limit(10000, TimeWindow::Day)
+            .to_key();
+
+        assert_ne!(per_second, per_day);
+        assert_eq!(per_second.to_string_key(), "domain:key1=value1#10/1s");
+        assert_eq!(per_day.to_string_key(), "domain:key1=value1#10000/86400s");
+    }
+
+    #[test]
+    fn test_descriptor_key_encoded_lookup() {
+        let descriptor = RateLimitDescriptor {
+            entries: vec![
+                Entry {
+                    key: "remote_address".to_string(),
+                    value: "10.1.2.3".to_string(),
+                },
+                Entry {
+                    key: "path".to_string(),
+                    value: "/api".to_string(),
+                },
+            ],
+            limit: None,
+        };
+
+        let key = DescriptorKeyRef::new("domain", &descriptor)
+            .with_aggregated_value(0, "10.1.2.0/24".parse().unwrap());
+        assert_eq!(key.to_key().as_str(), "domain:remote_address=10.1.2.0/24,path=/api");
+
+        // The encoding borrows as the owned key, so it can be used for lookups
+        let mut counters = std::collections::HashMap::new();
+        counters.insert(key.to_key(), 1);
+        assert_eq!(key.with_encoded(|encoded| counters.get(encoded).copied()), Some(1));
+
+        // Nested encodings fall back to a fresh buffer
+        let other = DescriptorKeyRef::new("other", &descriptor);
+        let nested = key.with_encoded(|outer| other.with_encoded(|inner| (outer.to_string(), inner.to_string())));
+        assert_eq!(nested.0, key.to_key().as_str());
+        assert_eq!(nested.1, other.to_key().as_str());
+    }
+
+    #[test]
+    fn test_descriptor_key_escapes_reserved_characters() {
+        let joined = DescriptorKey::new("domain", &descriptor(&[("a", "1,b=2")]));
+        let split = DescriptorKey::new("domain", &descriptor(&[("a", "1"), ("b", "2")]));
+        assert_ne!(joined, split);
+        assert_eq!(joined.as_str(), r"domain:a=1\,b\=2");
+
+        let suffixed = DescriptorKey::new("domain", &descriptor(&[("a", "1@5/60s")]));
+        let overridden = DescriptorKeyRef::new("domain", &descriptor(&[("a", "1")]))
+            .with_override(5, TimeWindow::Minute)
+            .to_key();
+        assert_ne!(suffixed, overridden);
+
+        let domain_colon = DescriptorKey::new("a:b", &descriptor(&[("c", "d")]));
+        let entry_colon = DescriptorKey::new("a", &descriptor(&[("b:c", "d")]));
+        assert_ne!(domain_colon, entry_colon);
+        assert_eq!(domain_colon.as_str(), r"a\:b:c=d");
+        assert_eq!(entry_colon.as_str(), "a:b:c=d");
+    }
+
+    #[test]
+    fn test_descriptor_key_decode() {
+        let key = DescriptorKeyRef::new("domain", &descriptor(&[("remote_address", "2001:db8::1"), ("a", "")]))
+            .with_rule_limit(10, TimeWindow::Second)
+            .to_key();
+        let parts = key.decode().unwrap();
+
+        assert_eq!(parts.domain, "domain");
+        assert_eq!(
+            parts.entries,
+            vec![
+                ("remote_address".to_string(), "2001:db8::1".to_string()),
+                ("a".to_string(), String::new())
+            ]
+        );
+        assert_eq!(parts.limit_override, None);
+        assert_eq!(parts.rule_limit, Some((10, TimeWindow::Second)));
+
+        assert!(DescriptorKey::from("no_domain_separator").decode().is_none());
+        assert!(DescriptorKey::from("domain:a=1\\").decode().is_none());
+        assert!(DescriptorKey::from("domain:a=1@5/7s").decode().is_none());
+    }
+
+    fn time_window() -> impl Strategy<Value = TimeWindow> {
+        prop_oneof![
+            Just(TimeWindow::Second),
+            Just(TimeWindow::Minute),
+            Just(TimeWindow::Hour),
+            Just(TimeWindow::Day),
+        ]
+    }
+
+    fn limit() -> impl Strategy<Value = Option<(u64, TimeWindow)>> {
+        proptest::option::of((any::<u64>(), time_window()))
+    }
+
+    /// Strings drawn mostly from reserved characters, to exercise escaping.
+    fn component() -> impl Strategy<Value = String> {
+        prop_oneof![any::<String>(), "[a-c:=,@#|\\\\/0-9s]{0,8}"]
+    }
+
+    proptest! {
+        #[test]
+        fn prop_descriptor_key_round_trips(
+            domain in component(),
+            entries in proptest::collection::vec((component(), component()), 0..4),
+            limit_override in limit(),
+            rule_limit in limit(),
+        ) {
+            let descriptor = RateLimitDescriptor {
+                entries: entries
+                    .iter()
+                    .map(|(key, value)| Entry { key: key.clone(), value: value.clone() })
+                    .collect(),
+                limit: None,
+            };
+            let mut key = DescriptorKeyRef::new(&domain, &descriptor);
+            if let Some((limit, window)) = limit_override {
+                key = key.with_override(limit, window);
+            }
+            if let Some((limit, window)) = rule_limit {
+                key = key.with_rule_limit(limit, window);
+            }
+
+            let parts = key.to_key().decode().unwrap();
+            prop_assert_eq!(parts, DescriptorKeyParts { domain, entries, limit_override, rule_limit });
+        }
+
+        #[test]
+        fn prop_distinct_descriptors_have_distinct_keys(
+            a in proptest::collection::vec((component(), component()), 0..3),
+            b in proptest::collection::vec((component(), component()), 0..3),
+        ) {
+            let to_descriptor = |entries: &[(String, String)]| RateLimitDescriptor {
+                entries: entries
+                    .iter()
+                    .map(|(key, value)| Entry { key: key.clone(), value: value.clone() })
+                    .collect(),
+                limit: None,
+            };
+            let (key_a, key_b) = (
+                DescriptorKey::new("domain", &to_descriptor(&a)),
+                DescriptorKey::new("domain", &to_descriptor(&b)),
+            );
+            prop_assert_eq!(a == b, key_a == key_b);
+        }
+    }
 }
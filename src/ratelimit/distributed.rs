@@ -1,48 +1,44 @@
 //! Distributed rate limiter using Chitchat cluster state.
 //!
 //! This module provides a distributed rate limiter that uses chitchat
-//! for gossip-based state synchronization across multiple nodes.
+//! for gossip-based state synchronization across multiple nodes. Limits of
+//! rules with `consistency: strong` are instead counted by the node owning
+//! each counter (see the `strong` module).
+//!
+//! Gossiped counters seen at their limit are marked with a block hint, and
+//! hits on a blocked counter are rejected from a lock-free cache without
+//! touching the cluster state until the counter's window ends.
+//!
+//! Counter keys of eventually consistent limits borrow from the resolved
+//! request and are formatted into a reused per-thread buffer, so checks don't
+//! build keys of their own; chitchat still copies the key and value of every
+//! counter it writes. Owned keys are only built for counters that outlive
+//! the request: those queued for broadcast and strongly consistent counters,
+//! which may be forwarded to their owner.
 
 use std::sync::Arc;
+use std::time::Duration;
 use async_trait::async_trait;
-use parking_lot::RwLock;
+use arc_swap::ArcSwap;
+use tokio::sync::mpsc;
 use tracing::{debug, trace};
 
 use crate::grpc::proto::envoy::extensions::common::ratelimit::v3::RateLimitDescriptor;
-use crate::grpc::proto::envoy::service::ratelimit::v3::rate_limit_response::{
-    Code, DescriptorStatus, RateLimit,
-};
-use crate::mesh::{Cluster, CounterKey};
+use crate::grpc::proto::envoy::service::ratelimit::v3::rate_limit_response::DescriptorStatus;
+use smallvec::SmallVec;
+
+use crate::mesh::{Cluster, CounterKey, CounterKeyRef, OwnedIncrement};
 
 use super::counter::TimeWindow;
-use super::descriptor::DescriptorKey;
-use super::rules::RateLimitConfig;
-
-/// Default rate limit when no specific limit is configured.
-const DEFAULT_LIMIT: u64 = 1000;
-/// Default time window when no specific window is configured.
-const DEFAULT_WINDOW: TimeWindow = TimeWindow::Second;
-
-/// Configuration for a rate limit.
-#[derive(Debug, Clone)]
-pub struct LimitConfig {
-    /// Maximum requests allowed in the time window
-    pub limit: u64,
-    /// Time window for the limit
-    pub window: TimeWindow,
-    /// Name/description of this limit
-    pub name: Option<String>,
-}
+use super::resolver::{limited_status, most_restrictive, Resolution, ResolvedLimit};
+use super::compiled::CompiledConfig;
+use super::rules::{Consistency, RateLimitConfig};
+use super::snapshot::{ClusterCounterSnapshot, OwnedCounterSnapshot, Snapshot, SnapshotSource};
+use super::strong::{StrongCounters, DEFAULT_FORWARD_TIMEOUT};
 
-impl Default for LimitConfig {
-    fn default() -> Self {
-        Self {
-            limit: DEFAULT_LIMIT,
-            window: DEFAULT_WINDOW,
-            name: None,
-        }
-    }
-}
+/// Most batches of counters waiting to be broadcast; further batches are
+/// left to gossip.
+const BROADCAST_QUEUE_SIZE: usize = 1024;
 
 /// A distributed rate limiter backed by Chitchat cluster state.
 ///
@@ -51,36 +47,66 @@ impl Default for LimitConfig {
 pub struct DistributedRateLimiter {
     /// The cluster for distributed state.
     cluster: Arc<Cluster>,
-    /// Rate limit configuration.
-    config: RwLock<RateLimitConfig>,
+    /// Compiled rate limit configuration, replaced atomically on update.
+    config: Arc<ArcSwap<CompiledConfig>>,
+    /// Counters of strongly consistent limits, kept by their owners.
+    strong: StrongCounters,
+    /// Queue of counters to broadcast to peers, if broadcast is enabled.
+    broadcasts: Option<mpsc::Sender<Vec<CounterKey>>>,
 }
 
 impl DistributedRateLimiter {
     /// Create a new distributed rate limiter.
     pub fn new(cluster: Arc<Cluster>) -> Self {
-        Self {
-            cluster,
-            config: RwLock::new(RateLimitConfig::new()),
-        }
+        Self::with_config(cluster, RateLimitConfig::new())
     }
 
     /// Create a new distributed rate limiter with configuration.
     pub fn with_config(cluster: Arc<Cluster>, config: RateLimitConfig) -> Self {
         Self {
+            strong: StrongCounters::new(cluster.clone(), DEFAULT_FORWARD_TIMEOUT),
+            broadcasts: Self::spawn_broadcaster(&cluster),
             cluster,
-            config: RwLock::new(config),
+            config: Arc::new(ArcSwap::from_pointee(CompiledConfig::new(config))),
         }
     }
 
+    /// Set how long to wait for the owner of a strongly consistent counter
+    /// before rehashing the counter to another node.
+    pub fn with_forward_timeout(mut self, timeout: Duration) -> Self {
+        self.strong = StrongCounters::new(self.cluster.clone(), timeout);
+        self
+    }
+
     /// Update the rate limit configuration.
+    ///
+    /// The configuration is compiled here, and checks already in progress
+    /// finish with the previous one.
     pub fn set_config(&self, config: RateLimitConfig) {
-        let mut cfg = self.config.write();
-        *cfg = config;
+        self.config.store(Arc::new(CompiledConfig::new(config)));
     }
 
     /// Get the current configuration.
     pub fn config(&self) -> RateLimitConfig {
-        self.config.read().clone()
+        self.config.load().config().clone()
+    }
+
+    /// Get the configuration, to share it with another store.
+    pub(crate) fn shared_config(&self) -> Arc<ArcSwap<CompiledConfig>> {
+        self.config.clone()
+    }
+
+    /// Publish the hash of the current configuration to the cluster, so
+    /// nodes can tell when their rules differ.
+    ///
+    /// The version published is the current time, so the newer of two
+    /// configurations can be told apart.
+    pub async fn publish_config(&self) {
+        let compiled = self.config.load();
+        let config = compiled.config();
+        self.cluster
+            .publish_config(config.config_hash(), Self::now_secs(), &config.domain_hashes())
+            .await;
     }
 
     /// Check the rate limit for a given domain and descriptor.
@@ -93,108 +119,321 @@ impl DistributedRateLimiter {
         descriptor: &RateLimitDescriptor,
         hits: u32,
     ) -> DescriptorStatus {
-        let descriptor_key = DescriptorKey::new(domain, descriptor);
-
-        // Get the limit configuration
-        let limit_config = self.get_limit_config(domain, descriptor);
-        let window_duration_secs = limit_config.window.duration().as_secs();
+        self.check_rate_limits(domain, std::slice::from_ref(descriptor), hits)
+            .await
+            .remove(0)
+    }
 
-        // Calculate the window boundary (floor to window start)
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        let window_start = (now / window_duration_secs) * window_duration_secs;
+    /// Check the rate limits for all descriptors of a request.
+    ///
+    /// Rules are resolved under a single config read and all counters are
+    /// incremented under a single cluster state lock. Each descriptor is
+    /// counted independently, and statuses are returned in descriptor order.
+    /// A descriptor with several limits counts each of them and reports the
+    /// most restrictive status.
+    pub async fn check_rate_limits(
+        &self,
+        domain: &str,
+        descriptors: &[RateLimitDescriptor],
+        hits: u32,
+    ) -> Vec<DescriptorStatus> {
+        let config = self.config.load();
+        let resolutions = Resolution::resolve_all(&config, domain, descriptors);
+        self.check_resolved(domain, &resolutions, hits).await
+    }
 
-        let counter_key = CounterKey::new(domain, &descriptor_key.to_string(), window_start);
+    /// Check the rate limits of resolved descriptors, counting each independently.
+    pub(crate) async fn check_resolved(
+        &self,
+        domain: &str,
+        resolutions: &[Resolution<'_>],
+        hits: u32,
+    ) -> Vec<DescriptorStatus> {
+        let now = Self::now_secs();
+        let counters = RequestCounters::new(domain, resolutions, &self.cluster, hits, now);
 
         trace!(
             domain = %domain,
-            descriptor = %descriptor_key,
-            window = window_start,
+            descriptor_count = resolutions.len(),
             hits = hits,
-            "Checking distributed rate limit"
+            "Checking distributed rate limits"
         );
 
-        // Increment the counter in cluster state
-        let current_count = self.cluster.increment_counter(&counter_key, hits as u64).await;
+        let (_, strong_totals) = self.strong.increment(&counters.strong, hits as u64, false).await;
+        let open_totals = self
+            .cluster
+            .increment_counters(&counters.open_keys, hits as u64)
+            .await;
+        self.broadcast(&counters);
+        self.block_exhausted(&counters, &open_totals).await;
+        let eventual_totals = counters.eventual_totals(open_totals);
+        let mut totals = counters.merge(strong_totals, eventual_totals).into_iter();
 
-        let within_limit = current_count <= limit_config.limit;
-        let remaining = limit_config.limit.saturating_sub(current_count);
+        resolutions
+            .iter()
+            .map(|resolution| match resolution {
+                Resolution::Limited(limits) => most_restrictive(limits.iter().map(|limit| {
+                    // A blocked counter has no fresh total and is at its limit
+                    let total = totals.next().flatten();
+                    let within_limit = total.is_some_and(|total| total <= limit.limit);
+                    let total = total.unwrap_or(limit.limit);
+                    if !within_limit {
+                        debug!(
+                            domain = %domain,
+                            descriptor = %limit.key,
+                            count = total,
+                            limit = limit.limit,
+                            "Distributed rate limit exceeded"
+                        );
+                    }
+                    Self::descriptor_status(within_limit, total, limit, now)
+                })),
+                uncounted => uncounted.uncounted_status().unwrap_or_default(),
+            })
+            .collect()
+    }
 
-        // Calculate time until window reset
-        let window_end = window_start + window_duration_secs;
-        let duration_until_reset = window_end.saturating_sub(now);
+    /// Check the rate limits for all descriptors of a request atomically.
+    ///
+    /// The cluster-wide counts for every descriptor are checked before anything
+    /// is incremented, and the increments are only committed if all descriptors
+    /// are within their limits, so a rejected request does not consume quota.
+    pub async fn check_rate_limits_atomic(
+        &self,
+        domain: &str,
+        descriptors: &[RateLimitDescriptor],
+        hits: u32,
+    ) -> Vec<DescriptorStatus> {
+        let config = self.config.load();
+        let resolutions = Resolution::resolve_all(&config, domain, descriptors);
+        self.check_atomic(domain, &resolutions, hits, false).await.1
+    }
+
+    /// Check the rate limits of resolved descriptors atomically.
+    ///
+    /// With `reject`, the request is rejected without counting anything, as
+    /// when another part of it is already known to be over the limit.
+    /// Returns whether the hits were counted, along with the statuses.
+    pub(crate) async fn check_atomic(
+        &self,
+        domain: &str,
+        resolutions: &[Resolution<'_>],
+        hits: u32,
+        reject: bool,
+    ) -> (bool, Vec<DescriptorStatus>) {
+        let now = Self::now_secs();
+        let counters = RequestCounters::new(domain, resolutions, &self.cluster, hits, now);
+        let any_denied = reject
+            || counters.blocked.contains(&true)
+            || resolutions.iter().any(|r| matches!(r, Resolution::Denied));
+
+        // Strongly consistent counters are checked first, since their hits
+        // can be released if the eventually consistent ones are over limit
+        let (committed, mut strong_totals) = if any_denied {
+            // A denied descriptor rejects the request, so only read the counts
+            (false, self.strong.counts(&counters.strong).await)
+        } else {
+            self.strong.increment(&counters.strong, hits as u64, true).await
+        };
 
-        let code = if within_limit {
-            Code::Ok
+        let (committed, open_totals) = if !committed {
+            let mut totals = Vec::with_capacity(counters.open_keys.len());
+            for key in &counters.open_keys {
+                totals.push(self.cluster.get_count(key).await);
+            }
+            (false, totals)
         } else {
+            // No counter is blocked, or the request would have been rejected
+            let open: SmallVec<[(CounterKeyRef, u64); 8]> = counters
+                .open_keys
+                .iter()
+                .copied()
+                .zip(counters.open_limits.iter().copied())
+                .collect();
+            let (committed, totals) = self
+                .cluster
+                .increment_counters_if_within(&open, hits as u64)
+                .await;
+            if committed {
+                self.broadcast(&counters);
+            } else {
+                let keys: Vec<CounterKey> = counters.strong.iter().map(|c| c.key.clone()).collect();
+                self.strong.release(&keys, hits as u64).await;
+                for total in &mut strong_totals {
+                    *total = total.saturating_sub(hits as u64);
+                }
+            }
+            (committed, totals)
+        };
+
+        if !committed {
             debug!(
                 domain = %domain,
-                descriptor = %descriptor_key,
-                count = current_count,
-                limit = limit_config.limit,
-                "Distributed rate limit exceeded"
+                descriptor_count = resolutions.len(),
+                "Distributed rate limit exceeded, no counters incremented"
             );
-            Code::OverLimit
-        };
-
-        DescriptorStatus {
-            code: code.into(),
-            current_limit: Some(RateLimit {
-                name: limit_config.name.unwrap_or_default(),
-                requests_per_unit: limit_config.limit as u32,
-                unit: limit_config.window.to_proto(),
-            }),
-            limit_remaining: remaining as u32,
-            duration_until_reset: Some(prost_types::Duration {
-                seconds: duration_until_reset as i64,
-                nanos: 0,
-            }),
-            quota_bucket: None,
         }
+
+        self.block_exhausted(&counters, &open_totals).await;
+        let eventual_totals = counters.eventual_totals(open_totals);
+        let mut totals = counters.merge(strong_totals, eventual_totals).into_iter();
+        let statuses = resolutions
+            .iter()
+            .map(|resolution| match resolution {
+                Resolution::Limited(limits) => most_restrictive(limits.iter().map(|limit| {
+                    // A blocked counter has no fresh total and is at its limit
+                    let total = totals.next().flatten();
+                    let within_limit = committed
+                        || total.is_some_and(|total| total.saturating_add(hits as u64) <= limit.limit);
+                    let total = total.unwrap_or(limit.limit);
+                    Self::descriptor_status(within_limit, total, limit, now)
+                })),
+                uncounted => uncounted.uncounted_status().unwrap_or_default(),
+            })
+            .collect();
+        (committed, statuses)
     }
 
-    /// Get the limit configuration for a descriptor.
-    fn get_limit_config(&self, domain: &str, descriptor: &RateLimitDescriptor) -> LimitConfig {
-        // Check if there's an override in the descriptor itself
-        if let Some(ref limit_override) = descriptor.limit {
-            let window = TimeWindow::from_proto(limit_override.unit).unwrap_or(DEFAULT_WINDOW);
-            return LimitConfig {
-                limit: limit_override.requests_per_unit as u64,
-                window,
-                name: None,
-            };
+    /// Start the task broadcasting queued counters to peers, if broadcast is enabled.
+    ///
+    /// Broadcasting takes the cluster state lock and sends a datagram to
+    /// every peer, so it is kept off the request path. The task ends once
+    /// the limiter or the cluster is dropped.
+    fn spawn_broadcaster(cluster: &Arc<Cluster>) -> Option<mpsc::Sender<Vec<CounterKey>>> {
+        if !cluster.broadcast_enabled() {
+            return None;
         }
+        let (sender, mut receiver) = mpsc::channel::<Vec<CounterKey>>(BROADCAST_QUEUE_SIZE);
+        let cluster = Arc::downgrade(cluster);
+        tokio::spawn(async move {
+            while let Some(keys) = receiver.recv().await {
+                let Some(cluster) = cluster.upgrade() else {
+                    break;
+                };
+                let keys: Vec<&CounterKey> = keys.iter().collect();
+                cluster.broadcast_counters(&keys).await;
+            }
+        });
+        Some(sender)
+    }
 
-        // Look up configured limits
-        let config = self.config.read();
-        if let Some(rule) = config.find_limit(domain, descriptor) {
-            return LimitConfig {
-                limit: rule.requests_per_unit,
-                window: rule.unit.into(),
-                name: rule.name.clone(),
-            };
+    /// Queue the counted eventually consistent counters whose rule asks for
+    /// it to be broadcast.
+    ///
+    /// Broadcasts are best effort: when the queue is full, the counters are
+    /// left to gossip.
+    fn broadcast(&self, counters: &RequestCounters) {
+        let Some(broadcasts) = &self.broadcasts else {
+            return;
+        };
+        let keys: Vec<CounterKey> = counters
+            .open_keys
+            .iter()
+            .zip(&counters.open_broadcast)
+            .filter(|(_, broadcast)| **broadcast)
+            .map(|(key, _)| key.to_key())
+            .collect();
+        if keys.is_empty() {
+            return;
+        }
+        if let Err(mpsc::error::TrySendError::Full(_)) = broadcasts.try_send(keys) {
+            trace!("Broadcast queue full, leaving counters to gossip");
         }
+    }
 
-        // Fall back to default limits
-        LimitConfig::default()
+    /// Block the eventually consistent counters that reached their limit.
+    ///
+    /// `open_totals` holds the fresh total of each counter that wasn't
+    /// already blocked. Counts only grow within a window, so no further hit
+    /// fits once a counter is at its limit.
+    async fn block_exhausted(&self, counters: &RequestCounters<'_>, open_totals: &[u64]) {
+        let exhausted: Vec<(CounterKeyRef, u64, u64)> = counters
+            .open_keys
+            .iter()
+            .zip(&counters.open_limits)
+            .zip(&counters.open_ends)
+            .zip(open_totals)
+            .filter(|(((_, limit), _), total)| **total >= **limit)
+            .map(|(((key, limit), window_end), _)| (*key, *limit, *window_end))
+            .collect();
+        if !exhausted.is_empty() {
+            debug!(key_count = exhausted.len(), "Blocking counters at their limit");
+            self.cluster.block_counters(&exhausted).await;
+        }
     }
 
-    /// Get the current counter value for a descriptor.
-    pub async fn get_counter_value(&self, domain: &str, descriptor: &RateLimitDescriptor) -> u64 {
-        let descriptor_key = DescriptorKey::new(domain, descriptor);
-        let limit_config = self.get_limit_config(domain, descriptor);
-        let window_duration_secs = limit_config.window.duration().as_secs();
+    /// Get the cluster counter key for a resolved limit in the window containing `now`.
+    fn counter_key<'a>(domain: &'a str, limit: &'a ResolvedLimit<'_>, now: u64) -> CounterKeyRef<'a> {
+        CounterKeyRef::new(domain, &limit.key, Self::window_start(now, limit.window))
+    }
 
-        let now = std::time::SystemTime::now()
+    /// Get the current time in epoch seconds.
+    fn now_secs() -> u64 {
+        std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
-            .as_secs();
-        let window_start = (now / window_duration_secs) * window_duration_secs;
+            .as_secs()
+    }
 
-        let counter_key = CounterKey::new(domain, &descriptor_key.to_string(), window_start);
-        self.cluster.get_count(&counter_key).await
+    /// Get the start of the window containing `now`, in epoch seconds.
+    fn window_start(now: u64, window: TimeWindow) -> u64 {
+        // Floor to the window boundary
+        let window_duration_secs = window.duration().as_secs();
+        (now / window_duration_secs) * window_duration_secs
+    }
+
+    /// Check whether a counter whose window started at `window_start` may still be counting.
+    ///
+    /// Counter keys carry the start of their window but not its length.
+    /// Windows start on a multiple of their length, so the longest window that
+    /// could start at `window_start` bounds when the counter ends. A counter
+    /// kept past its window is harmless, since only the current window's key
+    /// is ever read.
+    fn window_may_be_open(window_start: u64, now: u64) -> bool {
+        [TimeWindow::Day, TimeWindow::Hour, TimeWindow::Minute, TimeWindow::Second]
+            .iter()
+            .map(|window| window.duration().as_secs())
+            .find(|secs| window_start.is_multiple_of(*secs))
+            .is_some_and(|secs| window_start + secs > now)
+    }
+
+    /// Build the response status for a descriptor from its cluster-wide count.
+    fn descriptor_status(
+        within_limit: bool,
+        current_count: u64,
+        limit: &ResolvedLimit,
+        now: u64,
+    ) -> DescriptorStatus {
+        // Calculate time until window reset
+        let window_end = Self::window_start(now, limit.window) + limit.window.duration().as_secs();
+        let duration_until_reset = window_end.saturating_sub(now);
+
+        limited_status(
+            within_limit,
+            limit.to_proto(),
+            limit.limit.saturating_sub(current_count),
+            std::time::Duration::from_secs(duration_until_reset),
+        )
+    }
+
+    /// Get the current counter value for a descriptor.
+    ///
+    /// For descriptors with several limits, this is the counter of the first.
+    /// Returns 0 for descriptors that are not counted.
+    pub async fn get_counter_value(&self, domain: &str, descriptor: &RateLimitDescriptor) -> u64 {
+        let config = self.config.load();
+        let resolution = Resolution::resolve(&config, domain, descriptor);
+        match resolution.limits().first() {
+            Some(limit) if limit.consistency == Consistency::Strong => {
+                let counter = RequestCounters::owned_increment(domain, limit, Self::now_secs());
+                self.strong.counts(&[counter]).await.remove(0)
+            }
+            Some(limit) => {
+                let counter_key = Self::counter_key(domain, limit, Self::now_secs());
+                self.cluster.get_count(&counter_key).await
+            }
+            None => 0,
+        }
     }
 
     /// Get the cluster.
@@ -208,6 +447,104 @@ impl DistributedRateLimiter {
     }
 }
 
+/// The current-window counters of every limit of a request's limited
+/// descriptors, split by how they are counted.
+///
+/// Eventually consistent counters known to be at their limit are only
+/// recorded as blocked, since they are neither read nor written.
+struct RequestCounters<'a> {
+    /// Whether each limit, in order, is strongly consistent
+    strong_limits: SmallVec<[bool; 8]>,
+    /// Counters of the strongly consistent limits, in order
+    strong: Vec<OwnedIncrement>,
+    /// Whether each eventually consistent counter, in order, is known to be
+    /// at its limit
+    blocked: SmallVec<[bool; 8]>,
+    /// Cluster counter keys of the eventually consistent counters that
+    /// aren't blocked, in order
+    open_keys: SmallVec<[CounterKeyRef<'a>; 8]>,
+    /// Limits of the open counters
+    open_limits: SmallVec<[u64; 8]>,
+    /// End of each open counter's window, in epoch seconds
+    open_ends: SmallVec<[u64; 8]>,
+    /// Whether each open counter is broadcast
+    open_broadcast: SmallVec<[bool; 8]>,
+}
+
+impl<'a> RequestCounters<'a> {
+    /// Build the counters of the limits of resolved descriptors.
+    ///
+    /// A request without hits can't exceed a limit, so no counter is
+    /// blocked for it.
+    fn new(domain: &'a str, resolutions: &'a [Resolution<'_>], cluster: &Cluster, hits: u32, now: u64) -> Self {
+        let mut counters = Self {
+            strong_limits: SmallVec::new(),
+            strong: Vec::new(),
+            blocked: SmallVec::new(),
+            open_keys: SmallVec::new(),
+            open_limits: SmallVec::new(),
+            open_ends: SmallVec::new(),
+            open_broadcast: SmallVec::new(),
+        };
+        for limit in resolutions.iter().flat_map(Resolution::limits) {
+            let strong = limit.consistency == Consistency::Strong;
+            counters.strong_limits.push(strong);
+            if strong {
+                counters.strong.push(Self::owned_increment(domain, limit, now));
+                continue;
+            }
+
+            let key = DistributedRateLimiter::counter_key(domain, limit, now);
+            let blocked = hits > 0 && cluster.is_blocked(&key, limit.limit, now);
+            counters.blocked.push(blocked);
+            if !blocked {
+                counters.open_keys.push(key);
+                counters.open_limits.push(limit.limit);
+                counters.open_ends.push(
+                    DistributedRateLimiter::window_start(now, limit.window) + limit.window.duration().as_secs(),
+                );
+                counters.open_broadcast.push(limit.broadcast);
+            }
+        }
+        counters
+    }
+
+    /// Build the counter of a strongly consistent limit.
+    fn owned_increment(domain: &str, limit: &ResolvedLimit, now: u64) -> OwnedIncrement {
+        OwnedIncrement {
+            key: DistributedRateLimiter::counter_key(domain, limit, now).to_key(),
+            limit: limit.limit,
+            window_secs: limit.window.duration().as_secs(),
+        }
+    }
+
+    /// Spread the totals of the counters that aren't blocked over every
+    /// eventually consistent counter, with `None` for blocked ones.
+    fn eventual_totals(&self, open_totals: Vec<u64>) -> Vec<Option<u64>> {
+        let mut open_totals = open_totals.into_iter();
+        self.blocked
+            .iter()
+            .map(|blocked| if *blocked { None } else { open_totals.next() })
+            .collect()
+    }
+
+    /// Interleave the totals of both kinds of counters back into limit order.
+    fn merge(&self, strong_totals: Vec<u64>, eventual_totals: Vec<Option<u64>>) -> Vec<Option<u64>> {
+        let mut strong_totals = strong_totals.into_iter();
+        let mut eventual_totals = eventual_totals.into_iter();
+        self.strong_limits
+            .iter()
+            .map(|strong| {
+                if *strong {
+                    Some(strong_totals.next().unwrap_or_default())
+                } else {
+                    eventual_totals.next().unwrap_or_default()
+                }
+            })
+            .collect()
+    }
+}
+
 #[async_trait]
 impl super::backend::RateLimiterBackend for DistributedRateLimiter {
     async fn check_rate_limit(
@@ -218,14 +555,85 @@ impl super::backend::RateLimiterBackend for DistributedRateLimiter {
     ) -> DescriptorStatus {
         self.check_rate_limit(domain, descriptor, hits).await
     }
+
+    async fn check_rate_limits(
+        &self,
+        domain: &str,
+        descriptors: &[RateLimitDescriptor],
+        hits: u32,
+    ) -> Vec<DescriptorStatus> {
+        self.check_rate_limits(domain, descriptors, hits).await
+    }
+
+    async fn check_rate_limits_atomic(
+        &self,
+        domain: &str,
+        descriptors: &[RateLimitDescriptor],
+        hits: u32,
+    ) -> Vec<DescriptorStatus> {
+        self.check_rate_limits_atomic(domain, descriptors, hits).await
+    }
+}
+
+#[async_trait]
+impl SnapshotSource for DistributedRateLimiter {
+    /// Only this node's share of each counter is recorded; the other nodes'
+    /// shares are gossiped back to it after a restart. Strongly consistent
+    /// counters this node holds are recorded whole.
+    async fn snapshot(&self) -> Snapshot {
+        let mut snapshot = Snapshot::new();
+        snapshot.cluster_counters = self
+            .cluster
+            .own_counters()
+            .await
+            .into_iter()
+            .filter(|(_, count)| *count > 0)
+            .map(|(key, count)| ClusterCounterSnapshot {
+                key: key.as_chitchat_key().to_string(),
+                count,
+            })
+            .collect();
+        snapshot.owned_counters = self
+            .cluster
+            .owned_counts()
+            .into_iter()
+            .filter(|(_, count, _)| *count > 0)
+            .map(|(key, count, expires_at)| OwnedCounterSnapshot {
+                key: key.as_chitchat_key().to_string(),
+                count,
+                expires_at,
+            })
+            .collect();
+        snapshot
+    }
+
+    async fn restore(&self, snapshot: &Snapshot) -> usize {
+        let now = Self::now_secs();
+        let counters: Vec<(CounterKey, u64)> = snapshot
+            .cluster_counters
+            .iter()
+            .filter_map(|saved| Some((CounterKey::from_chitchat_key(&saved.key)?, saved.count)))
+            .filter(|(key, _)| Self::window_may_be_open(key.window(), now))
+            .collect();
+        self.cluster.restore_counters(&counters).await;
+
+        let owned: Vec<(CounterKey, u64, u64)> = snapshot
+            .owned_counters
+            .iter()
+            .filter(|saved| saved.expires_at > now)
+            .filter_map(|saved| Some((CounterKey::from_chitchat_key(&saved.key)?, saved.count, saved.expires_at)))
+            .collect();
+        self.cluster.restore_owned(&owned);
+        counters.len() + owned.len()
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::grpc::proto::envoy::service::ratelimit::v3::rate_limit_response::Code;
     use crate::grpc::proto::envoy::extensions::common::ratelimit::v3::rate_limit_descriptor::Entry;
-    use crate::mesh::ClusterConfig;
-    use std::time::Duration;
+    use crate::mesh::{ClusterConfig, KeyOwner};
 
     fn create_test_descriptor(key: &str, value: &str) -> RateLimitDescriptor {
         RateLimitDescriptor {
@@ -248,6 +656,8 @@ mod tests {
             gossip_interval: Duration::from_millis(50),
             dead_node_grace_period: Duration::from_secs(60),
             cache_ttl: Duration::from_millis(100), // Short TTL for tests
+            broadcast_listen_addr: None,
+            peer_addr: None,
         }
     }
 
@@ -331,6 +741,207 @@ descriptors:
         Arc::try_unwrap(cluster).unwrap().shutdown().await.unwrap();
     }
 
+    #[tokio::test]
+    async fn test_distributed_limiter_batch_check() {
+        let cluster_config = test_cluster_config(18953);
+        let cluster = Arc::new(Cluster::start(cluster_config).await.unwrap());
+
+        {
+            let yaml = r#"
+domain: test_domain
+descriptors:
+  - key: user
+    rate_limit:
+      requests_per_unit: 1
+      unit: minute
+      name: per_user
+  - key: org
+    rate_limit:
+      requests_per_unit: 100
+      unit: minute
+"#;
+            let config = RateLimitConfig::from_yaml(yaml).unwrap();
+            let limiter = DistributedRateLimiter::with_config(cluster.clone(), config);
+            let descriptors = vec![
+                create_test_descriptor("user", "alice"),
+                create_test_descriptor("org", "acme"),
+            ];
+
+            let statuses = limiter.check_rate_limits("test_domain", &descriptors, 1).await;
+            assert_eq!(statuses.len(), 2);
+            assert!(statuses.iter().all(|s| s.code() == Code::Ok));
+            assert_eq!(statuses[0].current_limit.as_ref().unwrap().name, "per_user");
+
+            // Descriptors are counted independently
+            let statuses = limiter.check_rate_limits("test_domain", &descriptors, 1).await;
+            assert_eq!(statuses[0].code(), Code::OverLimit);
+            assert_eq!(statuses[1].code(), Code::Ok);
+            assert_eq!(limiter.get_counter_value("test_domain", &descriptors[1]).await, 2);
+        }
+
+        Arc::try_unwrap(cluster).unwrap().shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_distributed_limiter_override_policy() {
+        use crate::grpc::proto::envoy::extensions::common::ratelimit::v3::rate_limit_descriptor::RateLimitOverride;
+
+        let cluster_config = test_cluster_config(18954);
+        let cluster = Arc::new(Cluster::start(cluster_config).await.unwrap());
+
+        {
+            let yaml = r#"
+domain: test_domain
+overrides:
+  max:
+    requests_per_unit: 2
+    unit: minute
+descriptors:
+  - key: api_key
+    rate_limit:
+      requests_per_unit: 100
+      unit: minute
+"#;
+            let config = RateLimitConfig::from_yaml(yaml).unwrap();
+            let limiter = DistributedRateLimiter::with_config(cluster.clone(), config);
+            let rule_descriptor = create_test_descriptor("api_key", "key");
+            let mut override_descriptor = rule_descriptor.clone();
+            override_descriptor.limit = Some(RateLimitOverride {
+                requests_per_unit: 1000,
+                unit: TimeWindow::Minute.to_proto(),
+            });
+
+            // The override is capped to 2/minute
+            for _ in 0..2 {
+                let status = limiter.check_rate_limit("test_domain", &override_descriptor, 1).await;
+                assert_eq!(status.code(), Code::Ok);
+            }
+            let status = limiter.check_rate_limit("test_domain", &override_descriptor, 1).await;
+            assert_eq!(status.code(), Code::OverLimit);
+
+            // The rule-based counter is separate and untouched
+            assert_eq!(limiter.get_counter_value("test_domain", &rule_descriptor).await, 0);
+            let status = limiter.check_rate_limit("test_domain", &rule_descriptor, 1).await;
+            assert_eq!(status.code(), Code::Ok);
+        }
+
+        Arc::try_unwrap(cluster).unwrap().shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_distributed_limiter_unmatched_policy() {
+        let cluster_config = test_cluster_config(18955);
+        let cluster = Arc::new(Cluster::start(cluster_config).await.unwrap());
+
+        {
+            let yaml = r#"
+unmatched:
+  action: allow
+unknown_domains:
+  action: deny
+domains:
+  test_domain:
+    domain: test_domain
+"#;
+            let config = RateLimitConfig::from_yaml(yaml).unwrap();
+            let limiter = DistributedRateLimiter::with_config(cluster.clone(), config);
+            let descriptor = create_test_descriptor("other", "value");
+
+            let status = limiter.check_rate_limit("test_domain", &descriptor, 1).await;
+            assert_eq!(status.code(), Code::Ok);
+            assert!(status.current_limit.is_none());
+
+            let status = limiter.check_rate_limit("unknown", &descriptor, 1).await;
+            assert_eq!(status.code(), Code::OverLimit);
+
+            assert_eq!(limiter.get_counter_value("test_domain", &descriptor).await, 0);
+        }
+
+        Arc::try_unwrap(cluster).unwrap().shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_distributed_limiter_multiple_limits() {
+        let cluster_config = test_cluster_config(18956);
+        let cluster = Arc::new(Cluster::start(cluster_config).await.unwrap());
+
+        {
+            let yaml = r#"
+domain: test_domain
+descriptors:
+  - key: api_key
+    rate_limit:
+      requests_per_unit: 100
+      unit: minute
+      name: per_minute
+    rate_limits:
+      - requests_per_unit: 3
+        unit: day
+        name: per_day
+"#;
+            let config = RateLimitConfig::from_yaml(yaml).unwrap();
+            let limiter = DistributedRateLimiter::with_config(cluster.clone(), config);
+            let descriptor = create_test_descriptor("api_key", "my_key");
+
+            for remaining in (0..3).rev() {
+                let status = limiter.check_rate_limit("test_domain", &descriptor, 1).await;
+                assert_eq!(status.code(), Code::Ok);
+                assert_eq!(status.limit_remaining, remaining);
+                assert_eq!(status.current_limit.unwrap().name, "per_day");
+            }
+
+            let status = limiter.check_rate_limit("test_domain", &descriptor, 1).await;
+            assert_eq!(status.code(), Code::OverLimit);
+            assert_eq!(status.current_limit.unwrap().name, "per_day");
+
+            // Both limits were counted
+            assert_eq!(limiter.get_counter_value("test_domain", &descriptor).await, 4);
+        }
+
+        Arc::try_unwrap(cluster).unwrap().shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_distributed_limiter_atomic_rejection() {
+        let cluster_config = test_cluster_config(18952);
+        let cluster = Arc::new(Cluster::start(cluster_config).await.unwrap());
+
+        {
+            let yaml = r#"
+domain: test_domain
+descriptors:
+  - key: user
+    rate_limit:
+      requests_per_unit: 2
+      unit: minute
+  - key: org
+    rate_limit:
+      requests_per_unit: 100
+      unit: minute
+"#;
+            let config = RateLimitConfig::from_yaml(yaml).unwrap();
+            let limiter = DistributedRateLimiter::with_config(cluster.clone(), config);
+            let descriptors = vec![
+                create_test_descriptor("user", "alice"),
+                create_test_descriptor("org", "acme"),
+            ];
+
+            for _ in 0..2 {
+                let statuses = limiter.check_rate_limits_atomic("test_domain", &descriptors, 1).await;
+                assert!(statuses.iter().all(|s| s.code() == Code::Ok));
+            }
+
+            let statuses = limiter.check_rate_limits_atomic("test_domain", &descriptors, 1).await;
+            assert_eq!(statuses[0].code(), Code::OverLimit);
+            assert_eq!(statuses[1].code(), Code::Ok);
+
+            // The org counter was not charged for the rejected request
+            assert_eq!(limiter.get_counter_value("test_domain", &descriptors[1]).await, 2);
+        }
+
+        Arc::try_unwrap(cluster).unwrap().shutdown().await.unwrap();
+    }
+
     #[tokio::test]
     async fn test_distributed_limiter_cluster_sync() {
         // Start first node
@@ -377,4 +988,379 @@ descriptors:
         Arc::try_unwrap(cluster1).unwrap().shutdown().await.unwrap();
         Arc::try_unwrap(cluster2).unwrap().shutdown().await.unwrap();
     }
+
+    #[tokio::test]
+    async fn test_distributed_limiter_broadcast() {
+        let yaml = r#"
+domain: test_domain
+descriptors:
+  - key: eager
+    rate_limit:
+      requests_per_unit: 3
+      unit: minute
+      broadcast: true
+"#;
+        // Gossip slowly, so increments are unlikely to be gossiped during the test
+        let mut config1 = test_cluster_config(18959);
+        config1.gossip_interval = Duration::from_secs(2);
+        config1.broadcast_listen_addr = Some("127.0.0.1:0".parse().unwrap());
+        let mut config2 = test_cluster_config(18960);
+        config2.gossip_interval = Duration::from_secs(2);
+        config2.broadcast_listen_addr = Some("127.0.0.1:0".parse().unwrap());
+        config2.seed_nodes = vec!["127.0.0.1:18959".to_string()];
+
+        let cluster1 = Arc::new(Cluster::start(config1).await.unwrap());
+        let cluster2 = Arc::new(Cluster::start(config2).await.unwrap());
+        for _ in 0..100 {
+            if cluster1.live_node_count().await == 2 && cluster2.live_node_count().await == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(cluster2.live_node_count().await, 2);
+
+        let config = RateLimitConfig::from_yaml(yaml).unwrap();
+        let limiter1 = DistributedRateLimiter::with_config(cluster1.clone(), config.clone());
+        let limiter2 = DistributedRateLimiter::with_config(cluster2.clone(), config);
+
+        let eager = create_test_descriptor("eager", "a");
+        for _ in 0..3 {
+            limiter1.check_rate_limit("test_domain", &eager, 1).await;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // The limit is exhausted on the peer without waiting for gossip
+        let status = limiter2.check_rate_limit("test_domain", &eager, 1).await;
+        assert_eq!(status.code(), Code::OverLimit);
+
+        drop((limiter1, limiter2));
+        Arc::try_unwrap(cluster1).unwrap().shutdown().await.unwrap();
+        Arc::try_unwrap(cluster2).unwrap().shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_distributed_limiter_block_hints() {
+        let yaml = r#"
+domain: test_domain
+descriptors:
+  - key: limited
+    rate_limit:
+      requests_per_unit: 2
+      unit: hour
+"#;
+        let config1 = test_cluster_config(18969);
+        let mut config2 = test_cluster_config(18970);
+        config2.seed_nodes = vec!["127.0.0.1:18969".to_string()];
+        let cluster1 = Arc::new(Cluster::start(config1).await.unwrap());
+        let cluster2 = Arc::new(Cluster::start(config2).await.unwrap());
+
+        let config = RateLimitConfig::from_yaml(yaml).unwrap();
+        let limiter1 = DistributedRateLimiter::with_config(cluster1.clone(), config.clone());
+        let limiter2 = DistributedRateLimiter::with_config(cluster2.clone(), config);
+        let descriptor = create_test_descriptor("limited", "a");
+
+        for _ in 0..2 {
+            let status = limiter1.check_rate_limit("test_domain", &descriptor, 1).await;
+            assert_eq!(status.code(), Code::Ok);
+        }
+        // The counter is at its limit, so later hits are rejected without being counted
+        let status = limiter1.check_rate_limit("test_domain", &descriptor, 1).await;
+        assert_eq!(status.code(), Code::OverLimit);
+        assert_eq!(status.limit_remaining, 0);
+        assert_eq!(limiter1.get_counter_value("test_domain", &descriptor).await, 2);
+
+        // The hint is gossiped to the peer, which rejects without counting either
+        let (key, _) = cluster1.own_counters().await.remove(0);
+        let now = DistributedRateLimiter::now_secs();
+        for _ in 0..100 {
+            if cluster2.is_blocked(&key, 2, now) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert!(cluster2.is_blocked(&key, 2, now));
+        let statuses = limiter2
+            .check_rate_limits_atomic("test_domain", std::slice::from_ref(&descriptor), 1)
+            .await;
+        assert_eq!(statuses[0].code(), Code::OverLimit);
+        assert!(cluster2.own_counters().await.is_empty());
+
+        // A raised limit isn't blocked by a hint for a lower one
+        limiter2.set_config(RateLimitConfig::from_yaml(&yaml.replace("2", "5")).unwrap());
+        let status = limiter2.check_rate_limit("test_domain", &descriptor, 1).await;
+        assert_eq!(status.code(), Code::Ok);
+
+        drop((limiter1, limiter2));
+        Arc::try_unwrap(cluster1).unwrap().shutdown().await.unwrap();
+        Arc::try_unwrap(cluster2).unwrap().shutdown().await.unwrap();
+    }
+
+    /// Serve a cluster node's peer service until the returned sender is dropped.
+    fn serve_peer(
+        cluster: Arc<Cluster>,
+        addr: std::net::SocketAddr,
+    ) -> (tokio::sync::oneshot::Sender<()>, tokio::task::JoinHandle<()>) {
+        use crate::grpc::proto::hivemind::peer::v1::peer_service_server::PeerServiceServer;
+        use crate::grpc::PeerServiceImpl;
+
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            tonic::transport::Server::builder()
+                .add_service(PeerServiceServer::new(PeerServiceImpl::new(cluster)))
+                .serve_with_shutdown(addr, async move {
+                    let _ = stopped.await;
+                })
+                .await
+                .unwrap();
+        });
+        (stop, server)
+    }
+
+    #[tokio::test]
+    async fn test_distributed_limiter_strong_consistency() {
+        let yaml = r#"
+domain: test_domain
+descriptors:
+  - key: credits
+    rate_limit:
+      requests_per_unit: 3
+      unit: hour
+      consistency: strong
+"#;
+        let peer_addr1: std::net::SocketAddr = "127.0.0.1:18964".parse().unwrap();
+        let peer_addr2: std::net::SocketAddr = "127.0.0.1:18965".parse().unwrap();
+        let mut config1 = test_cluster_config(18962);
+        config1.peer_addr = Some(peer_addr1);
+        let mut config2 = test_cluster_config(18963);
+        config2.peer_addr = Some(peer_addr2);
+        config2.seed_nodes = vec!["127.0.0.1:18962".to_string()];
+
+        let cluster1 = Arc::new(Cluster::start(config1).await.unwrap());
+        let cluster2 = Arc::new(Cluster::start(config2).await.unwrap());
+        let server1 = serve_peer(cluster1.clone(), peer_addr1);
+        let server2 = serve_peer(cluster2.clone(), peer_addr2);
+
+        let config = RateLimitConfig::from_yaml(yaml).unwrap();
+        let limiter1 = DistributedRateLimiter::with_config(cluster1.clone(), config.clone());
+        let limiter2 = DistributedRateLimiter::with_config(cluster2.clone(), config);
+
+        // Find a descriptor whose counter node 2 owns, once node 1 sees node 2's peer address
+        let now = DistributedRateLimiter::now_secs();
+        let compiled = limiter1.config.load();
+        let mut owned_by_peer = None;
+        for attempt in 0..1000 {
+            let descriptor = create_test_descriptor("credits", &attempt.to_string());
+            let resolution = Resolution::resolve(&compiled, "test_domain", &descriptor);
+            let key = DistributedRateLimiter::counter_key("test_domain", &resolution.limits()[0], now).to_key();
+            if let [KeyOwner::Peer { node_id, .. }] = &cluster1.counter_owners(&[&key], &[]).await[..] {
+                assert_eq!(node_id, cluster2.node_id());
+                owned_by_peer = Some(descriptor.clone());
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let descriptor = owned_by_peer.expect("node 2 should own some counters");
+
+        // Node 1 forwards its hits to node 2, which sees them straight away
+        for _ in 0..3 {
+            let status = limiter1.check_rate_limit("test_domain", &descriptor, 1).await;
+            assert_eq!(status.code(), Code::Ok);
+        }
+        let status = limiter2.check_rate_limit("test_domain", &descriptor, 1).await;
+        assert_eq!(status.code(), Code::OverLimit);
+        assert_eq!(limiter1.get_counter_value("test_domain", &descriptor).await, 4);
+
+        // Owned counters are not summed as gossiped shares
+        assert!(cluster1.own_counters().await.is_empty());
+        assert!(cluster2.own_counters().await.is_empty());
+
+        // Once the owner can't be reached, node 1 takes the counter over,
+        // continuing from the count node 2 gossiped
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        drop(server2.0);
+        server2.1.await.unwrap();
+        let status = limiter1.check_rate_limit("test_domain", &descriptor, 1).await;
+        assert_eq!(status.code(), Code::OverLimit);
+        assert_eq!(limiter1.get_counter_value("test_domain", &descriptor).await, 5);
+
+        drop((limiter1, limiter2, compiled));
+        drop(server1.0);
+        server1.1.await.unwrap();
+        Arc::try_unwrap(cluster1).unwrap().shutdown().await.unwrap();
+        Arc::try_unwrap(cluster2).unwrap().shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_distributed_limiter_strong_counts_survive_node_join() {
+        let yaml = r#"
+domain: test_domain
+descriptors:
+  - key: credits
+    rate_limit:
+      requests_per_unit: 3
+      unit: hour
+      consistency: strong
+"#;
+        let peer_addr1: std::net::SocketAddr = "127.0.0.1:18973".parse().unwrap();
+        let peer_addr2: std::net::SocketAddr = "127.0.0.1:18974".parse().unwrap();
+        let mut config1 = test_cluster_config(18971);
+        config1.peer_addr = Some(peer_addr1);
+        let mut config2 = test_cluster_config(18972);
+        config2.peer_addr = Some(peer_addr2);
+        config2.seed_nodes = vec!["127.0.0.1:18971".to_string()];
+
+        let config = RateLimitConfig::from_yaml(yaml).unwrap();
+        let cluster1 = Arc::new(Cluster::start(config1).await.unwrap());
+        let server1 = serve_peer(cluster1.clone(), peer_addr1);
+        let limiter1 = DistributedRateLimiter::with_config(cluster1.clone(), config.clone());
+
+        // Node 1 owns every counter while it is alone
+        let descriptors: Vec<RateLimitDescriptor> =
+            (0..20).map(|i| create_test_descriptor("credits", &i.to_string())).collect();
+        for descriptor in &descriptors {
+            limiter1.check_rate_limit("test_domain", descriptor, 2).await;
+        }
+
+        // Node 2 joins mid-window and takes over some of the counters
+        let cluster2 = Arc::new(Cluster::start(config2).await.unwrap());
+        let server2 = serve_peer(cluster2.clone(), peer_addr2);
+        let limiter2 = DistributedRateLimiter::with_config(cluster2.clone(), config);
+        let now = DistributedRateLimiter::now_secs();
+        let compiled = limiter1.config.load();
+        let mut moved = None;
+        for _ in 0..100 {
+            for descriptor in &descriptors {
+                let resolution = Resolution::resolve(&compiled, "test_domain", descriptor);
+                let key = DistributedRateLimiter::counter_key("test_domain", &resolution.limits()[0], now).to_key();
+                if let [KeyOwner::Peer { .. }] = &cluster1.counter_owners(&[&key], &[]).await[..] {
+                    moved = Some(descriptor.clone());
+                    break;
+                }
+            }
+            if moved.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        let descriptor = moved.expect("node 2 should own some counters");
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        // The new owner continues from node 1's count
+        assert_eq!(limiter2.get_counter_value("test_domain", &descriptor).await, 2);
+        let status = limiter1.check_rate_limit("test_domain", &descriptor, 1).await;
+        assert_eq!(status.code(), Code::Ok);
+        let status = limiter2.check_rate_limit("test_domain", &descriptor, 1).await;
+        assert_eq!(status.code(), Code::OverLimit);
+
+        drop((limiter1, limiter2, compiled));
+        for (stop, server) in [server1, server2] {
+            drop(stop);
+            server.await.unwrap();
+        }
+        Arc::try_unwrap(cluster1).unwrap().shutdown().await.unwrap();
+        Arc::try_unwrap(cluster2).unwrap().shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_distributed_limiter_atomic_releases_strong_counters() {
+        let yaml = r#"
+domain: test_domain
+descriptors:
+  - key: credits
+    rate_limit:
+      requests_per_unit: 5
+      unit: hour
+      consistency: strong
+  - key: burst
+    rate_limit:
+      requests_per_unit: 1
+      unit: hour
+"#;
+        let cluster = Arc::new(Cluster::start(test_cluster_config(18966)).await.unwrap());
+        {
+            let config = RateLimitConfig::from_yaml(yaml).unwrap();
+            let limiter = DistributedRateLimiter::with_config(cluster.clone(), config);
+            let credits = create_test_descriptor("credits", "a");
+            let descriptors = [credits.clone(), create_test_descriptor("burst", "a")];
+
+            let statuses = limiter.check_rate_limits_atomic("test_domain", &descriptors, 1).await;
+            assert!(statuses.iter().all(|s| s.code() == Code::Ok));
+
+            // The burst limit rejects the request, so the credit is given back
+            let statuses = limiter.check_rate_limits_atomic("test_domain", &descriptors, 1).await;
+            assert_eq!(statuses[0].code(), Code::Ok);
+            assert_eq!(statuses[1].code(), Code::OverLimit);
+            assert_eq!(limiter.get_counter_value("test_domain", &credits).await, 1);
+        }
+        Arc::try_unwrap(cluster).unwrap().shutdown().await.unwrap();
+    }
+
+    #[test]
+    fn test_window_may_be_open() {
+        let now = 1_700_000_130;
+
+        // Second-aligned windows end a second after they start
+        assert!(DistributedRateLimiter::window_may_be_open(now, now));
+        assert!(!DistributedRateLimiter::window_may_be_open(now - 1, now));
+
+        // A minute-aligned start may be a minute window
+        assert!(DistributedRateLimiter::window_may_be_open(1_700_000_100, now));
+        assert!(!DistributedRateLimiter::window_may_be_open(1_700_000_040, now));
+
+        // A day-aligned start may be a day window
+        assert!(DistributedRateLimiter::window_may_be_open(1_699_920_000, now));
+    }
+
+    #[tokio::test]
+    async fn test_distributed_limiter_snapshot_restore() {
+        let yaml = r#"
+domain: test_domain
+descriptors:
+  - key: api_key
+    rate_limit:
+      requests_per_unit: 5
+      unit: day
+"#;
+        let config = RateLimitConfig::from_yaml(yaml).unwrap();
+        let descriptor = create_test_descriptor("api_key", "my_key");
+
+        let cluster = Arc::new(Cluster::start(test_cluster_config(18957)).await.unwrap());
+        let mut snapshot = {
+            let limiter = DistributedRateLimiter::with_config(cluster.clone(), config.clone());
+            limiter.check_rate_limit("test_domain", &descriptor, 4).await;
+            limiter.snapshot().await
+        };
+        Arc::try_unwrap(cluster).unwrap().shutdown().await.unwrap();
+        assert_eq!(snapshot.cluster_counters.len(), 1);
+
+        // Strongly consistent counters are restored whole
+        let owned_key = CounterKey::new("test_domain", "test_domain:credits=a", 1_700_000_000);
+        snapshot.owned_counters.push(OwnedCounterSnapshot {
+            key: owned_key.as_chitchat_key().to_string(),
+            count: 3,
+            expires_at: u64::MAX,
+        });
+        // A minute window that ended while the node was down is discarded
+        snapshot.cluster_counters.push(ClusterCounterSnapshot {
+            key: CounterKey::new("test_domain", "test_domain:api_key=old", 1_700_000_040)
+                .as_chitchat_key()
+                .to_string(),
+            count: 5,
+        });
+
+        let cluster = Arc::new(Cluster::start(test_cluster_config(18958)).await.unwrap());
+        {
+            let limiter = DistributedRateLimiter::with_config(cluster.clone(), config);
+            assert_eq!(limiter.restore(&snapshot).await, 2);
+            assert_eq!(limiter.get_counter_value("test_domain", &descriptor).await, 4);
+            assert_eq!(cluster.owned_counts(), vec![(owned_key, 3, u64::MAX)]);
+            assert_eq!(limiter.snapshot().await.owned_counters, snapshot.owned_counters);
+
+            let status = limiter.check_rate_limit("test_domain", &descriptor, 1).await;
+            assert_eq!(status.code(), Code::Ok);
+            let status = limiter.check_rate_limit("test_domain", &descriptor, 1).await;
+            assert_eq!(status.code(), Code::OverLimit);
+        }
+        Arc::try_unwrap(cluster).unwrap().shutdown().await.unwrap();
+    }
 }
@@ -1,70 +1,59 @@
 //! Core rate limiter implementation.
 
-use std::collections::HashMap;
-use std::sync::RwLock;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+use arc_swap::ArcSwap;
+use dashmap::DashMap;
 use async_trait::async_trait;
+use smallvec::SmallVec;
 use tracing::{debug, trace};
 
+use crate::grpc::proto::envoy::extensions::common::ratelimit::v3::rate_limit_descriptor::{
+    Entry, RateLimitOverride,
+};
 use crate::grpc::proto::envoy::extensions::common::ratelimit::v3::RateLimitDescriptor;
 use crate::grpc::proto::envoy::service::ratelimit::v3::rate_limit_response::{
-    Code, DescriptorStatus, RateLimit,
+    DescriptorStatus, RateLimit,
 };
 
 use super::backend::RateLimiterBackend;
 use super::counter::{RateLimitCounter, TimeWindow};
-use super::descriptor::DescriptorKey;
+use super::descriptor::{DescriptorKey, DescriptorKeyParts};
+use super::resolver::{limited_status, most_restrictive, saturating_u32, Resolution, ResolvedLimit};
+use super::compiled::CompiledConfig;
 use super::rules::RateLimitConfig;
-
-/// Default rate limit when no specific limit is configured.
-const DEFAULT_LIMIT: u64 = 1000;
-/// Default time window when no specific window is configured.
-const DEFAULT_WINDOW: TimeWindow = TimeWindow::Second;
+use super::snapshot::{unix_millis, CounterSnapshot, Snapshot, SnapshotSource};
 
 /// The core rate limiter that manages rate limit counters.
 ///
 /// This struct is thread-safe and can be shared across multiple tasks.
+/// Counters live in a sharded concurrent map: checks against existing
+/// counters only take a shard read lock and then update the lock-free
+/// counter, so checks on different cores don't serialize.
 pub struct RateLimiter {
     /// Rate limit counters indexed by descriptor key
-    counters: RwLock<HashMap<DescriptorKey, RateLimitCounter>>,
-    /// Configured rate limits loaded from configuration
-    config: RwLock<RateLimitConfig>,
-}
-
-/// Configuration for a rate limit.
-#[derive(Debug, Clone)]
-pub struct LimitConfig {
-    /// Maximum requests allowed in the time window
-    pub limit: u64,
-    /// Time window for the limit
-    pub window: TimeWindow,
-    /// Name/description of this limit
-    pub name: Option<String>,
-}
-
-impl Default for LimitConfig {
-    fn default() -> Self {
-        Self {
-            limit: DEFAULT_LIMIT,
-            window: DEFAULT_WINDOW,
-            name: None,
-        }
-    }
+    counters: DashMap<DescriptorKey, RateLimitCounter>,
+    /// Compiled rate limits, replaced atomically on update so checks never lock them
+    config: Arc<ArcSwap<CompiledConfig>>,
 }
 
 impl RateLimiter {
     /// Create a new rate limiter with default settings.
     pub fn new() -> Self {
-        Self {
-            counters: RwLock::new(HashMap::new()),
-            config: RwLock::new(RateLimitConfig::new()),
-        }
+        Self::with_shared_config(Arc::new(ArcSwap::from_pointee(CompiledConfig::default())))
     }
 
     /// Create a new rate limiter with the given configuration.
     pub fn with_config(config: RateLimitConfig) -> Self {
+        Self::with_shared_config(Arc::new(ArcSwap::from_pointee(CompiledConfig::new(config))))
+    }
+
+    /// Create a new rate limiter whose configuration is shared with another
+    /// store, so updating either updates both.
+    pub(crate) fn with_shared_config(config: Arc<ArcSwap<CompiledConfig>>) -> Self {
         Self {
-            counters: RwLock::new(HashMap::new()),
-            config: RwLock::new(config),
+            counters: DashMap::new(),
+            config,
         }
     }
 
@@ -73,13 +62,12 @@ impl RateLimiter {
     /// This does not clear existing counters - they will continue with
     /// their current state but may use new limits on their next check.
     pub fn set_config(&self, config: RateLimitConfig) {
-        let mut cfg = self.config.write().unwrap();
-        *cfg = config;
+        self.config.store(Arc::new(CompiledConfig::new(config)));
     }
 
     /// Get the current configuration.
     pub fn config(&self) -> RateLimitConfig {
-        self.config.read().unwrap().clone()
+        self.config.load().config().clone()
     }
 
     /// Check the rate limit for a given domain and descriptor.
@@ -91,127 +79,256 @@ impl RateLimiter {
         descriptor: &RateLimitDescriptor,
         hits: u32,
     ) -> DescriptorStatus {
-        let key = DescriptorKey::new(domain, descriptor);
-
-        trace!(
-            key = %key,
-            hits = hits,
-            "Checking rate limit"
-        );
+        self.check_rate_limits(domain, std::slice::from_ref(descriptor), hits)
+            .await
+            .remove(0)
+    }
 
-        // Get or create the counter for this descriptor
-        let (within_limit, current_limit, remaining, duration_until_reset) = {
-            let mut counters = self.counters.write().unwrap();
+    /// Check the rate limits for all descriptors of a request.
+    ///
+    /// Rules are resolved under a single config read. Each descriptor is counted
+    /// independently, and statuses are returned in descriptor order. A
+    /// descriptor with several limits counts each of them and reports the
+    /// most restrictive status.
+    pub async fn check_rate_limits(
+        &self,
+        domain: &str,
+        descriptors: &[RateLimitDescriptor],
+        hits: u32,
+    ) -> Vec<DescriptorStatus> {
+        let config = self.config.load();
+        let resolutions = Resolution::resolve_all(&config, domain, descriptors);
+        self.check_resolved(&resolutions, hits)
+    }
 
-            let counter = counters
-                .entry(key.clone())
-                .or_insert_with(|| {
-                    // Look up the configured limit for this descriptor
-                    let config = self.get_limit_config(domain, descriptor);
-                    debug!(
-                        key = %key,
-                        limit = config.limit,
-                        window = ?config.window,
-                        "Creating new rate limit counter"
+    /// Check the rate limits of resolved descriptors, counting each independently.
+    pub(crate) fn check_resolved(&self, resolutions: &[Resolution], hits: u32) -> Vec<DescriptorStatus> {
+        resolutions
+            .iter()
+            .map(|resolution| match resolution {
+                Resolution::Limited(limits) => most_restrictive(limits.iter().map(|limit| {
+                    trace!(
+                        key = %limit.key,
+                        hits = hits,
+                        "Checking rate limit"
                     );
-                    RateLimitCounter::new(config.limit, config.window)
-                });
-
-            let within_limit = counter.increment(hits);
-            let current_limit = counter.limit();
-            let remaining = counter.remaining();
-            let duration_until_reset = counter.duration_until_reset();
-            let window = counter.window();
-
-            (
-                within_limit,
-                RateLimit {
-                    name: String::new(),
-                    requests_per_unit: current_limit as u32,
-                    unit: window.to_proto(),
-                },
-                remaining,
-                duration_until_reset,
-            )
-        };
 
-        let code = if within_limit {
-            Code::Ok
-        } else {
+                    self.with_counter(limit, |counter| {
+                        let within_limit = counter.increment(u64::from(hits));
+                        if !within_limit {
+                            debug!(
+                                key = %limit.key,
+                                "Rate limit exceeded"
+                            );
+                        }
+                        Self::descriptor_status(within_limit, limit, counter)
+                    })
+                })),
+                uncounted => uncounted.uncounted_status().unwrap_or_default(),
+            })
+            .collect()
+    }
+
+    /// Check the rate limits for all descriptors of a request atomically.
+    ///
+    /// The hits are reserved on every counter in turn, each only if it stays
+    /// within its limit. If any counter is full, the reservations made so far
+    /// are released, so a rejected request does not consume quota. While a
+    /// reservation is held, a concurrent request may see it and be rejected
+    /// conservatively, but a limit is never exceeded.
+    pub async fn check_rate_limits_atomic(
+        &self,
+        domain: &str,
+        descriptors: &[RateLimitDescriptor],
+        hits: u32,
+    ) -> Vec<DescriptorStatus> {
+        let config = self.config.load();
+        let resolutions = Resolution::resolve_all(&config, domain, descriptors);
+        self.check_atomic(domain, &resolutions, hits, false, None).1
+    }
+
+    /// Check the rate limits of resolved descriptors atomically, keeping
+    /// track of the hits counted so they can be released.
+    ///
+    /// With `reject`, the request is rejected without counting anything, as
+    /// when another part of it is already known to be over the limit. If the
+    /// hits are counted, they are added to `reservation`. Returns whether the
+    /// hits were counted, along with the statuses.
+    pub(crate) fn check_atomic(
+        &self,
+        domain: &str,
+        resolutions: &[Resolution],
+        hits: u32,
+        reject: bool,
+        reservation: Option<&mut Reservation>,
+    ) -> (bool, Vec<DescriptorStatus>) {
+        // The same descriptor may appear more than once in a request, so
+        // reserve the combined hits for each counter. Requests carry a
+        // handful of descriptors, so a linear scan finds repeated keys.
+        let mut required: SmallVec<[(&ResolvedLimit, u64); 8]> = SmallVec::new();
+        let mut any_denied = false;
+        for resolution in resolutions {
+            match resolution {
+                Resolution::Limited(limits) => {
+                    for limit in limits {
+                        match required.iter_mut().find(|(other, _)| other.key == limit.key) {
+                            Some((_, total)) => *total = total.saturating_add(u64::from(hits)),
+                            None => required.push((limit, u64::from(hits))),
+                        }
+                    }
+                }
+                Resolution::Allowed => {}
+                Resolution::Denied => any_denied = true,
+            }
+        }
+
+        let mut all_within = !any_denied && !reject;
+        if all_within {
+            let mut reserved: SmallVec<[_; 8]> = SmallVec::new();
+            for (limit, total) in &required {
+                match self.with_counter(limit, |counter| counter.try_increment(*total)) {
+                    Some(epoch) => reserved.push((*limit, epoch, *total)),
+                    None => {
+                        all_within = false;
+                        break;
+                    }
+                }
+            }
+
+            if !all_within {
+                for (limit, epoch, total) in reserved {
+                    self.with_counter(limit, |counter| counter.release(epoch, total));
+                }
+            } else if let Some(reservation) = reservation {
+                reservation.reserved.extend(reserved.into_iter().map(|(limit, epoch, total)| {
+                    (limit.key.with_encoded(|key| DescriptorKey::from(key)), epoch, total)
+                }));
+            }
+        }
+
+        if !all_within {
             debug!(
-                key = %key,
-                "Rate limit exceeded"
+                domain = %domain,
+                descriptor_count = resolutions.len(),
+                "Rate limit exceeded, no counters incremented"
             );
-            Code::OverLimit
-        };
-
-        DescriptorStatus {
-            code: code.into(),
-            current_limit: Some(current_limit),
-            limit_remaining: remaining as u32,
-            duration_until_reset: Some(prost_types::Duration {
-                seconds: duration_until_reset.as_secs() as i64,
-                nanos: duration_until_reset.subsec_nanos() as i32,
-            }),
-            quota_bucket: None,
         }
+
+        let statuses = resolutions
+            .iter()
+            .map(|resolution| match resolution {
+                Resolution::Limited(limits) => most_restrictive(limits.iter().map(|limit| {
+                    // A rejected request reports each counter against the
+                    // combined hits it needed, not just this descriptor's
+                    let total = required
+                        .iter()
+                        .find(|(other, _)| other.key == limit.key)
+                        .map_or(u64::from(hits), |(_, total)| *total);
+                    self.with_counter(limit, |counter| {
+                        let within_limit = all_within || !counter.would_exceed(total);
+                        Self::descriptor_status(within_limit, limit, counter)
+                    })
+                })),
+                uncounted => uncounted.uncounted_status().unwrap_or_default(),
+            })
+            .collect();
+        (all_within, statuses)
     }
 
-    /// Get the limit configuration for a descriptor.
+    /// Release hits counted by atomic checks.
     ///
-    /// This looks up the limit in the following order:
-    /// 1. Override specified in the descriptor itself
-    /// 2. Configured limit from the rate limit configuration
-    /// 3. Default limit
-    fn get_limit_config(&self, domain: &str, descriptor: &RateLimitDescriptor) -> LimitConfig {
-        // Check if there's an override in the descriptor itself
-        if let Some(ref limit_override) = descriptor.limit {
-            let window = TimeWindow::from_proto(limit_override.unit).unwrap_or(DEFAULT_WINDOW);
-            return LimitConfig {
-                limit: limit_override.requests_per_unit as u64,
-                window,
-                name: None,
-            };
+    /// Hits counted in a window that has since ended are not released.
+    pub(crate) fn release(&self, reservation: Reservation) {
+        for (key, epoch, hits) in reservation.reserved {
+            if let Some(counter) = self.counters.get(&key) {
+                counter.release(epoch, hits);
+            }
         }
+    }
 
-        // Look up configured limits based on domain and descriptor entries
-        let config = self.config.read().unwrap();
-        if let Some(rule) = config.find_limit(domain, descriptor) {
-            return LimitConfig {
-                limit: rule.requests_per_unit,
-                window: rule.unit.into(),
-                name: rule.name.clone(),
-            };
-        }
+    /// Run `f` with the counter for a resolved limit, creating it if it doesn't exist yet.
+    ///
+    /// Existing counters are found under a shard read lock by the key encoded
+    /// in a reused buffer, without allocating; only creating a counter takes
+    /// the shard's write lock and builds an owned key.
+    fn with_counter<R>(&self, limit: &ResolvedLimit, f: impl FnOnce(&RateLimitCounter) -> R) -> R {
+        limit.key.with_encoded(|key| {
+            if let Some(counter) = self.counters.get(key) {
+                return f(&counter);
+            }
 
-        // Fall back to default limits
-        LimitConfig::default()
+            let counter = self
+                .counters
+                .entry(DescriptorKey::from(key))
+                .or_insert_with(|| {
+                    debug!(
+                        key = %key,
+                        limit = limit.limit,
+                        window = ?limit.window,
+                        "Creating new rate limit counter"
+                    );
+                    RateLimitCounter::new(limit.limit, limit.window)
+                })
+                .downgrade();
+            f(&counter)
+        })
+    }
+
+    /// Build the response status for a descriptor from its counter.
+    ///
+    /// The limit is reported from the counter, which keeps the limit it was
+    /// created with until its key is no longer used.
+    fn descriptor_status(
+        within_limit: bool,
+        limit: &ResolvedLimit,
+        counter: &RateLimitCounter,
+    ) -> DescriptorStatus {
+        limited_status(
+            within_limit,
+            RateLimit {
+                name: limit.name.unwrap_or_default().to_string(),
+                requests_per_unit: saturating_u32(counter.limit()),
+                unit: counter.window().to_proto(),
+            },
+            counter.remaining(),
+            counter.duration_until_reset(),
+        )
     }
 
     /// Get the current counter value for a descriptor key.
     ///
+    /// For descriptors with several limits, this is the counter of the first.
     /// Returns `None` if no counter exists for the key.
     pub fn get_counter_value(&self, domain: &str, descriptor: &RateLimitDescriptor) -> Option<u64> {
-        let key = DescriptorKey::new(domain, descriptor);
-        let counters = self.counters.read().unwrap();
-        counters.get(&key).map(|c| c.current_count())
+        let config = self.config.load();
+        let resolution = Resolution::resolve(&config, domain, descriptor);
+        let limit = resolution.limits().first()?;
+        limit
+            .key
+            .with_encoded(|key| self.counters.get(key).map(|c| c.current_count()))
     }
 
     /// Clear all counters.
     ///
     /// This is primarily useful for testing.
     pub fn clear(&self) {
-        let mut counters = self.counters.write().unwrap();
-        counters.clear();
+        self.counters.clear();
     }
 
     /// Get the number of active counters.
     pub fn counter_count(&self) -> usize {
-        let counters = self.counters.read().unwrap();
-        counters.len()
+        self.counters.len()
     }
 }
 
+/// Hits counted by atomic checks, which can be released if the request is
+/// rejected elsewhere.
+#[derive(Debug, Default)]
+pub(crate) struct Reservation {
+    /// Counter keys, with the window epoch the hits were counted in and the hits
+    reserved: Vec<(DescriptorKey, u64, u64)>,
+}
+
 impl Default for RateLimiter {
     fn default() -> Self {
         Self::new()
@@ -228,11 +345,122 @@ impl RateLimiterBackend for RateLimiter {
     ) -> DescriptorStatus {
         self.check_rate_limit(domain, descriptor, hits).await
     }
+
+    async fn check_rate_limits(
+        &self,
+        domain: &str,
+        descriptors: &[RateLimitDescriptor],
+        hits: u32,
+    ) -> Vec<DescriptorStatus> {
+        self.check_rate_limits(domain, descriptors, hits).await
+    }
+
+    async fn check_rate_limits_atomic(
+        &self,
+        domain: &str,
+        descriptors: &[RateLimitDescriptor],
+        hits: u32,
+    ) -> Vec<DescriptorStatus> {
+        self.check_rate_limits_atomic(domain, descriptors, hits).await
+    }
+
+    /// Every descriptor is counted by this instance alone.
+    fn counts_across_cluster(&self, _domain: &str, _descriptors: &[RateLimitDescriptor]) -> bool {
+        false
+    }
+}
+
+#[async_trait]
+impl SnapshotSource for RateLimiter {
+    async fn snapshot(&self) -> Snapshot {
+        let mut snapshot = Snapshot::new();
+        snapshot.counters = self
+            .counters
+            .iter()
+            .filter_map(|entry| {
+                let counter = entry.value();
+                let (window_start, count) = counter.snapshot()?;
+                Some(CounterSnapshot {
+                    key: entry.key().to_string(),
+                    limit: counter.limit(),
+                    window_secs: counter.window().duration().as_secs(),
+                    window_start_ms: unix_millis(window_start),
+                    count,
+                })
+            })
+            .collect();
+        snapshot
+    }
+
+    /// Counters take the limit and window of the rule that counts their key
+    /// in the loaded configuration, and counters no rule counts any more are
+    /// discarded. Counters that already exist, because checks ran before the
+    /// restore, are kept as they are.
+    async fn restore(&self, snapshot: &Snapshot) -> usize {
+        let config = self.config.load();
+        let mut restored = 0;
+        for saved in &snapshot.counters {
+            let key = DescriptorKey::from(saved.key.as_str());
+            let Some((limit, window)) = current_limit(&config, &key) else {
+                debug!(key = %saved.key, "Discarding snapshot counter no rule counts");
+                continue;
+            };
+            let window_start = UNIX_EPOCH + Duration::from_millis(saved.window_start_ms);
+            let Some(counter) = RateLimitCounter::restore(limit, window, window_start, saved.count) else {
+                continue;
+            };
+
+            if let dashmap::Entry::Vacant(entry) = self.counters.entry(key) {
+                entry.insert(counter);
+                restored += 1;
+            }
+        }
+        restored
+    }
+}
+
+/// Get the limit and window the configuration counts a descriptor key under.
+///
+/// The key is decoded back into a descriptor and resolved again, so this is
+/// `None` if no rule resolves to the same key any more. Aggregated entries are
+/// resolved with their network's address, which aggregates to the same network.
+fn current_limit(config: &CompiledConfig, key: &DescriptorKey) -> Option<(u64, TimeWindow)> {
+    let DescriptorKeyParts {
+        domain,
+        entries,
+        limit_override,
+        ..
+    } = key.decode()?;
+    let descriptor = RateLimitDescriptor {
+        entries: entries
+            .into_iter()
+            .map(|(key, value)| Entry {
+                key,
+                value: match value.parse::<ipnet::IpNet>() {
+                    Ok(network) => network.network().to_string(),
+                    Err(_) => value,
+                },
+            })
+            .collect(),
+        limit: limit_override.map(|(requests_per_unit, window)| RateLimitOverride {
+            requests_per_unit: saturating_u32(requests_per_unit),
+            unit: window.to_proto(),
+        }),
+    };
+
+    let resolution = Resolution::resolve(config, &domain, &descriptor);
+    let limits = resolution.limits();
+    limits
+        .iter()
+        .find(|limit| limit.key.with_encoded(|encoded| encoded == key.as_str()))
+        .map(|limit| (limit.limit, limit.window))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::grpc::proto::envoy::service::ratelimit::v3::rate_limit_response::Code;
+    use crate::ratelimit::TimeWindow;
     use crate::grpc::proto::envoy::extensions::common::ratelimit::v3::rate_limit_descriptor::Entry;
 
     fn create_test_descriptor(key: &str, value: &str) -> RateLimitDescriptor {
@@ -306,6 +534,221 @@ mod tests {
         assert_eq!(status.code(), Code::OverLimit);
     }
 
+    #[tokio::test]
+    async fn test_override_policy_applied() {
+        use crate::grpc::proto::envoy::extensions::common::ratelimit::v3::rate_limit_descriptor::RateLimitOverride;
+
+        let yaml = r#"
+domains:
+  capped:
+    domain: capped
+    overrides:
+      max:
+        requests_per_unit: 3
+        unit: second
+    descriptors:
+      - key: api_key
+        rate_limit:
+          requests_per_unit: 100
+          unit: second
+  denied:
+    domain: denied
+    overrides:
+      allowed: false
+    descriptors:
+      - key: api_key
+        rate_limit:
+          requests_per_unit: 2
+          unit: second
+"#;
+        let config = RateLimitConfig::from_yaml(yaml).unwrap();
+        let limiter = RateLimiter::with_config(config);
+
+        let descriptor = RateLimitDescriptor {
+            entries: vec![Entry {
+                key: "api_key".to_string(),
+                value: "key".to_string(),
+            }],
+            limit: Some(RateLimitOverride {
+                requests_per_unit: 1_000_000,
+                unit: TimeWindow::Second.to_proto(),
+            }),
+        };
+
+        // The override is capped to 3/s
+        for _ in 0..3 {
+            let status = limiter.check_rate_limit("capped", &descriptor, 1).await;
+            assert_eq!(status.code(), Code::Ok);
+        }
+        let status = limiter.check_rate_limit("capped", &descriptor, 1).await;
+        assert_eq!(status.code(), Code::OverLimit);
+        assert_eq!(status.current_limit.unwrap().requests_per_unit, 3);
+
+        // The override is ignored and the 2/s rule applies
+        for _ in 0..2 {
+            let status = limiter.check_rate_limit("denied", &descriptor, 1).await;
+            assert_eq!(status.code(), Code::Ok);
+        }
+        let status = limiter.check_rate_limit("denied", &descriptor, 1).await;
+        assert_eq!(status.code(), Code::OverLimit);
+    }
+
+    #[tokio::test]
+    async fn test_override_counters_separate_from_rule_counters() {
+        use crate::grpc::proto::envoy::extensions::common::ratelimit::v3::rate_limit_descriptor::RateLimitOverride;
+
+        let limiter = RateLimiter::new();
+        let rule_descriptor = create_test_descriptor("test", "value");
+        let mut override_descriptor = rule_descriptor.clone();
+        override_descriptor.limit = Some(RateLimitOverride {
+            requests_per_unit: 5,
+            unit: TimeWindow::Minute.to_proto(),
+        });
+
+        limiter.check_rate_limit("domain", &rule_descriptor, 3).await;
+        limiter.check_rate_limit("domain", &override_descriptor, 2).await;
+
+        assert_eq!(limiter.counter_count(), 2);
+        assert_eq!(limiter.get_counter_value("domain", &rule_descriptor), Some(3));
+        assert_eq!(limiter.get_counter_value("domain", &override_descriptor), Some(2));
+
+        // A different override gets its own counter too
+        override_descriptor.limit.as_mut().unwrap().requests_per_unit = 10;
+        limiter.check_rate_limit("domain", &override_descriptor, 1).await;
+        assert_eq!(limiter.counter_count(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_unmatched_policies() {
+        let yaml = r#"
+unknown_domains:
+  action: deny
+domains:
+  allow_domain:
+    domain: allow_domain
+    unmatched:
+      action: allow
+    descriptors:
+      - key: api_key
+        rate_limit:
+          requests_per_unit: 1
+          unit: second
+  limit_domain:
+    domain: limit_domain
+    unmatched:
+      action: limit
+      requests_per_unit: 2
+      unit: minute
+"#;
+        let config = RateLimitConfig::from_yaml(yaml).unwrap();
+        let limiter = RateLimiter::with_config(config);
+        let unmatched = create_test_descriptor("other", "value");
+
+        // Unmatched descriptors are allowed without creating a counter
+        for _ in 0..10 {
+            let status = limiter.check_rate_limit("allow_domain", &unmatched, 1).await;
+            assert_eq!(status.code(), Code::Ok);
+            assert!(status.current_limit.is_none());
+        }
+        assert_eq!(limiter.counter_count(), 0);
+
+        // Matched descriptors in the same domain are still limited
+        let matched = create_test_descriptor("api_key", "key");
+        assert_eq!(limiter.check_rate_limit("allow_domain", &matched, 1).await.code(), Code::Ok);
+        assert_eq!(limiter.check_rate_limit("allow_domain", &matched, 1).await.code(), Code::OverLimit);
+
+        // Unmatched descriptors get the domain's default limit
+        for _ in 0..2 {
+            let status = limiter.check_rate_limit("limit_domain", &unmatched, 1).await;
+            assert_eq!(status.code(), Code::Ok);
+        }
+        let status = limiter.check_rate_limit("limit_domain", &unmatched, 1).await;
+        assert_eq!(status.code(), Code::OverLimit);
+        assert_eq!(status.current_limit.unwrap().unit, TimeWindow::Minute.to_proto());
+
+        // Unknown domains are denied
+        let status = limiter.check_rate_limit("unknown", &unmatched, 1).await;
+        assert_eq!(status.code(), Code::OverLimit);
+        assert_eq!(limiter.get_counter_value("unknown", &unmatched), None);
+    }
+
+    #[tokio::test]
+    async fn test_atomic_denied_descriptor_rejects_request() {
+        let yaml = r#"
+domain: test_domain
+unmatched:
+  action: deny
+descriptors:
+  - key: user
+    rate_limit:
+      requests_per_unit: 10
+      unit: second
+"#;
+        let config = RateLimitConfig::from_yaml(yaml).unwrap();
+        let limiter = RateLimiter::with_config(config);
+        let descriptors = vec![
+            create_test_descriptor("user", "alice"),
+            create_test_descriptor("other", "value"),
+        ];
+
+        let statuses = limiter.check_rate_limits_atomic("test_domain", &descriptors, 1).await;
+        assert_eq!(statuses[0].code(), Code::Ok);
+        assert_eq!(statuses[1].code(), Code::OverLimit);
+        assert_eq!(limiter.get_counter_value("test_domain", &descriptors[0]), Some(0));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_checks_respect_limit() {
+        let yaml = r#"
+domain: test_domain
+descriptors:
+  - key: user
+    rate_limit:
+      requests_per_unit: 100
+      unit: minute
+  - key: org
+    rate_limit:
+      requests_per_unit: 1000
+      unit: minute
+"#;
+        let config = RateLimitConfig::from_yaml(yaml).unwrap();
+        let limiter = Arc::new(RateLimiter::with_config(config));
+        let descriptors = vec![
+            create_test_descriptor("user", "alice"),
+            create_test_descriptor("org", "acme"),
+        ];
+
+        let tasks: Vec<_> = (0..8)
+            .map(|task| {
+                let limiter = limiter.clone();
+                let descriptors = descriptors.clone();
+                tokio::spawn(async move {
+                    let mut allowed = 0;
+                    for _ in 0..50 {
+                        let statuses = if task % 2 == 0 {
+                            limiter.check_rate_limits_atomic("test_domain", &descriptors, 1).await
+                        } else {
+                            limiter.check_rate_limits("test_domain", &descriptors[..1], 1).await
+                        };
+                        if statuses[0].code() == Code::Ok {
+                            allowed += 1;
+                        }
+                    }
+                    allowed
+                })
+            })
+            .collect();
+
+        let mut allowed = 0;
+        for task in tasks {
+            allowed += task.await.unwrap();
+        }
+
+        // Exactly the limit is allowed, and rejected atomic checks left no reservations behind
+        assert_eq!(allowed, 100);
+        assert!(limiter.get_counter_value("test_domain", &descriptors[1]).unwrap() <= 100);
+    }
+
     #[tokio::test]
     async fn test_different_domains_have_separate_counters() {
         let limiter = RateLimiter::new();
@@ -355,6 +798,172 @@ descriptors:
         assert_eq!(status.code(), Code::OverLimit);
     }
 
+    #[tokio::test]
+    async fn test_status_reports_rule_name() {
+        let yaml = r#"
+domain: test_domain
+descriptors:
+  - key: api_key
+    rate_limit:
+      requests_per_unit: 5
+      unit: minute
+      name: per_key
+"#;
+        let config = RateLimitConfig::from_yaml(yaml).unwrap();
+        let limiter = RateLimiter::with_config(config);
+        let descriptor = create_test_descriptor("api_key", "my_key");
+
+        let status = limiter.check_rate_limit("test_domain", &descriptor, 1).await;
+        let current_limit = status.current_limit.unwrap();
+        assert_eq!(current_limit.name, "per_key");
+        assert_eq!(current_limit.requests_per_unit, 5);
+        assert_eq!(current_limit.unit, TimeWindow::Minute.to_proto());
+    }
+
+    #[tokio::test]
+    async fn test_multiple_limits_per_descriptor() {
+        let yaml = r#"
+domain: test_domain
+descriptors:
+  - key: api_key
+    rate_limits:
+      - requests_per_unit: 3
+        unit: second
+        name: per_second
+      - requests_per_unit: 5
+        unit: day
+        name: per_day
+"#;
+        let config = RateLimitConfig::from_yaml(yaml).unwrap();
+        let limiter = RateLimiter::with_config(config);
+        let descriptor = create_test_descriptor("api_key", "my_key");
+
+        // Each limit has its own counter
+        let status = limiter.check_rate_limit("test_domain", &descriptor, 1).await;
+        assert_eq!(limiter.counter_count(), 2);
+        assert_eq!(status.code(), Code::Ok);
+
+        // The per-second limit has the least quota left
+        assert_eq!(status.current_limit.unwrap().name, "per_second");
+        assert_eq!(status.limit_remaining, 2);
+
+        let status = limiter.check_rate_limit("test_domain", &descriptor, 3).await;
+        assert_eq!(status.code(), Code::OverLimit);
+        assert_eq!(status.current_limit.unwrap().name, "per_second");
+    }
+
+    #[tokio::test]
+    async fn test_counters_survive_restart() {
+        let yaml = r#"
+domain: test_domain
+descriptors:
+  - key: api_key
+    rate_limit:
+      requests_per_unit: 5
+      unit: day
+"#;
+        let config = RateLimitConfig::from_yaml(yaml).unwrap();
+        let limiter = RateLimiter::with_config(config.clone());
+        let descriptor = create_test_descriptor("api_key", "my_key");
+        limiter.check_rate_limit("test_domain", &descriptor, 4).await;
+
+        let mut snapshot = limiter.snapshot().await;
+        assert_eq!(snapshot.counters.len(), 1);
+
+        // A window that ended while the service was down is discarded
+        snapshot.counters.push(CounterSnapshot {
+            key: "test_domain:api_key=expired".to_string(),
+            limit: 5,
+            window_secs: 86400,
+            window_start_ms: unix_millis(std::time::SystemTime::now()) - 2 * 86_400_000,
+            count: 5,
+        });
+
+        let restarted = RateLimiter::with_config(config);
+        assert_eq!(restarted.restore(&snapshot).await, 1);
+        assert_eq!(restarted.get_counter_value("test_domain", &descriptor), Some(4));
+
+        let status = restarted.check_rate_limit("test_domain", &descriptor, 1).await;
+        assert_eq!(status.code(), Code::Ok);
+        let status = restarted.check_rate_limit("test_domain", &descriptor, 1).await;
+        assert_eq!(status.code(), Code::OverLimit);
+
+        // Restored counters take the limit of the loaded rules
+        let raised = RateLimitConfig::from_yaml(&yaml.replace("requests_per_unit: 5", "requests_per_unit: 7")).unwrap();
+        let restarted = RateLimiter::with_config(raised);
+        assert_eq!(restarted.restore(&snapshot).await, 1);
+        let status = restarted.check_rate_limit("test_domain", &descriptor, 3).await;
+        assert_eq!(status.code(), Code::Ok);
+        assert_eq!(status.current_limit.unwrap().requests_per_unit, 7);
+        assert_eq!(status.limit_remaining, 0);
+
+        // Counters no rule counts any more are discarded
+        let removed = RateLimitConfig::from_yaml(r#"
+domain: test_domain
+unmatched:
+  action: allow
+descriptors:
+  - key: user
+    rate_limit:
+      requests_per_unit: 5
+      unit: day
+"#).unwrap();
+        let restarted = RateLimiter::with_config(removed);
+        assert_eq!(restarted.restore(&snapshot).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_restore_aggregated_counter() {
+        let yaml = r#"
+domain: test_domain
+descriptors:
+  - key: remote_address
+    aggregate:
+      ipv4_prefix: 24
+    rate_limit:
+      requests_per_unit: 5
+      unit: day
+"#;
+        let config = RateLimitConfig::from_yaml(yaml).unwrap();
+        let limiter = RateLimiter::with_config(config.clone());
+        let descriptor = create_test_descriptor("remote_address", "192.0.2.77");
+        limiter.check_rate_limit("test_domain", &descriptor, 2).await;
+
+        let restarted = RateLimiter::with_config(config);
+        assert_eq!(restarted.restore(&limiter.snapshot().await).await, 1);
+        let neighbour = create_test_descriptor("remote_address", "192.0.2.1");
+        assert_eq!(restarted.get_counter_value("test_domain", &neighbour), Some(2));
+    }
+
+    #[tokio::test]
+    async fn test_limits_above_u32() {
+        let yaml = r#"
+domain: test_domain
+descriptors:
+  - key: bytes
+    rate_limit:
+      requests_per_unit: 5000000000
+      unit: day
+"#;
+        let config = RateLimitConfig::from_yaml(yaml).unwrap();
+        let limiter = RateLimiter::with_config(config);
+        let descriptor = create_test_descriptor("bytes", "tenant");
+
+        let status = limiter.check_rate_limit("test_domain", &descriptor, u32::MAX).await;
+        assert_eq!(status.code(), Code::Ok);
+        assert_eq!(status.current_limit.unwrap().requests_per_unit, u32::MAX);
+        assert_eq!(status.limit_remaining, 705_032_705);
+
+        // The count passes u32::MAX instead of wrapping or saturating there
+        let status = limiter.check_rate_limit("test_domain", &descriptor, u32::MAX).await;
+        assert_eq!(status.code(), Code::OverLimit);
+        assert_eq!(status.limit_remaining, 0);
+        assert_eq!(
+            limiter.get_counter_value("test_domain", &descriptor),
+            Some(2 * u32::MAX as u64)
+        );
+    }
+
     #[tokio::test]
     async fn test_rate_limiter_hierarchical_config() {
         let yaml = r#"
@@ -460,4 +1069,98 @@ descriptors:
             assert_eq!(status.code(), Code::Ok);
         }
     }
+
+    #[tokio::test]
+    async fn test_batch_check_counts_independently() {
+        let yaml = r#"
+domain: test_domain
+descriptors:
+  - key: user
+    rate_limit:
+      requests_per_unit: 1
+      unit: second
+  - key: org
+    rate_limit:
+      requests_per_unit: 100
+      unit: second
+"#;
+        let config = RateLimitConfig::from_yaml(yaml).unwrap();
+        let limiter = RateLimiter::with_config(config);
+        let descriptors = vec![
+            create_test_descriptor("user", "alice"),
+            create_test_descriptor("org", "acme"),
+        ];
+
+        let statuses = limiter.check_rate_limits("test_domain", &descriptors, 1).await;
+        assert!(statuses.iter().all(|s| s.code() == Code::Ok));
+
+        let statuses = limiter.check_rate_limits("test_domain", &descriptors, 1).await;
+        assert_eq!(statuses[0].code(), Code::OverLimit);
+        assert_eq!(statuses[1].code(), Code::Ok);
+        assert_eq!(statuses[1].limit_remaining, 98);
+        assert_eq!(limiter.get_counter_value("test_domain", &descriptors[1]), Some(2));
+    }
+
+    #[tokio::test]
+    async fn test_atomic_rejection_does_not_consume_quota() {
+        let yaml = r#"
+domain: test_domain
+descriptors:
+  - key: user
+    rate_limit:
+      requests_per_unit: 2
+      unit: second
+  - key: org
+    rate_limit:
+      requests_per_unit: 100
+      unit: second
+"#;
+        let config = RateLimitConfig::from_yaml(yaml).unwrap();
+        let limiter = RateLimiter::with_config(config);
+        let descriptors = vec![
+            create_test_descriptor("user", "alice"),
+            create_test_descriptor("org", "acme"),
+        ];
+
+        for _ in 0..2 {
+            let statuses = limiter.check_rate_limits_atomic("test_domain", &descriptors, 1).await;
+            assert!(statuses.iter().all(|s| s.code() == Code::Ok));
+        }
+
+        // The per-user limit is exhausted, so the request is rejected
+        let statuses = limiter.check_rate_limits_atomic("test_domain", &descriptors, 1).await;
+        assert_eq!(statuses[0].code(), Code::OverLimit);
+        assert_eq!(statuses[1].code(), Code::Ok);
+
+        // Neither counter was incremented by the rejected request
+        assert_eq!(limiter.get_counter_value("test_domain", &descriptors[0]), Some(2));
+        assert_eq!(limiter.get_counter_value("test_domain", &descriptors[1]), Some(2));
+        assert_eq!(statuses[1].limit_remaining, 98);
+    }
+
+    #[tokio::test]
+    async fn test_atomic_duplicate_descriptors_share_quota() {
+        let yaml = r#"
+domain: test_domain
+descriptors:
+  - key: user
+    rate_limit:
+      requests_per_unit: 3
+      unit: hour
+"#;
+        let config = RateLimitConfig::from_yaml(yaml).unwrap();
+        let limiter = RateLimiter::with_config(config);
+        let descriptor = create_test_descriptor("user", "alice");
+        let descriptors = vec![descriptor.clone(), descriptor.clone()];
+
+        let statuses = limiter.check_rate_limits_atomic("test_domain", &descriptors, 1).await;
+        assert!(statuses.iter().all(|s| s.code() == Code::Ok));
+        assert_eq!(limiter.get_counter_value("test_domain", &descriptor), Some(2));
+
+        // A second request needs 2 more hits but only 1 remains
+        let statuses = limiter.check_rate_limits_atomic("test_domain", &descriptors, 1).await;
+        assert_eq!(statuses.len(), 2);
+        assert!(statuses.iter().all(|s| s.code() == Code::OverLimit));
+        assert_eq!(limiter.get_counter_value("test_domain", &descriptor), Some(2));
+    }
 }
@@ -6,10 +6,26 @@ mod descriptor;
 mod rules;
 mod distributed;
 mod backend;
+mod resolver;
+mod compiled;
+mod snapshot;
+mod scoped;
+mod strong;
 
-pub use limiter::{RateLimiter, LimitConfig};
+pub use limiter::RateLimiter;
 pub use counter::{RateLimitCounter, TimeWindow};
-pub use descriptor::DescriptorKey;
-pub use rules::{RateLimitConfig, DomainConfig, DescriptorConfig, RateLimitRule, TimeUnit};
+pub use descriptor::{DescriptorKey, DescriptorKeyParts, DescriptorKeyRef, EntryValue};
+pub(crate) use descriptor::{split_escaped, Escaped};
+pub use rules::{
+    RateLimitConfig, DomainConfig, DescriptorConfig, RateLimitRule, TimeUnit, OverridePolicy, MaxLimit,
+    MatchingMode, UnmatchedPolicy, ValueMatcher, IpAggregation, Consistency, Scope,
+};
 pub use distributed::DistributedRateLimiter;
-pub use backend::RateLimiterBackend;
+pub use scoped::ScopedRateLimiter;
+pub use backend::{EvaluationMode, RateLimiterBackend};
+pub use resolver::{LimitSource, Resolution, ResolvedLimit, ResolvedLimits, Resolutions};
+pub use compiled::{CompiledConfig, CompiledNode, LimitMatch};
+pub use snapshot::{
+    restore_counters, save_counters, spawn_snapshots, ClusterCounterSnapshot, CounterSnapshot,
+    OwnedCounterSnapshot, Snapshot, SnapshotSource,
+};
@@ -0,0 +1,434 @@
+//! Limit resolution shared by the local and distributed rate limiters.
+//!
+//! Both limiters resolve each request descriptor to a [`Resolution`] before
+//! touching any counters, so rule features behave the same in both modes
+//! and responses report limits consistently. Resolutions borrow from the
+//! request and the compiled configuration, so resolving doesn't copy
+//! descriptor entries or rule names, and are held inline for the handful of
+//! descriptors and limits a request carries, so resolving doesn't allocate.
+
+use std::time::Duration;
+
+use smallvec::{smallvec, SmallVec};
+
+use crate::grpc::proto::envoy::extensions::common::ratelimit::v3::RateLimitDescriptor;
+use crate::grpc::proto::envoy::service::ratelimit::v3::rate_limit_response::{
+    Code, DescriptorStatus, RateLimit,
+};
+
+use super::counter::TimeWindow;
+use super::descriptor::DescriptorKeyRef;
+use super::compiled::CompiledConfig;
+use super::rules::{Consistency, Scope, UnmatchedPolicy};
+
+/// Where a resolved limit came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitSource {
+    /// A request-time override sent by Envoy in the descriptor
+    Override,
+    /// A rule from the rate limit configuration
+    Rule,
+    /// The domain's default limit for unmatched descriptors
+    Unmatched,
+}
+
+/// A limit resolved for a descriptor.
+#[derive(Debug, Clone)]
+pub struct ResolvedLimit<'a> {
+    /// The key of the counter this descriptor is counted under
+    pub key: DescriptorKeyRef<'a>,
+    /// Maximum requests allowed in the time window
+    pub limit: u64,
+    /// Time window for the limit
+    pub window: TimeWindow,
+    /// Name/description of this limit
+    pub name: Option<&'a str>,
+    /// Where the limit came from
+    pub source: LimitSource,
+    /// Whether increments are broadcast to peers in distributed mode
+    pub broadcast: bool,
+    /// How the limit is counted across nodes in distributed mode
+    pub consistency: Consistency,
+    /// Whether the limit applies to each instance or to the whole cluster
+    pub scope: Scope,
+}
+
+impl ResolvedLimit<'_> {
+    /// Convert to the limit reported in a descriptor status.
+    pub fn to_proto(&self) -> RateLimit {
+        RateLimit {
+            name: self.name.unwrap_or_default().to_string(),
+            requests_per_unit: saturating_u32(self.limit),
+            unit: self.window.to_proto(),
+        }
+    }
+}
+
+/// The resolved limits of a descriptor.
+pub type ResolvedLimits<'a> = SmallVec<[ResolvedLimit<'a>; 2]>;
+
+/// The resolutions of every descriptor of a request, in order.
+pub type Resolutions<'a> = SmallVec<[Resolution<'a>; 4]>;
+
+/// How a descriptor is handled once its limits have been resolved.
+///
+/// Limits are kept inline so resolving a request doesn't allocate; the
+/// resolutions themselves only live on the stack for the length of a check.
+#[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
+pub enum Resolution<'a> {
+    /// Count the descriptor against each of the resolved limits
+    Limited(ResolvedLimits<'a>),
+    /// Allow the descriptor without counting it
+    Allowed,
+    /// Reject the descriptor without counting it
+    Denied,
+}
+
+impl<'a> Resolution<'a> {
+    /// Resolve how a descriptor is limited.
+    ///
+    /// This looks up the limit in the following order:
+    /// 1. Override specified in the descriptor itself, subject to the domain's override policy
+    /// 2. Configured limit from the rate limit configuration
+    /// 3. The domain's policy for unmatched descriptors
+    ///
+    /// Descriptors limited by an enforced override are keyed by that override,
+    /// so they never share a counter with the configured rule.
+    pub fn resolve(
+        config: &'a CompiledConfig,
+        domain: &'a str,
+        descriptor: &'a RateLimitDescriptor,
+    ) -> Self {
+        let key = DescriptorKeyRef::new(domain, descriptor);
+
+        // Check if there's an override in the descriptor itself
+        if let Some((limit, window)) = config.config().effective_override(domain, descriptor) {
+            return Resolution::Limited(smallvec![ResolvedLimit {
+                key: key.with_override(limit, window),
+                limit,
+                window,
+                name: None,
+                source: LimitSource::Override,
+                broadcast: false,
+                consistency: Consistency::Eventual,
+                scope: Scope::Global,
+            }]);
+        }
+
+        // Look up configured limits based on domain and descriptor entries
+        if let Some(limit_match) = config.find_match(domain, descriptor) {
+            let mut key = key;
+            for (entry_index, aggregate) in &limit_match.aggregations {
+                if let Some(network) = aggregate.apply(&descriptor.entries[*entry_index].value) {
+                    key = key.with_aggregated_value(*entry_index, network);
+                }
+            }
+
+            // The first limit of a rule is counted under the descriptor's key
+            // and every other limit under its own, so adding limits to a rule
+            // keeps the first limit's counter
+            let limits = limit_match
+                .rules()
+                .iter()
+                .enumerate()
+                .map(|(index, rule)| {
+                    let window: TimeWindow = rule.unit.into();
+                    ResolvedLimit {
+                        key: if index == 0 {
+                            key.clone()
+                        } else {
+                            key.clone().with_rule_limit(rule.requests_per_unit, window)
+                        },
+                        limit: rule.requests_per_unit,
+                        window,
+                        name: rule.name.as_deref(),
+                        source: LimitSource::Rule,
+                        broadcast: rule.broadcast,
+                        consistency: rule.consistency,
+                        scope: rule.scope,
+                    }
+                })
+                .collect();
+            return Resolution::Limited(limits);
+        }
+
+        // Fall back to the domain's unmatched policy
+        match config.config().unmatched_policy(domain) {
+            UnmatchedPolicy::Allow => Resolution::Allowed,
+            UnmatchedPolicy::Limit { requests_per_unit, unit } => Resolution::Limited(smallvec![ResolvedLimit {
+                key,
+                limit: *requests_per_unit,
+                window: (*unit).into(),
+                name: None,
+                source: LimitSource::Unmatched,
+                broadcast: false,
+                consistency: Consistency::Eventual,
+                scope: Scope::Global,
+            }]),
+            UnmatchedPolicy::Deny => Resolution::Denied,
+        }
+    }
+
+    /// Resolve every descriptor of a request, in order.
+    pub fn resolve_all(
+        config: &'a CompiledConfig,
+        domain: &'a str,
+        descriptors: &'a [RateLimitDescriptor],
+    ) -> Resolutions<'a> {
+        descriptors
+            .iter()
+            .map(|descriptor| Self::resolve(config, domain, descriptor))
+            .collect()
+    }
+
+    /// Get the resolved limits, which are empty if the descriptor is not counted.
+    pub fn limits(&self) -> &[ResolvedLimit<'a>] {
+        match self {
+            Resolution::Limited(limits) => limits,
+            _ => &[],
+        }
+    }
+
+    /// Build the status for a descriptor that is not counted.
+    ///
+    /// Returns `None` for limited descriptors, whose status depends on their counter.
+    pub fn uncounted_status(&self) -> Option<DescriptorStatus> {
+        let code = match self {
+            Resolution::Limited(_) => return None,
+            Resolution::Allowed => Code::Ok,
+            Resolution::Denied => Code::OverLimit,
+        };
+
+        Some(DescriptorStatus {
+            code: code.into(),
+            ..Default::default()
+        })
+    }
+}
+
+/// Pick the most restrictive of the statuses of a descriptor's limits.
+///
+/// An over-limit status wins over one within its limit; otherwise the
+/// status with the least remaining quota is reported.
+pub fn most_restrictive(statuses: impl IntoIterator<Item = DescriptorStatus>) -> DescriptorStatus {
+    statuses
+        .into_iter()
+        .min_by_key(|status| (status.code() != Code::OverLimit, status.limit_remaining))
+        .unwrap_or_default()
+}
+
+/// Convert a limit or count to the `u32` reported in responses.
+///
+/// Limits and counts are tracked as `u64`, so values that don't fit are
+/// reported as `u32::MAX` rather than wrapping to a small number.
+pub fn saturating_u32(value: u64) -> u32 {
+    u32::try_from(value).unwrap_or(u32::MAX)
+}
+
+/// Build the response status for a counted descriptor.
+pub fn limited_status(
+    within_limit: bool,
+    current_limit: RateLimit,
+    remaining: u64,
+    duration_until_reset: Duration,
+) -> DescriptorStatus {
+    let code = if within_limit { Code::Ok } else { Code::OverLimit };
+
+    DescriptorStatus {
+        code: code.into(),
+        current_limit: Some(current_limit),
+        limit_remaining: saturating_u32(remaining),
+        duration_until_reset: Some(prost_types::Duration {
+            seconds: duration_until_reset.as_secs() as i64,
+            nanos: duration_until_reset.subsec_nanos() as i32,
+        }),
+        quota_bucket: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ratelimit::RateLimitConfig;
+    use crate::grpc::proto::envoy::extensions::common::ratelimit::v3::rate_limit_descriptor::{
+        Entry, RateLimitOverride,
+    };
+
+    fn create_descriptor(key: &str, value: &str) -> RateLimitDescriptor {
+        RateLimitDescriptor {
+            entries: vec![Entry {
+                key: key.to_string(),
+                value: value.to_string(),
+            }],
+            limit: None,
+        }
+    }
+
+    fn test_config() -> RateLimitConfig {
+        let yaml = r#"
+domain: test_domain
+descriptors:
+  - key: api_key
+    rate_limit:
+      requests_per_unit: 10
+      unit: minute
+      name: per_key
+"#;
+        RateLimitConfig::from_yaml(yaml).unwrap()
+    }
+
+    #[test]
+    fn test_resolve_rule() {
+        let config = CompiledConfig::new(test_config());
+        let descriptor = create_descriptor("api_key", "a");
+        let resolution = Resolution::resolve(&config, "test_domain", &descriptor);
+        let limit = &resolution.limits()[0];
+
+        assert_eq!(limit.source, LimitSource::Rule);
+        assert_eq!(limit.limit, 10);
+        assert_eq!(limit.window, TimeWindow::Minute);
+        assert_eq!(limit.to_proto().name, "per_key");
+        assert!(resolution.uncounted_status().is_none());
+    }
+
+    #[test]
+    fn test_resolve_override() {
+        let config = CompiledConfig::new(test_config());
+        let mut descriptor = create_descriptor("api_key", "a");
+        descriptor.limit = Some(RateLimitOverride {
+            requests_per_unit: 3,
+            unit: TimeWindow::Second.to_proto(),
+        });
+
+        let resolution = Resolution::resolve(&config, "test_domain", &descriptor);
+        let limit = &resolution.limits()[0];
+
+        assert_eq!(limit.source, LimitSource::Override);
+        assert_eq!(limit.limit, 3);
+        assert_eq!(limit.key.to_string(), "test_domain:api_key=a@3/1s");
+    }
+
+    #[test]
+    fn test_resolve_aggregates_ip_values() {
+        let yaml = r#"
+domain: test_domain
+descriptors:
+  - key: remote_address
+    aggregate:
+      ipv4_prefix: 24
+      ipv6_prefix: 64
+    rate_limit:
+      requests_per_unit: 10
+      unit: second
+"#;
+        let config = CompiledConfig::new(RateLimitConfig::from_yaml(yaml).unwrap());
+        let key_for = |address: &str| {
+            let descriptor = create_descriptor("remote_address", address);
+            let resolution = Resolution::resolve(&config, "test_domain", &descriptor);
+            resolution.limits()[0].key.to_key()
+        };
+
+        assert_eq!(key_for("10.1.2.3"), key_for("10.1.2.200"));
+        assert_ne!(key_for("10.1.2.3"), key_for("10.1.3.3"));
+        assert_eq!(key_for("10.1.2.3").to_string(), "test_domain:remote_address=10.1.2.0/24");
+        assert_eq!(key_for("2001:db8:0:1::1"), key_for("2001:db8:0:1:ffff::2"));
+        assert_eq!(
+            key_for("2001:db8:0:1::1").to_string(),
+            "test_domain:remote_address=2001:db8:0:1::/64"
+        );
+
+        // Values that are not addresses are counted as-is
+        assert_eq!(key_for("unknown").to_string(), "test_domain:remote_address=unknown");
+    }
+
+    #[test]
+    fn test_resolve_multiple_rules() {
+        let yaml = r#"
+domain: test_domain
+descriptors:
+  - key: api_key
+    rate_limits:
+      - requests_per_unit: 10
+        unit: second
+      - requests_per_unit: 10000
+        unit: day
+"#;
+        let config = CompiledConfig::new(RateLimitConfig::from_yaml(yaml).unwrap());
+        let descriptor = create_descriptor("api_key", "a");
+        let resolution = Resolution::resolve(&config, "test_domain", &descriptor);
+        let limits = resolution.limits();
+
+        assert_eq!(limits.len(), 2);
+        assert_eq!(limits[0].key.to_string(), "test_domain:api_key=a");
+        assert_eq!(limits[1].key.to_string(), "test_domain:api_key=a#10000/86400s");
+
+        // Adding a limit keeps the counter of the existing one
+        let config = CompiledConfig::new(test_config());
+        let resolution = Resolution::resolve(&config, "test_domain", &descriptor);
+        assert_eq!(resolution.limits()[0].key.to_string(), "test_domain:api_key=a");
+    }
+
+    #[test]
+    fn test_limits_above_u32_saturate() {
+        let yaml = r#"
+domain: test_domain
+descriptors:
+  - key: bytes
+    rate_limit:
+      requests_per_unit: 5000000000
+      unit: day
+"#;
+        let config = CompiledConfig::new(RateLimitConfig::from_yaml(yaml).unwrap());
+        let descriptor = create_descriptor("bytes", "tenant");
+        let resolution = Resolution::resolve(&config, "test_domain", &descriptor);
+        let limit = &resolution.limits()[0];
+
+        assert_eq!(limit.limit, 5_000_000_000);
+        assert_eq!(limit.to_proto().requests_per_unit, u32::MAX);
+
+        let status = limited_status(true, limit.to_proto(), 4_294_967_296, Duration::ZERO);
+        assert_eq!(status.limit_remaining, u32::MAX);
+        let status = limited_status(true, limit.to_proto(), 4_294_967_295, Duration::ZERO);
+        assert_eq!(status.limit_remaining, u32::MAX);
+        let status = limited_status(true, limit.to_proto(), 4_294_967_294, Duration::ZERO);
+        assert_eq!(status.limit_remaining, 4_294_967_294);
+    }
+
+    #[test]
+    fn test_most_restrictive() {
+        let status = |code: Code, remaining: u32| DescriptorStatus {
+            code: code.into(),
+            limit_remaining: remaining,
+            ..Default::default()
+        };
+
+        let chosen = most_restrictive([status(Code::Ok, 5), status(Code::Ok, 2), status(Code::Ok, 9)]);
+        assert_eq!(chosen.limit_remaining, 2);
+
+        let chosen = most_restrictive([status(Code::Ok, 0), status(Code::OverLimit, 3)]);
+        assert_eq!(chosen.code(), Code::OverLimit);
+    }
+
+    #[test]
+    fn test_resolve_unmatched() {
+        let config = CompiledConfig::new(test_config());
+        let descriptor = create_descriptor("other", "a");
+        let resolution = Resolution::resolve(&config, "test_domain", &descriptor);
+        assert_eq!(resolution.limits()[0].source, LimitSource::Unmatched);
+
+        let mut config = test_config();
+        config.unmatched = UnmatchedPolicy::Allow;
+        let compiled = CompiledConfig::new(config.clone());
+        let descriptor = create_descriptor("other", "a");
+        let resolution = Resolution::resolve(&compiled, "other_domain", &descriptor);
+        assert!(resolution.limits().is_empty());
+        assert_eq!(resolution.uncounted_status().unwrap().code(), Code::Ok);
+
+        config.unmatched = UnmatchedPolicy::Deny;
+        let compiled = CompiledConfig::new(config);
+        let descriptor = create_descriptor("other", "a");
+        let resolution = Resolution::resolve(&compiled, "other_domain", &descriptor);
+        assert_eq!(resolution.uncounted_status().unwrap().code(), Code::OverLimit);
+    }
+}
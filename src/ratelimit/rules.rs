@@ -1,10 +1,14 @@
 //! Rate limit rules configuration and matching.
 //!
-//! This module handles loading and matching rate limit rules from configuration.
-//! It supports Envoy's rate limit configuration format with hierarchical descriptor matching.
+//! This module handles loading rate limit rules from configuration.
+//! It supports Envoy's rate limit configuration format with hierarchical descriptor matching;
+//! the rules are compiled for lookup by [`CompiledConfig`](super::CompiledConfig).
 
+use ipnet::IpNet;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
 use std::path::Path;
 use tracing::info;
 
@@ -12,12 +16,41 @@ use super::counter::TimeWindow;
 use crate::error::{HivemindError, Result};
 use crate::grpc::proto::envoy::extensions::common::ratelimit::v3::RateLimitDescriptor;
 
+/// Default rate limit for unmatched descriptors when no policy is configured.
+const DEFAULT_LIMIT: u64 = 1000;
+
 /// A complete rate limit configuration containing multiple domains.
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct RateLimitConfig {
     /// Map of domain name to domain configuration
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
     pub domains: HashMap<String, DomainConfig>,
+    /// How unmatched descriptors are handled in domains without their own policy
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub unmatched: UnmatchedPolicy,
+    /// How descriptors in domains missing from the configuration are handled
+    /// (if not set, the `unmatched` policy applies)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unknown_domains: Option<UnmatchedPolicy>,
+    /// Policy for limit overrides in domains without their own policy
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub overrides: OverridePolicy,
+    /// Policy for limit overrides sent in domains missing from the
+    /// configuration (if not set, the `overrides` policy applies)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unknown_domain_overrides: Option<OverridePolicy>,
+}
+
+/// A configuration file holding a single domain, which may also set how
+/// domains missing from the configuration are handled.
+#[derive(Deserialize)]
+struct SingleDomainFile {
+    #[serde(flatten)]
+    domain: DomainConfig,
+    #[serde(default)]
+    unknown_domains: Option<UnmatchedPolicy>,
+    #[serde(default)]
+    unknown_domain_overrides: Option<OverridePolicy>,
 }
 
 /// Configuration for a single rate limit domain.
@@ -26,8 +59,127 @@ pub struct DomainConfig {
     /// The domain name
     pub domain: String,
     /// Top-level descriptors for this domain
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub descriptors: Vec<DescriptorConfig>,
+    /// Policy for limit overrides sent by Envoy in request descriptors (if
+    /// not set, the configuration-wide `overrides` policy applies)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub overrides: Option<OverridePolicy>,
+    /// How descriptors matching no rule are handled (if not set, the
+    /// configuration-wide `unmatched` policy applies)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unmatched: Option<UnmatchedPolicy>,
+    /// How request descriptors are matched against the descriptor tree
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub matching: MatchingMode,
+}
+
+/// How request descriptors are matched against a domain's descriptor tree.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchingMode {
+    /// Use the most specific rule along any matching path. Descriptors with
+    /// extra trailing entries fall back to the deepest matching rule.
+    #[default]
+    Lenient,
+    /// Match like Envoy's reference service: every entry must match a node,
+    /// the highest-precedence matching node is chosen without backtracking,
+    /// and only the rule on the node matching the last entry applies.
+    Strict,
+}
+
+/// How descriptors that match no configured rule are handled.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum UnmatchedPolicy {
+    /// Allow without counting (Envoy's reference behavior)
+    Allow,
+    /// Count against a default limit
+    Limit {
+        /// Number of requests allowed per unit of time
+        requests_per_unit: u64,
+        /// The time unit
+        unit: TimeUnit,
+    },
+    /// Reject the request
+    Deny,
+}
+
+impl Default for UnmatchedPolicy {
+    fn default() -> Self {
+        UnmatchedPolicy::Limit {
+            requests_per_unit: DEFAULT_LIMIT,
+            unit: TimeUnit::Second,
+        }
+    }
+}
+
+/// Policy controlling request-time limit overrides for a domain.
+///
+/// Envoy can attach a `limit` to a descriptor from route configuration,
+/// which replaces the configured rule for that request. This policy decides
+/// whether such overrides are honored and how far they may go.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OverridePolicy {
+    /// Whether overrides are honored (if not, the configured rules apply)
+    #[serde(
+        default = "default_overrides_allowed",
+        skip_serializing_if = "is_default_overrides_allowed"
+    )]
+    pub allowed: bool,
+    /// Maximum rate an override may grant; larger overrides are capped to it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<MaxLimit>,
+}
+
+impl Default for OverridePolicy {
+    fn default() -> Self {
+        Self {
+            allowed: default_overrides_allowed(),
+            max: None,
+        }
+    }
+}
+
+fn default_overrides_allowed() -> bool {
+    true
+}
+
+fn is_default_overrides_allowed(allowed: &bool) -> bool {
+    *allowed == default_overrides_allowed()
+}
+
+/// An upper bound on the rate a limit override may grant.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MaxLimit {
+    /// Number of requests allowed per unit of time
+    pub requests_per_unit: u64,
+    /// The time unit
+    pub unit: TimeUnit,
+}
+
+impl OverridePolicy {
+    /// Apply this policy to an override, returning the limit to enforce.
+    ///
+    /// Returns `None` if overrides are not allowed. Overrides granting a
+    /// higher rate than `max` are replaced by `max`.
+    pub fn apply(&self, requests_per_unit: u64, window: TimeWindow) -> Option<(u64, TimeWindow)> {
+        if !self.allowed {
+            return None;
+        }
+
+        if let Some(ref max) = self.max {
+            let max_window: TimeWindow = max.unit.into();
+            // Compare rates without dividing: a/b > c/d  <=>  a*d > c*b
+            let requested = requests_per_unit as u128 * max_window.duration().as_secs() as u128;
+            let allowed = max.requests_per_unit as u128 * window.duration().as_secs() as u128;
+            if requested > allowed {
+                return Some((max.requests_per_unit, max_window));
+            }
+        }
+
+        Some((requests_per_unit, window))
+    }
 }
 
 /// Configuration for a rate limit descriptor.
@@ -35,23 +187,220 @@ pub struct DomainConfig {
 /// Descriptors form a tree structure where each node can have:
 /// - A key to match against
 /// - An optional value to match (if not present, matches any value)
-/// - An optional rate limit to apply at this level
+/// - Optional rate limits to apply at this level
 /// - Child descriptors for more specific matching
+///
+/// A node may carry several limits (e.g. per second and per day) through
+/// `rate_limits`, each counted separately. `rate_limit` is shorthand for a
+/// single limit and is combined with any in `rate_limits`.
+///
+/// A value ending in `*` matches any value with that prefix, as in Envoy.
+/// Alternatively, `value_regex` matches values against a regular expression,
+/// which must match the whole value, and `value_cidr` matches IP address
+/// values contained in any of the listed ranges. When several nodes match an
+/// entry, they are tried in order of precedence: exact, CIDR, prefix, regex,
+/// then any value, with narrower networks and longer prefixes first.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DescriptorConfig {
     /// The key to match
     pub key: String,
     /// Optional value to match (if not set, matches any value for this key)
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub value: Option<String>,
+    /// Optional regular expression the whole value must match
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value_regex: Option<String>,
+    /// Optional CIDR ranges, one of which must contain the (IP address) value
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value_cidr: Option<Vec<String>>,
+    /// Count IP address values by their enclosing network instead of per address
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aggregate: Option<IpAggregation>,
     /// Rate limit to apply at this level
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub rate_limit: Option<RateLimitRule>,
+    /// Additional rate limits to apply at this level, each with its own counter
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rate_limits: Vec<RateLimitRule>,
     /// Child descriptors for more specific matching
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub descriptors: Vec<DescriptorConfig>,
 }
 
+/// Aggregation of IP address values into their enclosing networks.
+///
+/// With `ipv4_prefix: 24`, requests from `10.1.2.3` and `10.1.2.200` share
+/// the counter for `10.1.2.0/24`. Values that are not IP addresses, or whose
+/// address family has no prefix configured, are counted as-is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IpAggregation {
+    /// Prefix length IPv4 addresses are aggregated to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ipv4_prefix: Option<u8>,
+    /// Prefix length IPv6 addresses are aggregated to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ipv6_prefix: Option<u8>,
+}
+
+impl IpAggregation {
+    /// Check that the prefix lengths are valid for their address families.
+    pub fn validate(&self) -> Result<()> {
+        if self.ipv4_prefix.is_some_and(|len| len > 32) || self.ipv6_prefix.is_some_and(|len| len > 128) {
+            return Err(HivemindError::Config(format!(
+                "Invalid aggregate prefix lengths: {:?}",
+                self
+            )));
+        }
+        Ok(())
+    }
+
+    /// Get the network a descriptor entry is counted under.
+    ///
+    /// Returns `None` if the value is counted as-is.
+    pub fn apply(&self, value: &str) -> Option<IpNet> {
+        let addr: IpAddr = value.parse().ok()?;
+        let prefix_len = match addr {
+            IpAddr::V4(_) => self.ipv4_prefix?,
+            IpAddr::V6(_) => self.ipv6_prefix?,
+        };
+        Some(IpNet::new(addr, prefix_len).ok()?.trunc())
+    }
+}
+
+/// A compiled matcher for descriptor entry values.
+#[derive(Debug, Clone)]
+pub enum ValueMatcher {
+    /// Matches one value exactly
+    Exact(String),
+    /// Matches IP address values within any of the networks
+    Cidr(Vec<IpNet>),
+    /// Matches values starting with a prefix
+    Prefix(String),
+    /// Matches values the regular expression matches in full
+    Regex(Regex),
+    /// Matches any value
+    Any,
+}
+
+impl ValueMatcher {
+    /// Compile the matcher for a descriptor's `value`, `value_regex` or `value_cidr`.
+    pub fn compile(config: &DescriptorConfig) -> Result<Self> {
+        match (&config.value, &config.value_regex, &config.value_cidr) {
+            (Some(value), None, None) => Ok(match value.strip_suffix('*') {
+                Some(prefix) => ValueMatcher::Prefix(prefix.to_string()),
+                None => ValueMatcher::Exact(value.to_string()),
+            }),
+            (None, Some(pattern), None) => Regex::new(&format!("^(?:{})$", pattern))
+                .map(ValueMatcher::Regex)
+                .map_err(|e| {
+                    HivemindError::Config(format!("Invalid value_regex '{}': {}", pattern, e))
+                }),
+            (None, None, Some(ranges)) => ranges
+                .iter()
+                .map(|range| {
+                    range.parse::<IpNet>().map_err(|e| {
+                        HivemindError::Config(format!("Invalid value_cidr '{}': {}", range, e))
+                    })
+                })
+                .collect::<Result<Vec<_>>>()
+                .map(ValueMatcher::Cidr),
+            (None, None, None) => Ok(ValueMatcher::Any),
+            _ => Err(HivemindError::Config(format!(
+                "descriptor '{}' can only set one of value, value_regex and value_cidr",
+                config.key
+            ))),
+        }
+    }
+
+    /// Check whether a value matches.
+    pub fn matches(&self, value: &str) -> bool {
+        match self {
+            ValueMatcher::Exact(expected) => expected == value,
+            ValueMatcher::Cidr(networks) => value
+                .parse::<IpAddr>()
+                .is_ok_and(|addr| networks.iter().any(|network| network.contains(&addr))),
+            ValueMatcher::Prefix(prefix) => value.starts_with(prefix.as_str()),
+            ValueMatcher::Regex(regex) => regex.is_match(value),
+            ValueMatcher::Any => true,
+        }
+    }
+
+    /// Precedence of this matcher among nodes matching the same entry (lower wins).
+    pub fn precedence(&self) -> u8 {
+        match self {
+            ValueMatcher::Exact(_) => 0,
+            ValueMatcher::Cidr(_) => 1,
+            ValueMatcher::Prefix(_) => 2,
+            ValueMatcher::Regex(_) => 3,
+            ValueMatcher::Any => 4,
+        }
+    }
+
+    /// Specificity of this matcher among nodes of the same precedence
+    /// (higher wins), so longer prefixes and narrower networks are tried first.
+    ///
+    /// A CIDR matcher is as specific as its narrowest network.
+    pub fn specificity(&self) -> usize {
+        match self {
+            ValueMatcher::Cidr(networks) => networks
+                .iter()
+                .map(|network| usize::from(network.prefix_len()))
+                .max()
+                .unwrap_or(0),
+            ValueMatcher::Prefix(prefix) => prefix.len(),
+            _ => 0,
+        }
+    }
+}
+
+impl DescriptorConfig {
+    /// Get the rate limits applied at this level.
+    pub fn rules(&self) -> impl Iterator<Item = &RateLimitRule> {
+        self.rate_limit.iter().chain(&self.rate_limits)
+    }
+
+    /// Check whether this node or any of its children counts a limit
+    /// across the cluster with strong consistency.
+    fn has_strong_limits(&self) -> bool {
+        self.rules()
+            .any(|rule| rule.consistency == Consistency::Strong && rule.scope == Scope::Global)
+            || self.descriptors.iter().any(Self::has_strong_limits)
+    }
+
+    /// Validate the value matchers, aggregation and scopes of this node and its children.
+    fn validate(&self) -> Result<()> {
+        if let Some(ref aggregate) = self.aggregate {
+            aggregate.validate()?;
+        }
+        // A descriptor is counted by either the local or the cluster store
+        let mut scopes = self.rules().map(|rule| rule.scope);
+        if let Some(scope) = scopes.next() {
+            if scopes.any(|other| other != scope) {
+                return Err(HivemindError::Config(format!(
+                    "Descriptor '{}' mixes local and global rate limits",
+                    self.key
+                )));
+            }
+        }
+        // Limits after the first are keyed by their rate, so identical
+        // limits would share a counter
+        let rules: Vec<&RateLimitRule> = self.rules().collect();
+        for (index, rule) in rules.iter().enumerate() {
+            if rules[..index]
+                .iter()
+                .any(|other| other.requests_per_unit == rule.requests_per_unit && other.unit == rule.unit)
+            {
+                return Err(HivemindError::Config(format!(
+                    "Descriptor '{}' has duplicate rate limit {}/{:?}",
+                    self.key, rule.requests_per_unit, rule.unit
+                )));
+            }
+        }
+        ValueMatcher::compile(self)?;
+        self.descriptors.iter().try_for_each(Self::validate)
+    }
+}
+
 /// A rate limit rule specifying the limit and time window.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RateLimitRule {
@@ -60,8 +409,43 @@ pub struct RateLimitRule {
     /// The time unit
     pub unit: TimeUnit,
     /// Optional name/description for this limit
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub name: Option<String>,
+    /// Push increments straight to peers instead of waiting for the next
+    /// gossip round, so low limits are enforced more accurately across nodes
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub broadcast: bool,
+    /// How the limit is counted across nodes in distributed mode
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub consistency: Consistency,
+    /// Whether the limit applies to each instance or to the whole cluster
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub scope: Scope,
+}
+
+/// Whether a limit applies to each instance or to the whole cluster.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Counted by each instance on its own, such as a limit protecting the
+    /// upstream the instance fronts.
+    Local,
+    /// Counted across every node of the cluster in distributed mode.
+    #[default]
+    Global,
+}
+
+/// How a limit is counted across nodes in distributed mode.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Consistency {
+    /// Each node counts its own hits and gossips them to its peers, so nodes
+    /// may admit a little over the limit before they hear from each other.
+    #[default]
+    Eventual,
+    /// The counter is kept by a single owner node, which every other node
+    /// forwards its hits to, so the limit is never exceeded.
+    Strong,
 }
 
 /// Time unit for rate limits (matches Envoy's configuration format).
@@ -103,15 +487,39 @@ impl RateLimitConfig {
     /// Load configuration from a YAML string.
     pub fn from_yaml(yaml: &str) -> Result<Self> {
         // First, try to parse as a single domain config (Envoy's typical format)
-        if let Ok(domain_config) = serde_yaml::from_str::<DomainConfig>(yaml) {
-            let mut config = RateLimitConfig::new();
-            config.domains.insert(domain_config.domain.clone(), domain_config);
-            return Ok(config);
-        }
+        let config = if let Ok(file) = serde_yaml::from_str::<SingleDomainFile>(yaml) {
+            let mut config = RateLimitConfig {
+                unknown_domains: file.unknown_domains,
+                unknown_domain_overrides: file.unknown_domain_overrides,
+                ..RateLimitConfig::new()
+            };
+            config.domains.insert(file.domain.domain.clone(), file.domain);
+            config
+        } else {
+            // Otherwise, try to parse as a full config with multiple domains
+            serde_yaml::from_str(yaml).map_err(|e| {
+                HivemindError::Config(format!("Failed to parse rate limit config: {}", e))
+            })?
+        };
 
-        // Otherwise, try to parse as a full config with multiple domains
-        serde_yaml::from_str(yaml)
-            .map_err(|e| HivemindError::Config(format!("Failed to parse rate limit config: {}", e)))
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Validate the value matchers and aggregation of every descriptor.
+    pub fn validate(&self) -> Result<()> {
+        self.domains
+            .values()
+            .flat_map(|domain_config| &domain_config.descriptors)
+            .try_for_each(DescriptorConfig::validate)
+    }
+
+    /// Check whether any limit is counted across the cluster with strong consistency.
+    pub fn has_strong_limits(&self) -> bool {
+        self.domains
+            .values()
+            .flat_map(|domain_config| &domain_config.descriptors)
+            .any(DescriptorConfig::has_strong_limits)
     }
 
     /// Get the configuration for a specific domain.
@@ -119,73 +527,111 @@ impl RateLimitConfig {
         self.domains.get(domain)
     }
 
-    /// Find the matching rate limit rule for a descriptor within a domain.
+    /// Get the limit override to enforce for a descriptor, if any.
     ///
-    /// This performs hierarchical matching, where more specific matches take precedence.
-    pub fn find_limit(
+    /// The override sent in the descriptor is subject to the domain's
+    /// override policy, whether or not it matches a rule. Overrides with an
+    /// unknown unit are ignored, so the configured rules apply.
+    pub fn effective_override(
         &self,
         domain: &str,
         descriptor: &RateLimitDescriptor,
-    ) -> Option<&RateLimitRule> {
-        let domain_config = self.get_domain(domain)?;
-        domain_config.find_limit(descriptor)
+    ) -> Option<(u64, TimeWindow)> {
+        let limit_override = descriptor.limit.as_ref()?;
+        let window = TimeWindow::from_proto(limit_override.unit)?;
+        let requests_per_unit = limit_override.requests_per_unit as u64;
+        self.override_policy(domain).apply(requests_per_unit, window)
     }
-}
 
-impl DomainConfig {
-    /// Find the matching rate limit rule for a descriptor.
-    pub fn find_limit(&self, descriptor: &RateLimitDescriptor) -> Option<&RateLimitRule> {
-        Self::find_limit_in_descriptors(&self.descriptors, &descriptor.entries, 0)
+    /// Get the policy for limit overrides sent in a domain.
+    pub fn override_policy(&self, domain: &str) -> &OverridePolicy {
+        match self.get_domain(domain) {
+            Some(domain_config) => domain_config.overrides.as_ref().unwrap_or(&self.overrides),
+            None => self.unknown_domain_overrides.as_ref().unwrap_or(&self.overrides),
+        }
     }
 
-    /// Recursively find a matching rate limit in the descriptor tree.
-    fn find_limit_in_descriptors<'a>(
-        configs: &'a [DescriptorConfig],
-        entries: &[crate::grpc::proto::envoy::extensions::common::ratelimit::v3::rate_limit_descriptor::Entry],
-        entry_index: usize,
-    ) -> Option<&'a RateLimitRule> {
-        if entry_index >= entries.len() {
-            return None;
+    /// Get the policy for descriptors in a domain that match no rule.
+    pub fn unmatched_policy(&self, domain: &str) -> &UnmatchedPolicy {
+        match self.get_domain(domain) {
+            Some(domain_config) => domain_config.unmatched.as_ref().unwrap_or(&self.unmatched),
+            None => self.unknown_domains.as_ref().unwrap_or(&self.unmatched),
         }
+    }
 
-        let entry = &entries[entry_index];
-        let mut best_match: Option<&RateLimitRule> = None;
+    /// Hash the whole configuration.
+    ///
+    /// Hashes depend only on the content of the configuration, not on the
+    /// order of its maps, so nodes loading the same rules agree on them.
+    pub fn config_hash(&self) -> u64 {
+        stable_hash(self)
+    }
 
-        for config in configs {
-            // Check if this config matches the current entry
-            if config.key != entry.key {
-                continue;
-            }
+    /// Hash each domain's rules, along with the unmatched and override
+    /// policies that apply to it.
+    pub fn domain_hashes(&self) -> BTreeMap<String, u64> {
+        self.domains
+            .iter()
+            .map(|(name, domain)| {
+                let policies = (self.unmatched_policy(name), self.override_policy(name));
+                (name.clone(), stable_hash(&(domain, policies)))
+            })
+            .collect()
+    }
+}
 
-            // Check value match (if value is specified in config)
-            let value_matches = match &config.value {
-                Some(v) => v == &entry.value,
-                None => true, // No value specified means match any value
-            };
+/// Hash a value's JSON form, with object keys sorted, using FNV-1a.
+///
+/// The hash must not change between releases, since nodes of different
+/// versions compare their hashes. Fields left at their default are not
+/// serialized, so a new defaulted field only changes the hash of
+/// configurations that set it; new fields must keep to this.
+fn stable_hash(value: &impl Serialize) -> u64 {
+    const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
 
-            if !value_matches {
-                continue;
-            }
+    let mut json = String::new();
+    let value = serde_json::to_value(value)
+        .expect("configuration types serialize to JSON, having only string map keys");
+    canonical_json(&value, &mut json);
+    json.bytes().fold(FNV_OFFSET, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME)
+    })
+}
 
-            // This config matches - check for more specific matches in children
-            if entry_index + 1 < entries.len() && !config.descriptors.is_empty() {
-                if let Some(child_limit) = Self::find_limit_in_descriptors(
-                    &config.descriptors,
-                    entries,
-                    entry_index + 1,
-                ) {
-                    // Found a more specific match
-                    return Some(child_limit);
+/// Whether a configuration field is at its default, and left out of hashes.
+fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+/// Write a JSON value with the keys of every object sorted.
+fn canonical_json(value: &serde_json::Value, out: &mut String) {
+    match value {
+        serde_json::Value::Object(map) => {
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_by_key(|(key, _)| *key);
+            out.push('{');
+            for (i, (key, value)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
                 }
+                out.push_str(&serde_json::Value::String(key.clone()).to_string());
+                out.push(':');
+                canonical_json(value, out);
             }
-
-            // Use this level's rate limit if it exists and we haven't found a more specific one
-            if let Some(ref limit) = config.rate_limit {
-                best_match = Some(limit);
+            out.push('}');
+        }
+        serde_json::Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                canonical_json(item, out);
             }
+            out.push(']');
         }
-
-        best_match
+        other => out.push_str(&other.to_string()),
     }
 }
 
@@ -246,129 +692,463 @@ descriptors:
     }
 
     #[test]
-    fn test_find_limit_simple() {
+    fn test_config_hashes() {
+        let yaml = r#"
+domains:
+  api:
+    domain: api
+    descriptors:
+      - key: user
+        rate_limit:
+          requests_per_unit: 10
+          unit: minute
+  web:
+    domain: web
+    descriptors:
+      - key: path
+        rate_limit:
+          requests_per_unit: 100
+          unit: second
+"#;
+        let config = RateLimitConfig::from_yaml(yaml).unwrap();
+        let reloaded = RateLimitConfig::from_yaml(yaml).unwrap();
+        assert_eq!(config.config_hash(), reloaded.config_hash());
+        assert_eq!(config.domain_hashes(), reloaded.domain_hashes());
+
+        // Changing one domain's rules only changes its hash
+        let changed = RateLimitConfig::from_yaml(&yaml.replace("10\n", "20\n")).unwrap();
+        let (hashes, changed_hashes) = (config.domain_hashes(), changed.domain_hashes());
+        assert_ne!(hashes["api"], changed_hashes["api"]);
+        assert_eq!(hashes["web"], changed_hashes["web"]);
+        assert_ne!(config.config_hash(), changed.config_hash());
+
+        // The default unmatched policy is part of each domain's rules
+        let mut strict = config.clone();
+        strict.unmatched = UnmatchedPolicy::Deny;
+        assert_ne!(strict.domain_hashes()["web"], hashes["web"]);
+
+        // So is the default override policy
+        let mut strict = config.clone();
+        strict.overrides.allowed = false;
+        assert_ne!(strict.domain_hashes()["web"], hashes["web"]);
+    }
+
+    #[test]
+    fn test_config_hash_pinned() {
+        let yaml = r#"
+domains:
+  api:
+    domain: api
+    descriptors:
+      - key: user
+        rate_limit:
+          requests_per_unit: 10
+          unit: minute
+"#;
+        // Pinned, since nodes running different releases compare hashes
+        let config = RateLimitConfig::from_yaml(yaml).unwrap();
+        assert_eq!(config.config_hash(), 7861550032358018152);
+        assert_eq!(config.domain_hashes()["api"], 17263091020255687704);
+
+        // Spelling out default values leaves the hashes unchanged
+        let explicit = yaml.replace(
+            "unit: minute\n",
+            "unit: minute\n          broadcast: false\n          scope: global\noverrides:\n  allowed: true\n",
+        );
+        let explicit = RateLimitConfig::from_yaml(&explicit).unwrap();
+        assert_eq!(explicit.config_hash(), config.config_hash());
+        assert_eq!(explicit.domain_hashes(), config.domain_hashes());
+    }
+
+    #[test]
+    fn test_descriptor_limits_share_scope() {
         let yaml = r#"
 domain: test_domain
 descriptors:
-  - key: api_key
-    rate_limit:
-      requests_per_unit: 1000
-      unit: minute
+  - key: upstream
+    rate_limits:
+      - requests_per_unit: 100
+        unit: second
+        scope: local
+      - requests_per_unit: 1000
+        unit: minute
+        scope: local
 "#;
         let config = RateLimitConfig::from_yaml(yaml).unwrap();
-        let descriptor = create_descriptor(&[("api_key", "some_key")]);
+        let rules: Vec<Scope> = config.domains["test_domain"].descriptors[0]
+            .rules()
+            .map(|rule| rule.scope)
+            .collect();
+        assert_eq!(rules, [Scope::Local, Scope::Local]);
 
-        let limit = config.find_limit("test_domain", &descriptor);
-        assert!(limit.is_some());
-        let limit = limit.unwrap();
-        assert_eq!(limit.requests_per_unit, 1000);
-        assert_eq!(limit.unit, TimeUnit::Minute);
+        let mixed = yaml.replacen("scope: local", "scope: global", 1);
+        assert!(RateLimitConfig::from_yaml(&mixed).is_err());
     }
 
     #[test]
-    fn test_find_limit_with_value_match() {
+    fn test_duplicate_limits_rejected() {
         let yaml = r#"
 domain: test_domain
 descriptors:
-  - key: source_cluster
-    value: premium
+  - key: api_key
     rate_limit:
-      requests_per_unit: 10000
+      requests_per_unit: 10
       unit: second
+    rate_limits:
+      - requests_per_unit: 10
+        unit: minute
+      - requests_per_unit: 10
+        unit: second
+        name: again
+"#;
+        let err = RateLimitConfig::from_yaml(yaml).unwrap_err();
+        assert!(err.to_string().contains("duplicate rate limit"));
+
+        let distinct = yaml.replacen("unit: second\n        name", "unit: hour\n        name", 1);
+        assert!(RateLimitConfig::from_yaml(&distinct).is_ok());
+    }
+
+    #[test]
+    fn test_consistency_defaults_to_eventual() {
+        let yaml = r#"
+domain: test_domain
+descriptors:
+  - key: api_key
+    rate_limit:
+      requests_per_unit: 100
+      unit: day
+      consistency: strong
   - key: source_cluster
-    value: basic
     rate_limit:
       requests_per_unit: 100
       unit: second
 "#;
         let config = RateLimitConfig::from_yaml(yaml).unwrap();
+        let descriptors = &config.domains["test_domain"].descriptors;
+        let consistency = |i: usize| descriptors[i].rate_limit.as_ref().unwrap().consistency;
+        assert_eq!(consistency(0), Consistency::Strong);
+        assert_eq!(consistency(1), Consistency::Eventual);
+        assert!(config.has_strong_limits());
 
-        // Premium tier
-        let descriptor = create_descriptor(&[("source_cluster", "premium")]);
-        let limit = config.find_limit("test_domain", &descriptor).unwrap();
-        assert_eq!(limit.requests_per_unit, 10000);
+        // Strong limits counted by each instance don't need a peer
+        let local = yaml.replace("consistency: strong", "consistency: strong\n      scope: local");
+        assert!(!RateLimitConfig::from_yaml(&local).unwrap().has_strong_limits());
+    }
+
+    fn create_override_descriptor(
+        entries: &[(&str, &str)],
+        requests_per_unit: u32,
+        window: TimeWindow,
+    ) -> RateLimitDescriptor {
+        use crate::grpc::proto::envoy::extensions::common::ratelimit::v3::rate_limit_descriptor::RateLimitOverride;
 
-        // Basic tier
-        let descriptor = create_descriptor(&[("source_cluster", "basic")]);
-        let limit = config.find_limit("test_domain", &descriptor).unwrap();
-        assert_eq!(limit.requests_per_unit, 100);
+        let mut descriptor = create_descriptor(entries);
+        descriptor.limit = Some(RateLimitOverride {
+            requests_per_unit,
+            unit: window.to_proto(),
+        });
+        descriptor
     }
 
     #[test]
-    fn test_find_limit_hierarchical() {
+    fn test_override_policy_defaults_to_allowed() {
         let yaml = r#"
 domain: test_domain
 descriptors:
-  - key: source_cluster
-    rate_limit:
-      requests_per_unit: 1000
-      unit: second
-    descriptors:
-      - key: destination_cluster
-        value: critical_service
-        rate_limit:
-          requests_per_unit: 100
-          unit: second
+  - key: api_key
 "#;
         let config = RateLimitConfig::from_yaml(yaml).unwrap();
+        let descriptor = create_override_descriptor(&[("api_key", "a")], 50, TimeWindow::Minute);
 
-        // Just source_cluster - should get top-level limit
-        let descriptor = create_descriptor(&[("source_cluster", "any")]);
-        let limit = config.find_limit("test_domain", &descriptor).unwrap();
-        assert_eq!(limit.requests_per_unit, 1000);
+        assert_eq!(
+            config.effective_override("test_domain", &descriptor),
+            Some((50, TimeWindow::Minute))
+        );
+        assert_eq!(
+            config.effective_override("other_domain", &descriptor),
+            Some((50, TimeWindow::Minute))
+        );
+        assert_eq!(config.effective_override("test_domain", &create_descriptor(&[("api_key", "a")])), None);
+    }
 
-        // source_cluster + destination_cluster - should get more specific limit
-        let descriptor = create_descriptor(&[
-            ("source_cluster", "any"),
-            ("destination_cluster", "critical_service"),
-        ]);
-        let limit = config.find_limit("test_domain", &descriptor).unwrap();
-        assert_eq!(limit.requests_per_unit, 100);
+    #[test]
+    fn test_override_policy_disallowed() {
+        let yaml = r#"
+domain: test_domain
+overrides:
+  allowed: false
+descriptors:
+  - key: api_key
+"#;
+        let config = RateLimitConfig::from_yaml(yaml).unwrap();
+        let descriptor = create_override_descriptor(&[("api_key", "a")], 50, TimeWindow::Minute);
+        assert_eq!(config.effective_override("test_domain", &descriptor), None);
     }
 
     #[test]
-    fn test_find_limit_no_match() {
+    fn test_override_policy_caps_at_max() {
         let yaml = r#"
 domain: test_domain
+overrides:
+  max:
+    requests_per_unit: 10
+    unit: second
 descriptors:
   - key: api_key
-    rate_limit:
-      requests_per_unit: 1000
+"#;
+        let config = RateLimitConfig::from_yaml(yaml).unwrap();
+
+        // 600/minute is exactly 10/second and is kept as-is
+        let descriptor = create_override_descriptor(&[("api_key", "a")], 600, TimeWindow::Minute);
+        assert_eq!(
+            config.effective_override("test_domain", &descriptor),
+            Some((600, TimeWindow::Minute))
+        );
+
+        // 601/minute exceeds the maximum rate and is capped
+        let descriptor = create_override_descriptor(&[("api_key", "a")], 601, TimeWindow::Minute);
+        assert_eq!(
+            config.effective_override("test_domain", &descriptor),
+            Some((10, TimeWindow::Second))
+        );
+    }
+
+    #[test]
+    fn test_override_policy_without_matching_rule() {
+        let yaml = r#"
+domains:
+  test_domain:
+    domain: test_domain
+    overrides:
+      max:
+        requests_per_unit: 10
+        unit: second
+    descriptors:
+      - key: api_key
+unknown_domain_overrides:
+  max:
+    requests_per_unit: 5
+    unit: second
+"#;
+        let config = RateLimitConfig::from_yaml(yaml).unwrap();
+        let descriptor = create_override_descriptor(&[("user", "a")], 1000, TimeWindow::Second);
+
+        // Descriptors matching no rule are capped by the domain's policy
+        assert_eq!(
+            config.effective_override("test_domain", &descriptor),
+            Some((10, TimeWindow::Second))
+        );
+        // Domains missing from the configuration are capped by their own policy
+        assert_eq!(
+            config.effective_override("other_domain", &descriptor),
+            Some((5, TimeWindow::Second))
+        );
+
+        let yaml = r#"
+domains: {}
+unknown_domain_overrides:
+  allowed: false
+"#;
+        let config = RateLimitConfig::from_yaml(yaml).unwrap();
+        assert_eq!(config.effective_override("other_domain", &descriptor), None);
+    }
+
+    #[test]
+    fn test_override_policy_per_domain_and_global() {
+        let yaml = r#"
+overrides:
+  allowed: false
+domains:
+  trusted:
+    domain: trusted
+    overrides:
+      allowed: true
+  other:
+    domain: other
+"#;
+        let config = RateLimitConfig::from_yaml(yaml).unwrap();
+        let descriptor = create_override_descriptor(&[("api_key", "a")], 50, TimeWindow::Minute);
+
+        assert_eq!(
+            config.effective_override("trusted", &descriptor),
+            Some((50, TimeWindow::Minute))
+        );
+        // Domains without their own policy, and unknown domains, fall back to it
+        assert_eq!(config.effective_override("other", &descriptor), None);
+        assert_eq!(config.effective_override("unknown", &descriptor), None);
+    }
+
+    #[test]
+    fn test_override_with_unknown_unit_ignored() {
+        let config = RateLimitConfig::from_yaml("domain: test_domain").unwrap();
+        let mut descriptor = create_override_descriptor(&[("api_key", "a")], 50, TimeWindow::Minute);
+        descriptor.limit.as_mut().unwrap().unit = 0;
+        assert_eq!(config.effective_override("test_domain", &descriptor), None);
+    }
+
+    #[test]
+    fn test_unmatched_policy_defaults_to_limit() {
+        let config = RateLimitConfig::new();
+        assert_eq!(
+            config.unmatched_policy("any_domain"),
+            &UnmatchedPolicy::Limit {
+                requests_per_unit: 1000,
+                unit: TimeUnit::Second,
+            }
+        );
+    }
+
+    #[test]
+    fn test_unmatched_policy_per_domain_and_global() {
+        let yaml = r#"
+unmatched:
+  action: allow
+unknown_domains:
+  action: deny
+domains:
+  strict:
+    domain: strict
+    unmatched:
+      action: limit
+      requests_per_unit: 10
       unit: minute
+  relaxed:
+    domain: relaxed
+"#;
+        let config = RateLimitConfig::from_yaml(yaml).unwrap();
+
+        assert_eq!(
+            config.unmatched_policy("strict"),
+            &UnmatchedPolicy::Limit {
+                requests_per_unit: 10,
+                unit: TimeUnit::Minute,
+            }
+        );
+        assert_eq!(config.unmatched_policy("relaxed"), &UnmatchedPolicy::Allow);
+        assert_eq!(config.unmatched_policy("unknown"), &UnmatchedPolicy::Deny);
+    }
+
+    #[test]
+    fn test_unknown_domains_inherit_unmatched_policy() {
+        let yaml = r#"
+unmatched:
+  action: allow
+domains:
+  test_domain:
+    domain: test_domain
+"#;
+        let config = RateLimitConfig::from_yaml(yaml).unwrap();
+        assert_eq!(config.unmatched_policy("unknown"), &UnmatchedPolicy::Allow);
+    }
+
+    #[test]
+    fn test_single_domain_file_sets_unknown_domains() {
+        let yaml = r#"
+domain: test_domain
+unmatched:
+  action: allow
+unknown_domains:
+  action: deny
+unknown_domain_overrides:
+  allowed: false
+"#;
+        let config = RateLimitConfig::from_yaml(yaml).unwrap();
+        assert_eq!(config.unmatched_policy("test_domain"), &UnmatchedPolicy::Allow);
+        assert_eq!(config.unmatched_policy("unknown"), &UnmatchedPolicy::Deny);
+        assert!(!config.override_policy("unknown").allowed);
+    }
+
+    #[test]
+    fn test_matching_defaults_to_lenient() {
+        let config = RateLimitConfig::from_yaml("domain: test_domain").unwrap();
+        assert_eq!(config.domains["test_domain"].matching, MatchingMode::Lenient);
+
+        let yaml = r#"
+domain: test_domain
+matching: strict
 "#;
         let config = RateLimitConfig::from_yaml(yaml).unwrap();
+        assert_eq!(config.domains["test_domain"].matching, MatchingMode::Strict);
+    }
 
-        // Different key - no match
-        let descriptor = create_descriptor(&[("other_key", "value")]);
-        let limit = config.find_limit("test_domain", &descriptor);
-        assert!(limit.is_none());
+    fn compile_matcher(yaml: &str) -> Result<ValueMatcher> {
+        let config: DescriptorConfig = serde_yaml::from_str(yaml).unwrap();
+        ValueMatcher::compile(&config)
+    }
 
-        // Different domain - no match
-        let descriptor = create_descriptor(&[("api_key", "value")]);
-        let limit = config.find_limit("other_domain", &descriptor);
-        assert!(limit.is_none());
+    #[test]
+    fn test_value_matcher_compile() {
+        assert!(matches!(
+            compile_matcher("{key: path, value: /api}").unwrap(),
+            ValueMatcher::Exact(ref v) if v == "/api"
+        ));
+        assert!(matches!(
+            compile_matcher("{key: path, value: /api/*}").unwrap(),
+            ValueMatcher::Prefix(ref p) if p == "/api/"
+        ));
+        assert!(matches!(compile_matcher("{key: path}").unwrap(), ValueMatcher::Any));
+
+        // Regexes must match the whole value
+        let matcher = compile_matcher("{key: path, value_regex: '/api/v1/users/[^/]+/orders'}").unwrap();
+        assert!(matcher.matches("/api/v1/users/42/orders"));
+        assert!(!matcher.matches("/api/v1/users/42/orders/7"));
+        assert!(!matcher.matches("/x/api/v1/users/42/orders"));
+
+        let matcher = compile_matcher("{key: remote_address, value_cidr: [10.0.0.0/8, 'fd00::/8']}").unwrap();
+        assert!(matcher.matches("10.20.30.40"));
+        assert!(matcher.matches("fd12::1"));
+        assert!(!matcher.matches("192.168.1.1"));
+        assert!(!matcher.matches("not-an-address"));
     }
 
     #[test]
-    fn test_find_limit_any_value() {
+    fn test_invalid_value_matchers_rejected() {
+        let yaml = r#"
+domain: test_domain
+descriptors:
+  - key: path
+    descriptors:
+      - key: method
+        value_regex: "(unclosed"
+"#;
+        assert!(RateLimitConfig::from_yaml(yaml).is_err());
+
+        let yaml = r#"
+domain: test_domain
+descriptors:
+  - key: path
+    value: /api
+    value_regex: "/api/.*"
+"#;
+        assert!(RateLimitConfig::from_yaml(yaml).is_err());
+
         let yaml = r#"
 domain: test_domain
 descriptors:
   - key: remote_address
-    rate_limit:
-      requests_per_unit: 50
-      unit: second
+    value_cidr: [10.0.0.0/33]
 "#;
-        let config = RateLimitConfig::from_yaml(yaml).unwrap();
+        assert!(RateLimitConfig::from_yaml(yaml).is_err());
 
-        // Should match any value for remote_address
-        let descriptor = create_descriptor(&[("remote_address", "192.168.1.1")]);
-        let limit = config.find_limit("test_domain", &descriptor).unwrap();
-        assert_eq!(limit.requests_per_unit, 50);
+        let yaml = r#"
+domain: test_domain
+descriptors:
+  - key: remote_address
+    aggregate:
+      ipv4_prefix: 40
+"#;
+        assert!(RateLimitConfig::from_yaml(yaml).is_err());
+    }
 
-        let descriptor = create_descriptor(&[("remote_address", "10.0.0.1")]);
-        let limit = config.find_limit("test_domain", &descriptor).unwrap();
-        assert_eq!(limit.requests_per_unit, 50);
+    #[test]
+    fn test_ip_aggregation() {
+        let aggregate = IpAggregation {
+            ipv4_prefix: Some(24),
+            ipv6_prefix: None,
+        };
+        assert_eq!(aggregate.apply("192.0.2.77"), Some("192.0.2.0/24".parse().unwrap()));
+        assert_eq!(aggregate.apply("2001:db8::1"), None);
+        assert_eq!(aggregate.apply("not-an-address"), None);
     }
 
     #[test]
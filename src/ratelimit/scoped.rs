@@ -0,0 +1,327 @@
+//! Rate limiter routing each descriptor by the scope of its limits.
+//!
+//! Limits protecting what a single instance fronts, such as its upstream,
+//! are counted by that instance alone (`scope: local`), while limits on a
+//! customer or API key are counted across the cluster (`scope: global`, the
+//! default). A [`ScopedRateLimiter`] holds both stores and sends each
+//! descriptor of a request to the one its limits call for. All limits of a
+//! descriptor share a scope, which configuration validation enforces, and
+//! descriptors without limits are answered by the local store.
+
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use smallvec::SmallVec;
+
+use crate::grpc::proto::envoy::extensions::common::ratelimit::v3::RateLimitDescriptor;
+use crate::grpc::proto::envoy::service::ratelimit::v3::rate_limit_response::DescriptorStatus;
+
+use super::backend::RateLimiterBackend;
+use super::compiled::CompiledConfig;
+use super::distributed::DistributedRateLimiter;
+use super::limiter::{RateLimiter, Reservation};
+use super::resolver::{Resolution, Resolutions};
+use super::rules::{RateLimitConfig, Scope};
+use super::snapshot::{Snapshot, SnapshotSource};
+
+/// A rate limiter counting local limits in-process and global limits across
+/// the cluster.
+///
+/// Both stores share one configuration, so they always switch to a new one
+/// together, and each request is resolved once and handed to the stores.
+pub struct ScopedRateLimiter {
+    /// Compiled configuration shared by both stores
+    config: Arc<ArcSwap<CompiledConfig>>,
+    /// Store for limits with local scope
+    local: RateLimiter,
+    /// Store for limits with global scope
+    global: DistributedRateLimiter,
+}
+
+/// The resolved descriptors of a request, split by the store that counts them.
+struct Routed<'a> {
+    /// Whether each descriptor, in order, is counted locally
+    local: SmallVec<[bool; 4]>,
+    /// Descriptors counted locally
+    local_resolutions: Resolutions<'a>,
+    /// Descriptors counted across the cluster
+    global_resolutions: Resolutions<'a>,
+}
+
+impl<'a> Routed<'a> {
+    /// Resolve every descriptor of a request and decide where it is counted.
+    fn new(config: &'a CompiledConfig, domain: &'a str, descriptors: &'a [RateLimitDescriptor]) -> Self {
+        let mut routed = Self {
+            local: SmallVec::new(),
+            local_resolutions: Resolutions::new(),
+            global_resolutions: Resolutions::new(),
+        };
+        for resolution in Resolution::resolve_all(config, domain, descriptors) {
+            let local = resolution.limits().iter().all(|limit| limit.scope == Scope::Local);
+            routed.local.push(local);
+            if local {
+                routed.local_resolutions.push(resolution);
+            } else {
+                routed.global_resolutions.push(resolution);
+            }
+        }
+        routed
+    }
+
+    /// Interleave the statuses of both stores back into descriptor order.
+    fn merge(&self, local: Vec<DescriptorStatus>, global: Vec<DescriptorStatus>) -> Vec<DescriptorStatus> {
+        let mut local_statuses = local.into_iter();
+        let mut global_statuses = global.into_iter();
+        self.local
+            .iter()
+            .map(|local| {
+                let statuses = if *local { &mut local_statuses } else { &mut global_statuses };
+                statuses.next().unwrap_or_default()
+            })
+            .collect()
+    }
+}
+
+impl ScopedRateLimiter {
+    /// Create a scoped rate limiter around a distributed rate limiter.
+    ///
+    /// The local store shares the distributed rate limiter's configuration.
+    pub fn new(global: DistributedRateLimiter) -> Self {
+        let config = global.shared_config();
+        Self {
+            local: RateLimiter::with_shared_config(config.clone()),
+            config,
+            global,
+        }
+    }
+
+    /// Update the rate limit configuration of both stores at once.
+    pub fn set_config(&self, config: RateLimitConfig) {
+        self.config.store(Arc::new(CompiledConfig::new(config)));
+    }
+
+    /// Get the current configuration.
+    pub fn config(&self) -> RateLimitConfig {
+        self.config.load().config().clone()
+    }
+
+    /// Get the store for limits with local scope.
+    pub fn local(&self) -> &RateLimiter {
+        &self.local
+    }
+
+    /// Get the store for limits with global scope.
+    pub fn global(&self) -> &DistributedRateLimiter {
+        &self.global
+    }
+}
+
+#[async_trait]
+impl RateLimiterBackend for ScopedRateLimiter {
+    async fn check_rate_limit(
+        &self,
+        domain: &str,
+        descriptor: &RateLimitDescriptor,
+        hits: u32,
+    ) -> DescriptorStatus {
+        self.check_rate_limits(domain, std::slice::from_ref(descriptor), hits)
+            .await
+            .remove(0)
+    }
+
+    async fn check_rate_limits(
+        &self,
+        domain: &str,
+        descriptors: &[RateLimitDescriptor],
+        hits: u32,
+    ) -> Vec<DescriptorStatus> {
+        let config = self.config.load();
+        let routed = Routed::new(&config, domain, descriptors);
+        if routed.global_resolutions.is_empty() {
+            return self.local.check_resolved(&routed.local_resolutions, hits);
+        }
+        let global = self
+            .global
+            .check_resolved(domain, &routed.global_resolutions, hits)
+            .await;
+        if routed.local_resolutions.is_empty() {
+            return global;
+        }
+        let local = self.local.check_resolved(&routed.local_resolutions, hits);
+        routed.merge(local, global)
+    }
+
+    /// Local descriptors are checked first, and their hits are released if
+    /// the global descriptors turn out to be over the limit, so a rejected
+    /// request consumes no quota in either store.
+    async fn check_rate_limits_atomic(
+        &self,
+        domain: &str,
+        descriptors: &[RateLimitDescriptor],
+        hits: u32,
+    ) -> Vec<DescriptorStatus> {
+        let config = self.config.load();
+        let routed = Routed::new(&config, domain, descriptors);
+        if routed.global_resolutions.is_empty() {
+            return self
+                .local
+                .check_atomic(domain, &routed.local_resolutions, hits, false, None)
+                .1;
+        }
+        if routed.local_resolutions.is_empty() {
+            return self
+                .global
+                .check_atomic(domain, &routed.global_resolutions, hits, false)
+                .await
+                .1;
+        }
+
+        let mut reservation = Reservation::default();
+        let (local_committed, mut local) = self.local.check_atomic(
+            domain,
+            &routed.local_resolutions,
+            hits,
+            false,
+            Some(&mut reservation),
+        );
+        let (global_committed, global) = self
+            .global
+            .check_atomic(domain, &routed.global_resolutions, hits, !local_committed)
+            .await;
+        if local_committed && !global_committed {
+            self.local.release(reservation);
+            local = self
+                .local
+                .check_atomic(domain, &routed.local_resolutions, hits, true, None)
+                .1;
+        }
+        routed.merge(local, global)
+    }
+
+    fn counts_across_cluster(&self, domain: &str, descriptors: &[RateLimitDescriptor]) -> bool {
+        let config = self.config.load();
+        let routed = Routed::new(&config, domain, descriptors);
+        !routed.global_resolutions.is_empty()
+    }
+}
+
+#[async_trait]
+impl SnapshotSource for ScopedRateLimiter {
+    /// Local counters, this node's share of the cluster counters and the
+    /// strongly consistent counters it holds are recorded in the same snapshot.
+    async fn snapshot(&self) -> Snapshot {
+        let mut snapshot = self.local.snapshot().await;
+        let global = self.global.snapshot().await;
+        snapshot.cluster_counters = global.cluster_counters;
+        snapshot.owned_counters = global.owned_counters;
+        snapshot
+    }
+
+    async fn restore(&self, snapshot: &Snapshot) -> usize {
+        self.local.restore(snapshot).await + self.global.restore(snapshot).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grpc::proto::envoy::extensions::common::ratelimit::v3::rate_limit_descriptor::Entry;
+    use crate::grpc::proto::envoy::service::ratelimit::v3::rate_limit_response::Code;
+    use crate::mesh::{Cluster, ClusterConfig};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    const CONFIG: &str = r#"
+domain: test_domain
+descriptors:
+  - key: upstream
+    rate_limit:
+      requests_per_unit: 2
+      unit: hour
+      scope: local
+  - key: customer
+    rate_limit:
+      requests_per_unit: 1
+      unit: hour
+"#;
+
+    fn create_test_descriptor(key: &str, value: &str) -> RateLimitDescriptor {
+        RateLimitDescriptor {
+            entries: vec![Entry {
+                key: key.to_string(),
+                value: value.to_string(),
+            }],
+            limit: None,
+        }
+    }
+
+    async fn start_cluster(port: u16) -> Arc<Cluster> {
+        let addr: std::net::SocketAddr = ([127, 0, 0, 1], port).into();
+        let config = ClusterConfig {
+            node_id: format!("test-node-{}", port),
+            listen_addr: addr,
+            advertise_addr: addr,
+            cluster_id: "test-cluster".to_string(),
+            gossip_interval: Duration::from_millis(50),
+            ..Default::default()
+        };
+        Arc::new(Cluster::start(config).await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_scoped_limiter_routes_descriptors() {
+        let cluster = start_cluster(18967).await;
+        {
+            let config = RateLimitConfig::from_yaml(CONFIG).unwrap();
+            let limiter = ScopedRateLimiter::new(DistributedRateLimiter::with_config(cluster.clone(), config));
+            let upstream = create_test_descriptor("upstream", "a");
+            let customer = create_test_descriptor("customer", "a");
+
+            let statuses = limiter
+                .check_rate_limits("test_domain", &[upstream.clone(), customer.clone()], 1)
+                .await;
+            assert!(statuses.iter().all(|s| s.code() == Code::Ok));
+
+            // Each descriptor is counted only by the store its scope calls for
+            assert_eq!(limiter.local().get_counter_value("test_domain", &upstream), Some(1));
+            assert_eq!(limiter.local().get_counter_value("test_domain", &customer), None);
+            assert_eq!(limiter.global().get_counter_value("test_domain", &customer).await, 1);
+            assert_eq!(limiter.global().get_counter_value("test_domain", &upstream).await, 0);
+
+            // Statuses come back in descriptor order
+            let statuses = limiter
+                .check_rate_limits("test_domain", &[customer.clone(), upstream.clone()], 1)
+                .await;
+            assert_eq!(statuses[0].code(), Code::OverLimit);
+            assert_eq!(statuses[1].code(), Code::Ok);
+
+            // Both stores switch to a new configuration together
+            limiter.set_config(RateLimitConfig::from_yaml(&CONFIG.replace("test_domain", "other")).unwrap());
+            assert!(limiter.local().config().get_domain("other").is_some());
+            assert!(limiter.global().config().get_domain("other").is_some());
+        }
+        Arc::try_unwrap(cluster).unwrap().shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_scoped_limiter_atomic_releases_local_hits() {
+        let cluster = start_cluster(18968).await;
+        {
+            let config = RateLimitConfig::from_yaml(CONFIG).unwrap();
+            let limiter = ScopedRateLimiter::new(DistributedRateLimiter::with_config(cluster.clone(), config));
+            let upstream = create_test_descriptor("upstream", "a");
+            let descriptors = [upstream.clone(), create_test_descriptor("customer", "a")];
+
+            let statuses = limiter.check_rate_limits_atomic("test_domain", &descriptors, 1).await;
+            assert!(statuses.iter().all(|s| s.code() == Code::Ok));
+
+            // The global limit rejects the request, so the local hit is given back
+            let statuses = limiter.check_rate_limits_atomic("test_domain", &descriptors, 1).await;
+            assert_eq!(statuses[0].code(), Code::Ok);
+            assert_eq!(statuses[1].code(), Code::OverLimit);
+            assert_eq!(limiter.local().get_counter_value("test_domain", &upstream), Some(1));
+        }
+        Arc::try_unwrap(cluster).unwrap().shutdown().await.unwrap();
+    }
+}
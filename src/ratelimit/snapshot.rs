@@ -0,0 +1,273 @@
+//! Counter snapshots that survive restarts.
+//!
+//! Counters live in memory, so without snapshots a restart resets every
+//! window, including day-long ones. A snapshot records each live counter
+//! with the start of its window in wall-clock time, which unlike the
+//! monotonic clock the counters run on is meaningful to the next process.
+//! On startup the snapshot is restored and counters whose window has ended
+//! in the meantime are discarded.
+//!
+//! Snapshots are written to a temporary file, flushed to disk and renamed
+//! over the previous one, so a crash while writing never leaves a truncated
+//! snapshot. The writes run on the blocking thread pool, so a slow disk never
+//! stalls the runtime serving checks.
+
+use std::fs::File;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::error::{HivemindError, Result};
+
+/// Version of the snapshot file format.
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// A local counter in a snapshot.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CounterSnapshot {
+    /// The encoded descriptor key
+    pub key: String,
+    /// The limit the counter was created with. Restored counters take the
+    /// limit of the rule counting them in the loaded configuration instead.
+    pub limit: u64,
+    /// Length of the counter's window in seconds
+    pub window_secs: u64,
+    /// Start of the counter's current window, in milliseconds since the Unix epoch
+    pub window_start_ms: u64,
+    /// Hits counted in the window
+    pub count: u64,
+}
+
+/// This node's share of a cluster counter in a snapshot.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClusterCounterSnapshot {
+    /// The chitchat counter key, which includes the window start
+    pub key: String,
+    /// Hits counted by this node in the window
+    pub count: u64,
+}
+
+/// A strongly consistent counter held by this node in a snapshot.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OwnedCounterSnapshot {
+    /// The chitchat counter key, which includes the window start
+    pub key: String,
+    /// Hits counted in the window
+    pub count: u64,
+    /// End of the counter's window, in seconds since the Unix epoch
+    pub expires_at: u64,
+}
+
+/// The counters of a rate limiter at a point in time.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Snapshot file format version
+    pub version: u32,
+    /// When the snapshot was taken, in milliseconds since the Unix epoch
+    pub taken_at_ms: u64,
+    /// Counters of the local rate limiter
+    #[serde(default)]
+    pub counters: Vec<CounterSnapshot>,
+    /// This node's counters in distributed mode
+    #[serde(default)]
+    pub cluster_counters: Vec<ClusterCounterSnapshot>,
+    /// Strongly consistent counters this node held in distributed mode
+    #[serde(default)]
+    pub owned_counters: Vec<OwnedCounterSnapshot>,
+}
+
+impl Snapshot {
+    /// Create an empty snapshot taken now.
+    pub fn new() -> Self {
+        Self {
+            version: SNAPSHOT_VERSION,
+            taken_at_ms: unix_millis(SystemTime::now()),
+            counters: Vec::new(),
+            cluster_counters: Vec::new(),
+            owned_counters: Vec::new(),
+        }
+    }
+
+    /// Load a snapshot from a file.
+    ///
+    /// Returns `None` if the file doesn't exist.
+    pub fn load(path: impl AsRef<Path>) -> Result<Option<Self>> {
+        let contents = match std::fs::read(path.as_ref()) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let snapshot: Snapshot = serde_json::from_slice(&contents)
+            .map_err(|e| HivemindError::Snapshot(e.to_string()))?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(HivemindError::Snapshot(format!(
+                "unsupported snapshot version {}",
+                snapshot.version
+            )));
+        }
+        Ok(Some(snapshot))
+    }
+
+    /// Write the snapshot to a file, replacing any previous snapshot.
+    pub async fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref().to_path_buf();
+        let contents =
+            serde_json::to_vec(self).map_err(|e| HivemindError::Snapshot(e.to_string()))?;
+
+        tokio::task::spawn_blocking(move || write_replacing(&path, &contents))
+            .await
+            .map_err(|e| HivemindError::Snapshot(e.to_string()))?
+    }
+
+    /// Get the number of counters in the snapshot.
+    pub fn len(&self) -> usize {
+        self.counters.len() + self.cluster_counters.len() + self.owned_counters.len()
+    }
+
+    /// Check whether the snapshot has no counters.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for Snapshot {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A rate limiter whose counters can be snapshotted and restored.
+#[async_trait]
+pub trait SnapshotSource: Send + Sync {
+    /// Take a snapshot of the live counters.
+    async fn snapshot(&self) -> Snapshot;
+
+    /// Restore counters from a snapshot, discarding those whose window has ended.
+    ///
+    /// Returns the number of counters restored.
+    async fn restore(&self, snapshot: &Snapshot) -> usize;
+}
+
+/// Restore a rate limiter's counters from the snapshot at `path`, if there is one.
+///
+/// A missing or unreadable snapshot is logged and otherwise ignored, so the
+/// limiter starts with empty counters.
+pub async fn restore_counters(source: &dyn SnapshotSource, path: &Path) -> usize {
+    match Snapshot::load(path) {
+        Ok(Some(snapshot)) => {
+            let restored = source.restore(&snapshot).await;
+            info!(
+                path = %path.display(),
+                restored = restored,
+                discarded = snapshot.len() - restored,
+                "Restored rate limit counters from snapshot"
+            );
+            restored
+        }
+        Ok(None) => {
+            info!(path = %path.display(), "No counter snapshot found");
+            0
+        }
+        Err(e) => {
+            warn!(path = %path.display(), error = %e, "Failed to load counter snapshot");
+            0
+        }
+    }
+}
+
+/// Snapshot a rate limiter's counters to `path`.
+pub async fn save_counters(source: &dyn SnapshotSource, path: &Path) -> Result<()> {
+    let snapshot = source.snapshot().await;
+    snapshot.save(path).await?;
+    debug!(
+        path = %path.display(),
+        counters = snapshot.len(),
+        "Saved counter snapshot"
+    );
+    Ok(())
+}
+
+/// Spawn a task that snapshots a rate limiter's counters every `interval`.
+pub fn spawn_snapshots(
+    source: Arc<dyn SnapshotSource>,
+    path: impl AsRef<Path>,
+    interval: Duration,
+) -> tokio::task::JoinHandle<()> {
+    let path = path.as_ref().to_path_buf();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        // The first tick completes immediately
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            if let Err(e) = save_counters(source.as_ref(), &path).await {
+                warn!(path = %path.display(), error = %e, "Failed to save counter snapshot");
+            }
+        }
+    })
+}
+
+/// Write `contents` to a temporary file next to `path` and rename it over `path`.
+///
+/// The file is synced before the rename, so the rename never exposes a file
+/// whose contents haven't reached the disk.
+fn write_replacing(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+
+    let mut file = File::create(&temp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+/// Convert a wall-clock time to milliseconds since the Unix epoch.
+pub(crate) fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_snapshot_save_and_load() {
+        let dir = std::env::temp_dir().join(format!("hivemind-snapshot-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("counters.json");
+
+        assert!(Snapshot::load(&path).unwrap().is_none());
+
+        let mut snapshot = Snapshot::new();
+        snapshot.counters.push(CounterSnapshot {
+            key: "domain:api_key=a".to_string(),
+            limit: 10,
+            window_secs: 86400,
+            window_start_ms: 1_700_000_000_000,
+            count: 7,
+        });
+        snapshot.cluster_counters.push(ClusterCounterSnapshot {
+            key: "counter|domain|domain:api_key=a|1700000000".to_string(),
+            count: 3,
+        });
+        snapshot.save(&path).await.unwrap();
+
+        assert_eq!(Snapshot::load(&path).unwrap(), Some(snapshot));
+
+        std::fs::write(&path, "not json").unwrap();
+        assert!(Snapshot::load(&path).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
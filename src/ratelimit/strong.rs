@@ -0,0 +1,142 @@
+//! Strongly consistent counters in distributed mode.
+//!
+//! Each counter of a `consistency: strong` limit is kept by the node that
+//! owns its key, and other nodes forward their increments to it over the
+//! peer service. If the owner can't be reached, it is excluded and the key
+//! is rehashed to the next owner; this node always remains a candidate, so
+//! a request is never left without an owner.
+//!
+//! An increment whose reply is lost may have been applied before the
+//! counter is rehashed, so a failing owner can make a counter overcount, but
+//! never undercount.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tracing::warn;
+
+use crate::grpc::PeerClient;
+use crate::mesh::{Cluster, CounterKey, KeyOwner, OwnedIncrement};
+
+/// Default timeout for forwarding increments to an owner.
+pub(super) const DEFAULT_FORWARD_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Counters kept by the node owning each key.
+#[derive(Debug)]
+pub(super) struct StrongCounters {
+    /// The cluster, which tracks owners and holds the counters this node owns
+    cluster: Arc<Cluster>,
+    /// Client for forwarding increments to other owners
+    peers: PeerClient,
+}
+
+impl StrongCounters {
+    /// Create strongly consistent counters on a cluster.
+    pub fn new(cluster: Arc<Cluster>, forward_timeout: Duration) -> Self {
+        Self {
+            cluster,
+            peers: PeerClient::new(forward_timeout),
+        }
+    }
+
+    /// Add `hits` to every counter at its owner and return each count, in order.
+    ///
+    /// With `atomic`, nothing is added unless every counter stays within its
+    /// limit: counters are checked by their owners, and hits committed on some
+    /// owners are released if another owner rejects them. Returns whether the
+    /// hits were added.
+    pub async fn increment(&self, counters: &[OwnedIncrement], hits: u64, atomic: bool) -> (bool, Vec<u64>) {
+        let mut totals = vec![0; counters.len()];
+        let mut committed = true;
+        let mut committed_groups: Vec<(KeyOwner, Vec<usize>)> = Vec::new();
+        let mut pending: Vec<usize> = (0..counters.len()).collect();
+        let mut excluded: Vec<String> = Vec::new();
+
+        while !pending.is_empty() {
+            let keys: Vec<&CounterKey> = pending.iter().map(|&i| &counters[i].key).collect();
+            let owners = self.cluster.counter_owners(&keys, &excluded).await;
+            let mut groups: HashMap<KeyOwner, Vec<usize>> = HashMap::new();
+            for (index, owner) in pending.drain(..).zip(owners) {
+                groups.entry(owner).or_default().push(index);
+            }
+
+            for (owner, indices) in groups {
+                let batch: Vec<OwnedIncrement> = indices.iter().map(|&i| counters[i].clone()).collect();
+                let result = match &owner {
+                    KeyOwner::Local => Ok(self.cluster.increment_owned(&batch, hits, atomic).await),
+                    KeyOwner::Peer { node_id, addr } => {
+                        self.peers.increment(*addr, node_id, &batch, hits, atomic).await
+                    }
+                };
+                match result {
+                    Ok((group_committed, group_totals)) => {
+                        for (&index, total) in indices.iter().zip(group_totals) {
+                            totals[index] = total;
+                        }
+                        if group_committed {
+                            committed_groups.push((owner, indices));
+                        } else {
+                            committed = false;
+                        }
+                    }
+                    Err(status) => {
+                        // Only peers fail, so the owner always has a node ID
+                        let node_id = owner.node_id().unwrap_or_default().to_string();
+                        warn!(
+                            owner = %node_id,
+                            error = %status,
+                            "Failed to forward increments to counter owner, rehashing"
+                        );
+                        excluded.push(node_id);
+                        pending.extend(indices);
+                    }
+                }
+            }
+        }
+
+        if !committed && hits > 0 {
+            for (owner, indices) in committed_groups {
+                let keys: Vec<CounterKey> = indices.iter().map(|&i| counters[i].key.clone()).collect();
+                self.release_at(&owner, &keys, hits).await;
+                for index in indices {
+                    totals[index] = totals[index].saturating_sub(hits);
+                }
+            }
+        }
+        (committed, totals)
+    }
+
+    /// Get the count of every counter from its owner, in order.
+    pub async fn counts(&self, counters: &[OwnedIncrement]) -> Vec<u64> {
+        self.increment(counters, 0, false).await.1
+    }
+
+    /// Take `hits` back from every counter at its owner.
+    ///
+    /// Releasing is best effort: hits on an owner that can't be reached stay
+    /// counted.
+    pub async fn release(&self, keys: &[CounterKey], hits: u64) {
+        let key_refs: Vec<&CounterKey> = keys.iter().collect();
+        let owners = self.cluster.counter_owners(&key_refs, &[]).await;
+        let mut groups: HashMap<KeyOwner, Vec<CounterKey>> = HashMap::new();
+        for (key, owner) in keys.iter().zip(owners) {
+            groups.entry(owner).or_default().push(key.clone());
+        }
+        for (owner, keys) in groups {
+            self.release_at(&owner, &keys, hits).await;
+        }
+    }
+
+    /// Take `hits` back from counters at one owner.
+    async fn release_at(&self, owner: &KeyOwner, keys: &[CounterKey], hits: u64) {
+        match owner {
+            KeyOwner::Local => self.cluster.release_owned(keys, hits),
+            KeyOwner::Peer { node_id, addr } => {
+                if let Err(status) = self.peers.release(*addr, node_id, keys, hits).await {
+                    warn!(owner = %node_id, error = %status, "Failed to release hits at counter owner");
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,166 @@
+//! Readiness of the service to make rate limit decisions.
+//!
+//! A node that has just joined a cluster hasn't yet received its peers'
+//! counters, so it would admit traffic as if every count were near zero. The
+//! node reports not-ready until it has caught up, and answers requests in
+//! the meantime according to a [`NotReadyPolicy`].
+//!
+//! During graceful shutdown the node reports not-ready again, so load
+//! balancers stop routing to it, but keeps evaluating requests until it exits.
+
+use std::str::FromStr;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+/// How rate limit requests are answered while the node is not ready.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NotReadyPolicy {
+    /// Evaluate requests against whatever state has been received so far.
+    #[default]
+    Evaluate,
+    /// Count requests, but allow them even if they are over the limit.
+    Allow,
+    /// Reject requests as over the limit without counting them.
+    Deny,
+    /// Fail requests with `UNAVAILABLE`, leaving the decision to the
+    /// client's failure mode.
+    Unavailable,
+}
+
+impl FromStr for NotReadyPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "evaluate" => Ok(Self::Evaluate),
+            "allow" => Ok(Self::Allow),
+            "deny" => Ok(Self::Deny),
+            "unavailable" => Ok(Self::Unavailable),
+            _ => Err(format!(
+                "unknown policy {:?}, expected evaluate, allow, deny or unavailable",
+                s
+            )),
+        }
+    }
+}
+
+/// Lifecycle stage of the node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadinessStatus {
+    /// Starting up; decisions may be made on incomplete state.
+    Starting,
+    /// Ready to make rate limit decisions.
+    Ready,
+    /// Shutting down; requests are still evaluated until the node exits.
+    Draining,
+}
+
+/// Shared readiness state.
+///
+/// Clones share the same state, so the task that decides readiness and the
+/// servers that report it each hold their own handle.
+#[derive(Debug, Clone)]
+pub struct Readiness {
+    state: Arc<watch::Sender<ReadinessStatus>>,
+}
+
+impl Readiness {
+    /// Create a readiness state that starts out not ready.
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(watch::Sender::new(ReadinessStatus::Starting)),
+        }
+    }
+
+    /// Create a readiness state that is ready from the start.
+    pub fn ready() -> Self {
+        let readiness = Self::new();
+        readiness.mark_ready();
+        readiness
+    }
+
+    /// Get the current status.
+    pub fn status(&self) -> ReadinessStatus {
+        *self.state.borrow()
+    }
+
+    /// Check whether the node is ready.
+    pub fn is_ready(&self) -> bool {
+        self.status() == ReadinessStatus::Ready
+    }
+
+    /// Mark the node as ready, unless it is already draining.
+    pub fn mark_ready(&self) {
+        self.state.send_if_modified(|status| {
+            let starting = *status == ReadinessStatus::Starting;
+            if starting {
+                *status = ReadinessStatus::Ready;
+            }
+            starting
+        });
+    }
+
+    /// Mark the node as draining for shutdown.
+    pub fn mark_draining(&self) {
+        self.state.send_replace(ReadinessStatus::Draining);
+    }
+
+    /// Subscribe to status changes.
+    pub fn subscribe(&self) -> watch::Receiver<ReadinessStatus> {
+        self.state.subscribe()
+    }
+
+    /// Wait until the node is ready.
+    pub async fn wait_ready(&self) {
+        let mut receiver = self.subscribe();
+        // The sender lives as long as self, so this can't fail
+        let _ = receiver
+            .wait_for(|status| *status == ReadinessStatus::Ready)
+            .await;
+    }
+}
+
+impl Default for Readiness {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_readiness() {
+        let readiness = Readiness::new();
+        assert_eq!(readiness.status(), ReadinessStatus::Starting);
+        assert!(!readiness.is_ready());
+        assert!(Readiness::ready().is_ready());
+
+        let waiter = tokio::spawn({
+            let readiness = readiness.clone();
+            async move { readiness.wait_ready().await }
+        });
+        readiness.mark_ready();
+        waiter.await.unwrap();
+        assert!(readiness.is_ready());
+
+        // A draining node stays draining
+        readiness.mark_draining();
+        readiness.mark_ready();
+        assert_eq!(readiness.status(), ReadinessStatus::Draining);
+        assert!(!readiness.is_ready());
+    }
+
+    #[test]
+    fn test_not_ready_policy_parsing() {
+        assert_eq!("evaluate".parse(), Ok(NotReadyPolicy::Evaluate));
+        assert_eq!("allow".parse(), Ok(NotReadyPolicy::Allow));
+        assert_eq!("deny".parse(), Ok(NotReadyPolicy::Deny));
+        assert_eq!("unavailable".parse(), Ok(NotReadyPolicy::Unavailable));
+        assert!("open".parse::<NotReadyPolicy>().is_err());
+    }
+}
@@ -0,0 +1,132 @@
+//! Heap allocations on the rate limit check path.
+//!
+//! A counting global allocator tracks the allocations made by the current
+//! thread, so checks against counters that already exist can be asserted
+//! not to allocate beyond the statuses they return.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use futures::executor::block_on;
+
+use hivemind::grpc::proto::envoy::extensions::common::ratelimit::v3::rate_limit_descriptor::Entry;
+use hivemind::grpc::proto::envoy::extensions::common::ratelimit::v3::RateLimitDescriptor;
+use hivemind::mesh::{AsCounterKey, CounterKeyRef};
+use hivemind::ratelimit::{CompiledConfig, RateLimitConfig, RateLimiter, Resolution};
+
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|allocations| allocations.set(allocations.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|allocations| allocations.set(allocations.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Count the allocations made by the current thread while running `f`.
+fn allocations<R>(f: impl FnOnce() -> R) -> (usize, R) {
+    let before = ALLOCATIONS.with(Cell::get);
+    let result = f();
+    (ALLOCATIONS.with(Cell::get) - before, result)
+}
+
+const CONFIG: &str = r#"
+domain: bench
+descriptors:
+  - key: remote_address
+    aggregate:
+      ipv4_prefix: 24
+    rate_limit:
+      requests_per_unit: 1000000000
+      unit: second
+  - key: api_key
+    rate_limits:
+      - requests_per_unit: 1000000000
+        unit: second
+      - requests_per_unit: 1000000000
+        unit: day
+  - key: tenant
+    descriptors:
+      - key: path
+        value: "/api/*"
+        rate_limit:
+          requests_per_unit: 1000000000
+          unit: second
+  - key: user_agent
+    rate_limit:
+      requests_per_unit: 1000000000
+      unit: second
+"#;
+
+fn entries(pairs: &[(&str, &str)]) -> RateLimitDescriptor {
+    RateLimitDescriptor {
+        entries: pairs
+            .iter()
+            .map(|(key, value)| Entry {
+                key: key.to_string(),
+                value: value.to_string(),
+            })
+            .collect(),
+        limit: None,
+    }
+}
+
+/// A request with four descriptors: one aggregated, one nested and one with two limits.
+fn request() -> Vec<RateLimitDescriptor> {
+    vec![
+        entries(&[("remote_address", "10.0.1.17")]),
+        entries(&[("api_key", "key-1")]),
+        entries(&[("tenant", "tenant-1"), ("path", "/api/items/1")]),
+        entries(&[("user_agent", "envoy/1.31")]),
+    ]
+}
+
+#[test]
+fn test_local_check_allocates_only_statuses() {
+    let limiter = RateLimiter::with_config(RateLimitConfig::from_yaml(CONFIG).unwrap());
+    let request = request();
+
+    // The first check creates the counters and the per-thread key buffer
+    block_on(limiter.check_rate_limits("bench", &request, 1));
+
+    let (count, statuses) = allocations(|| block_on(limiter.check_rate_limits("bench", &request, 1)));
+    assert_eq!(statuses.len(), 4);
+    assert_eq!(count, 1, "only the statuses should be allocated");
+}
+
+#[test]
+fn test_counter_keys_do_not_allocate() {
+    let config = CompiledConfig::new(RateLimitConfig::from_yaml(CONFIG).unwrap());
+    let request = request();
+    let key_lengths = || {
+        let resolutions = Resolution::resolve_all(&config, "bench", &request);
+        resolutions
+            .iter()
+            .flat_map(Resolution::limits)
+            .map(|limit| CounterKeyRef::new("bench", &limit.key, 0).with_chitchat_key(str::len))
+            .sum::<usize>()
+    };
+
+    // The first keys grow the per-thread key buffers
+    let expected = key_lengths();
+
+    let (count, total) = allocations(key_lengths);
+    assert_eq!(total, expected);
+    assert_eq!(count, 0, "resolving a request and formatting its counter keys should not allocate");
+}